@@ -0,0 +1,83 @@
+// click.rs
+//! Metronome click generation from a tempo and time signature, so the UI can
+//! flash a beat indicator without running its own timer.
+
+/// A single metronome click.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClickInfo {
+    /// Time of the click in seconds (same clock as the MIDI timestamps).
+    pub time: f64,
+    /// True for the first beat of a bar.
+    pub is_downbeat: bool,
+}
+
+/// Generates clicks on every beat of a bar, starting at `origin`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClickGenerator {
+    /// Tempo in quarter notes per minute.
+    pub bpm: f64,
+    /// Beats per bar.
+    pub numerator: u8,
+    /// Beat unit (4 = quarter note, 8 = eighth note).
+    pub denominator: u8,
+    /// Time of the first downbeat in seconds.
+    pub origin: f64,
+}
+
+impl ClickGenerator {
+    /// Seconds between clicks, or None if the tempo or time signature is invalid.
+    pub fn click_interval(&self) -> Option<f64> {
+        if !self.bpm.is_finite() || self.bpm <= 0.0 || self.numerator == 0 || self.denominator == 0 {
+            return None;
+        }
+        Some(60.0 / self.bpm * 4.0 / self.denominator as f64)
+    }
+
+    /// Gets the first click strictly after `after`.
+    pub fn next_click(&self, after: f64) -> Option<ClickInfo> {
+        let interval = self.click_interval()?;
+        let index = if after < self.origin {
+            0
+        } else {
+            ((after - self.origin) / interval).floor() as u64 + 1
+        };
+
+        Some(ClickInfo {
+            time: self.origin + index as f64 * interval,
+            is_downbeat: index % self.numerator as u64 == 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clicks_120_bpm_4_4() {
+        let generator = ClickGenerator { bpm: 120.0, numerator: 4, denominator: 4, origin: 0.0 };
+
+        let mut after = -1.0;
+        let mut clicks = Vec::new();
+        for _ in 0..9 {
+            let click = generator.next_click(after).unwrap();
+            clicks.push(click);
+            after = click.time;
+        }
+
+        for (i, click) in clicks.iter().enumerate() {
+            assert!((click.time - i as f64 * 0.5).abs() < 1e-9);
+            assert_eq!(click.is_downbeat, i % 4 == 0);
+        }
+
+        // Between beats
+        assert_eq!(generator.next_click(2.1), Some(ClickInfo { time: 2.5, is_downbeat: false }));
+    }
+
+    #[test]
+    fn test_invalid_tempo() {
+        let generator = ClickGenerator { bpm: 0.0, numerator: 4, denominator: 4, origin: 0.0 };
+        assert_eq!(generator.next_click(0.0), None);
+    }
+}
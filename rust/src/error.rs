@@ -0,0 +1,58 @@
+// error.rs
+
+/// Errors from decoding MIDI data and reading/writing MIDI files.
+#[derive(Debug, thiserror::Error)]
+pub enum MidiError {
+    #[error("Invalid MIDI data: {0}")]
+    InvalidData(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Timing error: {0}")]
+    TimingError(String),
+}
+
+/// Error code for success, as reported over FFI.
+pub const ERROR_NONE: i32 = 0;
+/// Error code for a null pointer or out-of-range argument, as reported over FFI.
+pub const ERROR_INVALID_ARGUMENT: i32 = 1;
+/// Error code for a panic caught at the FFI boundary.
+pub const ERROR_PANIC: i32 = 5;
+
+impl MidiError {
+    /// Stable numeric code for this error, as reported over FFI.
+    pub fn code(&self) -> i32 {
+        match self {
+            MidiError::InvalidData(_) => 2,
+            MidiError::Io(_) => 3,
+            MidiError::TimingError(_) => 4,
+        }
+    }
+}
+
+/// Outcome of an FFI call that reports why it failed; see the `*_status`
+/// functions.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiStatus {
+    Ok = 0,
+    /// A required pointer argument was null.
+    NullPointer = 1,
+    /// A length or size argument was zero or negative.
+    BadLength = 2,
+    /// The MIDI data or another argument was invalid (e.g. a truncated
+    /// message or a non-finite timestamp).
+    InvalidData = 3,
+    /// The arguments were valid but the operation failed (e.g. the buffer is full).
+    ProcessingError = 4,
+    /// The call panicked; the panic was caught at the FFI boundary.
+    Panic = 5,
+}
+
+impl From<&MidiError> for MidiStatus {
+    fn from(error: &MidiError) -> Self {
+        match error {
+            MidiError::InvalidData(_) => MidiStatus::InvalidData,
+            MidiError::Io(_) | MidiError::TimingError(_) => MidiStatus::ProcessingError,
+        }
+    }
+}
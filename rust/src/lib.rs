@@ -9,17 +9,33 @@
 //! 
 //! Expand or modify as needed for ring buffers, real-time safe data structures, etc.
 
+// The extern "C" entry points below are called from C++ and null-check their
+// pointer arguments themselves, so they are deliberately not marked `unsafe`.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
+mod click;
+mod error;
 mod midi_engine;
+mod midi_file;
 mod shared_buffer;
 mod ml;
+mod parser;
+mod timestamp;
+mod ump;
 
-use crate::midi_engine::MidiEngine;
+use crate::error::MidiStatus;
+use crate::click::ClickInfo;
+use crate::midi_engine::{
+    EngineConfig, ExpressionTarget, MessageCounts, MidiEngine, NoteFilter, RetentionPolicy, TransformConfig, VelocityCurve,
+};
 use crate::shared_buffer::{SharedMidiBuffer, MidiEvent};
-use crate::ml::{ModelContextProtocol, ModelType};
-use crate::ml::context::Insight;
+use crate::ml::{MlContext, ModelContextProtocol, ModelType};
+use crate::ml::context::{Insight, ModelMetadata, Scale, ScaleTemplate};
+use crate::ml::pattern::PatternRecognitionModel;
 use std::slice;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_void};
+use std::sync::Arc;
 
 /// If you want an error enum, define one. But for a minimal skeleton, we skip it.
 
@@ -56,6 +72,78 @@ pub extern "C" fn create_midi_engine() -> *mut RustMidiEngineHandle {
     Box::into_raw(Box::new(handle))
 }
 
+/// Engine settings for `create_midi_engine_with_config`, validated the same
+/// way as the matching setters.
+#[repr(C)]
+pub struct CEngineConfig {
+    /// Velocity curve type, parameter and table, as for `set_velocity_curve`.
+    pub curve_type: i32,
+    pub curve_param: f64,
+    pub curve_table: *const u8,
+    pub transpose: i8,
+    /// Output channel (0-15) for each input channel.
+    pub channel_map: [u8; 16],
+    pub min_note: u8,
+    pub max_note: u8,
+    pub allowed_channels: u16,
+    /// Zero or negative disables stuck-note reaping.
+    pub stuck_note_timeout: f64,
+    pub input_offset: f64,
+    pub max_sysex_size: usize,
+    pub sysex_timeout: f64,
+    /// Clock messages per quarter note (24 for MIDI 1.0 devices).
+    pub ppqn: u16,
+}
+
+/// Creates a MidiEngine with every setting applied at once, e.g. from a saved
+/// preset. Returns null if the config is null or any setting is invalid.
+/// Destroy the engine with `destroy_midi_engine`.
+#[no_mangle]
+pub extern "C" fn create_midi_engine_with_config(config: *const CEngineConfig) -> *mut RustMidiEngineHandle {
+    if config.is_null() {
+        return std::ptr::null_mut();
+    }
+    let config = unsafe { &*config };
+
+    let Some(velocity_curve) = velocity_curve_from_c(config.curve_type, config.curve_param, config.curve_table) else {
+        return std::ptr::null_mut();
+    };
+    if config.channel_map.iter().any(|&channel| channel > 15)
+        || config.min_note > config.max_note
+        || config.max_note > 127
+        || config.stuck_note_timeout.is_nan()
+        || !config.input_offset.is_finite()
+        || config.max_sysex_size == 0
+        || config.sysex_timeout.is_nan()
+        || config.sysex_timeout <= 0.0
+        || config.ppqn == 0
+    {
+        return std::ptr::null_mut();
+    }
+
+    let engine = MidiEngine::with_config(EngineConfig {
+        velocity_curve,
+        transform: TransformConfig {
+            transpose_semitones: config.transpose,
+            channel_map: config.channel_map,
+        },
+        note_filter: NoteFilter {
+            min_note: config.min_note,
+            max_note: config.max_note,
+            allowed_channels: config.allowed_channels,
+        },
+        stuck_note_timeout: (config.stuck_note_timeout > 0.0).then_some(config.stuck_note_timeout),
+        input_offset: config.input_offset,
+        max_sysex_size: config.max_sysex_size,
+        sysex_timeout: config.sysex_timeout,
+        ppqn: config.ppqn,
+        ..EngineConfig::default()
+    });
+    Box::into_raw(Box::new(RustMidiEngineHandle {
+        engine: Box::new(engine),
+    }))
+}
+
 /// Destroys the MidiEngine pointer previously created by `create_midi_engine`.
 #[no_mangle]
 pub extern "C" fn destroy_midi_engine(handle: *mut RustMidiEngineHandle) {
@@ -68,7 +156,9 @@ pub extern "C" fn destroy_midi_engine(handle: *mut RustMidiEngineHandle) {
 }
 
 /// Processes a MIDI message by copying it into the engine's storage.
-/// Returns `false` if arguments are invalid (e.g., null pointer, out of range).
+/// Returns `false` if arguments are invalid (e.g., null pointer, out of range)
+/// or the message is empty, truncated or malformed.
+/// Safe to call concurrently with the other engine functions on the same handle.
 #[no_mangle]
 pub extern "C" fn process_midi_message(
     handle: *mut RustMidiEngineHandle,
@@ -76,656 +166,3100 @@ pub extern "C" fn process_midi_message(
     len: usize,
     timestamp: f64,
 ) -> bool {
+    process_midi_message_status(handle, data, len, timestamp) == MidiStatus::Ok
+}
+
+/// Like `process_midi_message`, but returns a `MidiStatus` saying why a
+/// message was rejected.
+#[no_mangle]
+pub extern "C" fn process_midi_message_status(
+    handle: *mut RustMidiEngineHandle,
+    data: *const u8,
+    len: usize,
+    timestamp: f64,
+) -> MidiStatus {
     // Basic validation
-    if handle.is_null() || data.is_null() || len == 0 || len > midi_engine::MAX_MIDI_MESSAGE_SIZE {
-        return false;
+    if handle.is_null() || data.is_null() {
+        return MidiStatus::NullPointer;
+    }
+    if len == 0 {
+        return MidiStatus::BadLength;
+    }
+    if !timestamp.is_finite() {
+        log::error!("Rejecting MIDI message with timestamp {}", timestamp);
+        return MidiStatus::InvalidData;
     }
 
     // Convert the raw pointer to a slice for safe read
     let slice = unsafe { slice::from_raw_parts(data, len) };
 
-    // Access the engine
-    let engine_handle = unsafe { &mut *handle };
-    engine_handle.engine.process_message(slice, timestamp);
+    // Access the engine (shared: the engine synchronizes internally)
+    let engine_handle = unsafe { &*handle };
+    catch_panic(|| match engine_handle.engine.try_process_message(slice, timestamp) {
+        Ok(_) => MidiStatus::Ok,
+        Err(e) => MidiStatus::from(&e),
+    })
+}
 
-    true
+/// Runs the body of a `*_status` function, reporting a panic as
+/// `MidiStatus::Panic` rather than letting it reach C.
+fn catch_panic(f: impl FnOnce() -> MidiStatus) -> MidiStatus {
+    catch_panic_or(f, || MidiStatus::Panic)
 }
 
-/// Clears all stored messages (optional utility).
-#[no_mangle]
-pub extern "C" fn clear_midi_messages(handle: *mut RustMidiEngineHandle) {
-    if handle.is_null() {
-        return;
+/// Runs the body of an FFI function, returning `on_panic()` instead if it
+/// panics, since unwinding into C aborts the host.
+fn catch_panic_or<T>(f: impl FnOnce() -> T, on_panic: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        log::error!("Panic caught at the FFI boundary");
+        on_panic()
+    })
+}
+
+/// Error details returned over FFI.
+/// `message` is null on success; otherwise it must be freed with `free_error_message`.
+#[repr(C)]
+pub struct ErrorInfo {
+    /// 0 = none, 1 = invalid argument, 2 = invalid MIDI data, 3 = I/O error,
+    /// 4 = timing error
+    pub code: i32,
+    pub message: *mut c_char,
+}
+
+/// Result of `process_midi_message_ex`.
+#[repr(C)]
+pub struct ProcessResult {
+    pub success: bool,
+    pub error: ErrorInfo,
+}
+
+impl ProcessResult {
+    fn ok() -> Self {
+        ProcessResult {
+            success: true,
+            error: ErrorInfo { code: error::ERROR_NONE, message: std::ptr::null_mut() },
+        }
     }
-    unsafe {
-        let engine_handle = &mut *handle;
-        engine_handle.engine.clear();
+
+    fn err(code: i32, message: &str) -> Self {
+        let message = CString::new(message).map_or(std::ptr::null_mut(), CString::into_raw);
+        ProcessResult {
+            success: false,
+            error: ErrorInfo { code, message },
+        }
     }
 }
 
-/// Creates a new SharedMidiBuffer with the specified capacity.
-/// Returns an opaque pointer to the buffer.
+/// Like `process_midi_message`, but reports why a message was rejected.
+/// A panic while processing is reported with code 5 (`ERROR_PANIC`).
+/// On failure, `error.message` must be freed with `free_error_message`.
 #[no_mangle]
-pub extern "C" fn create_shared_midi_buffer(capacity: usize) -> *mut SharedMidiBufferHandle {
-    let buffer = SharedMidiBuffer::new(capacity);
-    let handle = SharedMidiBufferHandle {
-        buffer: Box::new(buffer),
-    };
-    Box::into_raw(Box::new(handle))
-}
+pub extern "C" fn process_midi_message_ex(
+    handle: *mut RustMidiEngineHandle,
+    data: *const u8,
+    len: usize,
+    timestamp: f64,
+) -> ProcessResult {
+    if handle.is_null() || data.is_null() {
+        return ProcessResult::err(error::ERROR_INVALID_ARGUMENT, "null pointer argument");
+    }
+    if !timestamp.is_finite() {
+        return ProcessResult::err(error::ERROR_INVALID_ARGUMENT, &format!("timestamp {} is not finite", timestamp));
+    }
 
-/// Creates a SharedMidiBuffer from an existing memory address.
-/// This is useful for sharing memory between C++ and Rust.
-/// 
-/// # Safety
-/// 
-/// The caller must ensure that:
-/// - The pointer points to a valid memory region of at least `capacity` bytes
-/// - The memory remains valid for the lifetime of this object
-/// - No other code will free this memory while this object exists
-#[no_mangle]
-pub unsafe extern "C" fn create_shared_midi_buffer_from_raw(
-    buffer_ptr: *mut u8,
-    capacity: usize,
-) -> *mut SharedMidiBufferHandle {
-    let buffer = SharedMidiBuffer::from_raw(buffer_ptr, capacity);
-    let handle = SharedMidiBufferHandle {
-        buffer: Box::new(buffer),
-    };
-    Box::into_raw(Box::new(handle))
+    let slice = unsafe { slice::from_raw_parts(data, len) };
+    let engine_handle = unsafe { &*handle };
+    catch_panic_or(
+        || match engine_handle.engine.try_process_message(slice, timestamp) {
+            Ok(_) => ProcessResult::ok(),
+            Err(e) => ProcessResult::err(e.code(), &e.to_string()),
+        },
+        || ProcessResult::err(error::ERROR_PANIC, "panic while processing the message"),
+    )
 }
 
-/// Destroys a SharedMidiBuffer.
+/// Frees an error message returned in an `ErrorInfo`.
 #[no_mangle]
-pub extern "C" fn destroy_shared_midi_buffer(handle: *mut SharedMidiBufferHandle) {
-    if handle.is_null() {
+pub extern "C" fn free_error_message(message: *mut c_char) {
+    if message.is_null() {
         return;
     }
     unsafe {
-        drop(Box::from_raw(handle));
+        drop(CString::from_raw(message));
     }
 }
 
-/// Gets the raw pointer to the buffer.
-/// This is useful for sharing memory between C++ and Rust.
+/// A MIDI message passed in from C for batch processing.
+#[repr(C)]
+pub struct CMidiEventIn {
+    pub data: *const u8,
+    pub len: usize,
+    pub timestamp: f64,
+}
+
+/// Processes a batch of MIDI messages in a single FFI call.
+/// Entries with a null pointer, an out-of-range length, a non-finite timestamp or
+/// an invalid message are skipped.
+/// Returns the number of messages that were processed.
 #[no_mangle]
-pub extern "C" fn get_shared_midi_buffer_ptr(handle: *const SharedMidiBufferHandle) -> *const u8 {
-    if handle.is_null() {
-        return std::ptr::null();
+pub extern "C" fn process_midi_messages_batch(
+    handle: *mut RustMidiEngineHandle,
+    events: *const CMidiEventIn,
+    count: usize,
+) -> usize {
+    if handle.is_null() || events.is_null() || count == 0 {
+        return 0;
     }
-    unsafe {
-        let buffer_handle = &*handle;
-        buffer_handle.buffer.as_ptr()
+
+    let events = unsafe { slice::from_raw_parts(events, count) };
+    let engine_handle = unsafe { &*handle };
+
+    // A panic must not unwind into C, so the whole batch runs under one guard
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut processed = 0;
+        for event in events {
+            if event.data.is_null() || event.len == 0 || !event.timestamp.is_finite() {
+                continue;
+            }
+            let data = unsafe { slice::from_raw_parts(event.data, event.len) };
+            if engine_handle.engine.try_process_message(data, event.timestamp).is_ok() {
+                processed += 1;
+            }
+        }
+        processed
+    }));
+
+    result.unwrap_or(0)
+}
+
+/// Re-analyzes a captured session: clears the engine's stored messages and
+/// statistics (keeping its configuration), then processes the events in order
+/// with their original timestamps, so the same input can be analyzed again
+/// after changing e.g. the velocity curve or transform. Pass the raw captured
+/// input, not messages read back from the engine, which are already transformed.
+/// Returns false if an argument is null or any event was skipped as invalid
+/// (the rest are still replayed).
+#[no_mangle]
+pub extern "C" fn replay_events(
+    handle: *mut RustMidiEngineHandle,
+    events: *const CMidiEventIn,
+    count: usize,
+) -> bool {
+    if handle.is_null() || events.is_null() {
+        return false;
     }
+    unsafe { (*handle).engine.reset() };
+    process_midi_messages_batch(handle, events, count) == count
 }
 
-/// Gets the mutable raw pointer to the buffer.
-/// This is useful for sharing memory between C++ and Rust.
+/// Processes a stream of MIDI 2.0 Universal MIDI Packets.
+/// Channel-voice and system packets are downscaled to MIDI 1.0 messages and fed
+/// through the engine with the given timestamp; other packets are skipped.
+/// Returns the number of messages that were processed; messages the engine
+/// rejects are logged and not counted. Returns 0 if processing panics.
 #[no_mangle]
-pub extern "C" fn get_shared_midi_buffer_mut_ptr(handle: *mut SharedMidiBufferHandle) -> *mut u8 {
-    if handle.is_null() {
-        return std::ptr::null_mut();
+pub extern "C" fn process_ump_message(
+    handle: *mut RustMidiEngineHandle,
+    data: *const u32,
+    word_count: usize,
+    timestamp: f64,
+) -> usize {
+    if handle.is_null() || data.is_null() || word_count == 0 {
+        return 0;
     }
-    unsafe {
-        let buffer_handle = &mut *handle;
-        buffer_handle.buffer.as_mut_ptr()
+    if !timestamp.is_finite() {
+        log::error!("Rejecting UMP message with timestamp {}", timestamp);
+        return 0;
     }
+
+    let words = unsafe { slice::from_raw_parts(data, word_count) };
+    let engine_handle = unsafe { &*handle };
+
+    catch_panic_or(
+        || {
+            let mut processed = 0;
+            let mut offset = 0;
+            while offset < words.len() {
+                let packet_end = (offset + ump::packet_len(words[offset])).min(words.len());
+                if let Some(message) = ump::to_midi1(&words[offset..packet_end]) {
+                    match engine_handle.engine.try_process_message(&message, timestamp) {
+                        Ok(_) => processed += 1,
+                        Err(e) => log::warn!("Rejected UMP packet at word {}: {}", offset, e),
+                    }
+                }
+                offset = packet_end;
+            }
+            processed
+        },
+        || 0,
+    )
 }
 
-/// Writes a MIDI event to the buffer.
-/// Returns true if the write was successful, false if the buffer is full.
+/// Sets the curve applied to incoming note-on velocities.
+/// `curve_type`: 0 = linear, 1 = exponential with exponent `param`,
+/// 2 = logarithmic, 3 = custom lookup `table` of 128 output velocities.
+/// Returns false for a null handle, an unknown type, or a missing table.
 #[no_mangle]
-pub extern "C" fn write_midi_event(
-    handle: *mut SharedMidiBufferHandle,
-    data: *const u8,
-    len: usize,
-    timestamp: u64,
-    device_name: *const c_char,
+pub extern "C" fn set_velocity_curve(
+    handle: *mut RustMidiEngineHandle,
+    curve_type: i32,
+    param: f64,
+    table: *const u8,
 ) -> bool {
-    if handle.is_null() || data.is_null() || device_name.is_null() || len == 0 {
+    if handle.is_null() {
         return false;
     }
-    
-    unsafe {
-        let buffer_handle = &mut *handle;
-        
-        // Convert C string to Rust string
-        let device_name_cstr = CStr::from_ptr(device_name);
-        let device_name_str = match device_name_cstr.to_str() {
-            Ok(s) => s,
-            Err(_) => return false,
-        };
-        
-        // Convert data pointer to Vec<u8>
-        let data_slice = slice::from_raw_parts(data, len);
-        let data_vec = data_slice.to_vec();
-        
-        // Create MidiEvent
-        let event = MidiEvent {
-            data: data_vec,
-            timestamp,
-            device_name: device_name_str.to_string(),
-        };
-        
-        // Write to buffer
-        buffer_handle.buffer.write(&event)
-    }
+    let Some(curve) = velocity_curve_from_c(curve_type, param, table) else {
+        return false;
+    };
+
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine.set_velocity_curve(curve);
+    true
 }
 
-/// Reads a MIDI event from the buffer.
-/// Returns a pointer to a newly allocated MidiEvent if successful, null if the buffer is empty.
-/// The caller is responsible for freeing the returned MidiEvent using free_midi_event.
-#[repr(C)]
-pub struct CMidiEvent {
-    pub data: *mut u8,
-    pub data_len: usize,
-    pub timestamp: u64,
-    pub device_name: *mut c_char,
+/// Builds a velocity curve from its C description (see `set_velocity_curve`).
+fn velocity_curve_from_c(curve_type: i32, param: f64, table: *const u8) -> Option<VelocityCurve> {
+    let curve = match curve_type {
+        0 => VelocityCurve::Linear,
+        1 if param.is_finite() && param > 0.0 => VelocityCurve::Exponential(param),
+        2 => VelocityCurve::Logarithmic,
+        3 if !table.is_null() => {
+            let mut lookup = [0u8; 128];
+            lookup.copy_from_slice(unsafe { slice::from_raw_parts(table, 128) });
+            VelocityCurve::Custom(lookup)
+        },
+        _ => return None,
+    };
+    Some(curve)
 }
 
+/// Sets the transposition (in semitones) applied to incoming note-on/note-off
+/// messages. Transposed notes outside 0-127 are dropped.
 #[no_mangle]
-pub extern "C" fn read_midi_event(handle: *mut SharedMidiBufferHandle) -> *mut CMidiEvent {
+pub extern "C" fn set_transpose(handle: *mut RustMidiEngineHandle, semitones: i8) -> bool {
     if handle.is_null() {
-        return std::ptr::null_mut();
-    }
-    
-    unsafe {
-        let buffer_handle = &mut *handle;
-        
-        // Try to read an event
-        match buffer_handle.buffer.read() {
-            Some(event) => {
-                // Allocate memory for data
-                let data_len = event.data.len();
-                let data = libc::malloc(data_len) as *mut u8;
-                if data.is_null() {
-                    return std::ptr::null_mut();
-                }
-                
-                // Copy data
-                std::ptr::copy_nonoverlapping(event.data.as_ptr(), data, data_len);
-                
-                // Allocate memory for device name
-                let device_name_len = event.device_name.len() + 1; // +1 for null terminator
-                let device_name = libc::malloc(device_name_len) as *mut c_char;
-                if device_name.is_null() {
-                    libc::free(data as *mut libc::c_void);
-                    return std::ptr::null_mut();
-                }
-                
-                // Copy device name
-                std::ptr::copy_nonoverlapping(
-                    event.device_name.as_ptr() as *const c_char,
-                    device_name,
-                    event.device_name.len()
-                );
-                // Add null terminator
-                *device_name.add(event.device_name.len()) = 0;
-                
-                // Allocate memory for CMidiEvent
-                let c_event = libc::malloc(std::mem::size_of::<CMidiEvent>()) as *mut CMidiEvent;
-                if c_event.is_null() {
-                    libc::free(data as *mut libc::c_void);
-                    libc::free(device_name as *mut libc::c_void);
-                    return std::ptr::null_mut();
-                }
-                
-                // Initialize CMidiEvent
-                (*c_event).data = data;
-                (*c_event).data_len = data_len;
-                (*c_event).timestamp = event.timestamp;
-                (*c_event).device_name = device_name;
-                
-                c_event
-            },
-            None => std::ptr::null_mut(),
-        }
+        return false;
     }
+    let engine = unsafe { &(*handle).engine };
+    engine.update_transform(|transform| transform.transpose_semitones = semitones);
+    true
 }
 
-/// Frees a MidiEvent that was returned by read_midi_event.
+/// Remaps input channel `from` (0-15) of channel-voice messages to channel `to`.
+/// Returns false for a null handle or an out-of-range channel.
 #[no_mangle]
-pub extern "C" fn free_midi_event(event: *mut CMidiEvent) {
-    if event.is_null() {
-        return;
-    }
-    
-    unsafe {
-        // Free data
-        if !(*event).data.is_null() {
-            libc::free((*event).data as *mut libc::c_void);
-        }
-        
-        // Free device name
-        if !(*event).device_name.is_null() {
-            libc::free((*event).device_name as *mut libc::c_void);
-        }
-        
-        // Free event
-        libc::free(event as *mut libc::c_void);
+pub extern "C" fn set_channel_map(handle: *mut RustMidiEngineHandle, from: u8, to: u8) -> bool {
+    if handle.is_null() || from > 15 || to > 15 {
+        return false;
     }
+    let engine = unsafe { &(*handle).engine };
+    engine.update_transform(|transform| transform.channel_map[from as usize] = to);
+    true
 }
 
-/// Gets the current timestamp in microseconds.
+/// Restricts incoming note-on/note-off messages to notes `min_note..=max_note`
+/// on the channels set in `allowed_channels` (bit 0 = channel 0).
+/// Returns false for a null handle or an invalid range.
 #[no_mangle]
-pub extern "C" fn get_current_timestamp() -> u64 {
-    SharedMidiBuffer::current_timestamp()
+pub extern "C" fn set_note_filter(
+    handle: *mut RustMidiEngineHandle,
+    min_note: u8,
+    max_note: u8,
+    allowed_channels: u16,
+) -> bool {
+    if handle.is_null() || min_note > max_note || max_note > 127 {
+        return false;
+    }
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine.set_note_filter(NoteFilter { min_note, max_note, allowed_channels });
+    true
 }
 
-/// Creates a new ModelContext.
-/// Returns an opaque pointer to the context.
+/// Routes controller `controller` (0-127) of incoming CC messages to a channel
+/// expression dimension instead of its usual meaning. `target`: 0 = remove
+/// the mapping, 1 = pitch bend (64 = center), 2 = pressure, 3 = timbre.
+/// Returns false for a null handle, an invalid controller or an unknown target.
 #[no_mangle]
-pub extern "C" fn create_model_context() -> *mut ModelContextHandle {
-    let context = ModelContextProtocol::new();
-    let handle = ModelContextHandle {
-        context: Box::new(context),
+pub extern "C" fn map_cc(handle: *mut RustMidiEngineHandle, controller: u8, target: i32) -> bool {
+    if handle.is_null() || controller > 127 {
+        return false;
+    }
+    let target = match target {
+        0 => None,
+        1 => Some(ExpressionTarget::PitchBend),
+        2 => Some(ExpressionTarget::Pressure),
+        3 => Some(ExpressionTarget::Timbre),
+        _ => return false,
     };
-    Box::into_raw(Box::new(handle))
+    unsafe { (*handle).engine.map_cc(controller, target) };
+    true
 }
 
-/// Destroys a ModelContext.
-#[no_mangle]
-pub extern "C" fn destroy_model_context(handle: *mut ModelContextHandle) {
-    if handle.is_null() {
-        return;
-    }
-    unsafe {
-        drop(Box::from_raw(handle));
+/// Callback type for forwarded messages: (data, len, timestamp, user_data).
+/// The data is only valid for the duration of the call.
+pub type CForwardCallback = extern "C" fn(*const u8, usize, f64, *mut c_void);
+
+/// User data handed back to a C callback from whichever thread processes
+/// messages; the C side is responsible for that being safe.
+struct CallbackUserData(*mut c_void);
+
+unsafe impl Send for CallbackUserData {}
+unsafe impl Sync for CallbackUserData {}
+
+impl CallbackUserData {
+    fn get(&self) -> *mut c_void {
+        self.0
     }
 }
 
-/// Loads a model into the context.
-/// Returns true if successful, false otherwise.
+/// Registers a callback that receives every message the engine stores (after
+/// the transform), for MIDI thru. Messages that are invalid or dropped by the
+/// note filter are not forwarded. The callback runs on the thread calling
+/// `process_midi_message`, without any lock held, so it may process messages
+/// on the same engine or call this function. Passing a null callback
+/// unregisters any previously registered one; after that returns the callback
+/// is not invoked again, though a message already being forwarded still
+/// completes.
 #[no_mangle]
-pub extern "C" fn load_model(handle: *mut ModelContextHandle, model_type: i32) -> bool {
+pub extern "C" fn register_forward_callback(
+    handle: *mut RustMidiEngineHandle,
+    callback: Option<CForwardCallback>,
+    user_data: *mut c_void,
+) -> bool {
     if handle.is_null() {
         return false;
     }
     
-    let model_type = match model_type {
-        0 => ModelType::PatternRecognition,
-        1 => ModelType::StyleClassification,
-        2 => ModelType::PerformanceAnalysis,
-        _ => return false,
-    };
+    let engine_handle = unsafe { &*handle };
+    let user_data = CallbackUserData(user_data);
+    engine_handle.engine.set_forward_callback(callback.map(|callback| -> midi_engine::ForwardCallback {
+        Arc::new(move |data: &[u8], timestamp: f64| {
+            callback(data.as_ptr(), data.len(), timestamp, user_data.get());
+        })
+    }));
     
-    unsafe {
-        let context_handle = &mut *handle;
-        context_handle.context.load_model(model_type).is_ok()
-    }
+    true
 }
 
-/// Processes a MIDI event in the model context.
+/// Nudges the stored note onsets toward a grid of `grid_division` notes per
+/// whole note at `bpm`, by `strength` (0.0-1.0). Note durations are kept.
+/// Returns false for a null handle or invalid arguments.
 #[no_mangle]
-pub extern "C" fn process_model_event(
-    handle: *mut ModelContextHandle,
-    data: *const u8,
-    len: usize,
-    timestamp: u64,
-    device_name: *const c_char,
+pub extern "C" fn quantize_midi_messages(
+    handle: *mut RustMidiEngineHandle,
+    grid_division: u32,
+    bpm: f64,
+    strength: f64,
 ) -> bool {
-    if handle.is_null() || data.is_null() || device_name.is_null() || len == 0 {
+    if handle.is_null() {
         return false;
     }
-    
-    unsafe {
-        let context_handle = &mut *handle;
-        
-        // Convert C string to Rust string
-        let device_name_cstr = CStr::from_ptr(device_name);
-        let device_name_str = match device_name_cstr.to_str() {
-            Ok(s) => s,
-            Err(_) => return false,
-        };
-        
-        // Convert data pointer to Vec<u8>
-        let data_slice = slice::from_raw_parts(data, len);
-        let data_vec = data_slice.to_vec();
-        
-        // Create MidiEvent
-        let event = MidiEvent {
-            data: data_vec,
-            timestamp,
-            device_name: device_name_str.to_string(),
-        };
-        
-        // Process event
-        context_handle.context.process_event(event);
-        true
-    }
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine.quantize(grid_division, bpm, strength)
 }
 
-/// Represents an insight from the model context.
-#[repr(C)]
-pub struct CInsight {
-    /// Type of insight (0 = Pattern, 1 = Performance, 2 = Style)
-    pub insight_type: i32,
-    /// Description of the insight
-    pub description: *mut c_char,
-    /// Score or confidence (0.0 - 1.0)
-    pub score: f64,
+/// Sets how long the engine keeps stored messages. `policy`: 0 = keep every
+/// message, 1 = keep the latest `value` messages, 2 = keep the messages
+/// within `value` seconds of the newest one. Running stats are unaffected.
+/// Returns false for a null handle, an unknown policy or an invalid value.
+#[no_mangle]
+pub extern "C" fn set_retention_policy(handle: *mut RustMidiEngineHandle, policy: i32, value: f64) -> bool {
+    if handle.is_null() {
+        return false;
+    }
+    let policy = match policy {
+        0 => RetentionPolicy::Unlimited,
+        1 if value.is_finite() && value >= 0.0 => RetentionPolicy::Count(value as usize),
+        2 if value.is_finite() && value >= 0.0 => RetentionPolicy::Duration(value),
+        _ => return false,
+    };
+    unsafe { (*handle).engine.set_retention_policy(policy) };
+    true
 }
 
-/// Generates insights from the model context.
-/// Returns an array of insights and sets the count.
-/// The caller is responsible for freeing the returned insights using free_insights.
+/// Clears all stored messages (optional utility).
 #[no_mangle]
-pub extern "C" fn generate_insights(
-    handle: *mut ModelContextHandle,
-    count: *mut usize,
-) -> *mut CInsight {
-    if handle.is_null() || count.is_null() {
-        return std::ptr::null_mut();
+pub extern "C" fn clear_midi_messages(handle: *mut RustMidiEngineHandle) {
+    if handle.is_null() {
+        return;
     }
-    
     unsafe {
-        let context_handle = &*handle;
-        
-        // Generate insights
-        let insights = context_handle.context.generate_insights();
-        
-        // Set count
-        *count = insights.len();
-        
-        if insights.is_empty() {
-            return std::ptr::null_mut();
-        }
-        
-        // Allocate memory for insights
-        let c_insights = libc::malloc(insights.len() * std::mem::size_of::<CInsight>()) as *mut CInsight;
-        if c_insights.is_null() {
-            return std::ptr::null_mut();
-        }
-        
-        // Convert insights to C format
-        for (i, insight) in insights.iter().enumerate() {
-            let (insight_type, description, score) = match insight {
-                Insight::Pattern(pattern) => {
-                    let desc = format!("Pattern detected with {} events, occurred {} times", 
-                                      pattern.events.len(), pattern.occurrence_count);
-                    (0, desc, pattern.significance_score)
-                },
-                Insight::Performance { description, score, .. } => {
-                    (1, description.clone(), *score)
-                },
-                Insight::Style { style, confidence } => {
-                    let desc = format!("Style detected: {}", style);
-                    (2, desc, *confidence)
-                },
-            };
-            
-            // Convert description to C string
-            let c_description = match CString::new(description) {
-                Ok(s) => s.into_raw(),
-                Err(_) => std::ptr::null_mut(),
-            };
-            
-            // Initialize CInsight
-            let c_insight = c_insights.add(i);
-            (*c_insight).insight_type = insight_type;
-            (*c_insight).description = c_description;
-            (*c_insight).score = score;
-        }
-        
-        c_insights
+        let engine_handle = &*handle;
+        engine_handle.engine.clear();
     }
 }
 
-/// Frees insights that were returned by generate_insights.
+/// Clears the stored messages, statistics and held notes to start a fresh
+/// measurement, keeping the velocity curve, transform, note filter and
+/// stuck-note timeout.
 #[no_mangle]
-pub extern "C" fn free_insights(insights: *mut CInsight, count: usize) {
-    if insights.is_null() || count == 0 {
+pub extern "C" fn reset_midi_stats(handle: *mut RustMidiEngineHandle) {
+    if handle.is_null() {
         return;
     }
-    
-    unsafe {
-        // Free each insight
-        for i in 0..count {
-            let insight = insights.add(i);
-            
-            // Free description
-            if !(*insight).description.is_null() {
-                let _ = CString::from_raw((*insight).description);
-            }
-        }
-        
-        // Free insights array
-        libc::free(insights as *mut libc::c_void);
+    unsafe { (*handle).engine.reset() }
+}
+
+/// Releases every held note at `now`, storing a note-off for each, so the
+/// last notes of a session keep their durations (e.g. call before
+/// `reset_midi_stats` or `destroy_midi_engine`). Returns the number of notes
+/// released, or -1 if the handle is null or `now` is not finite.
+#[no_mangle]
+pub extern "C" fn finalize_midi_stats(handle: *mut RustMidiEngineHandle, now: f64) -> i32 {
+    if handle.is_null() || !now.is_finite() {
+        return -1;
     }
+    unsafe { (*handle).engine.finalize(now) as i32 }
 }
 
-// ML FFI functions
+/// Gets the most recent clock-derived tempo in BPM, or the tempo estimated
+/// from note onsets if no clock has been received.
+/// Returns -1.0 if the handle is null.
 #[no_mangle]
-pub extern "C" fn create_ml_context() -> *mut c_void {
-    // Create a new ML context
-    let context = Box::new(ml::context::MusicalContext::new());
-    Box::into_raw(context) as *mut c_void
+pub extern "C" fn get_current_bpm(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
+    }
+    unsafe { (*handle).engine.stats().current_bpm }
 }
 
+/// Gets the current tempo in microseconds per quarter note, ready for a
+/// Standard MIDI File tempo meta-event. Returns 0 if the tempo is unknown,
+/// or -1 if the handle is null.
 #[no_mangle]
-pub extern "C" fn destroy_ml_context(context: *mut c_void) {
-    // Safety: This function should only be called with a valid context pointer
-    if !context.is_null() {
-        unsafe {
-            let _ = Box::from_raw(context as *mut ml::context::MusicalContext);
-        }
+pub extern "C" fn get_tempo_us_per_quarter(handle: *const RustMidiEngineHandle) -> i64 {
+    if handle.is_null() {
+        return -1;
     }
+    unsafe { (*handle).engine.stats().tempo_us_per_quarter() as i64 }
 }
 
+/// Gets the tempo in BPM estimated from the recent note onsets.
+/// Returns -1.0 if the handle is null or there are too few onsets.
 #[no_mangle]
-pub extern "C" fn load_model_ml(context: *mut c_void, file_path: *const c_char) -> i32 {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() || file_path.is_null() {
+pub extern "C" fn get_onset_bpm(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
+    }
+    unsafe { (*handle).engine.stats().onset_bpm.unwrap_or(-1.0) }
+}
+
+/// Gets the running average tempo in BPM.
+/// Returns -1.0 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_average_bpm(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
+    }
+    unsafe { (*handle).engine.stats().average_bpm }
+}
+
+/// Gets the clock jitter (seconds) of the most recent clock interval.
+/// Returns -1.0 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_jitter(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
+    }
+    unsafe { (*handle).engine.stats().jitter }
+}
+
+/// Gets the tempo stability score (0.0-1.0, 1.0 = perfectly steady clock) over
+/// the last 96 clock intervals.
+/// Returns -1.0 if the handle is null or fewer than 96 intervals have been seen.
+#[no_mangle]
+pub extern "C" fn get_tempo_stability(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
+    }
+    unsafe { (*handle).engine.stats().tempo_stability.unwrap_or(-1.0) }
+}
+
+/// Gets the number of MIDI clock messages seen so far.
+/// Returns -1 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_clock_count(handle: *const RustMidiEngineHandle) -> i32 {
+    if handle.is_null() {
         return -1;
     }
-    
-    // Convert the file path to a Rust string
-    let file_path = unsafe {
-        CStr::from_ptr(file_path).to_string_lossy().into_owned()
-    };
-    
-    // Load the model
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy model ID
-        1
+    unsafe { (*handle).engine.stats().clock_count }
+}
+
+/// Gets the number of notes currently held. Returns -1 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_active_note_count(handle: *const RustMidiEngineHandle) -> i32 {
+    if handle.is_null() {
+        return -1;
     }
+    unsafe { (*handle).engine.active_note_count() as i32 }
 }
 
+/// Sets how long (seconds) a note may be held before it is presumed stuck and
+/// released with a synthesized note-off. Zero or a negative value disables this.
 #[no_mangle]
-pub extern "C" fn unload_model(context: *mut c_void, model_id: i32) -> bool {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() {
+pub extern "C" fn set_stuck_note_timeout(handle: *mut RustMidiEngineHandle, max_age: f64) -> bool {
+    if handle.is_null() || max_age.is_nan() {
         return false;
     }
-    
-    // Unload the model
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return true
-        true
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine.set_stuck_note_timeout((max_age > 0.0).then_some(max_age));
+    true
+}
+
+/// Sets the longest SysEx message (bytes, including 0xF0 and 0xF7) the engine
+/// accepts; the default is 65536. Non-SysEx messages are limited to 3 bytes.
+/// Returns false if the handle is null or the size is 0.
+#[no_mangle]
+pub extern "C" fn set_max_sysex_size(handle: *mut RustMidiEngineHandle, max_size: usize) -> bool {
+    if handle.is_null() || max_size == 0 {
+        return false;
     }
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine.set_max_sysex_size(max_size);
+    true
 }
 
+/// Sets how long (seconds) an unterminated SysEx may stay in progress before it
+/// is abandoned; the default is 0.5. Returns false if the handle is null or the
+/// timeout is not positive.
 #[no_mangle]
-pub extern "C" fn process_midi_message_ml(context: *mut c_void, data: *const u8, size: i32, device_name: *const c_char) {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() || data.is_null() || size <= 0 || device_name.is_null() {
-        return;
+pub extern "C" fn set_sysex_timeout(handle: *mut RustMidiEngineHandle, timeout: f64) -> bool {
+    if handle.is_null() || timeout.is_nan() || timeout <= 0.0 {
+        return false;
     }
-    
-    // Convert the device name to a Rust string
-    let device_name = unsafe {
-        CStr::from_ptr(device_name).to_string_lossy().into_owned()
-    };
-    
-    // Convert the MIDI data to a Rust slice
-    let data = unsafe {
-        std::slice::from_raw_parts(data, size as usize)
-    };
-    
-    // Process the MIDI message
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        
-        // Create a MIDI message from the data
-        let message = match data[0] & 0xF0 {
-            0x80 => ml::context::MidiMessage::NoteOff {
-                channel: (data[0] & 0x0F) as u8,
-                note: data[1],
-                velocity: data[2],
-            },
-            0x90 => ml::context::MidiMessage::NoteOn {
-                channel: (data[0] & 0x0F) as u8,
-                note: data[1],
-                velocity: data[2],
-            },
-            0xA0 => ml::context::MidiMessage::PolyphonicAftertouch {
-                channel: (data[0] & 0x0F) as u8,
-                note: data[1],
-                pressure: data[2],
-            },
-            0xB0 => ml::context::MidiMessage::ControlChange {
-                channel: (data[0] & 0x0F) as u8,
-                controller: data[1],
-                value: data[2],
-            },
-            0xC0 => ml::context::MidiMessage::ProgramChange {
-                channel: (data[0] & 0x0F) as u8,
-                program: data[1],
-            },
-            0xD0 => ml::context::MidiMessage::ChannelAftertouch {
-                channel: (data[0] & 0x0F) as u8,
-                pressure: data[1],
-            },
-            0xE0 => ml::context::MidiMessage::PitchBend {
-                channel: (data[0] & 0x0F) as u8,
-                value: ((data[2] as u16) << 7) | (data[1] as u16),
-            },
-            _ => ml::context::MidiMessage::Other,
-        };
-        
-        // Update the context with the message
-        context.update(message);
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine.set_sysex_timeout(timeout);
+    true
+}
+
+/// Sets the transport latency (seconds) subtracted from every incoming
+/// timestamp, so timing from different devices can be compared. Adjusted
+/// timestamps are clamped to 0.
+/// Returns false if the handle is null or the offset is not finite.
+#[no_mangle]
+pub extern "C" fn set_input_offset(handle: *mut RustMidiEngineHandle, offset: f64) -> bool {
+    if handle.is_null() || !offset.is_finite() {
+        return false;
     }
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine.set_input_offset(offset);
+    true
 }
 
+/// Gets the last program selected on `channel` (0-15).
+/// Returns -1 if the handle is null or the channel is out of range.
 #[no_mangle]
-pub extern "C" fn get_num_insights(context: *mut c_void, model_id: i32) -> i32 {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() {
-        return 0;
+pub extern "C" fn get_program(handle: *const RustMidiEngineHandle, channel: u8) -> i32 {
+    if handle.is_null() || channel > 15 {
+        return -1;
     }
-    
-    // Get the number of insights
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy value
-        1
+    unsafe { (*handle).engine.stats().current_program[channel as usize] as i32 }
+}
+
+/// Gets the bank selected on `channel` (0-15) as (MSB << 7) | LSB.
+/// Returns -1 if the handle is null or the channel is out of range.
+#[no_mangle]
+pub extern "C" fn get_bank(handle: *const RustMidiEngineHandle, channel: u8) -> i32 {
+    if handle.is_null() || channel > 15 {
+        return -1;
     }
+    unsafe { (*handle).engine.stats().current_bank[channel as usize] as i32 }
 }
 
+/// Checks whether the MIDI link is alive at time `now` (seconds): false once a
+/// device that sent Active Sensing has been silent for more than 300ms.
+/// Always true until the first Active Sensing message. False if the handle is null.
 #[no_mangle]
-pub extern "C" fn get_insight_description(context: *mut c_void, model_id: i32, insight_index: i32) -> *const c_char {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() || insight_index < 0 {
-        return std::ptr::null();
+pub extern "C" fn is_midi_link_alive(handle: *const RustMidiEngineHandle, now: f64) -> bool {
+    if handle.is_null() {
+        return false;
     }
-    
-    // Get the insight description
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy description
-        CString::new("Example insight").unwrap().into_raw()
+    unsafe { (*handle).engine.is_link_alive(now) }
+}
+
+/// Gets the coefficient of variation of the gaps between stored note onsets
+/// (0.0 = perfectly even). Returns -1.0 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_rhythmic_regularity(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
     }
+    unsafe { (*handle).engine.rhythmic_regularity() }
 }
 
+/// A held note returned by `get_active_notes`.
+#[repr(C)]
+pub struct CActiveNote {
+    pub note: u8,
+    pub channel: u8,
+    pub velocity: u8,
+    /// 0-16383 with 8192 as center.
+    pub pitch_bend: u16,
+    pub pressure: u8,
+    pub timbre: u8,
+    /// Timestamp of the note-on in seconds.
+    pub start_time: f64,
+}
+
+/// Copies up to `max` of the currently held notes, oldest first, into the
+/// caller-provided `out` array. Returns the number of notes written.
 #[no_mangle]
-pub extern "C" fn get_insight_score(context: *mut c_void, model_id: i32, insight_index: i32) -> f32 {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() || insight_index < 0 {
-        return 0.0;
+pub extern "C" fn get_active_notes(
+    handle: *const RustMidiEngineHandle,
+    out: *mut CActiveNote,
+    max: usize,
+) -> usize {
+    if handle.is_null() || out.is_null() || max == 0 {
+        return 0;
     }
     
-    // Get the insight score
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy score
-        0.75
+    let notes = unsafe { (*handle).engine.active_notes() };
+    let count = notes.len().min(max);
+    for (i, note) in notes.iter().take(count).enumerate() {
+        let c_note = CActiveNote {
+            note: note.note,
+            channel: note.channel,
+            velocity: note.velocity,
+            pitch_bend: note.pitch_bend,
+            pressure: note.pressure,
+            timbre: note.timbre,
+            start_time: note.start_time,
+        };
+        unsafe { out.add(i).write(c_note) };
     }
+    
+    count
 }
 
+/// Gets the pitch classes of the currently held notes as a 12-bit mask
+/// (bit 0 = C, bit 1 = C#, etc.), for consumers that only need the harmony.
+/// Returns 0 if no notes are held or the handle is null.
 #[no_mangle]
-pub extern "C" fn get_insight_type(context: *mut c_void, model_id: i32, insight_index: i32) -> i32 {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() || insight_index < 0 {
+pub extern "C" fn get_active_pitch_classes(handle: *const RustMidiEngineHandle) -> u16 {
+    if handle.is_null() {
         return 0;
     }
-    
-    // Get the insight type
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy type (0 = Pattern)
-        0
-    }
+    unsafe { (*handle).engine.active_pitch_class_set() }
 }
 
+/// Gets the mean release velocity of the explicit note-offs (0x80) received.
+/// Returns 0.0 if there have been none, or -1.0 if the handle is null.
 #[no_mangle]
-pub extern "C" fn get_model_description(context: *mut c_void, model_id: i32) -> *const c_char {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() {
-        return std::ptr::null();
+pub extern "C" fn get_average_release_velocity(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
     }
-    
-    // Get the model description
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy description
-        CString::new("Example model").unwrap().into_raw()
+    unsafe { (*handle).engine.stats().average_release_velocity }
+}
+
+/// Gets the highest release velocity of the explicit note-offs (0x80) received.
+/// Returns 0 if there have been none, or -1 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_peak_release_velocity(handle: *const RustMidiEngineHandle) -> i32 {
+    if handle.is_null() {
+        return -1;
     }
+    unsafe { (*handle).engine.stats().peak_release_velocity as i32 }
 }
 
+/// A completed note returned by `get_notes_in_range`.
+#[repr(C)]
+pub struct CCompletedNote {
+    pub note: u8,
+    pub channel: u8,
+    pub velocity: u8,
+    /// Timestamps of the note-on and note-off in seconds.
+    pub start_time: f64,
+    pub end_time: f64,
+}
+
+/// Copies up to `max` of the stored notes that sound within `start..=end`
+/// seconds, ordered by start time, into the caller-provided `out` array.
+/// Notes still held are not included. Returns the number of notes written.
 #[no_mangle]
-pub extern "C" fn get_model_version(context: *mut c_void, model_id: i32) -> *const c_char {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() {
-        return std::ptr::null();
+pub extern "C" fn get_notes_in_range(
+    handle: *const RustMidiEngineHandle,
+    start: f64,
+    end: f64,
+    out: *mut CCompletedNote,
+    max: usize,
+) -> usize {
+    if handle.is_null() || out.is_null() || max == 0 || start.is_nan() || end.is_nan() {
+        return 0;
     }
     
-    // Get the model version
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy version
-        CString::new("1.0.0").unwrap().into_raw()
+    let notes = unsafe { (*handle).engine.notes_in_range(start, end) };
+    let count = notes.len().min(max);
+    for (i, note) in notes.iter().take(count).enumerate() {
+        let c_note = CCompletedNote {
+            note: note.note,
+            channel: note.channel,
+            velocity: note.velocity,
+            start_time: note.start_time,
+            end_time: note.end_time,
+        };
+        unsafe { out.add(i).write(c_note) };
     }
+    
+    count
 }
 
+/// Gets the number of note-offs that were timestamped before their note-on.
+/// Returns -1 if the handle is null.
 #[no_mangle]
-pub extern "C" fn get_model_author(context: *mut c_void, model_id: i32) -> *const c_char {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() {
-        return std::ptr::null();
+pub extern "C" fn get_out_of_order_note_count(handle: *const RustMidiEngineHandle) -> i64 {
+    if handle.is_null() {
+        return -1;
     }
-    
-    // Get the model author
-    unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy author
-        CString::new("Example author").unwrap().into_raw()
+    unsafe { (*handle).engine.stats().out_of_order_notes as i64 }
+}
+
+/// Gets the number of MIDI clocks presumed dropped, judged from clock
+/// intervals that are a whole multiple of the one before.
+/// Returns -1 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_dropped_clock_count(handle: *const RustMidiEngineHandle) -> i64 {
+    if handle.is_null() {
+        return -1;
     }
+    unsafe { (*handle).engine.stats().dropped_clocks as i64 }
 }
 
+/// Copies the number of messages of each type processed into `out`.
+/// Returns false if the handle or `out` is null.
 #[no_mangle]
-pub extern "C" fn get_model_license(context: *mut c_void, model_id: i32) -> *const c_char {
-    // Safety: This function should only be called with a valid context pointer
-    if context.is_null() {
-        return std::ptr::null();
+pub extern "C" fn get_message_counts(handle: *const RustMidiEngineHandle, out: *mut MessageCounts) -> bool {
+    if handle.is_null() || out.is_null() {
+        return false;
     }
-    
-    // Get the model license
     unsafe {
-        let context = &mut *(context as *mut ml::context::MusicalContext);
-        // For now, just return a dummy license
-        CString::new("MIT").unwrap().into_raw()
+        *out = (*handle).engine.stats().message_counts;
     }
-}
\ No newline at end of file
+    true
+}
+
+/// The most commonly polled stats, gathered by `get_midi_snapshot`.
+#[repr(C)]
+pub struct MidiSnapshot {
+    pub current_bpm: f64,
+    pub average_bpm: f64,
+    pub jitter: f64,
+    pub clock_count: i32,
+    pub active_note_count: u32,
+    pub peak_polyphony: u32,
+    /// Pitch class of the detected key (0 = C), or -1 if none is detected.
+    pub key: i32,
+    /// Scale of the detected key: 0 = major, 1 = minor, or -1 if none is detected.
+    pub scale: i32,
+}
+
+/// Fills `out` with the engine's timing and note stats and, if `ml_context`
+/// is not null, the key detected by that ML context, in a single call.
+/// `ml_context` must be null or a context returned by `create_ml_context`;
+/// it is typed as that context so other handles, such as a
+/// `ModelContextHandle`, don't type-check as one.
+/// Returns false if the handle or `out` is null.
+#[no_mangle]
+pub extern "C" fn get_midi_snapshot(
+    handle: *const RustMidiEngineHandle,
+    ml_context: *const MlContext,
+    out: *mut MidiSnapshot,
+) -> bool {
+    if handle.is_null() || out.is_null() {
+        return false;
+    }
+    let engine = unsafe { &(*handle).engine };
+    let stats = engine.stats();
+    
+    let detected_key = unsafe { ml_context.as_ref() }.and_then(|context| context.musical_context.detected_key());
+    let (key, scale) = match detected_key {
+        Some((key, Scale::Major)) => (key as i32, 0),
+        Some((key, Scale::Minor)) => (key as i32, 1),
+        None => (-1, -1),
+    };
+    
+    unsafe {
+        *out = MidiSnapshot {
+            current_bpm: stats.current_bpm,
+            average_bpm: stats.average_bpm,
+            jitter: stats.jitter,
+            clock_count: stats.clock_count,
+            active_note_count: engine.active_note_count() as u32,
+            peak_polyphony: stats.peak_polyphony,
+            key,
+            scale,
+        };
+    }
+    true
+}
+
+/// Gets the fraction (0.0 - 1.0) of stored notes that received poly or channel
+/// aftertouch while held. Returns -1.0 if the handle is null.
+#[no_mangle]
+pub extern "C" fn get_aftertouch_usage(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
+    }
+    unsafe { (*handle).engine.aftertouch_stats().usage }
+}
+
+/// Estimates the swing ratio (percent, 50.0 = straight, ~66.7 = triplet swing)
+/// of the stored note onsets at the current clock tempo. Returns -1.0 if the
+/// handle is null, the tempo is unknown, or there are too few notes.
+#[no_mangle]
+pub extern "C" fn get_swing_ratio(handle: *const RustMidiEngineHandle) -> f64 {
+    if handle.is_null() {
+        return -1.0;
+    }
+    unsafe { (*handle).engine.swing_ratio() }
+}
+
+/// Gets the next metronome click after `after_timestamp` at the current clock
+/// tempo, in a `numerator`/`denominator` time signature with bars counted from
+/// the first clock message. `time` is -1.0 if the handle is null or the tempo
+/// or time signature is unknown.
+#[no_mangle]
+pub extern "C" fn get_next_click(
+    handle: *const RustMidiEngineHandle,
+    after_timestamp: f64,
+    numerator: u8,
+    denominator: u8,
+) -> ClickInfo {
+    let none = ClickInfo { time: -1.0, is_downbeat: false };
+    if handle.is_null() {
+        return none;
+    }
+    let engine_handle = unsafe { &*handle };
+    engine_handle.engine
+        .click_generator(numerator, denominator)
+        .next_click(after_timestamp)
+        .unwrap_or(none)
+}
+
+/// Writes the messages currently stored in the engine to a Format-0 Standard
+/// MIDI File at `path`, using `ppqn` ticks per quarter note at `tempo_bpm`.
+/// The engine keeps its messages. Returns false on invalid arguments or if the
+/// file could not be written.
+#[no_mangle]
+pub extern "C" fn export_midi_file(
+    handle: *const RustMidiEngineHandle,
+    path: *const c_char,
+    ppqn: u16,
+    tempo_bpm: f64,
+) -> bool {
+    if handle.is_null() || path.is_null() {
+        return false;
+    }
+    
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    let engine_handle = unsafe { &*handle };
+    
+    // The engine keeps seconds; files are written from microsecond timestamps
+    let events: Vec<MidiEvent> = engine_handle.engine.snapshot().into_iter()
+        .map(|event| MidiEvent {
+            data: event.data.to_vec(),
+            timestamp: timestamp::secs_to_micros(event.timestamp),
+            device_name: String::new(),
+        })
+        .collect();
+    
+    match midi_file::write_smf(&events, ppqn, tempo_bpm, &path) {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Failed to export {}: {}", path, e);
+            false
+        }
+    }
+}
+
+/// Reads a Format-0/1 Standard MIDI File and feeds its events through the engine
+/// in time order, with timestamps in seconds from the start of the file.
+/// Returns the number of events processed, or -1 if the file could not be read.
+#[no_mangle]
+pub extern "C" fn import_midi_file(handle: *mut RustMidiEngineHandle, path: *const c_char) -> i32 {
+    if handle.is_null() || path.is_null() {
+        return -1;
+    }
+    
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    let events = match midi_file::read_smf(&path) {
+        Ok(events) => events,
+        Err(e) => {
+            log::error!("Failed to import {}: {}", path, e);
+            return -1;
+        }
+    };
+    
+    let engine_handle = unsafe { &*handle };
+    let mut processed = 0;
+    for event in &events {
+        if engine_handle.engine.try_process_message(&event.data, timestamp::micros_to_secs(event.timestamp)).is_ok() {
+            processed += 1;
+        }
+    }
+    
+    processed
+}
+
+/// Creates a new SharedMidiBuffer with the specified capacity.
+/// Returns an opaque pointer to the buffer.
+#[no_mangle]
+pub extern "C" fn create_shared_midi_buffer(capacity: usize) -> *mut SharedMidiBufferHandle {
+    let buffer = SharedMidiBuffer::new(capacity);
+    let handle = SharedMidiBufferHandle {
+        buffer: Box::new(buffer),
+    };
+    Box::into_raw(Box::new(handle))
+}
+
+/// Creates a SharedMidiBuffer from an existing memory address.
+/// This is useful for sharing memory between C++ and Rust.
+/// 
+/// # Safety
+/// 
+/// The caller must ensure that:
+/// - The pointer points to a valid memory region of at least `capacity` bytes
+/// - The memory remains valid for the lifetime of this object
+/// - No other code will free this memory while this object exists
+#[no_mangle]
+pub unsafe extern "C" fn create_shared_midi_buffer_from_raw(
+    buffer_ptr: *mut u8,
+    capacity: usize,
+) -> *mut SharedMidiBufferHandle {
+    let buffer = SharedMidiBuffer::from_raw(buffer_ptr, capacity);
+    let handle = SharedMidiBufferHandle {
+        buffer: Box::new(buffer),
+    };
+    Box::into_raw(Box::new(handle))
+}
+
+/// Destroys a SharedMidiBuffer.
+#[no_mangle]
+pub extern "C" fn destroy_shared_midi_buffer(handle: *mut SharedMidiBufferHandle) {
+    if handle.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Gets the raw pointer to the buffer.
+/// This is useful for sharing memory between C++ and Rust.
+#[no_mangle]
+pub extern "C" fn get_shared_midi_buffer_ptr(handle: *const SharedMidiBufferHandle) -> *const u8 {
+    if handle.is_null() {
+        return std::ptr::null();
+    }
+    unsafe {
+        let buffer_handle = &*handle;
+        buffer_handle.buffer.as_ptr()
+    }
+}
+
+/// Gets the mutable raw pointer to the buffer.
+/// This is useful for sharing memory between C++ and Rust.
+#[no_mangle]
+pub extern "C" fn get_shared_midi_buffer_mut_ptr(handle: *mut SharedMidiBufferHandle) -> *mut u8 {
+    if handle.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe {
+        let buffer_handle = &mut *handle;
+        buffer_handle.buffer.as_mut_ptr()
+    }
+}
+
+/// Discards every unread event, keeping the buffer and its memory. Must be
+/// called from the consumer side, like the reads; writers may keep running.
+#[no_mangle]
+pub extern "C" fn clear_shared_midi_buffer(handle: *mut SharedMidiBufferHandle) {
+    if handle.is_null() {
+        return;
+    }
+    unsafe { (*handle).buffer.clear() }
+}
+
+/// Gets the number of unread events in the buffer (0 if the handle is null).
+#[no_mangle]
+pub extern "C" fn shared_buffer_event_count(handle: *const SharedMidiBufferHandle) -> usize {
+    if handle.is_null() {
+        return 0;
+    }
+    unsafe { (*handle).buffer.event_count() }
+}
+
+/// Gets the number of bytes that can still be written to the buffer
+/// (0 if the handle is null). Each event needs 20 bytes plus its data and
+/// device name.
+#[no_mangle]
+pub extern "C" fn shared_buffer_available_bytes(handle: *const SharedMidiBufferHandle) -> usize {
+    if handle.is_null() {
+        return 0;
+    }
+    unsafe { (*handle).buffer.available_bytes() }
+}
+
+/// Writes a MIDI event to the buffer.
+/// Invalid UTF-8 in the device name is replaced with U+FFFD, and names longer
+/// than `MAX_DEVICE_NAME_LEN` bytes are truncated.
+/// Returns true if the write was successful, false if the buffer is full.
+#[no_mangle]
+pub extern "C" fn write_midi_event(
+    handle: *mut SharedMidiBufferHandle,
+    data: *const u8,
+    len: usize,
+    timestamp: u64,
+    device_name: *const c_char,
+) -> bool {
+    write_midi_event_status(handle, data, len, timestamp, device_name) == MidiStatus::Ok
+}
+
+/// Like `write_midi_event`, but returns a `MidiStatus`: `ProcessingError` if
+/// the buffer is full.
+#[no_mangle]
+pub extern "C" fn write_midi_event_status(
+    handle: *mut SharedMidiBufferHandle,
+    data: *const u8,
+    len: usize,
+    timestamp: u64,
+    device_name: *const c_char,
+) -> MidiStatus {
+    if handle.is_null() || data.is_null() || device_name.is_null() {
+        return MidiStatus::NullPointer;
+    }
+    if len == 0 {
+        return MidiStatus::BadLength;
+    }
+    
+    unsafe {
+        let buffer_handle = &mut *handle;
+        
+        // Convert C string to Rust string, replacing invalid UTF-8 like `read` does
+        let device_name = CStr::from_ptr(device_name).to_string_lossy();
+        
+        // Convert data pointer to Vec<u8>
+        let data_slice = slice::from_raw_parts(data, len);
+        let data_vec = data_slice.to_vec();
+        
+        // Create MidiEvent
+        let event = MidiEvent {
+            data: data_vec,
+            timestamp,
+            device_name: device_name.into_owned(),
+        };
+        
+        // Write to buffer
+        catch_panic(|| if buffer_handle.buffer.write(&event) { MidiStatus::Ok } else { MidiStatus::ProcessingError })
+    }
+}
+
+/// A MIDI event handed to C.
+/// The struct (when returned by pointer), its data and its device name are
+/// allocated by Rust and must only be released with the matching `free_*`
+/// function, never with C's `free`.
+#[repr(C)]
+pub struct CMidiEvent {
+    pub data: *mut u8,
+    pub data_len: usize,
+    pub timestamp: u64,
+    pub device_name: *mut c_char,
+}
+
+/// Creates a `CMidiEvent` holding copies of the given event fields. The data is a
+/// boxed slice and the device name a `CString`; both must be released with
+/// `free_c_midi_event_fields`. Interior NULs in the device name are dropped.
+fn new_c_midi_event(data: &[u8], timestamp: u64, device_name: &str) -> CMidiEvent {
+    let data: Box<[u8]> = data.into();
+    let data_len = data.len();
+    let device_name = CString::new(device_name.replace('\0', "")).unwrap_or_default();
+    
+    CMidiEvent {
+        data: Box::into_raw(data) as *mut u8,
+        data_len,
+        timestamp,
+        device_name: device_name.into_raw(),
+    }
+}
+
+/// Frees the data and device name owned by a `CMidiEvent`, but not the struct itself.
+///
+/// # Safety
+///
+/// `event` must point to a `CMidiEvent` created by `new_c_midi_event`, whose
+/// fields have not been freed or modified since.
+unsafe fn free_c_midi_event_fields(event: *mut CMidiEvent) {
+    // Free data
+    if !(*event).data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut((*event).data, (*event).data_len)));
+        (*event).data = std::ptr::null_mut();
+    }
+    
+    // Free device name
+    if !(*event).device_name.is_null() {
+        drop(CString::from_raw((*event).device_name));
+        (*event).device_name = std::ptr::null_mut();
+    }
+}
+
+/// Reads a MIDI event from the buffer.
+/// Returns a pointer to a newly allocated CMidiEvent if successful, null if the buffer is empty.
+/// The caller is responsible for freeing the returned event using free_midi_event.
+#[no_mangle]
+pub extern "C" fn read_midi_event(handle: *mut SharedMidiBufferHandle) -> *mut CMidiEvent {
+    if handle.is_null() {
+        return std::ptr::null_mut();
+    }
+    
+    let buffer_handle = unsafe { &mut *handle };
+    
+    // Try to read an event
+    match buffer_handle.buffer.read() {
+        Some(event) => Box::into_raw(Box::new(new_c_midi_event(&event.data, event.timestamp, &event.device_name))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Reads up to `max` events from the buffer into the caller-provided `out` array.
+/// Unlike `read_midi_event`, no `CMidiEvent` is allocated; only each event's data
+/// and device name are, and those must be released with `free_drained_events`.
+/// Returns the number of events written.
+#[no_mangle]
+pub extern "C" fn read_midi_events_batch(
+    handle: *mut SharedMidiBufferHandle,
+    out: *mut CMidiEvent,
+    max: usize,
+) -> usize {
+    if handle.is_null() || out.is_null() || max == 0 {
+        return 0;
+    }
+    
+    let buffer_handle = unsafe { &*handle };
+    let mut events = Vec::with_capacity(max.min(1024));
+    buffer_handle.buffer.read_into(&mut events, max);
+    
+    for (i, event) in events.iter().enumerate() {
+        unsafe { out.add(i).write(new_c_midi_event(&event.data, event.timestamp, &event.device_name)) };
+    }
+    
+    events.len()
+}
+
+/// Frees a MidiEvent that was returned by read_midi_event.
+#[no_mangle]
+pub extern "C" fn free_midi_event(event: *mut CMidiEvent) {
+    if event.is_null() {
+        return;
+    }
+    
+    unsafe {
+        free_c_midi_event_fields(event);
+        
+        // Free event
+        drop(Box::from_raw(event));
+    }
+}
+
+/// Moves up to `max` stored messages out of the engine into the caller-provided
+/// `out` array, oldest first. Each event's data and device name (always empty,
+/// as the engine does not track devices) are allocated like `read_midi_event`'s
+/// and must be released with `free_drained_events`. Timestamps are converted
+/// from seconds to microseconds.
+/// Returns the number of events written.
+#[no_mangle]
+pub extern "C" fn drain_midi_messages(
+    handle: *mut RustMidiEngineHandle,
+    out: *mut CMidiEvent,
+    max: usize,
+) -> usize {
+    if handle.is_null() || out.is_null() || max == 0 {
+        return 0;
+    }
+    
+    let engine_handle = unsafe { &*handle };
+    let events = engine_handle.engine.drain(max);
+    
+    for (i, event) in events.iter().enumerate() {
+        let timestamp = timestamp::secs_to_micros(event.timestamp);
+        unsafe { out.add(i).write(new_c_midi_event(&event.data, timestamp, "")) };
+    }
+    
+    events.len()
+}
+
+/// Frees the per-event allocations of `count` events filled by `drain_midi_messages`
+/// or `read_midi_events_batch`.
+/// The array itself belongs to the caller.
+#[no_mangle]
+pub extern "C" fn free_drained_events(events: *mut CMidiEvent, count: usize) {
+    if events.is_null() {
+        return;
+    }
+    
+    unsafe {
+        for i in 0..count {
+            free_c_midi_event_fields(events.add(i));
+        }
+    }
+}
+
+/// Gets the current timestamp in microseconds.
+#[no_mangle]
+pub extern "C" fn get_current_timestamp() -> u64 {
+    SharedMidiBuffer::current_timestamp()
+}
+
+/// Creates a new ModelContext that keeps the `window_size` most recent events
+/// (0 for the default of 1000).
+/// Returns an opaque pointer to the context.
+#[no_mangle]
+pub extern "C" fn create_model_context(window_size: usize) -> *mut ModelContextHandle {
+    let context = if window_size == 0 {
+        ModelContextProtocol::new()
+    } else {
+        ModelContextProtocol::with_capacity(window_size)
+    };
+    let handle = ModelContextHandle {
+        context: Box::new(context),
+    };
+    Box::into_raw(Box::new(handle))
+}
+
+/// Destroys a ModelContext.
+#[no_mangle]
+pub extern "C" fn destroy_model_context(handle: *mut ModelContextHandle) {
+    if handle.is_null() {
+        return;
+    }
+    unsafe {
+        // Make sure the callback can never fire once destruction has begun
+        (*handle).context.clear_insight_callback();
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Loads a model into the context.
+/// Returns true if successful, false otherwise.
+#[no_mangle]
+pub extern "C" fn load_model(handle: *mut ModelContextHandle, model_type: i32) -> bool {
+    if handle.is_null() {
+        return false;
+    }
+    
+    let model_type = match model_type {
+        0 => ModelType::PatternRecognition,
+        1 => ModelType::StyleClassification,
+        2 => ModelType::PerformanceAnalysis,
+        _ => return false,
+    };
+    
+    unsafe {
+        let context_handle = &mut *handle;
+        context_handle.context.load_model(model_type).is_ok()
+    }
+}
+
+/// Sets the score (0.0 - 1.0) an insight must exceed to be reported by
+/// `generate_insights` and the insight callback. Values outside the range are
+/// clamped; the default is 0.5.
+/// Returns false if the handle is null or the threshold is NaN.
+#[no_mangle]
+pub extern "C" fn set_significance_threshold(handle: *mut ModelContextHandle, threshold: f64) -> bool {
+    if handle.is_null() || threshold.is_nan() {
+        return false;
+    }
+    
+    let context_handle = unsafe { &mut *handle };
+    context_handle.context.set_significance_threshold(threshold);
+    true
+}
+
+/// Enables or disables fuzzy matching of learned patterns. With fuzzy
+/// matching, transposed or slightly varied motifs whose intervals are within
+/// `threshold` edits of a learned pattern are reported. Disabled by default.
+/// Returns false if the handle is null.
+#[no_mangle]
+pub extern "C" fn set_fuzzy_matching(handle: *mut ModelContextHandle, enabled: bool, threshold: usize) -> bool {
+    if handle.is_null() {
+        return false;
+    }
+    
+    let context_handle = unsafe { &mut *handle };
+    context_handle.context.set_fuzzy_matching(enabled, threshold);
+    true
+}
+
+/// Saves the patterns learned by the active model to `path`.
+/// Returns true if successful, false otherwise.
+#[no_mangle]
+pub extern "C" fn save_patterns(handle: *mut ModelContextHandle, path: *const c_char) -> bool {
+    if handle.is_null() || path.is_null() {
+        return false;
+    }
+    
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    let context_handle = unsafe { &*handle };
+    match context_handle.context.save_patterns(&path) {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Failed to save patterns: {}", e);
+            false
+        }
+    }
+}
+
+/// Replaces the patterns learned by the active model with those saved by
+/// `save_patterns`.
+/// Returns true if successful, false otherwise.
+#[no_mangle]
+pub extern "C" fn load_patterns(handle: *mut ModelContextHandle, path: *const c_char) -> bool {
+    if handle.is_null() || path.is_null() {
+        return false;
+    }
+    
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    let context_handle = unsafe { &mut *handle };
+    match context_handle.context.load_patterns(&path) {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Failed to load patterns: {}", e);
+            false
+        }
+    }
+}
+
+/// Processes a MIDI event in the model context.
+#[no_mangle]
+pub extern "C" fn process_model_event(
+    handle: *mut ModelContextHandle,
+    data: *const u8,
+    len: usize,
+    timestamp: u64,
+    device_name: *const c_char,
+) -> bool {
+    if handle.is_null() || data.is_null() || device_name.is_null() || len == 0 {
+        return false;
+    }
+    
+    unsafe {
+        let context_handle = &mut *handle;
+        
+        // Convert C string to Rust string, replacing invalid UTF-8 like `write_midi_event` does
+        let device_name = CStr::from_ptr(device_name).to_string_lossy().into_owned();
+        
+        // Convert data pointer to Vec<u8>
+        let data_slice = slice::from_raw_parts(data, len);
+        let data_vec = data_slice.to_vec();
+        
+        // Create MidiEvent
+        let event = MidiEvent {
+            data: data_vec,
+            timestamp,
+            device_name,
+        };
+        
+        // Process the event, then notify through a shared reference only, so
+        // a callback that calls back into the context doesn't alias a
+        // mutable borrow
+        context_handle.context.observe_event(event);
+        (*handle).context.notify_insight_callback();
+        true
+    }
+}
+
+/// Processes a batch of MIDI messages from one device in the model context,
+/// then notifies the insight callback once. Timestamps are in seconds. Runs of
+/// equal messages (within a millisecond of each other, as a controller
+/// resending the same value sends them) are processed once. Entries with a
+/// null pointer, a zero length or a non-finite timestamp are skipped.
+/// Returns the number of messages processed.
+#[no_mangle]
+pub extern "C" fn process_model_events_batch(
+    handle: *mut ModelContextHandle,
+    events: *const CMidiEventIn,
+    count: usize,
+    device_name: *const c_char,
+) -> usize {
+    if handle.is_null() || events.is_null() || device_name.is_null() || count == 0 {
+        return 0;
+    }
+    
+    let events = unsafe { slice::from_raw_parts(events, count) };
+    let device_name = unsafe { CStr::from_ptr(device_name) }.to_string_lossy();
+    let events: Vec<MidiEvent> = events.iter()
+        .filter(|event| !event.data.is_null() && event.len > 0 && event.timestamp.is_finite())
+        .map(|event| MidiEvent {
+            data: unsafe { slice::from_raw_parts(event.data, event.len) }.to_vec(),
+            timestamp: timestamp::secs_to_micros(event.timestamp),
+            device_name: device_name.to_string(),
+        })
+        .collect();
+    
+    // As in `process_model_event`, notify through a shared reference only
+    let processed = unsafe { &mut *handle }.context.observe_events(events);
+    unsafe { &*handle }.context.notify_insight_callback();
+    processed
+}
+
+/// Represents an insight from the model context.
+#[repr(C)]
+pub struct CInsight {
+    /// Type of insight (0 = Pattern, 1 = Performance, 2 = Style)
+    pub insight_type: i32,
+    /// Description of the insight
+    pub description: *mut c_char,
+    /// Score or confidence (0.0 - 1.0)
+    pub score: f64,
+}
+
+/// Splits an insight into its C-facing type code, description and score.
+fn describe_insight(insight: &Insight) -> (i32, String, f64) {
+    match insight {
+        Insight::Pattern(pattern) => {
+            let mut desc = format!("Pattern detected with {} events", pattern.events.len());
+            // Name the notes played, e.g. "(C4 E4 G4)"
+            let notes: Vec<String> = pattern.events.iter()
+                .filter(|event| event.data.len() >= 3 && event.data[0] & 0xF0 == 0x90 && event.data[2] > 0)
+                .map(|event| ml::context::note_name(event.data[1]))
+                .collect();
+            if !notes.is_empty() {
+                desc.push_str(&format!(" ({})", notes.join(" ")));
+            }
+            desc.push_str(&format!(", occurred {} times", pattern.occurrence_count));
+            (0, desc, pattern.significance_score)
+        },
+        Insight::Performance { description, score, .. } => {
+            (1, description.clone(), *score)
+        },
+        Insight::Style { style, confidence } => {
+            let desc = format!("Style detected: {}", style);
+            (2, desc, *confidence)
+        },
+    }
+}
+
+/// Converts an insight to its C representation.
+/// The description is allocated with `CString::into_raw` and must be released
+/// with `CString::from_raw` (see `free_insights`).
+fn insight_to_c(insight: &Insight) -> CInsight {
+    let (insight_type, description, score) = describe_insight(insight);
+    
+    // Convert description to C string
+    let description = match CString::new(description) {
+        Ok(s) => s.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    };
+    
+    CInsight {
+        insight_type,
+        description,
+        score,
+    }
+}
+
+/// Generates insights from the model context.
+/// Returns an array of insights and sets the count.
+/// The caller is responsible for freeing the returned insights using free_insights
+/// (never with C's `free`), passing the same count.
+#[no_mangle]
+pub extern "C" fn generate_insights(
+    handle: *mut ModelContextHandle,
+    count: *mut usize,
+) -> *mut CInsight {
+    if handle.is_null() || count.is_null() {
+        return std::ptr::null_mut();
+    }
+    
+    unsafe {
+        let context_handle = &*handle;
+        
+        // Generate insights
+        let insights = context_handle.context.generate_insights();
+        
+        // Set count
+        *count = insights.len();
+        
+        if insights.is_empty() {
+            return std::ptr::null_mut();
+        }
+        
+        // Convert insights to C format
+        let c_insights: Box<[CInsight]> = insights.iter().map(insight_to_c).collect();
+        Box::into_raw(c_insights) as *mut CInsight
+    }
+}
+
+/// Generates insights from the events of a single device.
+/// The result is freed with free_insights, like generate_insights.
+#[no_mangle]
+pub extern "C" fn generate_device_insights(
+    handle: *mut ModelContextHandle,
+    device_name: *const c_char,
+    count: *mut usize,
+) -> *mut CInsight {
+    if handle.is_null() || device_name.is_null() || count.is_null() {
+        return std::ptr::null_mut();
+    }
+    
+    unsafe {
+        let context_handle = &*handle;
+        let device_name = CStr::from_ptr(device_name).to_string_lossy();
+        
+        let insights = context_handle.context.generate_insights_for_device(&device_name);
+        
+        *count = insights.len();
+        
+        if insights.is_empty() {
+            return std::ptr::null_mut();
+        }
+        
+        let c_insights: Box<[CInsight]> = insights.iter().map(insight_to_c).collect();
+        Box::into_raw(c_insights) as *mut CInsight
+    }
+}
+
+/// Frees insights that were returned by generate_insights.
+/// `count` must be the count generate_insights reported.
+#[no_mangle]
+pub extern "C" fn free_insights(insights: *mut CInsight, count: usize) {
+    if insights.is_null() || count == 0 {
+        return;
+    }
+    
+    unsafe {
+        // Free each insight
+        for i in 0..count {
+            let insight = insights.add(i);
+            
+            // Free description
+            if !(*insight).description.is_null() {
+                let _ = CString::from_raw((*insight).description);
+            }
+        }
+        
+        // Free insights array
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(insights, count)));
+    }
+}
+
+/// Callback type for pushed insights: (insights, count, user_data).
+/// The insights array and its descriptions are only valid for the duration of the call.
+pub type CInsightCallback = extern "C" fn(*const CInsight, usize, *mut c_void);
+
+/// Registers a callback invoked whenever processing an event produces insights.
+/// Passing a null callback unregisters any previously registered one.
+///
+/// The callback runs on the thread calling `process_model_event`, without
+/// any lock held, so it may call the read-only getters (e.g.
+/// `generate_insights`) or this function on the same context. A notification
+/// already under way when the callback is replaced still completes.
+#[no_mangle]
+pub extern "C" fn register_insight_callback(
+    handle: *mut ModelContextHandle,
+    callback: Option<CInsightCallback>,
+    user_data: *mut c_void,
+) -> bool {
+    if handle.is_null() {
+        return false;
+    }
+    
+    let context_handle = unsafe { &*handle };
+    let user_data = CallbackUserData(user_data);
+    
+    match callback {
+        Some(callback) => {
+            context_handle.context.set_insight_callback(Arc::new(move |insights: &[Insight]| {
+                let c_insights: Vec<CInsight> = insights.iter().map(insight_to_c).collect();
+                callback(c_insights.as_ptr(), c_insights.len(), user_data.get());
+                
+                // Reclaim the descriptions now that the callback has returned
+                for c_insight in c_insights {
+                    if !c_insight.description.is_null() {
+                        unsafe { drop(CString::from_raw(c_insight.description)) };
+                    }
+                }
+            }));
+        },
+        None => context_handle.context.clear_insight_callback(),
+    }
+    
+    true
+}
+
+// ML FFI functions
+#[no_mangle]
+pub extern "C" fn create_ml_context() -> *mut c_void {
+    // Create a new ML context
+    let context = Box::new(MlContext::new());
+    Box::into_raw(context) as *mut c_void
+}
+
+#[no_mangle]
+pub extern "C" fn destroy_ml_context(context: *mut c_void) {
+    // Safety: This function should only be called with a valid context pointer
+    if !context.is_null() {
+        unsafe {
+            let _ = Box::from_raw(context as *mut MlContext);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn load_model_ml(context: *mut c_void, file_path: *const c_char) -> i32 {
+    // Safety: This function should only be called with a valid context pointer
+    if context.is_null() || file_path.is_null() {
+        return -1;
+    }
+    
+    // Convert the file path to a Rust string
+    let file_path = unsafe {
+        CStr::from_ptr(file_path).to_string_lossy().into_owned()
+    };
+    
+    // Load the model
+    let model = match PatternRecognitionModel::from_file(&file_path) {
+        Ok(model) => model,
+        Err(e) => {
+            log::error!("{}", e);
+            return -1;
+        }
+    };
+    
+    unsafe {
+        let context = &mut *(context as *mut MlContext);
+        context.add_model(Box::new(model))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn unload_model(context: *mut c_void, model_id: i32) -> bool {
+    // Safety: This function should only be called with a valid context pointer
+    if context.is_null() {
+        return false;
+    }
+    
+    // Unload the model
+    unsafe {
+        let context = &mut *(context as *mut MlContext);
+        context.remove_model(model_id)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn process_midi_message_ml(context: *mut c_void, data: *const u8, size: i32, device_name: *const c_char) {
+    process_midi_message_ml_status(context, data, size, device_name);
+}
+
+/// Like `process_midi_message_ml`, but returns a `MidiStatus` saying why a
+/// message was ignored.
+#[no_mangle]
+pub extern "C" fn process_midi_message_ml_status(
+    context: *mut c_void,
+    data: *const u8,
+    size: i32,
+    device_name: *const c_char,
+) -> MidiStatus {
+    // Safety: This function should only be called with a valid context pointer
+    if context.is_null() || data.is_null() || device_name.is_null() {
+        return MidiStatus::NullPointer;
+    }
+    if size <= 0 {
+        return MidiStatus::BadLength;
+    }
+    
+    // Convert the device name to a Rust string
+    let device_name = unsafe {
+        CStr::from_ptr(device_name).to_string_lossy().into_owned()
+    };
+    
+    // Convert the MIDI data to a Rust slice
+    let data = unsafe {
+        std::slice::from_raw_parts(data, size as usize)
+    };
+    
+    // Process the MIDI message
+    unsafe {
+        let context = &mut *(context as *mut MlContext);
+        
+        if let Err(e) = parser::parse_midi_message(data) {
+            log::warn!("Ignoring MIDI message from {}: {}", device_name, e);
+            return MidiStatus::from(&e);
+        }
+        
+        // The ML contexts work in microseconds, like the shared buffer
+        let event = MidiEvent {
+            data: data.to_vec(),
+            timestamp: SharedMidiBuffer::current_timestamp(),
+            device_name,
+        };
+        
+        // Update the context and models with the message
+        catch_panic(|| {
+            context.process_event(&event);
+            MidiStatus::Ok
+        })
+    }
+}
+
+/// Gets the insight at `insight_index` from a loaded model, if both exist.
+fn model_insight(context: *mut c_void, model_id: i32, insight_index: i32) -> Option<Insight> {
+    if context.is_null() || insight_index < 0 {
+        return None;
+    }
+    
+    let context = unsafe { &*(context as *const MlContext) };
+    context.insight(model_id, insight_index as usize)
+}
+
+/// Gets the number of insights a loaded model currently reports.
+#[no_mangle]
+pub extern "C" fn get_num_insights(context: *mut c_void, model_id: i32) -> i32 {
+    if context.is_null() {
+        return 0;
+    }
+    
+    let context = unsafe { &*(context as *const MlContext) };
+    context.insight_count(model_id).map_or(0, |count| count as i32)
+}
+
+/// Gets the description of an insight.
+/// The returned string must be freed with `free_model_string`.
+#[no_mangle]
+pub extern "C" fn get_insight_description(context: *mut c_void, model_id: i32, insight_index: i32) -> *mut c_char {
+    match model_insight(context, model_id, insight_index) {
+        Some(insight) => match CString::new(describe_insight(&insight).1) {
+            Ok(s) => s.into_raw(),
+            Err(_) => std::ptr::null_mut(),
+        },
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Gets the score of an insight (0.0 if it does not exist).
+#[no_mangle]
+pub extern "C" fn get_insight_score(context: *mut c_void, model_id: i32, insight_index: i32) -> f32 {
+    model_insight(context, model_id, insight_index)
+        .map_or(0.0, |insight| describe_insight(&insight).2 as f32)
+}
+
+/// Gets the type of an insight (0 = Pattern, 1 = Performance, 2 = Style, -1 if it does not exist).
+#[no_mangle]
+pub extern "C" fn get_insight_type(context: *mut c_void, model_id: i32, insight_index: i32) -> i32 {
+    model_insight(context, model_id, insight_index)
+        .map_or(-1, |insight| describe_insight(&insight).0)
+}
+
+/// Copies one metadata field of a loaded model into a newly allocated C string.
+/// Returns null if the context is null or no model has the given ID.
+fn model_metadata_string(
+    context: *mut c_void,
+    model_id: i32,
+    field: impl Fn(&ModelMetadata) -> &str,
+) -> *mut c_char {
+    if context.is_null() {
+        return std::ptr::null_mut();
+    }
+    
+    let context = unsafe { &*(context as *const MlContext) };
+    match context.model(model_id) {
+        Some(model) => match CString::new(field(&model.metadata())) {
+            Ok(s) => s.into_raw(),
+            Err(_) => std::ptr::null_mut(),
+        },
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Gets the description of a loaded model.
+/// The returned string must be freed with `free_model_string`.
+#[no_mangle]
+pub extern "C" fn get_model_description(context: *mut c_void, model_id: i32) -> *mut c_char {
+    model_metadata_string(context, model_id, |metadata| &metadata.description)
+}
+
+/// Gets the version of a loaded model.
+/// The returned string must be freed with `free_model_string`.
+#[no_mangle]
+pub extern "C" fn get_model_version(context: *mut c_void, model_id: i32) -> *mut c_char {
+    model_metadata_string(context, model_id, |metadata| &metadata.version)
+}
+
+/// Gets the author of a loaded model.
+/// The returned string must be freed with `free_model_string`.
+#[no_mangle]
+pub extern "C" fn get_model_author(context: *mut c_void, model_id: i32) -> *mut c_char {
+    model_metadata_string(context, model_id, |metadata| &metadata.author)
+}
+
+/// Gets the license of a loaded model.
+/// The returned string must be freed with `free_model_string`.
+#[no_mangle]
+pub extern "C" fn get_model_license(context: *mut c_void, model_id: i32) -> *mut c_char {
+    model_metadata_string(context, model_id, |metadata| &metadata.license)
+}
+
+/// Gets the detected key of the ML context's notes, e.g. "C" or "F#", or
+/// "Unknown" until enough notes have been played. Returns null if the context is null.
+/// The returned string must be freed with `free_model_string`.
+#[no_mangle]
+pub extern "C" fn get_detected_key(context: *mut c_void) -> *mut c_char {
+    detected_key_string(context, |key, _| ml::context::pitch_class_name(key))
+}
+
+/// Gets the scale of the detected key, "Major", "Minor" or the name of a
+/// template added with `add_scale_template`, or "Unknown" until enough notes
+/// have been played. Returns null if the context is null.
+/// The returned string must be freed with `free_model_string`.
+#[no_mangle]
+pub extern "C" fn get_detected_scale(context: *mut c_void) -> *mut c_char {
+    detected_key_string(context, |_, template| template.name())
+}
+
+/// A note returned by `get_recent_notes_ml`.
+#[repr(C)]
+pub struct CRecentNote {
+    pub note: u8,
+    pub channel: u8,
+    pub velocity: u8,
+    /// Whether the note's key is still down; `end_time` and `duration` are 0 if so.
+    pub held: bool,
+    /// Timestamps of the note-on and note-off in seconds.
+    pub start_time: f64,
+    pub end_time: f64,
+    /// Duration in seconds.
+    pub duration: f64,
+    /// The note's name, e.g. "C4", NUL-terminated.
+    pub name: [c_char; 8],
+}
+    
+/// Copies up to `max` of the ML context's most recent notes, oldest first,
+/// into the caller-provided `out` array. Returns the number of notes written.
+#[no_mangle]
+pub extern "C" fn get_recent_notes_ml(context: *mut c_void, out: *mut CRecentNote, max: usize) -> usize {
+    if context.is_null() || out.is_null() || max == 0 {
+        return 0;
+    }
+    
+    let context = unsafe { &*(context as *const MlContext) };
+    let notes = context.musical_context.recent_notes();
+    let count = notes.len().min(max);
+    for (i, note) in notes.iter().skip(notes.len() - count).enumerate() {
+        let mut name = [0 as c_char; 8];
+        for (dst, src) in name.iter_mut().zip(note.name().bytes()) {
+            *dst = src as c_char;
+        }
+        let c_note = CRecentNote {
+            note: note.note,
+            channel: note.channel,
+            velocity: note.velocity,
+            held: note.end_time.is_none(),
+            start_time: timestamp::micros_to_secs(note.start_time),
+            end_time: note.end_time.map_or(0.0, timestamp::micros_to_secs),
+            duration: note.duration.map_or(0.0, timestamp::micros_to_secs),
+            name,
+        };
+        unsafe { out.add(i).write(c_note) };
+    }
+
+    count
+}
+
+/// Adds a named scale for the key detector to consider, as `count` intervals
+/// in semitones above the tonic (e.g. 0, 2, 3, 5, 7, 9, 10 for Dorian).
+/// A scale with the same name is replaced. Returns false if an argument is
+/// null or the intervals don't include 0 or exceed 11.
+#[no_mangle]
+pub extern "C" fn add_scale_template(
+    context: *mut c_void,
+    name: *const c_char,
+    intervals: *const u8,
+    count: usize,
+) -> bool {
+    if context.is_null() || name.is_null() || intervals.is_null() {
+        return false;
+    }
+    
+    let context = unsafe { &mut *(context as *mut MlContext) };
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    let intervals = unsafe { slice::from_raw_parts(intervals, count) };
+    match ScaleTemplate::new(&name, intervals) {
+        Ok(template) => {
+            context.musical_context.add_scale_template(template);
+            true
+        },
+        Err(e) => {
+            log::warn!("Rejected scale template: {}", e);
+            false
+        },
+    }
+}
+
+/// Describes the detected key of an ML context in a newly allocated C string.
+fn detected_key_string(context: *mut c_void, describe: impl Fn(u8, &ScaleTemplate) -> &str) -> *mut c_char {
+    if context.is_null() {
+        return std::ptr::null_mut();
+    }
+    
+    let context = unsafe { &*(context as *const MlContext) };
+    let description = context.musical_context.detected_scale().map_or("Unknown", |(key, template)| describe(key, template));
+    match CString::new(description) {
+        Ok(s) => s.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees a string returned by one of the `get_model_*` or `get_detected_*` functions.
+#[no_mangle]
+pub extern "C" fn free_model_string(s: *mut c_char) {
+    if s.is_null() {
+        return;
+    }
+    unsafe {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Gets the name of a MIDI note number, e.g. "C4" or "F#3".
+/// `middle_c_octave` selects the octave numbering of middle C (note 60),
+/// typically 4 ("C4") or 3 ("C3"). Returns null if `note` is out of range.
+/// The returned string must be freed with `free_note_name`.
+#[no_mangle]
+pub extern "C" fn midi_note_name(note: u8, middle_c_octave: i32) -> *mut c_char {
+    if note > 127 {
+        return std::ptr::null_mut();
+    }
+    match CString::new(ml::context::note_name_with_octave(note, middle_c_octave)) {
+        Ok(s) => s.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees a string returned by `midi_note_name`.
+#[no_mangle]
+pub extern "C" fn free_note_name(s: *mut c_char) {
+    if s.is_null() {
+        return;
+    }
+    unsafe {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ml::context::{dedup_insights, MidiModel};
+    use crate::ml::pattern::PatternTemplate;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    
+    #[test]
+    fn test_timing_getters() {
+        let handle = create_midi_engine();
+        assert_eq!(get_tempo_us_per_quarter(handle), 0);
+        
+        // 120 BPM at 24 PPQN
+        let interval = 60.0 / (120.0 * 24.0);
+        let clock = [0xF8u8];
+        for i in 0..25 {
+            assert!(process_midi_message(handle, clock.as_ptr(), clock.len(), i as f64 * interval));
+        }
+        
+        assert!((get_current_bpm(handle) - 120.0).abs() < 1e-6);
+        assert!((get_average_bpm(handle) - 120.0).abs() < 1e-6);
+        assert!(get_jitter(handle) < 1e-9);
+        assert_eq!(get_clock_count(handle), 25);
+        assert_eq!(get_tempo_us_per_quarter(handle), 500_000);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_timing_getters_null() {
+        assert_eq!(get_current_bpm(std::ptr::null()), -1.0);
+        assert_eq!(get_tempo_us_per_quarter(std::ptr::null()), -1);
+        assert_eq!(get_average_bpm(std::ptr::null()), -1.0);
+        assert_eq!(get_jitter(std::ptr::null()), -1.0);
+        assert_eq!(get_clock_count(std::ptr::null()), -1);
+    }
+    
+    struct AlwaysInsightModel;
+    
+    impl MidiModel for AlwaysInsightModel {
+        fn process_event(&mut self, _event: &MidiEvent, _context: &ml::context::MusicalContext) {}
+        
+        fn generate_insights(&self, _context: &ml::context::MusicalContext) -> Vec<Insight> {
+            vec![Insight::Style { style: "Test".to_string(), confidence: 0.9 }]
+        }
+        
+        fn metadata(&self) -> ModelMetadata {
+            ModelMetadata {
+                name: "Always".to_string(),
+                description: "Reports an insight for every event".to_string(),
+                version: "0.0.0".to_string(),
+                author: "Tests".to_string(),
+                license: "MIT".to_string(),
+            }
+        }
+    }
+    
+    extern "C" fn count_insights(insights: *const CInsight, count: usize, user_data: *mut c_void) {
+        assert!(!insights.is_null());
+        let first = unsafe { &*insights };
+        assert_eq!(first.insight_type, 2);
+        assert!((first.score - 0.9).abs() < 1e-9);
+        unsafe { *(user_data as *mut usize) += count };
+    }
+    
+    #[test]
+    fn test_process_model_events_batch() {
+        let handle = create_model_context(0);
+        let device = CString::new("Test Device").unwrap();
+        let cc = [0xB0u8, 1, 64];
+        let note_on = [0x90u8, 60, 100];
+        let mut events: Vec<CMidiEventIn> = (0..5)
+            .map(|_| CMidiEventIn { data: cc.as_ptr(), len: cc.len(), timestamp: 0.5 })
+            .collect();
+        events.push(CMidiEventIn { data: std::ptr::null(), len: 3, timestamp: 0.5 });
+        events.push(CMidiEventIn { data: note_on.as_ptr(), len: note_on.len(), timestamp: 0.6 });
+        
+        // The burst of identical CCs is processed once, the invalid entry skipped
+        assert_eq!(process_model_events_batch(handle, events.as_ptr(), events.len(), device.as_ptr()), 2);
+        assert_eq!(process_model_events_batch(handle, events.as_ptr(), 0, device.as_ptr()), 0);
+        assert_eq!(process_model_events_batch(std::ptr::null_mut(), events.as_ptr(), events.len(), device.as_ptr()), 0);
+        
+        destroy_model_context(handle);
+    }
+    
+    #[test]
+    fn test_insight_callback() {
+        let handle = create_model_context(0);
+        unsafe {
+            (*handle).context.register_model("always", Box::new(AlwaysInsightModel));
+            (*handle).context.activate_model("always").unwrap();
+        }
+        
+        let mut received = 0usize;
+        assert!(register_insight_callback(handle, Some(count_insights), &mut received as *mut usize as *mut c_void));
+        
+        let note_on = [0x90u8, 60, 100];
+        let device = CString::new("Test Device").unwrap();
+        assert!(process_model_event(handle, note_on.as_ptr(), note_on.len(), 0, device.as_ptr()));
+        assert_eq!(received, 1);
+        
+        // Unchanged insights aren't pushed again, except to a new callback
+        assert!(process_model_event(handle, note_on.as_ptr(), note_on.len(), 1, device.as_ptr()));
+        assert_eq!(received, 1);
+        assert!(register_insight_callback(handle, Some(count_insights), &mut received as *mut usize as *mut c_void));
+        assert!(process_model_event(handle, note_on.as_ptr(), note_on.len(), 2, device.as_ptr()));
+        assert_eq!(received, 2);
+        
+        // Unregistering stops further notifications
+        assert!(register_insight_callback(handle, None, std::ptr::null_mut()));
+        assert!(process_model_event(handle, note_on.as_ptr(), note_on.len(), 3, device.as_ptr()));
+        assert_eq!(received, 2);
+        
+        destroy_model_context(handle);
+    }
+    
+    #[test]
+    fn test_insight_callback_reentrant() {
+        static NOTIFIED: AtomicUsize = AtomicUsize::new(0);
+        
+        // Reads the insights again through the FFI, then unregisters itself
+        extern "C" fn reenter(_insights: *const CInsight, count: usize, user_data: *mut c_void) {
+            let handle = user_data as *mut ModelContextHandle;
+            let mut reread = 0usize;
+            let insights = generate_insights(handle, &mut reread);
+            assert_eq!(reread, count);
+            free_insights(insights, reread);
+            assert!(register_insight_callback(handle, None, std::ptr::null_mut()));
+            NOTIFIED.fetch_add(1, Ordering::SeqCst);
+        }
+        
+        let handle = create_model_context(0);
+        unsafe {
+            (*handle).context.register_model("always", Box::new(AlwaysInsightModel));
+            (*handle).context.activate_model("always").unwrap();
+        }
+        assert!(register_insight_callback(handle, Some(reenter), handle as *mut c_void));
+        
+        let note_on = [0x90u8, 60, 100];
+        let device = CString::new("Test Device").unwrap();
+        assert!(process_model_event(handle, note_on.as_ptr(), note_on.len(), 0, device.as_ptr()));
+        assert_eq!(NOTIFIED.load(Ordering::SeqCst), 1);
+        
+        // The callback unregistered itself
+        assert!(process_model_event(handle, note_on.as_ptr(), note_on.len(), 1, device.as_ptr()));
+        assert_eq!(NOTIFIED.load(Ordering::SeqCst), 1);
+        
+        destroy_model_context(handle);
+    }
+    
+    /// Counts its `generate_insights` calls.
+    struct CountingModel {
+        calls: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+    
+    impl MidiModel for CountingModel {
+        fn process_event(&mut self, _event: &MidiEvent, _context: &ml::context::MusicalContext) {}
+        
+        fn generate_insights(&self, context: &ml::context::MusicalContext) -> Vec<Insight> {
+            self.calls.set(self.calls.get() + 1);
+            let confidence = context.messages().len() as f64 / 10.0;
+            vec![Insight::Style { style: "Test".to_string(), confidence }]
+        }
+        
+        fn metadata(&self) -> ModelMetadata {
+            AlwaysInsightModel.metadata()
+        }
+    }
+    
+    #[test]
+    fn test_insights_cached() {
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut protocol = ModelContextProtocol::new();
+        protocol.register_model("counting", Box::new(CountingModel { calls: calls.clone() }));
+        protocol.activate_model("counting").unwrap();
+        
+        let note_on = |timestamp| MidiEvent { data: vec![0x90, 60, 100], timestamp, device_name: "Test".to_string() };
+        protocol.process_event(note_on(0));
+        
+        let first = protocol.generate_insights();
+        let second = protocol.generate_insights();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(format!("{:?}", first), format!("{:?}", second));
+        
+        // A new event invalidates the cache
+        protocol.process_event(note_on(1_000));
+        let third = protocol.generate_insights();
+        assert_eq!(calls.get(), 2);
+        assert_ne!(format!("{:?}", third), format!("{:?}", first));
+        
+        // So does a configuration change
+        protocol.set_significance_threshold(0.5);
+        protocol.generate_insights();
+        assert_eq!(calls.get(), 3);
+    }
+    
+    #[test]
+    fn test_process_batch() {
+        let handle = create_midi_engine();
+        
+        let interval = 60.0 / (120.0 * 24.0);
+        let clock = [0xF8u8];
+        let note_on = [0x90u8, 60, 100];
+        let note_off = [0x80u8, 60, 0];
+        
+        let mut batch = Vec::new();
+        for i in 0..4 {
+            batch.push(CMidiEventIn { data: clock.as_ptr(), len: clock.len(), timestamp: i as f64 * interval });
+        }
+        batch.push(CMidiEventIn { data: note_on.as_ptr(), len: note_on.len(), timestamp: 0.01 });
+        batch.push(CMidiEventIn { data: note_off.as_ptr(), len: note_off.len(), timestamp: 0.05 });
+        // Invalid entries are skipped
+        batch.push(CMidiEventIn { data: std::ptr::null(), len: 3, timestamp: 0.06 });
+        batch.push(CMidiEventIn { data: note_on.as_ptr(), len: 0, timestamp: 0.07 });
+        
+        let processed = process_midi_messages_batch(handle, batch.as_ptr(), batch.len());
+        assert_eq!(processed, 6);
+        assert_eq!(get_clock_count(handle), 4);
+        assert!((get_current_bpm(handle) - 120.0).abs() < 1e-6);
+        
+        assert_eq!(process_midi_messages_batch(handle, std::ptr::null(), 4), 0);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_replay_events() {
+        let handle = create_midi_engine();
+        let messages: [&[u8]; 4] = [&[0x90, 60, 64], &[0x90, 64, 100], &[0x80, 60, 0], &[0x80, 64, 0]];
+        let capture: Vec<CMidiEventIn> = messages.iter().enumerate()
+            .map(|(i, data)| CMidiEventIn { data: data.as_ptr(), len: data.len(), timestamp: i as f64 * 0.25 })
+            .collect();
+        assert_eq!(process_midi_messages_batch(handle, capture.as_ptr(), capture.len()), 4);
+        let velocities = |handle: *mut RustMidiEngineHandle| -> Vec<u8> {
+            let mut out: Vec<CCompletedNote> = Vec::with_capacity(4);
+            let count = get_notes_in_range(handle, 0.0, 1.0, out.as_mut_ptr(), 4);
+            unsafe { out.set_len(count) };
+            out.iter().map(|note| note.velocity).collect()
+        };
+        assert_eq!(velocities(handle), vec![64, 100]);
+        
+        // Re-analyze the same capture with a softer curve and a transposition
+        assert!(set_velocity_curve(handle, 1, 2.0, std::ptr::null()));
+        assert!(set_transpose(handle, 12));
+        assert!(replay_events(handle, capture.as_ptr(), capture.len()));
+        assert_eq!(velocities(handle), vec![32, 79]);
+        assert_eq!(unsafe { &*handle }.engine.snapshot()[0].data, vec![0x90, 72, 32]);
+        let mut out: Vec<CActiveNote> = Vec::with_capacity(1);
+        assert_eq!(get_active_notes(handle, out.as_mut_ptr(), 1), 0);
+        
+        let invalid = [CMidiEventIn { data: std::ptr::null(), len: 3, timestamp: 0.0 }];
+        assert!(!replay_events(handle, invalid.as_ptr(), 1));
+        assert!(!replay_events(handle, std::ptr::null(), 0));
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_concurrent_transform_setters() {
+        let handle = create_midi_engine();
+        // Raw pointers aren't Send; the engine itself is shared safely
+        let address = handle as usize;
+        let setters: Vec<_> = (0..16u8)
+            .map(|channel| std::thread::spawn(move || {
+                let handle = address as *mut RustMidiEngineHandle;
+                for _ in 0..100 {
+                    assert!(set_channel_map(handle, channel, 15 - channel));
+                    assert!(set_transpose(handle, channel as i8));
+                }
+            }))
+            .collect();
+        for setter in setters {
+            setter.join().unwrap();
+        }
+        
+        // No setter's change was lost to another's
+        for channel in 0..16u8 {
+            assert!(process_midi_message(handle, [0x90 | channel, 60, 100].as_ptr(), 3, 0.0));
+        }
+        let stored = unsafe { &*handle }.engine.snapshot();
+        let channels: Vec<u8> = stored.iter().map(|event| event.data[0] & 0x0F).collect();
+        assert_eq!(channels, (0..16).map(|channel| 15 - channel).collect::<Vec<u8>>());
+        assert!((60..76).contains(&stored[0].data[1]));
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_process_ump_message() {
+        let handle = create_midi_engine();
+        
+        // MIDI 2.0 note-on (channel 0, note 60, velocity 0x8000), a utility
+        // NOOP that is skipped, and a MIDI 1.0 note-off
+        let words = [0x4090_3C00u32, 0x8000_0000, 0x0000_0000, 0x2080_3C00];
+        assert_eq!(process_ump_message(handle, words.as_ptr(), words.len(), 0.5), 2);
+        
+        let messages = unsafe { &*handle }.engine.snapshot();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].data, vec![0x90, 60, 64]);
+        assert_eq!(messages[0].timestamp, 0.5);
+        assert_eq!(messages[1].data, vec![0x80, 60, 0]);
+        
+        assert_eq!(process_ump_message(handle, std::ptr::null(), 2, 0.0), 0);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_midi_note_name() {
+        let name = midi_note_name(61, 4);
+        assert_eq!(unsafe { CStr::from_ptr(name) }.to_str().unwrap(), "C#4");
+        free_note_name(name);
+        
+        let name = midi_note_name(60, 3);
+        assert_eq!(unsafe { CStr::from_ptr(name) }.to_str().unwrap(), "C3");
+        free_note_name(name);
+        
+        assert!(midi_note_name(128, 4).is_null());
+    }
+    
+    #[test]
+    fn test_get_next_click() {
+        let handle = create_midi_engine();
+        assert_eq!(get_next_click(handle, 0.0, 4, 4).time, -1.0);
+        
+        // 120 BPM clock starting at 1.0s
+        let interval = 60.0 / (120.0 * 24.0);
+        for i in 0..25 {
+            process_midi_message(handle, [0xF8u8].as_ptr(), 1, 1.0 + i as f64 * interval);
+        }
+        
+        let click = get_next_click(handle, 1.2, 4, 4);
+        assert!((click.time - 1.5).abs() < 1e-9);
+        assert!(!click.is_downbeat);
+        
+        let click = get_next_click(handle, 2.9, 4, 4);
+        assert!((click.time - 3.0).abs() < 1e-9);
+        assert!(click.is_downbeat);
+        
+        assert_eq!(get_next_click(handle, 1.0, 0, 4).time, -1.0);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_process_midi_message_ex() {
+        let handle = create_midi_engine();
+        
+        let note_on = [0x90u8, 60, 100];
+        let result = process_midi_message_ex(handle, note_on.as_ptr(), note_on.len(), 0.0);
+        assert!(result.success);
+        assert_eq!(result.error.code, 0);
+        assert!(result.error.message.is_null());
+        
+        let result = process_midi_message_ex(handle, note_on.as_ptr(), 0, 0.0);
+        assert!(!result.success);
+        assert_eq!(result.error.code, 2);
+        let message = unsafe { CStr::from_ptr(result.error.message) }.to_str().unwrap().to_string();
+        assert!(message.starts_with("Invalid MIDI data"), "{}", message);
+        free_error_message(result.error.message);
+        
+        let result = process_midi_message_ex(std::ptr::null_mut(), note_on.as_ptr(), 3, 0.0);
+        assert_eq!(result.error.code, 1);
+        free_error_message(result.error.message);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_status_codes() {
+        let handle = create_midi_engine();
+        let note_on = [0x90u8, 60, 100];
+        
+        assert_eq!(process_midi_message_status(handle, note_on.as_ptr(), 3, 0.0), MidiStatus::Ok);
+        assert_eq!(process_midi_message_status(std::ptr::null_mut(), note_on.as_ptr(), 3, 0.0), MidiStatus::NullPointer);
+        assert_eq!(process_midi_message_status(handle, std::ptr::null(), 3, 0.0), MidiStatus::NullPointer);
+        assert_eq!(process_midi_message_status(handle, note_on.as_ptr(), 0, 0.0), MidiStatus::BadLength);
+        assert_eq!(process_midi_message_status(handle, note_on.as_ptr(), 2, 0.0), MidiStatus::InvalidData);
+        assert_eq!(process_midi_message_status(handle, note_on.as_ptr(), 3, f64::NAN), MidiStatus::InvalidData);
+        
+        // A panicking forward callback is caught at the boundary
+        unsafe { &*handle }.engine.set_forward_callback(Some(Arc::new(|_, _| panic!("injected panic"))));
+        assert_eq!(process_midi_message_status(handle, note_on.as_ptr(), 3, 0.1), MidiStatus::Panic);
+        unsafe { &*handle }.engine.set_forward_callback(None);
+        assert_eq!(process_midi_message_status(handle, note_on.as_ptr(), 3, 0.2), MidiStatus::Ok);
+        destroy_midi_engine(handle);
+        
+        let context = create_ml_context();
+        let device = CString::new("Test Device").unwrap();
+        assert_eq!(process_midi_message_ml_status(context, note_on.as_ptr(), 3, device.as_ptr()), MidiStatus::Ok);
+        assert_eq!(process_midi_message_ml_status(context, note_on.as_ptr(), 3, std::ptr::null()), MidiStatus::NullPointer);
+        assert_eq!(process_midi_message_ml_status(context, note_on.as_ptr(), -1, device.as_ptr()), MidiStatus::BadLength);
+        assert_eq!(process_midi_message_ml_status(context, [0x3Cu8].as_ptr(), 1, device.as_ptr()), MidiStatus::InvalidData);
+        destroy_ml_context(context);
+        
+        let buffer = create_shared_midi_buffer(64);
+        assert_eq!(write_midi_event_status(buffer, note_on.as_ptr(), 3, 0, device.as_ptr()), MidiStatus::Ok);
+        assert_eq!(write_midi_event_status(buffer, note_on.as_ptr(), 0, 0, device.as_ptr()), MidiStatus::BadLength);
+        let mut status = MidiStatus::Ok;
+        for _ in 0..8 {
+            status = write_midi_event_status(buffer, note_on.as_ptr(), 3, 0, device.as_ptr());
+        }
+        assert_eq!(status, MidiStatus::ProcessingError);
+        destroy_shared_midi_buffer(buffer);
+    }
+    
+    #[test]
+    fn test_large_sysex() {
+        let handle = create_midi_engine();
+        let mut dump = vec![0xF0u8, 0x43];
+        dump.resize(2047, 0x11);
+        dump.push(0xF7);
+        
+        assert!(process_midi_message(handle, dump.as_ptr(), dump.len(), 0.0));
+        assert!(set_max_sysex_size(handle, 1024));
+        let result = process_midi_message_ex(handle, dump.as_ptr(), dump.len(), 0.1);
+        assert!(!result.success);
+        assert_eq!(result.error.code, 2);
+        free_error_message(result.error.message);
+        assert!(!set_max_sysex_size(handle, 0));
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_non_finite_timestamps_rejected() {
+        let handle = create_midi_engine();
+        let clock = [0xF8u8];
+        assert!(process_midi_message(handle, clock.as_ptr(), clock.len(), 0.0));
+        
+        for timestamp in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(!process_midi_message(handle, clock.as_ptr(), clock.len(), timestamp));
+            let result = process_midi_message_ex(handle, clock.as_ptr(), clock.len(), timestamp);
+            assert!(!result.success);
+            assert_eq!(result.error.code, error::ERROR_INVALID_ARGUMENT);
+            free_error_message(result.error.message);
+        }
+        
+        let stats = unsafe { (*handle).engine.stats() };
+        assert_eq!(stats.clock_count, 1);
+        assert_eq!(stats.last_clock_time, 0.0);
+        assert_eq!(unsafe { (*handle).engine.snapshot().len() }, 1);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    extern "C" fn collect_forwarded(data: *const u8, len: usize, _timestamp: f64, user_data: *mut c_void) {
+        let forwarded = unsafe { &mut *(user_data as *mut Vec<Vec<u8>>) };
+        forwarded.push(unsafe { slice::from_raw_parts(data, len) }.to_vec());
+    }
+    
+    #[test]
+    fn test_forward_callback() {
+        let handle = create_midi_engine();
+        let mut forwarded: Vec<Vec<u8>> = Vec::new();
+        assert!(register_forward_callback(handle, Some(collect_forwarded), &mut forwarded as *mut Vec<Vec<u8>> as *mut c_void));
+        assert!(set_note_filter(handle, 48, 72, 0xFFFF));
+        assert!(set_transpose(handle, 12));
+        
+        let kept = [0x90u8, 60, 100];
+        let filtered = [0x90u8, 80, 100];
+        assert!(process_midi_message(handle, kept.as_ptr(), kept.len(), 0.0));
+        assert!(process_midi_message(handle, filtered.as_ptr(), filtered.len(), 0.1));
+        assert_eq!(forwarded, vec![vec![0x90, 72, 100]]);
+        
+        // Unregistering stops forwarding
+        assert!(register_forward_callback(handle, None, std::ptr::null_mut()));
+        assert!(process_midi_message(handle, kept.as_ptr(), kept.len(), 0.2));
+        assert_eq!(forwarded.len(), 1);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_forward_callback_reentrant() {
+        static FORWARDED: AtomicUsize = AtomicUsize::new(0);
+        
+        // Unregisters itself, then echoes a note-off into the same engine
+        extern "C" fn echo(_data: *const u8, _len: usize, timestamp: f64, user_data: *mut c_void) {
+            let handle = user_data as *mut RustMidiEngineHandle;
+            assert!(register_forward_callback(handle, None, std::ptr::null_mut()));
+            let note_off = [0x80u8, 60, 0];
+            assert!(process_midi_message(handle, note_off.as_ptr(), note_off.len(), timestamp + 0.1));
+            FORWARDED.fetch_add(1, Ordering::SeqCst);
+        }
+        
+        let handle = create_midi_engine();
+        assert!(register_forward_callback(handle, Some(echo), handle as *mut c_void));
+        
+        let note_on = [0x90u8, 60, 100];
+        assert!(process_midi_message(handle, note_on.as_ptr(), note_on.len(), 0.0));
+        assert_eq!(FORWARDED.load(Ordering::SeqCst), 1);
+        assert_eq!(unsafe { (*handle).engine.snapshot().len() }, 2);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_get_active_notes() {
+        let handle = create_midi_engine();
+        for (i, message) in [[0x90u8, 60, 100], [0x93, 64, 90], [0x90, 67, 80]].iter().enumerate() {
+            assert!(process_midi_message(handle, message.as_ptr(), message.len(), i as f64 * 0.1));
+        }
+        
+        let mut out: Vec<CActiveNote> = Vec::with_capacity(2);
+        let count = get_active_notes(handle, out.as_mut_ptr(), 2);
+        assert_eq!(count, 2);
+        unsafe { out.set_len(count) };
+        assert_eq!((out[0].note, out[0].channel), (60, 0));
+        assert_eq!((out[1].note, out[1].channel), (64, 3));
+        assert_eq!(out[1].pitch_bend, 8192);
+        
+        assert_eq!(get_active_notes(handle, std::ptr::null_mut(), 2), 0);
+        assert_eq!(get_active_notes(std::ptr::null(), out.as_mut_ptr(), 2), 0);
+        assert_eq!(get_active_pitch_classes(handle), 0b0000_1001_0001);
+        assert_eq!(get_active_pitch_classes(std::ptr::null()), 0);
+        
+        assert!(map_cc(handle, 16, 3));
+        assert!(process_midi_message(handle, [0xB3u8, 16, 99].as_ptr(), 3, 0.5));
+        let count = get_active_notes(handle, out.as_mut_ptr(), 2);
+        assert_eq!(out[1].timbre, 99);
+        assert_eq!(count, 2);
+        assert!(!map_cc(handle, 16, 4));
+        assert!(!map_cc(handle, 128, 1));
+        
+        assert_eq!(finalize_midi_stats(handle, 1.0), 3);
+        assert_eq!(get_active_note_count(handle), 0);
+        assert_eq!(finalize_midi_stats(handle, f64::NAN), -1);
+        assert_eq!(finalize_midi_stats(std::ptr::null_mut(), 1.0), -1);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_create_midi_engine_with_config() {
+        let mut channel_map = [0u8; 16];
+        for (channel, mapped) in channel_map.iter_mut().enumerate() {
+            *mapped = channel as u8;
+        }
+        let mut config = CEngineConfig {
+            curve_type: 0,
+            curve_param: 0.0,
+            curve_table: std::ptr::null(),
+            transpose: -12,
+            channel_map,
+            min_note: 0,
+            max_note: 127,
+            allowed_channels: 0xFFFF,
+            stuck_note_timeout: 0.0,
+            input_offset: 0.0,
+            max_sysex_size: 1024,
+            sysex_timeout: 0.5,
+            ppqn: 48,
+        };
+        
+        let handle = create_midi_engine_with_config(&config);
+        assert!(!handle.is_null());
+        let interval = 60.0 / (90.0 * 48.0);
+        for i in 0..4 {
+            assert!(process_midi_message(handle, [0xF8u8].as_ptr(), 1, i as f64 * interval));
+        }
+        assert!((get_current_bpm(handle) - 90.0).abs() < 0.1);
+        assert!(process_midi_message(handle, [0x90u8, 60, 100].as_ptr(), 3, 1.0));
+        let mut out: Vec<CActiveNote> = Vec::with_capacity(1);
+        assert_eq!(get_active_notes(handle, out.as_mut_ptr(), 1), 1);
+        unsafe { out.set_len(1) };
+        assert_eq!(out[0].note, 48);
+        destroy_midi_engine(handle);
+        
+        config.ppqn = 0;
+        assert!(create_midi_engine_with_config(&config).is_null());
+        config.ppqn = 24;
+        config.curve_type = 3;
+        assert!(create_midi_engine_with_config(&config).is_null());
+        assert!(create_midi_engine_with_config(std::ptr::null()).is_null());
+    }
+    
+    #[test]
+    fn test_read_and_free_midi_event() {
+        let handle = create_shared_midi_buffer(1024);
+        let data = [0x90u8, 60, 100];
+        let device = CString::new("Alloc Device").unwrap();
+        assert!(write_midi_event(handle, data.as_ptr(), data.len(), 42, device.as_ptr()));
+        
+        let event = read_midi_event(handle);
+        assert!(!event.is_null());
+        unsafe {
+            assert_eq!(slice::from_raw_parts((*event).data, (*event).data_len), &data);
+            assert_eq!((*event).timestamp, 42);
+            assert_eq!(CStr::from_ptr((*event).device_name).to_str().unwrap(), "Alloc Device");
+        }
+        free_midi_event(event);
+        free_midi_event(std::ptr::null_mut());
+        
+        assert!(read_midi_event(handle).is_null());
+        destroy_shared_midi_buffer(handle);
+    }
+    
+    #[test]
+    fn test_write_midi_event_device_names() {
+        let handle = create_shared_midi_buffer(4096);
+        let data = [0x90u8, 60, 100];
+        let read_name = || {
+            let event = read_midi_event(handle);
+            assert!(!event.is_null());
+            let name = unsafe { CStr::from_ptr((*event).device_name) }.to_string_lossy().into_owned();
+            free_midi_event(event);
+            name
+        };
+        
+        // Not UTF-8
+        let latin1 = CString::new(vec![b'C', b'a', b'f', 0xE9]).unwrap();
+        assert!(write_midi_event(handle, data.as_ptr(), data.len(), 1, latin1.as_ptr()));
+        assert_eq!(read_name(), "Caf\u{FFFD}");
+        
+        // Over-long
+        let long = CString::new("x".repeat(1000)).unwrap();
+        assert!(write_midi_event(handle, data.as_ptr(), data.len(), 2, long.as_ptr()));
+        assert_eq!(read_name(), "x".repeat(shared_buffer::MAX_DEVICE_NAME_LEN));
+        
+        destroy_shared_midi_buffer(handle);
+    }
+    
+    #[test]
+    fn test_generate_and_free_insights() {
+        let handle = create_model_context(0);
+        unsafe {
+            (*handle).context.register_model("always", Box::new(AlwaysInsightModel));
+            (*handle).context.activate_model("always").unwrap();
+        }
+        
+        let mut count = 0usize;
+        let insights = generate_insights(handle, &mut count);
+        assert_eq!(count, 1);
+        unsafe {
+            assert_eq!((*insights).insight_type, 2);
+            assert!(!(*insights).description.is_null());
+        }
+        free_insights(insights, count);
+        
+        destroy_model_context(handle);
+    }
+    
+    #[test]
+    fn test_process_model_event_non_utf8_device() {
+        let handle = create_model_context(0);
+        unsafe {
+            (*handle).context.register_model("always", Box::new(AlwaysInsightModel));
+            (*handle).context.activate_model("always").unwrap();
+        }
+        
+        // The event is kept under the lossy name rather than rejected
+        let latin1 = CString::new(vec![b'C', b'a', b'f', 0xE9]).unwrap();
+        let note_on = [0x90u8, 60, 100];
+        assert!(process_model_event(handle, note_on.as_ptr(), 3, 0, latin1.as_ptr()));
+        let device = CString::new("Caf\u{FFFD}").unwrap();
+        let mut count = 0usize;
+        let insights = generate_device_insights(handle, device.as_ptr(), &mut count);
+        assert_eq!(count, 1);
+        free_insights(insights, count);
+        
+        destroy_model_context(handle);
+    }
+    
+    #[test]
+    fn test_save_and_load_patterns() {
+        let device = CString::new("Test Device").unwrap();
+        let play = |handle: *mut ModelContextHandle, notes: &[u8], start: u64| {
+            for (i, &note) in notes.iter().enumerate() {
+                let note_on = [0x90u8, note, 100];
+                let timestamp = start + i as u64 * 250_000;
+                assert!(process_model_event(handle, note_on.as_ptr(), 3, timestamp, device.as_ptr()));
+            }
+        };
+        let pattern_descriptions = |handle: *mut ModelContextHandle| {
+            let insights = unsafe { &*handle }.context.generate_insights();
+            insights.iter()
+                .filter(|insight| matches!(insight, Insight::Pattern(_)))
+                .map(|insight| describe_insight(insight).1)
+                .collect::<Vec<_>>()
+        };
+        
+        // Learn C-E-G six times
+        let handle = create_model_context(0);
+        assert!(load_model(handle, 0));
+        play(handle, &[60, 64, 67].repeat(6), 0);
+        
+        let path = std::env::temp_dir().join(format!("midi_engine_patterns_{}.bin", std::process::id()));
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        assert!(save_patterns(handle, path.as_ptr()));
+        destroy_model_context(handle);
+        
+        // A fresh context recognizes the motif the first time it is played again
+        let handle = create_model_context(0);
+        assert!(load_model(handle, 0));
+        play(handle, &[60, 64, 67], 0);
+        assert!(pattern_descriptions(handle).is_empty());
+        
+        let handle_loaded = create_model_context(0);
+        assert!(load_model(handle_loaded, 0));
+        assert!(load_patterns(handle_loaded, path.as_ptr()));
+        play(handle_loaded, &[60, 64, 67], 0);
+        assert_eq!(pattern_descriptions(handle_loaded), vec!["Pattern detected with 3 events (C4 E4 G4), occurred 7 times"]);
+        
+        // Missing files fail without touching the learned patterns
+        let missing = CString::new("/nonexistent/patterns.bin").unwrap();
+        assert!(!load_patterns(handle_loaded, missing.as_ptr()));
+        assert!(!save_patterns(std::ptr::null_mut(), path.as_ptr()));
+        
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+        destroy_model_context(handle);
+        destroy_model_context(handle_loaded);
+    }
+    
+    #[test]
+    fn test_significance_threshold() {
+        let handle = create_model_context(0);
+        let mut model = PatternRecognitionModel::new();
+        model.add_template(PatternTemplate { name: "Fifth".to_string(), notes: vec![60, 67], significance: 0.6 });
+        unsafe {
+            (*handle).context.register_model("pattern", Box::new(model));
+            (*handle).context.activate_model("pattern").unwrap();
+        }
+        
+        let device = CString::new("Test Device").unwrap();
+        for (i, note) in [60u8, 67].into_iter().enumerate() {
+            let note_on = [0x90u8, note, 100];
+            assert!(process_model_event(handle, note_on.as_ptr(), 3, i as u64 * 250_000, device.as_ptr()));
+        }
+        let count = |handle: *mut ModelContextHandle| {
+            let mut count = 0usize;
+            let insights = generate_insights(handle, &mut count);
+            free_insights(insights, count);
+            count
+        };
+        
+        assert_eq!(count(handle), 1);
+        assert!(set_significance_threshold(handle, 0.9));
+        assert_eq!(count(handle), 0);
+        assert!(set_significance_threshold(handle, 0.5));
+        assert_eq!(count(handle), 1);
+        
+        // Clamped into range
+        assert!(set_significance_threshold(handle, 7.0));
+        assert_eq!(unsafe { &*handle }.context.significance_threshold(), 1.0);
+        assert!(!set_significance_threshold(handle, f64::NAN));
+        assert!(!set_significance_threshold(std::ptr::null_mut(), 0.5));
+        
+        destroy_model_context(handle);
+    }
+    
+    #[test]
+    fn test_fuzzy_matching() {
+        let device = CString::new("Test Device").unwrap();
+        let play = |handle: *mut ModelContextHandle, notes: &[u8]| {
+            for (i, &note) in notes.iter().enumerate() {
+                let note_on = [0x90u8, note, 100];
+                assert!(process_model_event(handle, note_on.as_ptr(), 3, i as u64 * 250_000, device.as_ptr()));
+            }
+        };
+        let has_pattern = |handle: *mut ModelContextHandle| {
+            unsafe { &*handle }.context.generate_insights().iter().any(|insight| matches!(insight, Insight::Pattern(_)))
+        };
+        
+        // Learn a C major arpeggio
+        let handle = create_model_context(0);
+        assert!(load_model(handle, 0));
+        play(handle, &[60, 64, 67].repeat(6));
+        let path = std::env::temp_dir().join(format!("midi_engine_fuzzy_{}.bin", std::process::id()));
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        assert!(save_patterns(handle, path.as_ptr()));
+        destroy_model_context(handle);
+        
+        // Models loaded after enabling fuzzy matching pick up the setting, and
+        // recognize the arpeggio transposed to D
+        let handle = create_model_context(0);
+        assert!(set_fuzzy_matching(handle, true, 0));
+        assert!(load_model(handle, 0));
+        assert!(load_patterns(handle, path.as_ptr()));
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+        play(handle, &[62, 66, 69]);
+        assert!(has_pattern(handle));
+        
+        assert!(set_fuzzy_matching(handle, false, 0));
+        assert!(!has_pattern(handle));
+        assert!(!set_fuzzy_matching(std::ptr::null_mut(), true, 1));
+        
+        destroy_model_context(handle);
+    }
+    
+    /// Reads and frees a string returned by a `get_model_*` function
+    fn take_model_string(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let value = unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned();
+        free_model_string(s);
+        value
+    }
+    
+    /// Writes a model file into the temp directory and returns its path
+    fn write_model_file(name: &str, contents: &str) -> CString {
+        let path = std::env::temp_dir().join(format!("midi_engine_{}_{}.model", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        CString::new(path.to_str().unwrap()).unwrap()
+    }
+    
+    #[test]
+    fn test_model_metadata() {
+        let context = create_ml_context();
+        let path = write_model_file("metadata", r#"{ "templates": [] }"#);
+        let model_id = load_model_ml(context, path.as_ptr());
+        assert!(model_id >= 0);
+        
+        let expected = PatternRecognitionModel::new().metadata();
+        assert_eq!(take_model_string(get_model_description(context, model_id)), expected.description);
+        assert_eq!(take_model_string(get_model_version(context, model_id)), env!("CARGO_PKG_VERSION"));
+        assert_eq!(take_model_string(get_model_author(context, model_id)), expected.author);
+        assert_eq!(take_model_string(get_model_license(context, model_id)), expected.license);
+        assert_ne!(take_model_string(get_model_description(context, model_id)), "Example model");
+        
+        // Unknown model IDs have no metadata
+        assert!(get_model_version(context, model_id + 1).is_null());
+        
+        destroy_ml_context(context);
+    }
+    
+    #[test]
+    fn test_detected_key() {
+        let context = create_ml_context();
+        let device = CString::new("Test Device").unwrap();
+        let play = |notes: &[u8]| {
+            for &note in notes {
+                let note_on = [0x90u8, note, 100];
+                let note_off = [0x80u8, note, 0];
+                process_midi_message_ml(context, note_on.as_ptr(), 3, device.as_ptr());
+                process_midi_message_ml(context, note_off.as_ptr(), 3, device.as_ptr());
+            }
+        };
+        
+        play(&[60, 62, 64]);
+        assert_eq!(take_model_string(get_detected_key(context)), "Unknown");
+        assert_eq!(take_model_string(get_detected_scale(context)), "Unknown");
+        
+        // C major scale and tonic triad
+        play(&[65, 67, 69, 71, 72, 60, 64, 67, 60]);
+        assert_eq!(take_model_string(get_detected_key(context)), "C");
+        assert_eq!(take_model_string(get_detected_scale(context)), "Major");
+        
+        assert!(get_detected_key(std::ptr::null_mut()).is_null());
+        
+        // G Mixolydian, centered on G
+        let mixolydian = CString::new("Mixolydian").unwrap();
+        assert!(add_scale_template(context, mixolydian.as_ptr(), [0u8, 2, 4, 5, 7, 9, 10].as_ptr(), 7));
+        play(&[67, 71, 74, 67, 65, 67, 71, 74, 67, 65, 67, 74, 67, 71, 65, 67, 74, 67, 71, 67]);
+        assert_eq!(take_model_string(get_detected_key(context)), "G");
+        assert_eq!(take_model_string(get_detected_scale(context)), "Mixolydian");
+        
+        assert!(!add_scale_template(context, mixolydian.as_ptr(), [2u8, 4].as_ptr(), 2));
+        assert!(!add_scale_template(context, std::ptr::null(), [0u8].as_ptr(), 1));
+        destroy_ml_context(context);
+    }
+    
+    #[test]
+    fn test_get_recent_notes_ml() {
+        let context = create_ml_context();
+        let device = CString::new("Test Device").unwrap();
+        for message in [[0x90u8, 60, 100], [0x80, 60, 0], [0x92, 70, 80]] {
+            process_midi_message_ml(context, message.as_ptr(), 3, device.as_ptr());
+        }
+        
+        let empty = CRecentNote {
+            note: 0, channel: 0, velocity: 0, held: false,
+            start_time: 0.0, end_time: 0.0, duration: 0.0, name: [0; 8],
+        };
+        let mut out = [CRecentNote { ..empty }, empty];
+        assert_eq!(get_recent_notes_ml(context, out.as_mut_ptr(), 2), 2);
+        let name = |note: &CRecentNote| unsafe { CStr::from_ptr(note.name.as_ptr()) }.to_str().unwrap().to_string();
+        assert_eq!((out[0].note, out[0].velocity, out[0].held, name(&out[0])), (60, 100, false, "C4".to_string()));
+        assert!(out[0].end_time >= out[0].start_time);
+        assert!((out[0].duration - (out[0].end_time - out[0].start_time)).abs() < 1e-6);
+        assert_eq!((out[1].note, out[1].channel, out[1].held, name(&out[1])), (70, 2, true, "A#4".to_string()));
+        assert_eq!((out[1].end_time, out[1].duration), (0.0, 0.0));
+        
+        // With less room, the most recent notes are returned
+        assert_eq!(get_recent_notes_ml(context, out.as_mut_ptr(), 1), 1);
+        assert_eq!(out[0].note, 70);
+        
+        assert_eq!(get_recent_notes_ml(std::ptr::null_mut(), out.as_mut_ptr(), 2), 0);
+        assert_eq!(get_recent_notes_ml(context, std::ptr::null_mut(), 2), 0);
+        destroy_ml_context(context);
+    }
+    
+    #[test]
+    fn test_midi_snapshot() {
+        let handle = create_midi_engine();
+        let interval = 60.0 / (120.0 * 24.0);
+        for i in 0..48 {
+            let time = i as f64 * interval;
+            assert!(process_midi_message(handle, [0xF8u8].as_ptr(), 1, time));
+            if i % 12 == 0 {
+                assert!(process_midi_message(handle, [0x90u8, 60 + i as u8 / 12, 100].as_ptr(), 3, time));
+            }
+        }
+        assert!(process_midi_message(handle, [0x80u8, 60, 0].as_ptr(), 3, 1.0));
+        
+        let context = create_ml_context();
+        let device = CString::new("Test Device").unwrap();
+        for note in [60u8, 62, 64, 65, 67, 69, 71, 72, 60, 64, 67, 60] {
+            process_midi_message_ml(context, [0x90u8, note, 100].as_ptr(), 3, device.as_ptr());
+            process_midi_message_ml(context, [0x80u8, note, 0].as_ptr(), 3, device.as_ptr());
+        }
+        
+        let mut snapshot = std::mem::MaybeUninit::<MidiSnapshot>::uninit();
+        assert!(get_midi_snapshot(handle, context as *const MlContext, snapshot.as_mut_ptr()));
+        let snapshot = unsafe { snapshot.assume_init() };
+        assert_eq!(snapshot.current_bpm, get_current_bpm(handle));
+        assert_eq!(snapshot.average_bpm, get_average_bpm(handle));
+        assert_eq!(snapshot.jitter, get_jitter(handle));
+        assert_eq!(snapshot.clock_count, get_clock_count(handle));
+        assert_eq!(snapshot.active_note_count as i32, get_active_note_count(handle));
+        assert_eq!((snapshot.active_note_count, snapshot.peak_polyphony), (3, 4));
+        assert_eq!((snapshot.key, snapshot.scale), (0, 0));
+        
+        let mut snapshot = std::mem::MaybeUninit::<MidiSnapshot>::uninit();
+        assert!(get_midi_snapshot(handle, std::ptr::null(), snapshot.as_mut_ptr()));
+        assert_eq!(unsafe { snapshot.assume_init() }.key, -1);
+        assert!(!get_midi_snapshot(handle, context as *const MlContext, std::ptr::null_mut()));
+        
+        let mut counts = MessageCounts::default();
+        assert!(get_message_counts(handle, &mut counts));
+        assert_eq!((counts.clocks, counts.note_ons, counts.note_offs), (48, 4, 1));
+        assert!(!get_message_counts(std::ptr::null(), &mut counts));
+        
+        destroy_ml_context(context);
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_insight_queries_match_generated_insights() {
+        let context = create_ml_context();
+        let path = write_model_file("triad", r#"{
+            "templates": [ { "name": "C major triad", "notes": [60, 64, 67], "significance": 0.9 } ]
+        }"#);
+        let model_id = load_model_ml(context, path.as_ptr());
+        
+        let device = CString::new("Test Device").unwrap();
+        for note in [60u8, 64, 67, 60, 64, 67] {
+            let note_on = [0x90u8, note, 100];
+            process_midi_message_ml(context, note_on.as_ptr(), 3, device.as_ptr());
+        }
+        
+        let ml_context = unsafe { &*(context as *const MlContext) };
+        let expected = dedup_insights(ml_context.model(model_id).unwrap().generate_insights(&ml_context.musical_context));
+        assert!(!expected.is_empty());
+        assert_eq!(get_num_insights(context, model_id), expected.len() as i32);
+        
+        let description = take_model_string(get_insight_description(context, model_id, 0));
+        assert_eq!(description, describe_insight(&expected[0]).1);
+        assert!(!description.contains("Example"));
+        assert_eq!(get_insight_type(context, model_id, 0), describe_insight(&expected[0]).0);
+        
+        destroy_ml_context(context);
+    }
+    
+    #[test]
+    fn test_load_model_file() {
+        let context = create_ml_context();
+        let path = write_model_file("arpeggio", r#"{
+            "templates": [ { "name": "C major arpeggio", "notes": [60, 64, 67], "significance": 0.8 } ]
+        }"#);
+        let model_id = load_model_ml(context, path.as_ptr());
+        assert!(model_id >= 0);
+        assert_eq!(get_num_insights(context, model_id), 0);
+        
+        let device = CString::new("Test Device").unwrap();
+        for note in [60u8, 64, 67] {
+            let note_on = [0x90u8, note, 100];
+            let note_off = [0x80u8, note, 0];
+            process_midi_message_ml(context, note_on.as_ptr(), 3, device.as_ptr());
+            process_midi_message_ml(context, note_off.as_ptr(), 3, device.as_ptr());
+        }
+        
+        assert_eq!(get_num_insights(context, model_id), 1);
+        assert_eq!(get_insight_type(context, model_id, 0), 0);
+        assert!((get_insight_score(context, model_id, 0) - 0.8).abs() < 1e-6);
+        let description = get_insight_description(context, model_id, 0);
+        assert_eq!(take_model_string(description), "Pattern detected with 3 events (C4 E4 G4), occurred 1 times");
+        assert!(get_insight_description(context, model_id, 1).is_null());
+        
+        // Unloading removes the model and its insights
+        assert!(unload_model(context, model_id));
+        assert!(!unload_model(context, model_id));
+        assert_eq!(get_num_insights(context, model_id), 0);
+        
+        // Missing or malformed files fail to load
+        let missing = CString::new("/nonexistent/model.model").unwrap();
+        assert_eq!(load_model_ml(context, missing.as_ptr()), -1);
+        let malformed = write_model_file("malformed", "not json");
+        assert_eq!(load_model_ml(context, malformed.as_ptr()), -1);
+        
+        destroy_ml_context(context);
+    }
+    
+    #[test]
+    fn test_drain_midi_messages() {
+        let handle = create_midi_engine();
+        
+        let messages: [&[u8]; 4] = [&[0x90, 60, 100], &[0xB0, 1, 64], &[0x80, 60, 0], &[0xF8]];
+        for (i, message) in messages.iter().enumerate() {
+            assert!(process_midi_message(handle, message.as_ptr(), message.len(), i as f64 * 0.5));
+        }
+        
+        let mut out: Vec<CMidiEvent> = (0..3).map(|_| CMidiEvent {
+            data: std::ptr::null_mut(),
+            data_len: 0,
+            timestamp: 0,
+            device_name: std::ptr::null_mut(),
+        }).collect();
+        
+        // First drain is limited by the output capacity
+        assert_eq!(drain_midi_messages(handle, out.as_mut_ptr(), out.len()), 3);
+        for (i, event) in out.iter().enumerate() {
+            let data = unsafe { slice::from_raw_parts(event.data, event.data_len) };
+            assert_eq!(data, messages[i]);
+            assert_eq!(event.timestamp, i as u64 * 500_000);
+            assert_eq!(unsafe { CStr::from_ptr(event.device_name) }.to_bytes(), b"");
+        }
+        free_drained_events(out.as_mut_ptr(), 3);
+        
+        // The rest comes out next, then the engine is empty
+        assert_eq!(drain_midi_messages(handle, out.as_mut_ptr(), out.len()), 1);
+        let data = unsafe { slice::from_raw_parts(out[0].data, out[0].data_len) };
+        assert_eq!(data, messages[3]);
+        free_drained_events(out.as_mut_ptr(), 1);
+        assert_eq!(drain_midi_messages(handle, out.as_mut_ptr(), out.len()), 0);
+        
+        destroy_midi_engine(handle);
+    }
+    
+    #[test]
+    fn test_note_duration_across_timestamp_units() {
+        let note_on = [0x90u8, 60, 100];
+        let note_off = [0x80u8, 60, 0];
+        
+        // f64 seconds in, u64 microseconds out
+        let seconds_engine = create_midi_engine();
+        assert!(process_midi_message(seconds_engine, note_on.as_ptr(), 3, 0.3));
+        assert!(process_midi_message(seconds_engine, note_off.as_ptr(), 3, 1.05));
+        let mut out: Vec<CMidiEvent> = (0..2).map(|_| CMidiEvent {
+            data: std::ptr::null_mut(),
+            data_len: 0,
+            timestamp: 0,
+            device_name: std::ptr::null_mut(),
+        }).collect();
+        assert_eq!(drain_midi_messages(seconds_engine, out.as_mut_ptr(), 2), 2);
+        let micros_duration = out[1].timestamp - out[0].timestamp;
+        free_drained_events(out.as_mut_ptr(), 2);
+        assert_eq!(micros_duration, 750_000);
+        
+        // u64 microseconds in (via a MIDI file), f64 seconds out
+        let events = [
+            MidiEvent { data: note_on.to_vec(), timestamp: 300_000, device_name: String::new() },
+            MidiEvent { data: note_off.to_vec(), timestamp: 1_050_000, device_name: String::new() },
+        ];
+        let path = std::env::temp_dir().join(format!("midi_engine_units_{}.mid", std::process::id()));
+        let path = path.to_str().unwrap();
+        midi_file::write_smf(&events, 960, 120.0, path).unwrap();
+        let micros_engine = create_midi_engine();
+        let path_c = CString::new(path).unwrap();
+        assert_eq!(import_midi_file(micros_engine, path_c.as_ptr()), 2);
+        std::fs::remove_file(path).unwrap();
+        let messages = unsafe { &*micros_engine }.engine.snapshot();
+        let seconds_duration = messages[1].timestamp - messages[0].timestamp;
+        
+        assert!((seconds_duration - 0.75).abs() < 1e-9);
+        assert_eq!(timestamp::secs_to_micros(seconds_duration), micros_duration);
+        
+        destroy_midi_engine(seconds_engine);
+        destroy_midi_engine(micros_engine);
+    }
+    
+    #[test]
+    fn test_read_midi_events_batch() {
+        let handle = create_shared_midi_buffer(4096);
+        let device = CString::new("Batch Device").unwrap();
+        for i in 0..10u8 {
+            let data = [0x90, i, 0x7F];
+            assert!(write_midi_event(handle, data.as_ptr(), data.len(), i as u64, device.as_ptr()));
+        }
+        
+        let mut out: Vec<CMidiEvent> = (0..16).map(|_| CMidiEvent {
+            data: std::ptr::null_mut(),
+            data_len: 0,
+            timestamp: 0,
+            device_name: std::ptr::null_mut(),
+        }).collect();
+        
+        let count = read_midi_events_batch(handle, out.as_mut_ptr(), out.len());
+        assert_eq!(count, 10);
+        for (i, event) in out.iter().take(count).enumerate() {
+            let data = unsafe { slice::from_raw_parts(event.data, event.data_len) };
+            assert_eq!(data, &[0x90, i as u8, 0x7F]);
+            assert_eq!(event.timestamp, i as u64);
+            assert_eq!(unsafe { CStr::from_ptr(event.device_name) }, device.as_c_str());
+        }
+        free_drained_events(out.as_mut_ptr(), count);
+        assert_eq!(shared_buffer_event_count(handle), 0);
+        
+        let data = [0x80u8, 1, 0];
+        assert!(write_midi_event(handle, data.as_ptr(), data.len(), 20, device.as_ptr()));
+        clear_shared_midi_buffer(handle);
+        assert_eq!(shared_buffer_event_count(handle), 0);
+        assert!(read_midi_event(handle).is_null());
+        clear_shared_midi_buffer(std::ptr::null_mut());
+        
+        destroy_shared_midi_buffer(handle);
+    }
+}
@@ -1,26 +1,192 @@
 // midi_engine.rs
 
-/// Maximum allowed MIDI message size (including SysEx).
-pub const MAX_MIDI_MESSAGE_SIZE: usize = 1024;
+use std::collections::{HashMap, VecDeque};
+use crate::click::ClickGenerator;
+use crate::error::MidiError;
+use crate::parser::{message_len, normalize_pitch_bend, parse_midi_message, ParsedMessage};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Maximum size of a non-SysEx message (the longest MIDI 1.0 message is 3 bytes).
+pub const MAX_CHANNEL_MESSAGE_SIZE: usize = 3;
+
+/// Default maximum size of a SysEx message; see `MidiEngine::set_max_sysex_size`.
+pub const DEFAULT_MAX_SYSEX_SIZE: usize = 65536;
+
+/// Default time (seconds) a SysEx may stay unterminated; see `MidiEngine::set_sysex_timeout`.
+pub const DEFAULT_SYSEX_TIMEOUT: f64 = 0.5;
+
+/// Clock messages per quarter note sent by MIDI 1.0 devices.
+pub const DEFAULT_PPQN: u16 = 24;
+
+/// Silence (seconds) after an Active Sensing message before the link is presumed lost.
+pub const ACTIVE_SENSING_TIMEOUT: f64 = 0.3;
+
+/// Number of recent clock intervals (4 beats at 24 PPQN) the tempo stability
+/// score is computed over.
+pub const TEMPO_STABILITY_WINDOW: usize = 96;
+
+/// Shortest clock interval (seconds) measured, i.e. 1000 BPM at 24 PPQN; scaled
+/// for other clock resolutions. Clocks arriving closer together are treated as
+/// duplicates and ignored.
+pub const MIN_CLOCK_INTERVAL: f64 = 60.0 / (1000.0 * 24.0);
+
+/// Longest clock interval (seconds) measured; longer gaps (e.g. the transport
+/// was stopped) do not update the tempo.
+pub const MAX_CLOCK_INTERVAL: f64 = 2.0;
+
+/// How close (as a fraction of the previous interval) a clock interval must be
+/// to a whole multiple of the previous one to count as dropped clocks rather
+/// than a tempo change.
+pub const DROPPED_CLOCK_TOLERANCE: f64 = 0.15;
+
+/// Number of recent note onsets the onset-based tempo estimate is computed over.
+pub const ONSET_TEMPO_WINDOW: usize = 16;
+
+/// Fewest note onsets needed for an onset-based tempo estimate.
+const MIN_TEMPO_ONSETS: usize = 4;
+
+/// Tempo range (BPM) searched by the onset-based tempo estimate.
+const ONSET_TEMPO_RANGE: std::ops::RangeInclusive<u32> = 40..=240;
+
+/// Timing spread (seconds) tolerated between an onset gap and a whole number of beats.
+const ONSET_TIMING_TOLERANCE: f64 = 0.025;
+
+/// Most buckets `MidiEngine::polyphony_timeline` returns.
+pub const MAX_TIMELINE_BUCKETS: usize = 100_000;
+
+/// Controller carrying timbre (the MPE "slide" dimension).
+pub const TIMBRE_CONTROLLER: u8 = 74;
+
+/// Channel expression dimension a mapped controller drives; see `MidiEngine::map_cc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpressionTarget {
+    /// Pitch bend, with controller value 64 as center.
+    PitchBend,
+    /// Channel pressure.
+    Pressure,
+    /// Timbre, as if sent on `TIMBRE_CONTROLLER`.
+    Timbre,
+}
+
+impl ExpressionTarget {
+    /// Applies a controller value to the channel's expression.
+    fn apply(self, value: u8, expression: &mut ChannelExpression) {
+        match self {
+            // Scale each half separately so 0, 64 and 127 reach the bottom,
+            // center and top of the 14-bit range
+            ExpressionTarget::PitchBend => {
+                let value = value.min(127) as u32;
+                expression.pitch_bend = if value <= 64 {
+                    value * 128
+                } else {
+                    8192 + (value - 64) * 8191 / 63
+                } as u16;
+            },
+            ExpressionTarget::Pressure => expression.pressure = value,
+            ExpressionTarget::Timbre => expression.timbre = value,
+        }
+    }
+}
+
+/// Callback receiving every message the engine stores, as the (possibly
+/// transformed) bytes and the timestamp in seconds. Shared so that a message
+/// being forwarded keeps its callback alive if the callback is replaced.
+pub type ForwardCallback = Arc<dyn Fn(&[u8], f64) + Send + Sync>;
+
+/// The raw bytes of a stored message. Channel and system common/real-time
+/// messages are kept inline, so storing them doesn't allocate; SysEx packets
+/// go on the heap.
+#[derive(Clone, PartialEq, Eq)]
+pub enum MessageBytes {
+    /// A message of up to MAX_CHANNEL_MESSAGE_SIZE bytes; the first `len` are used.
+    Inline {
+        bytes: [u8; MAX_CHANNEL_MESSAGE_SIZE],
+        len: u8,
+    },
+    /// A longer message (SysEx).
+    Heap(Vec<u8>),
+}
+
+impl std::ops::Deref for MessageBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            MessageBytes::Inline { bytes, len } => &bytes[..*len as usize],
+            MessageBytes::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl std::ops::DerefMut for MessageBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            MessageBytes::Inline { bytes, len } => &mut bytes[..*len as usize],
+            MessageBytes::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl From<&[u8]> for MessageBytes {
+    fn from(data: &[u8]) -> Self {
+        if data.len() <= MAX_CHANNEL_MESSAGE_SIZE {
+            let mut bytes = [0; MAX_CHANNEL_MESSAGE_SIZE];
+            bytes[..data.len()].copy_from_slice(data);
+            MessageBytes::Inline { bytes, len: data.len() as u8 }
+        } else {
+            MessageBytes::Heap(data.to_vec())
+        }
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for MessageBytes {
+    fn from(data: [u8; N]) -> Self {
+        MessageBytes::from(&data[..])
+    }
+}
+
+impl PartialEq<Vec<u8>> for MessageBytes {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for MessageBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
 
 /// Holds a single MIDI message + timestamp.
 #[derive(Debug, Clone)]
 pub struct MidiEvent {
-    /// The raw bytes of the MIDI message (up to MAX_MIDI_MESSAGE_SIZE).
-    pub data: Vec<u8>,
+    /// The raw bytes of the MIDI message (up to MAX_CHANNEL_MESSAGE_SIZE, or
+    /// the engine's SysEx limit for SysEx).
+    pub data: MessageBytes,
     /// Timestamp in seconds (e.g., from Time::getMillisecondCounterHiRes() / 1000.0).
     pub timestamp: f64,
 }
 
 /// Statistics for MIDI timing analysis
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct MidiStats {
     // Timing stats
     pub current_bpm: f64,
     pub average_bpm: f64,
     pub jitter: f64,
+    /// 1 - coefficient of variation of the last `TEMPO_STABILITY_WINDOW` clock
+    /// intervals, clamped to 0.0-1.0 (None until that many have been seen)
+    pub tempo_stability: Option<f64>,
+    /// Tempo estimated from the last `ONSET_TEMPO_WINDOW` note onsets (None
+    /// until there are enough). Used as `current_bpm` until a clock is received.
+    pub onset_bpm: Option<f64>,
     pub clock_count: i32,
     pub last_clock_time: f64,
+    pub first_clock_time: f64,
+    /// Clocks presumed lost, from intervals that are a whole multiple of the
+    /// one before
+    pub dropped_clocks: u32,
     
     // MTC stats
     pub mtc_hours: i32,
@@ -32,68 +198,1068 @@ pub struct MidiStats {
     // SPP stats
     pub current_beat: i16,
     pub sysex_in_progress: bool,
+    
+    /// Whether the transport is running: set by Start and Continue, cleared by Stop
+    pub transport_playing: bool,
+    
+    // Release velocity of explicit note-offs (0x80); note-ons with velocity 0
+    // and force-released notes carry none and are not counted
+    pub release_count: u32,
+    pub average_release_velocity: f64,
+    pub peak_release_velocity: u8,
+    
+    /// Note-offs timestamped before their note-on (e.g. reordered by USB jitter)
+    pub out_of_order_notes: u32,
+    /// Most notes held at once
+    pub peak_polyphony: u32,
+    /// Messages evicted or refused because the fixed-capacity store was full
+    pub storage_overflows: u32,
+    /// Messages seen of each type
+    pub message_counts: MessageCounts,
+    
+    // Active Sensing (None until the first 0xFE is seen)
+    pub last_active_sensing: Option<f64>,
+    
+    // Patch selection per channel
+    pub current_program: [u8; 16],
+    /// Bank as (CC#0 MSB << 7) | CC#32 LSB
+    pub current_bank: [u16; 16],
+}
+
+impl MidiStats {
+    /// The current tempo in microseconds per quarter note, the unit of the
+    /// Standard MIDI File tempo meta-event. Returns 0 if the tempo is unknown.
+    pub fn tempo_us_per_quarter(&self) -> u32 {
+        if !self.current_bpm.is_finite() || self.current_bpm <= 0.0 {
+            return 0;
+        }
+        (60_000_000.0 / self.current_bpm).round() as u32
+    }
+}
+
+/// Number of messages of each type processed, matching the categories of
+/// `MidiMessageType`. Shared with C as is.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    /// Note-ons with a non-zero velocity
+    pub note_ons: u32,
+    /// Note-offs, including note-ons with velocity 0
+    pub note_offs: u32,
+    pub control_changes: u32,
+    pub pitch_bends: u32,
+    pub program_changes: u32,
+    /// Channel aftertouch
+    pub aftertouch: u32,
+    pub poly_aftertouch: u32,
+    pub clocks: u32,
+    pub starts: u32,
+    pub stops: u32,
+    pub continues: u32,
+    /// SysEx messages (continuation packets are not counted again)
+    pub sysex: u32,
+    /// Every other message, e.g. MTC, song position and active sensing
+    pub other: u32,
+}
+
+impl MessageCounts {
+    fn count(&mut self, message: &ParsedMessage) {
+        let counter = match message {
+            ParsedMessage::NoteOn { .. } => &mut self.note_ons,
+            ParsedMessage::NoteOff { .. } => &mut self.note_offs,
+            ParsedMessage::ControlChange { .. } => &mut self.control_changes,
+            ParsedMessage::PitchBend { .. } => &mut self.pitch_bends,
+            ParsedMessage::ProgramChange { .. } => &mut self.program_changes,
+            ParsedMessage::ChannelAftertouch { .. } => &mut self.aftertouch,
+            ParsedMessage::PolyAftertouch { .. } => &mut self.poly_aftertouch,
+            ParsedMessage::Clock => &mut self.clocks,
+            ParsedMessage::Start => &mut self.starts,
+            ParsedMessage::Stop => &mut self.stops,
+            ParsedMessage::Continue => &mut self.continues,
+            ParsedMessage::SysEx(_) => &mut self.sysex,
+            _ => &mut self.other,
+        };
+        *counter += 1;
+    }
+}
+
+/// Mapping applied to incoming note-on velocities before they are stored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum VelocityCurve {
+    /// Velocities pass through unchanged.
+    #[default]
+    Linear,
+    /// `127 * (v / 127)^exponent`: exponents above 1.0 soften mid velocities,
+    /// below 1.0 boost them.
+    Exponential(f64),
+    /// Logarithmic response that boosts soft playing.
+    Logarithmic,
+    /// Lookup table mapping each input velocity to an output velocity.
+    Custom([u8; 128]),
+}
+
+impl VelocityCurve {
+    /// Maps a note-on velocity (1-127) through the curve.
+    /// A non-zero velocity never maps to 0, which would turn the note-on into a note-off.
+    pub fn apply(&self, velocity: u8) -> u8 {
+        let velocity = velocity.min(127);
+        let mapped = match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Exponential(exponent) => {
+                (127.0 * (velocity as f64 / 127.0).powf(*exponent)).round() as u8
+            },
+            VelocityCurve::Logarithmic => {
+                (127.0 * (1.0 + velocity as f64).ln() / 128.0f64.ln()).round() as u8
+            },
+            VelocityCurve::Custom(table) => table[velocity as usize].min(127),
+        };
+        if velocity > 0 { mapped.max(1) } else { 0 }
+    }
+}
+
+/// Transposition and channel remapping applied to incoming messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformConfig {
+    /// Semitones added to note-on/note-off note numbers. Notes pushed outside
+    /// 0-127 are dropped.
+    pub transpose_semitones: i8,
+    /// Output channel for each input channel of channel-voice messages.
+    pub channel_map: [u8; 16],
+}
+
+impl Default for TransformConfig {
+    fn default() -> Self {
+        let mut channel_map = [0u8; 16];
+        for (channel, mapped) in channel_map.iter_mut().enumerate() {
+            *mapped = channel as u8;
+        }
+        TransformConfig {
+            transpose_semitones: 0,
+            channel_map,
+        }
+    }
+}
+
+/// Range and channel filter for incoming note-on/note-off messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteFilter {
+    /// Lowest note passed through.
+    pub min_note: u8,
+    /// Highest note passed through.
+    pub max_note: u8,
+    /// Bitmask of channels passed through (bit 0 = channel 0 / "channel 1").
+    pub allowed_channels: u16,
+}
+
+impl NoteFilter {
+    /// Checks whether a note on the given input channel passes the filter.
+    pub fn allows(&self, channel: u8, note: u8) -> bool {
+        (self.min_note..=self.max_note).contains(&note) && self.allowed_channels & (1 << (channel & 0x0F)) != 0
+    }
+}
+
+impl Default for NoteFilter {
+    fn default() -> Self {
+        NoteFilter {
+            min_note: 0,
+            max_note: 127,
+            allowed_channels: 0xFFFF,
+        }
+    }
+}
+
+/// How long the engine keeps stored messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RetentionPolicy {
+    /// Keep every message until it is drained or cleared.
+    #[default]
+    Unlimited,
+    /// Keep at most this many of the most recent messages.
+    Count(usize),
+    /// Keep the messages timestamped within this many seconds of the newest one,
+    /// however many there are.
+    Duration(f64),
+}
+
+impl RetentionPolicy {
+    /// Evicts the messages the policy no longer keeps, oldest first.
+    fn apply(self, messages: &mut VecDeque<MidiEvent>) {
+        let evicted = match self {
+            RetentionPolicy::Unlimited => 0,
+            RetentionPolicy::Count(max) => messages.len().saturating_sub(max),
+            RetentionPolicy::Duration(seconds) => {
+                let Some(newest) = messages.back() else {
+                    return;
+                };
+                let cutoff = newest.timestamp - seconds;
+                messages.iter().position(|event| event.timestamp >= cutoff).unwrap_or(messages.len())
+            },
+        };
+        messages.drain(..evicted);
+    }
+}
+
+/// What an engine with a fixed capacity does with a message when its store is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Evict the oldest stored message to make room.
+    DropOldest,
+    /// Leave the store as is; the message is still analyzed and forwarded.
+    Refuse,
+}
+
+/// Every engine setting, so a saved preset can be applied in one step with
+/// `MidiEngine::with_config`. The default matches `MidiEngine::new`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    /// Curve applied to note-on velocities.
+    pub velocity_curve: VelocityCurve,
+    /// Transposition and channel remapping.
+    pub transform: TransformConfig,
+    /// Notes outside this range or channel set are dropped.
+    pub note_filter: NoteFilter,
+    /// Held notes older than this (seconds) are force-released; None disables reaping.
+    pub stuck_note_timeout: Option<f64>,
+    /// A note-on for a held note ends it before starting again, rather than
+    /// holding the note twice.
+    pub retrigger_mode: bool,
+    /// Latency (seconds) subtracted from incoming timestamps.
+    pub input_offset: f64,
+    /// Longest SysEx message accepted.
+    pub max_sysex_size: usize,
+    /// Seconds a SysEx may stay unterminated before it is abandoned.
+    pub sysex_timeout: f64,
+    /// Clock messages per quarter note; must not be 0.
+    pub ppqn: u16,
+    /// Controllers routed to a channel expression dimension.
+    pub cc_mapping: HashMap<u8, ExpressionTarget>,
+    /// How long stored messages are kept.
+    pub retention: RetentionPolicy,
+    /// Most messages stored at once and what happens beyond that. The store's
+    /// slots are allocated up front and it never grows, and messages other
+    /// than SysEx are stored inline, so once every note and controller in use
+    /// has been seen, processing them doesn't allocate. None stores without a
+    /// limit.
+    pub fixed_capacity: Option<(usize, OverflowPolicy)>,
+}
+
+impl EngineConfig {
+    pub fn velocity_curve(mut self, curve: VelocityCurve) -> Self {
+        self.velocity_curve = curve;
+        self
+    }
+
+    pub fn transpose(mut self, semitones: i8) -> Self {
+        self.transform.transpose_semitones = semitones;
+        self
+    }
+
+    pub fn channel_map(mut self, channel_map: [u8; 16]) -> Self {
+        self.transform.channel_map = channel_map;
+        self
+    }
+
+    pub fn note_filter(mut self, filter: NoteFilter) -> Self {
+        self.note_filter = filter;
+        self
+    }
+
+    pub fn stuck_note_timeout(mut self, max_age: Option<f64>) -> Self {
+        self.stuck_note_timeout = max_age;
+        self
+    }
+
+    pub fn retrigger_mode(mut self, enabled: bool) -> Self {
+        self.retrigger_mode = enabled;
+        self
+    }
+
+    pub fn input_offset(mut self, offset: f64) -> Self {
+        self.input_offset = offset;
+        self
+    }
+
+    pub fn max_sysex_size(mut self, max_size: usize) -> Self {
+        self.max_sysex_size = max_size;
+        self
+    }
+
+    pub fn sysex_timeout(mut self, timeout: f64) -> Self {
+        self.sysex_timeout = timeout;
+        self
+    }
+
+    pub fn ppqn(mut self, ppqn: u16) -> Self {
+        self.ppqn = ppqn;
+        self
+    }
+
+    pub fn map_cc(mut self, controller: u8, target: ExpressionTarget) -> Self {
+        self.cc_mapping.insert(controller, target);
+        self
+    }
+
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        self.retention = policy;
+        self
+    }
+
+    pub fn fixed_capacity(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.fixed_capacity = Some((capacity, policy));
+        self
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            velocity_curve: VelocityCurve::default(),
+            transform: TransformConfig::default(),
+            note_filter: NoteFilter::default(),
+            stuck_note_timeout: None,
+            retrigger_mode: false,
+            input_offset: 0.0,
+            max_sysex_size: DEFAULT_MAX_SYSEX_SIZE,
+            sysex_timeout: DEFAULT_SYSEX_TIMEOUT,
+            ppqn: DEFAULT_PPQN,
+            cc_mapping: HashMap::new(),
+            retention: RetentionPolicy::default(),
+            fixed_capacity: None,
+        }
+    }
+}
+
+/// Aftertouch use among the stored messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AftertouchStats {
+    /// Mean of the non-zero pressure values received (0.0 if none).
+    pub average_pressure: f64,
+    /// Highest pressure value received.
+    pub peak_pressure: u8,
+    /// Fraction of notes that received any pressure while held, from either
+    /// poly or channel aftertouch (0.0 if there are no notes).
+    pub usage: f64,
+}
+
+/// A held note, with the expression currently applied to its channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ActiveNote {
+    /// Output channel (after the transform).
+    pub channel: u8,
+    /// Output note (after the transform).
+    pub note: u8,
+    /// Note-on velocity after the velocity curve.
+    pub velocity: u8,
+    /// Channel pitch bend, 0-16383 with 8192 as center.
+    pub pitch_bend: u16,
+    /// Channel aftertouch pressure.
+    pub pressure: u8,
+    /// Last value of the timbre controller (CC 74) on the channel.
+    pub timbre: u8,
+    /// Timestamp of the note-on.
+    pub start_time: f64,
+}
+
+impl ActiveNote {
+    /// Channel pitch bend from -1.0 (full down) to 1.0 (full up), 0.0 at center.
+    pub fn normalized_pitch_bend(&self) -> f64 {
+        normalize_pitch_bend(self.pitch_bend)
+    }
+}
+
+/// A note from the stored messages, from its note-on to its note-off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompletedNote {
+    pub channel: u8,
+    pub note: u8,
+    /// Note-on velocity.
+    pub velocity: u8,
+    /// Timestamp of the note-on.
+    pub start_time: f64,
+    /// Timestamp of the note-off, never before `start_time`.
+    pub end_time: f64,
+}
+
+impl CompletedNote {
+    /// Length of the note in seconds.
+    pub fn duration(&self) -> f64 {
+        self.end_time - self.start_time
+    }
+}
+
+/// Expression state of one output channel.
+#[derive(Debug, Clone, Copy)]
+struct ChannelExpression {
+    pitch_bend: u16,
+    pressure: u8,
+    timbre: u8,
+}
+
+impl Default for ChannelExpression {
+    fn default() -> Self {
+        ChannelExpression { pitch_bend: 8192, pressure: 0, timbre: 64 }
+    }
+}
+
+/// State guarded by the engine's lock.
+#[derive(Debug, Default)]
+struct EngineState {
+    /// Stored messages.
+    messages: VecDeque<MidiEvent>,
+    /// How long stored messages are kept.
+    retention: RetentionPolicy,
+    /// Most messages stored at once and what happens beyond that.
+    fixed_capacity: Option<(usize, OverflowPolicy)>,
+    /// Running timing/MTC/SPP statistics, updated by `process_message`.
+    stats: MidiStats,
+    /// Curve applied to note-on velocities.
+    velocity_curve: VelocityCurve,
+    /// Transposition and channel remapping.
+    transform: TransformConfig,
+    /// Notes outside this range or channel set are dropped.
+    note_filter: NoteFilter,
+    /// Held notes keyed by input (channel, note), so note-offs match their
+    /// note-on after a transform change. A retriggered note is held once per
+    /// note-on, oldest first, and note-offs release them in that order. A
+    /// key's queue is kept once empty, so replaying the key doesn't allocate.
+    held_notes: HashMap<(u8, u8), VecDeque<HeldNote>>,
+    /// Pitch bend, pressure and timbre of each output channel.
+    channel_expression: [ChannelExpression; 16],
+    /// Controllers routed to a channel expression dimension.
+    cc_mapping: HashMap<u8, ExpressionTarget>,
+    /// Held notes older than this (seconds) are force-released; None disables reaping.
+    stuck_note_timeout: Option<f64>,
+    /// A note-on for a held note ends it before starting again.
+    retrigger_mode: bool,
+    /// Note-offs ending the notes a retriggered note-on restarts, stored and
+    /// forwarded ahead of the note-on by `MidiEngine::store_and_forward`.
+    retriggered: Vec<MessageBytes>,
+    /// Latency (seconds) subtracted from incoming timestamps.
+    input_offset: f64,
+    /// The most recent clock intervals (seconds), for the tempo stability score.
+    clock_intervals: VecDeque<f64>,
+    /// Time (seconds) between the last two clocks as received, for spotting
+    /// dropped clocks.
+    last_clock_interval: Option<f64>,
+    /// Clock intervals measured into the average tempo (the first clock,
+    /// resyncs and long gaps measure none).
+    measured_intervals: u32,
+    /// Set by Start and Continue: the next clock restarts interval measurement.
+    clock_resync: bool,
+    /// Longest SysEx message accepted.
+    max_sysex_size: usize,
+    /// Timestamp of the 0xF0 of the SysEx in progress, if any.
+    sysex_start: Option<f64>,
+    /// Bytes received so far of the SysEx in progress.
+    sysex_buffer: Vec<u8>,
+    /// The most recent complete SysEx, reassembled from its packets.
+    last_sysex: Option<Vec<u8>>,
+    /// Seconds a SysEx may stay unterminated before it is abandoned.
+    sysex_timeout: f64,
+    /// Clock messages per quarter note.
+    ppqn: u16,
+    /// Timestamps of the most recent note-ons, for the onset-based tempo estimate.
+    recent_onsets: VecDeque<f64>,
+}
+
+/// A note-on that has not been released yet.
+#[derive(Debug, Clone, Copy)]
+struct HeldNote {
+    /// Output channel the note-on was sent on.
+    channel: u8,
+    /// Output note (None if the note-on was dropped).
+    note: Option<u8>,
+    /// Note-on velocity after the velocity curve.
+    velocity: u8,
+    /// Timestamp of the note-on.
+    start_time: f64,
+}
+
+impl EngineState {
+    /// Stores a message, unless a full fixed-capacity store refuses it.
+    fn store(&mut self, event: MidiEvent) {
+        if let Some((capacity, policy)) = self.fixed_capacity {
+            if self.messages.len() >= capacity {
+                self.stats.storage_overflows += 1;
+                if policy == OverflowPolicy::Refuse || self.messages.pop_front().is_none() {
+                    return;
+                }
+            }
+        }
+        self.messages.push_back(event);
+    }
+
+    /// Number of held notes that were not dropped by the transform.
+    fn active_note_count(&self) -> usize {
+        self.held_notes.values().flatten().filter(|held| held.note.is_some()).count()
+    }
+
+    /// Applies the note filter, transform and velocity curve to a message.
+    /// Returns None if the message should be dropped.
+    fn transform_message(&mut self, data: &[u8], timestamp: f64) -> Option<MessageBytes> {
+        let mut message = MessageBytes::from(data);
+        let status = message[0];
+        if !(0x80..0xF0).contains(&status) {
+            return Some(message);
+        }
+        
+        let channel = status & 0x0F;
+        message[0] = (status & 0xF0) | (self.transform.channel_map[channel as usize] & 0x0F);
+        
+        if message.len() >= 3 && (status & 0xF0 == 0x80 || status & 0xF0 == 0x90) {
+            let key = (channel, message[1]);
+            let is_note_on = status & 0xF0 == 0x90 && message[2] > 0;
+            
+            // Held notes are always released, even if the filter changed since
+            let releasing_held = !is_note_on && self.held_notes.get(&key).is_some_and(|held| !held.is_empty());
+            if !releasing_held && !self.note_filter.allows(channel, key.1) {
+                return None;
+            }
+            
+            let note = if is_note_on {
+                if self.retrigger_mode {
+                    // Controllers that resend note-on for held keys (or never
+                    // send note-off) restart the note instead of stacking it
+                    for held in self.held_notes.remove(&key).into_iter().flatten() {
+                        if let HeldNote { channel, note: Some(note), .. } = held {
+                            self.retriggered.push([0x80 | channel, note, 0].into());
+                        }
+                    }
+                }
+                let transposed = message[1] as i16 + self.transform.transpose_semitones as i16;
+                let note = (0..=127).contains(&transposed).then_some(transposed as u8);
+                message[2] = self.velocity_curve.apply(message[2]);
+                self.held_notes.entry(key).or_default().push_back(HeldNote {
+                    channel: message[0] & 0x0F,
+                    note,
+                    velocity: message[2],
+                    start_time: timestamp,
+                });
+                if note.is_some() {
+                    let held = self.active_note_count() as u32;
+                    self.stats.peak_polyphony = self.stats.peak_polyphony.max(held);
+                }
+                note
+            } else {
+                // Release whatever the note-on was mapped to
+                match self.release_held(key) {
+                    Some(held) => {
+                        if timestamp < held.start_time {
+                            self.stats.out_of_order_notes += 1;
+                        }
+                        message[0] = (message[0] & 0xF0) | held.channel;
+                        held.note
+                    },
+                    None => {
+                        let transposed = message[1] as i16 + self.transform.transpose_semitones as i16;
+                        (0..=127).contains(&transposed).then_some(transposed as u8)
+                    },
+                }
+            };
+            message[1] = note?;
+        }
+        
+        Some(message)
+    }
+
+    /// Abandons the SysEx in progress if its 0xF7 has not arrived within the
+    /// timeout, so a dropped terminator doesn't leave it open forever.
+    fn expire_sysex(&mut self, now: f64) {
+        let Some(start) = self.sysex_start else {
+            return;
+        };
+        if now - start > self.sysex_timeout {
+            let error = MidiError::TimingError(format!(
+                "SysEx started at {:.3}s not terminated within {}s, discarding it",
+                start,
+                self.sysex_timeout
+            ));
+            log::warn!("{}", error);
+            self.abandon_sysex();
+        }
+    }
+
+    /// Starts a SysEx from the packet holding its 0xF0, replacing any SysEx
+    /// still in progress.
+    fn start_sysex(&mut self, bytes: &[u8], timestamp: f64) {
+        self.sysex_buffer = bytes.to_vec();
+        // A complete message ends with 0xF7; otherwise the rest follows in later packets
+        if bytes.len() >= 2 && bytes.last() == Some(&0xF7) {
+            self.finish_sysex();
+        } else {
+            self.sysex_start = Some(timestamp);
+            self.stats.sysex_in_progress = true;
+        }
+    }
+
+    /// Appends a packet of data bytes to the SysEx in progress, completing it
+    /// if the packet holds the 0xF7.
+    fn continue_sysex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        let end = data.iter().position(|&byte| byte == 0xF7);
+        let fragment = &data[..end.map_or(data.len(), |end| end + 1)];
+        if let Some(&status) = fragment.iter().find(|&&byte| byte >= 0x80 && byte != 0xF7) {
+            self.abandon_sysex();
+            return Err(MidiError::InvalidData(format!("unexpected status byte {:#04x} inside SysEx", status)));
+        }
+        if self.sysex_buffer.len() + fragment.len() > self.max_sysex_size {
+            self.abandon_sysex();
+            return Err(MidiError::InvalidData(format!("SysEx exceeds the {}-byte limit", self.max_sysex_size)));
+        }
+        
+        self.sysex_buffer.extend_from_slice(fragment);
+        if end.is_some() {
+            self.finish_sysex();
+        }
+        Ok(())
+    }
+
+    /// Completes the SysEx in progress on a lone 0xF7.
+    fn end_sysex(&mut self) {
+        if self.sysex_start.is_some() {
+            self.sysex_buffer.push(0xF7);
+            self.finish_sysex();
+        }
+    }
+
+    fn finish_sysex(&mut self) {
+        MidiEngine::update_mtc_full_frame(&self.sysex_buffer, &mut self.stats);
+        self.last_sysex = Some(std::mem::take(&mut self.sysex_buffer));
+        self.sysex_start = None;
+        self.stats.sysex_in_progress = false;
+    }
+
+    fn abandon_sysex(&mut self) {
+        self.sysex_buffer.clear();
+        self.sysex_start = None;
+        self.stats.sysex_in_progress = false;
+    }
+
+    /// Removes the oldest held instance of an input (channel, note).
+    fn release_held(&mut self, key: (u8, u8)) -> Option<HeldNote> {
+        self.held_notes.get_mut(&key)?.pop_front()
+    }
+
+    /// Force-releases held notes that started more than `max_age` seconds before
+    /// `now`, storing a note-off at `now` for each. Returns the number released.
+    fn reap_stuck_notes(&mut self, now: f64, max_age: f64) -> usize {
+        let mut stuck = Vec::new();
+        self.held_notes.retain(|_, held| {
+            held.retain(|instance| {
+                let is_stuck = now - instance.start_time > max_age;
+                if is_stuck {
+                    stuck.push(*instance);
+                }
+                !is_stuck
+            });
+            !held.is_empty()
+        });
+        
+        stuck.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+        for instance in &stuck {
+            if let HeldNote { channel, note: Some(note), .. } = *instance {
+                self.store(MidiEvent {
+                    data: [0x80 | channel, note, 0].into(),
+                    timestamp: now,
+                });
+            }
+        }
+        
+        stuck.len()
+    }
 }
 
 /// The main engine that stores or observes incoming MIDI traffic.
-/// If you want advanced storage (ring buffer, etc.), add it here.
-#[derive(Debug)]
+/// Messages are kept in a ring buffer, optionally of fixed capacity
+/// (see `EngineConfig::fixed_capacity`).
+///
+/// All methods take `&self` and serialize access through an internal lock,
+/// so one thread (e.g. the audio callback) may process messages while
+/// another (e.g. the UI) reads stats or clears the engine.
 pub struct MidiEngine {
-    // If you need concurrency, store data in an Arc<Mutex<...>> or similar.
-    // For now, we just keep a list for demonstration.
-    pub messages: Vec<MidiEvent>,
+    state: Mutex<EngineState>,
+    /// Locked separately from the state so the callback runs without holding
+    /// the state lock.
+    forward: Mutex<Option<ForwardCallback>>,
+}
+
+impl fmt::Debug for MidiEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MidiEngine")
+            .field("state", &self.state)
+            .field("forward", &self.forward.lock().map(|forward| forward.is_some()))
+            .finish()
+    }
+}
+
+/// Locks one of the engine's mutexes. A thread that panicked while holding
+/// it (e.g. in a forward callback) leaves it poisoned; rather than failing
+/// every later call, carry on with the data as the panic left it.
+fn lock_or_recover<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        log::warn!("MidiEngine {} lock poisoned by a panic, recovering", name);
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
 }
 
 impl MidiEngine {
     /// Create a brand-new engine instance.
     pub fn new() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+
+    /// Creates an engine with every setting taken from `config`.
+    pub fn with_config(config: EngineConfig) -> Self {
         MidiEngine {
-            messages: Vec::new(),
+            state: Mutex::new(EngineState {
+                velocity_curve: config.velocity_curve,
+                transform: config.transform,
+                note_filter: config.note_filter,
+                stuck_note_timeout: config.stuck_note_timeout,
+                retrigger_mode: config.retrigger_mode,
+                input_offset: config.input_offset,
+                max_sysex_size: config.max_sysex_size,
+                sysex_timeout: config.sysex_timeout,
+                ppqn: config.ppqn,
+                cc_mapping: config.cc_mapping,
+                retention: config.retention,
+                messages: config.fixed_capacity
+                    .map_or_else(VecDeque::new, |(capacity, _)| VecDeque::with_capacity(capacity)),
+                fixed_capacity: config.fixed_capacity,
+                // The tempo windows never grow past these, so they don't reallocate
+                clock_intervals: VecDeque::with_capacity(TEMPO_STABILITY_WINDOW + 1),
+                recent_onsets: VecDeque::with_capacity(ONSET_TEMPO_WINDOW + 1),
+                ..EngineState::default()
+            }),
+            forward: Mutex::new(None),
         }
     }
 
-    /// Process a new incoming MIDI message (already validated).
-    /// For now, we just store it in `messages`. 
+    /// Locks the engine state.
+    fn state(&self) -> MutexGuard<'_, EngineState> {
+        lock_or_recover(&self.state, "state")
+    }
+
+    /// Process a new incoming MIDI message.
+    /// Updates the running stats and returns a snapshot of them.
+    /// Invalid messages (see `try_process_message`) are ignored.
     /// In a real-time scenario, you might want a lock-free ring buffer
     /// or immediately forward it to C++ instead.
-    pub fn process_message(&mut self, data: &[u8], timestamp: f64) -> MidiStats {
-        let mut stats = MidiStats::default();
+    pub fn process_message(&self, data: &[u8], timestamp: f64) -> MidiStats {
+        self.try_process_message(data, timestamp).unwrap_or_else(|_| self.stats())
+    }
+
+    /// Process a new incoming MIDI message, rejecting empty, truncated,
+    /// oversized or malformed messages with `MidiError::InvalidData` instead of
+    /// storing them. Messages dropped by the note filter or transform are not
+    /// an error. `timestamp` must be finite; the FFI entry points reject
+    /// NaN and infinite timestamps before they get here.
+    pub fn try_process_message(&self, data: &[u8], timestamp: f64) -> Result<MidiStats, MidiError> {
+        debug_assert!(timestamp.is_finite(), "non-finite timestamp {}", timestamp);
+        
+        // System real-time bytes may be interleaved anywhere in a SysEx stream;
+        // handle them as their own messages, ahead of the SysEx bytes around them
+        let in_sysex = matches!(data.first(), Some(&byte) if byte == 0xF0 || byte < 0x80);
+        if in_sysex && data.iter().skip(1).any(|&byte| byte >= 0xF8) {
+            let (realtime, rest): (Vec<u8>, Vec<u8>) = data.iter().partition(|&&byte| byte >= 0xF8);
+            // Undefined real-time bytes (0xF9, 0xFD) are dropped
+            for byte in realtime.into_iter().filter(|&byte| message_len(byte).is_some()) {
+                self.try_process_message(&[byte], timestamp)?;
+            }
+            return self.try_process_message(&rest, timestamp);
+        }
+        
+        let mut state = self.state();
+        let timestamp = (timestamp - state.input_offset).max(0.0);
+        
+        // A packet of data bytes continues the SysEx in progress
+        let continuation = state.sysex_start.is_some() && data.first().is_some_and(|&byte| byte < 0x80);
+        let limit = match data.first() {
+            Some(0xF0) => state.max_sysex_size,
+            _ if continuation => state.max_sysex_size,
+            _ => MAX_CHANNEL_MESSAGE_SIZE,
+        };
+        if data.len() > limit {
+            return Err(MidiError::InvalidData(format!(
+                "message of {} bytes exceeds the {}-byte limit",
+                data.len(),
+                limit
+            )));
+        }
         
-        // Validate message size
-        if data.is_empty() {
-            return stats;
+        if let Some(max_age) = state.stuck_note_timeout {
+            state.reap_stuck_notes(timestamp, max_age);
+        }
+        state.expire_sysex(timestamp);
+        if continuation && state.sysex_start.is_some() {
+            state.continue_sysex(data)?;
+            return Ok(self.store_and_forward(state, Some(data.into()), timestamp));
+        }
+        
+        // Validate before the transform so held-note bookkeeping only sees complete messages
+        parse_midi_message(data)?;
+        let message = match state.transform_message(data, timestamp) {
+            Some(message) => message,
+            // A note-on dropped by the transform still ends the notes it retriggered
+            None if !state.retriggered.is_empty() => return Ok(self.store_and_forward(state, None, timestamp)),
+            None => return Ok(state.stats.clone()),
+        };
+        let parsed = parse_midi_message(&message)?;
+        state.stats.message_counts.count(&parsed);
+        match &parsed {
+            ParsedMessage::SysEx(bytes) => state.start_sysex(bytes, timestamp),
+            ParsedMessage::EndOfExclusive => state.end_sysex(),
+            _ => {}
         }
         
-        match data[0] {
-            0xF8 => self.update_timing(timestamp, &mut stats),
-            0xF1 if data.len() >= 2 => self.update_mtc(data[1], &mut stats),
-            0xF2 if data.len() >= 3 => self.update_spp(data[1], data[2], &mut stats),
-            0xF0 => {
-                // Handle SysEx start - could buffer for reassembly
-                stats.sysex_in_progress = true;
+        let EngineState {
+            stats,
+            clock_intervals,
+            last_clock_interval,
+            measured_intervals,
+            clock_resync,
+            channel_expression,
+            cc_mapping,
+            recent_onsets,
+            ppqn,
+            ..
+        } = &mut *state;
+        match parsed {
+            ParsedMessage::Clock => {
+                Self::update_timing(
+                    timestamp,
+                    *ppqn,
+                    stats,
+                    clock_intervals,
+                    last_clock_interval,
+                    measured_intervals,
+                    clock_resync,
+                );
+            },
+            ParsedMessage::Start => {
+                stats.transport_playing = true;
+                stats.current_beat = 0;
+                *clock_resync = true;
+            },
+            // Resumes from the current song position, keeping the tempo average
+            ParsedMessage::Continue => {
+                stats.transport_playing = true;
+                *clock_resync = true;
+            },
+            ParsedMessage::Stop => stats.transport_playing = false,
+            ParsedMessage::MtcQuarterFrame(data) => Self::update_mtc(data, stats),
+            ParsedMessage::SongPosition(position) => Self::update_spp(position, stats),
+            ParsedMessage::ActiveSensing => stats.last_active_sensing = Some(timestamp),
+            ParsedMessage::ProgramChange { channel, program } => {
+                stats.current_program[channel as usize] = program;
+            },
+            ParsedMessage::ControlChange { channel, controller, value } => {
+                // A mapping overrides the controller's usual meaning
+                if let Some(target) = cc_mapping.get(&controller) {
+                    target.apply(value, &mut channel_expression[channel as usize]);
+                } else {
+                    if controller == TIMBRE_CONTROLLER {
+                        channel_expression[channel as usize].timbre = value;
+                    }
+                    Self::update_bank(channel, controller, value, stats);
+                }
             },
-            0xF7 => {
-                // Handle SysEx end
-                stats.sysex_in_progress = false;
+            ParsedMessage::NoteOn { .. } => Self::update_onset_tempo(timestamp, stats, recent_onsets),
+            ParsedMessage::NoteOff { velocity, .. } if message[0] & 0xF0 == 0x80 => {
+                stats.release_count += 1;
+                stats.average_release_velocity +=
+                    (velocity as f64 - stats.average_release_velocity) / stats.release_count as f64;
+                stats.peak_release_velocity = stats.peak_release_velocity.max(velocity);
+            },
+            ParsedMessage::PitchBend { channel, value } => {
+                channel_expression[channel as usize].pitch_bend = value;
+            },
+            ParsedMessage::ChannelAftertouch { channel, pressure } => {
+                channel_expression[channel as usize].pressure = pressure;
             },
             _ => {}  // Other message types
         }
         
+        Ok(self.store_and_forward(state, Some(message), timestamp))
+    }
+
+    /// Stores an analyzed message, preceded by the note-offs of any notes it
+    /// retriggered, then releases the lock and hands them to the forward
+    /// callback in the same order. Returns the updated stats.
+    fn store_and_forward(&self, mut state: MutexGuard<'_, EngineState>, message: Option<MessageBytes>, timestamp: f64) -> MidiStats {
+        let retriggered = std::mem::take(&mut state.retriggered);
+        for note_off in &retriggered {
+            state.stats.message_counts.note_offs += 1;
+            state.store(MidiEvent {
+                data: note_off.clone(),
+                timestamp,
+            });
+        }
+        if let Some(message) = &message {
+            state.store(MidiEvent {
+                data: message.clone(),
+                timestamp,
+            });
+        }
+        let retention = state.retention;
+        retention.apply(&mut state.messages);
+        let stats = state.stats.clone();
+        drop(state);
+        
+        // Copied out so the callback runs without the lock, and may process
+        // messages or replace itself
+        let forward = lock_or_recover(&self.forward, "forward callback").clone();
+        if let Some(forward) = forward {
+            for message in retriggered.iter().chain(&message) {
+                forward(message, timestamp);
+            }
+        }
+        
         stats
     }
 
-    fn update_timing(&mut self, timestamp: f64, stats: &mut MidiStats) {
-        if let Some(last_event) = self.messages.last() {
-            let delta = timestamp - last_event.timestamp;
-            if delta > 0.0 && delta < 2.0 {  // Ignore gaps > 2 seconds
-                stats.current_bpm = 60.0 / (delta * 24.0);  // 24 PPQN
-                stats.jitter = delta - (60.0 / (stats.current_bpm * 24.0));
+    /// Gets the most recent complete SysEx message, reassembled from the
+    /// packets it arrived in (real-time bytes interleaved in it removed).
+    pub fn last_sysex(&self) -> Option<Vec<u8>> {
+        self.state().last_sysex.clone()
+    }
+
+    /// Sets the callback that receives each message after it is analyzed and
+    /// stored; messages rejected or dropped by the note filter are never
+    /// forwarded. None removes the callback. Once this returns, the previous
+    /// callback will not be called again, though a message already being
+    /// forwarded to it still completes.
+    pub fn set_forward_callback(&self, callback: Option<ForwardCallback>) {
+        *lock_or_recover(&self.forward, "forward callback") = callback;
+    }
+
+    /// Gets a snapshot of the current stats.
+    pub fn stats(&self) -> MidiStats {
+        self.state().stats.clone()
+    }
+
+    fn update_timing(
+        timestamp: f64,
+        ppqn: u16,
+        stats: &mut MidiStats,
+        clock_intervals: &mut VecDeque<f64>,
+        last_clock_interval: &mut Option<f64>,
+        measured_intervals: &mut u32,
+        resync: &mut bool,
+    ) {
+        let ppqn = ppqn as f64;
+        if stats.clock_count == 0 {
+            stats.first_clock_time = timestamp;
+        } else if *resync {
+            // First clock after Start/Continue: the gap back to the last clock
+            // before the pause says nothing about the tempo, so measuring
+            // restarts from here
+            *last_clock_interval = None;
+        } else {
+            let delta = timestamp - stats.last_clock_time;
+            if delta < MIN_CLOCK_INTERVAL * DEFAULT_PPQN as f64 / ppqn {
+                // Duplicate (or out-of-order) clock from a glitchy callback
+                return;
+            }
+            if delta < MAX_CLOCK_INTERVAL {
+                // An interval close to a multiple of the previous one means
+                // clocks went missing; measure the tempo from a single pulse.
+                // The interval is compared with the previous one as received,
+                // so a sudden halving of the tempo is only miscounted once.
+                let mut pulse = delta;
+                if let Some(previous) = *last_clock_interval {
+                    let multiple = (delta / previous).round();
+                    if multiple >= 2.0 && (delta - multiple * previous).abs() <= DROPPED_CLOCK_TOLERANCE * previous {
+                        stats.dropped_clocks += multiple as u32 - 1;
+                        pulse = delta / multiple;
+                    }
+                }
+                *last_clock_interval = Some(delta);
+                let delta = pulse;
+                
+                stats.current_bpm = 60.0 / (delta * ppqn);
+                
+                // Running mean over every measured interval
+                *measured_intervals += 1;
+                stats.average_bpm += (stats.current_bpm - stats.average_bpm) / *measured_intervals as f64;
+                
+                // Deviation of this interval from the one implied by the average tempo
+                let expected = 60.0 / (stats.average_bpm * ppqn);
+                stats.jitter = (delta - expected).abs();
+                
+                clock_intervals.push_back(delta);
+                if clock_intervals.len() > TEMPO_STABILITY_WINDOW {
+                    clock_intervals.pop_front();
+                }
+                stats.tempo_stability = (clock_intervals.len() == TEMPO_STABILITY_WINDOW)
+                    .then(|| Self::stability(clock_intervals));
+            } else {
+                *last_clock_interval = None;
             }
         }
+        *resync = false;
         stats.clock_count += 1;
         stats.last_clock_time = timestamp;
     }
 
-    fn update_mtc(&mut self, data: u8, stats: &mut MidiStats) {
+    fn update_onset_tempo(timestamp: f64, stats: &mut MidiStats, recent_onsets: &mut VecDeque<f64>) {
+        recent_onsets.push_back(timestamp);
+        if recent_onsets.len() > ONSET_TEMPO_WINDOW {
+            recent_onsets.pop_front();
+        }
+        stats.onset_bpm = Self::estimate_onset_tempo(recent_onsets);
+        
+        // Any clock-derived tempo takes precedence
+        if let (0, Some(bpm)) = (stats.clock_count, stats.onset_bpm) {
+            stats.current_bpm = bpm;
+        }
+    }
+
+    /// Finds the beat period that best explains the gaps between every pair of
+    /// onsets: each gap scores by how close it is to a whole number of beats,
+    /// weighted toward gaps of fewer beats so that subdivisions of the beat
+    /// don't win. Returns the tempo in whole BPM.
+    fn estimate_onset_tempo(onsets: &VecDeque<f64>) -> Option<f64> {
+        if onsets.len() < MIN_TEMPO_ONSETS {
+            return None;
+        }
+        
+        // Iterated once per candidate tempo rather than collected, so
+        // processing a note-on doesn't allocate
+        let gaps = || {
+            onsets.iter().enumerate()
+                .flat_map(move |(i, earlier)| onsets.iter().skip(i + 1).map(move |later| (later - earlier).abs()))
+                .filter(|&gap| gap > 0.0 && gap <= MAX_CLOCK_INTERVAL)
+        };
+        
+        let mut best = None;
+        let mut best_score = 0.0;
+        for bpm in ONSET_TEMPO_RANGE {
+            let beat = 60.0 / bpm as f64;
+            let score: f64 = gaps()
+                .map(|gap| {
+                    let beats = (gap / beat).round().max(1.0);
+                    let error = gap - beats * beat;
+                    (-error * error / (2.0 * ONSET_TIMING_TOLERANCE * ONSET_TIMING_TOLERANCE)).exp() / beats
+                })
+                .sum();
+            if score > best_score {
+                best = Some(bpm as f64);
+                best_score = score;
+            }
+        }
+        best
+    }
+
+    /// 1 - coefficient of variation of the intervals, clamped to 0.0-1.0.
+    fn stability(intervals: &VecDeque<f64>) -> f64 {
+        let count = intervals.len() as f64;
+        let mean = intervals.iter().sum::<f64>() / count;
+        let variance = intervals.iter().map(|interval| (interval - mean).powi(2)).sum::<f64>() / count;
+        (1.0 - variance.sqrt() / mean).clamp(0.0, 1.0)
+    }
+
+    fn update_mtc(data: u8, stats: &mut MidiStats) {
         let mtc_type = (data >> 4) & 0x7;
         let value = data & 0x0F;
         
@@ -107,23 +1273,1726 @@ impl MidiEngine {
             6 => stats.mtc_hours = (stats.mtc_hours & 0xF0) | value as i32,
             7 => {
                 stats.mtc_hours = (stats.mtc_hours & 0x0F) | ((value as i32 & 0x1) << 4);
-                stats.mtc_frame_rate = match (value >> 1) & 0x3 {
-                    0 => 24.0,
-                    1 => 25.0,
-                    2 => 29.97,
-                    _ => 30.0,
-                };
+                stats.mtc_frame_rate = Self::mtc_frame_rate(value >> 1);
             }
             _ => {}
         }
     }
 
-    fn update_spp(&mut self, lsb: u8, msb: u8, stats: &mut MidiStats) {
-        stats.current_beat = ((msb as i16) << 7) | (lsb as i16);
+    /// Sets the MTC fields from a full-frame message, the Universal Real-Time
+    /// SysEx `F0 7F <device> 01 01 hr mn sc fr F7` that devices send when
+    /// locating, instead of waiting for eight quarter frames. Other SysEx
+    /// messages are ignored.
+    fn update_mtc_full_frame(sysex: &[u8], stats: &mut MidiStats) {
+        let [0xF0, 0x7F, _, 0x01, 0x01, hours, minutes, seconds, frames, 0xF7] = *sysex else {
+            return;
+        };
+        // The hours byte carries the frame rate in bits 5-6
+        stats.mtc_hours = (hours & 0x1F) as i32;
+        stats.mtc_frame_rate = Self::mtc_frame_rate(hours >> 5);
+        stats.mtc_minutes = (minutes & 0x3F) as i32;
+        stats.mtc_seconds = (seconds & 0x3F) as i32;
+        stats.mtc_frames = (frames & 0x1F) as i32;
     }
 
-    /// Clear all stored messages (if you want a "reset" feature).
-    pub fn clear(&mut self) {
-        self.messages.clear();
+    /// Frames per second for an MTC rate code (0-3).
+    fn mtc_frame_rate(code: u8) -> f64 {
+        match code & 0x3 {
+            0 => 24.0,
+            1 => 25.0,
+            2 => 29.97,
+            _ => 30.0,
+        }
+    }
+
+    fn update_spp(position: u16, stats: &mut MidiStats) {
+        stats.current_beat = position as i16;
+    }
+
+    fn update_bank(channel: u8, controller: u8, value: u8, stats: &mut MidiStats) {
+        let bank = &mut stats.current_bank[channel as usize];
+        match controller {
+            0 => *bank = (*bank & 0x007F) | ((value as u16) << 7),
+            32 => *bank = (*bank & 0x3F80) | value as u16,
+            _ => {}
+        }
     }
-}
\ No newline at end of file
+
+    /// Sets the curve applied to the velocity of subsequent note-on messages.
+    pub fn set_velocity_curve(&self, curve: VelocityCurve) {
+        self.state().velocity_curve = curve;
+    }
+
+    /// Changes the transposition and channel remapping applied to subsequent
+    /// messages, in place under a single lock so concurrent changes to
+    /// different fields are all kept. Notes already held are still released
+    /// on the note they were sent as.
+    pub fn update_transform(&self, update: impl FnOnce(&mut TransformConfig)) {
+        update(&mut self.state().transform);
+    }
+
+    /// Sets the range and channel filter for subsequent note-on/note-off messages.
+    pub fn set_note_filter(&self, filter: NoteFilter) {
+        self.state().note_filter = filter;
+    }
+
+    /// Routes a controller to a channel expression dimension, overriding its
+    /// usual meaning (e.g. bank select or timbre); None removes the mapping.
+    pub fn map_cc(&self, controller: u8, target: Option<ExpressionTarget>) {
+        let mut state = self.state();
+        match target {
+            Some(target) => state.cc_mapping.insert(controller, target),
+            None => state.cc_mapping.remove(&controller),
+        };
+    }
+
+    /// Checks the Active Sensing watchdog: false once a device that has sent
+    /// Active Sensing goes quiet for longer than `ACTIVE_SENSING_TIMEOUT`.
+    /// The watchdog only arms after the first Active Sensing message.
+    pub fn is_link_alive(&self, now: f64) -> bool {
+        match self.state().stats.last_active_sensing {
+            Some(last) => now - last <= ACTIVE_SENSING_TIMEOUT,
+            None => true,
+        }
+    }
+
+    /// Number of notes currently held (note-on seen, no note-off yet).
+    pub fn active_note_count(&self) -> usize {
+        self.state().active_note_count()
+    }
+
+    /// Gets the notes currently held, oldest first.
+    pub fn active_notes(&self) -> Vec<ActiveNote> {
+        let state = self.state();
+        let mut notes: Vec<ActiveNote> = state.held_notes.values()
+            .flatten()
+            .filter_map(|held| {
+                let expression = state.channel_expression[held.channel as usize];
+                Some(ActiveNote {
+                    channel: held.channel,
+                    note: held.note?,
+                    velocity: held.velocity,
+                    pitch_bend: expression.pitch_bend,
+                    pressure: expression.pressure,
+                    timbre: expression.timbre,
+                    start_time: held.start_time,
+                })
+            })
+            .collect();
+        notes.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+        notes
+    }
+
+    /// Gets the pitch classes of the held notes as a 12-bit mask (bit 0 = C,
+    /// bit 1 = C#, etc.), whatever their channel.
+    pub fn active_pitch_class_set(&self) -> u16 {
+        self.state().held_notes.values()
+            .flatten()
+            .filter_map(|held| held.note)
+            .fold(0, |mask, note| mask | 1 << (note % 12))
+    }
+
+    /// Sets whether a note-on for a held note ends it (storing a note-off)
+    /// before starting it again. Off by default: the note is held once per
+    /// note-on and each needs its own note-off.
+    pub fn set_retrigger_mode(&self, enabled: bool) {
+        self.state().retrigger_mode = enabled;
+    }
+
+    /// Sets how long (seconds) a note may be held before it is presumed stuck
+    /// (e.g. its note-off was lost) and released by `process_message`.
+    /// None disables reaping.
+    pub fn set_stuck_note_timeout(&self, max_age: Option<f64>) {
+        self.state().stuck_note_timeout = max_age;
+    }
+
+    /// Sets the longest SysEx message (in bytes, including 0xF0 and 0xF7)
+    /// accepted by `process_message`; the default is `DEFAULT_MAX_SYSEX_SIZE`.
+    pub fn set_max_sysex_size(&self, max_size: usize) {
+        self.state().max_sysex_size = max_size;
+    }
+
+    /// Sets how long (seconds) a SysEx may stay unterminated before it is
+    /// abandoned; the default is `DEFAULT_SYSEX_TIMEOUT`.
+    pub fn set_sysex_timeout(&self, timeout: f64) {
+        self.state().sysex_timeout = timeout;
+    }
+
+    /// Sets the transport latency (seconds) subtracted from the timestamp of
+    /// every incoming message, so engines fed by different devices line up.
+    /// Adjusted timestamps are clamped to 0.
+    pub fn set_input_offset(&self, offset: f64) {
+        self.state().input_offset = offset;
+    }
+
+    /// Releases notes held for longer than `max_age` seconds at `now`, storing
+    /// a note-off for each. Returns the number of notes released.
+    pub fn reap_stuck_notes(&self, now: f64, max_age: f64) -> usize {
+        self.state().reap_stuck_notes(now, max_age)
+    }
+
+    /// Releases every held note at `now`, storing a note-off for each, so the
+    /// notes still sounding when a session ends are included (with their
+    /// durations) in `notes_in_range`. Call before `reset` or dropping the
+    /// engine to keep them. Returns the number of notes released.
+    pub fn finalize(&self, now: f64) -> usize {
+        self.state().reap_stuck_notes(now, f64::NEG_INFINITY)
+    }
+
+    /// Creates a metronome for the current clock tempo in the given time
+    /// signature, with bars counted from the first clock message.
+    pub fn click_generator(&self, numerator: u8, denominator: u8) -> ClickGenerator {
+        let stats = self.stats();
+        ClickGenerator {
+            bpm: stats.current_bpm,
+            numerator,
+            denominator,
+            origin: stats.first_clock_time,
+        }
+    }
+
+    /// Timestamps of the stored note-on messages (velocity > 0), in order.
+    fn note_onsets(&self) -> Vec<f64> {
+        let mut onsets: Vec<f64> = self.state().messages.iter()
+            .filter(|event| event.data.len() >= 3 && event.data[0] & 0xF0 == 0x90 && event.data[2] > 0)
+            .map(|event| event.timestamp)
+            .collect();
+        onsets.sort_by(|a, b| a.total_cmp(b));
+        onsets
+    }
+
+    /// Gaps (seconds) between consecutive note onsets among the stored messages.
+    pub fn inter_onset_intervals(&self) -> Vec<f64> {
+        self.note_onsets().windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
+
+    /// Coefficient of variation of the inter-onset intervals: 0.0 for perfectly
+    /// even onsets, larger for more irregular timing. Returns 0.0 if there are
+    /// fewer than two intervals.
+    pub fn rhythmic_regularity(&self) -> f64 {
+        let intervals = self.inter_onset_intervals();
+        if intervals.len() < 2 {
+            return 0.0;
+        }
+        
+        let count = intervals.len() as f64;
+        let mean = intervals.iter().sum::<f64>() / count;
+        if mean <= 0.0 {
+            return 0.0;
+        }
+        let variance = intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / count;
+        variance.sqrt() / mean
+    }
+
+    /// Estimates the swing ratio (percent) of the stored eighth-note onsets at the
+    /// current clock tempo: the on-beat eighth's share of each beat, so 50.0 is
+    /// straight and about 66.7 is triplet swing.
+    ///
+    /// The beat grid is anchored at the first onset. Returns -1.0 if the tempo
+    /// is unknown or there are too few on-beat and off-beat onsets to compare.
+    pub fn swing_ratio(&self) -> f64 {
+        let bpm = self.stats().current_bpm;
+        let onsets = self.note_onsets();
+        if bpm <= 0.0 || onsets.len() < 4 {
+            return -1.0;
+        }
+        
+        let beat = 60.0 / bpm;
+        let mut on_beat = 0;
+        let mut off_beat_phases = Vec::new();
+        for onset in &onsets {
+            let phase = ((onset - onsets[0]) / beat).rem_euclid(1.0);
+            if !(0.15..=0.85).contains(&phase) {
+                on_beat += 1;
+            } else if phase >= 0.35 {
+                // Off-beat eighths land between straight (0.5) and heavy swing (0.75+)
+                off_beat_phases.push(phase);
+            }
+        }
+        
+        if on_beat < 2 || off_beat_phases.len() < 2 {
+            return -1.0;
+        }
+        
+        off_beat_phases.iter().sum::<f64>() / off_beat_phases.len() as f64 * 100.0
+    }
+
+    /// Changes in the number of sounding notes among the stored messages, as
+    /// (timestamp, +1 for a note-on / -1 for a note-off), in time order.
+    /// Note-offs with no matching note-on are ignored.
+    ///
+    /// Note-offs are matched to note-ons in arrival order, like
+    /// `notes_in_range`; a note-off timestamped before its note-on ends the
+    /// note at its start.
+    fn note_changes(&self) -> Vec<(f64, i32)> {
+        let events: Vec<(f64, MessageBytes)> = self.state().messages.iter()
+            .filter(|event| event.data.len() >= 3 && matches!(event.data[0] & 0xF0, 0x80 | 0x90))
+            .map(|event| (event.timestamp, event.data.clone()))
+            .collect();
+        
+        // Start times of the sounding notes per (channel, note), oldest first
+        let mut sounding: HashMap<(u8, u8), VecDeque<f64>> = HashMap::new();
+        let mut changes = Vec::new();
+        for (timestamp, data) in events {
+            let key = (data[0] & 0x0F, data[1]);
+            if data[0] & 0xF0 == 0x90 && data[2] > 0 {
+                sounding.entry(key).or_default().push_back(timestamp);
+                changes.push((timestamp, 1));
+            } else if let Some(start_time) = sounding.get_mut(&key).and_then(VecDeque::pop_front) {
+                changes.push((timestamp.max(start_time), -1));
+            }
+        }
+        // Stable, so a note's +1 stays ahead of a -1 at the same time
+        changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        changes
+    }
+
+    /// Notes among the stored messages that sound at some point within
+    /// `start..=end` seconds, ordered by start time. Notes still held are not
+    /// included.
+    ///
+    /// Note-offs are matched to note-ons in arrival order, like the engine
+    /// does live; a note-off timestamped before its note-on (counted in
+    /// `MidiStats::out_of_order_notes`) ends the note at its start.
+    pub fn notes_in_range(&self, start: f64, end: f64) -> Vec<CompletedNote> {
+        let events: Vec<(f64, MessageBytes)> = self.state().messages.iter()
+            .filter(|event| event.data.len() >= 3 && matches!(event.data[0] & 0xF0, 0x80 | 0x90))
+            .map(|event| (event.timestamp, event.data.clone()))
+            .collect();
+        
+        // Sounding (start time, velocity) per (channel, note), oldest first
+        let mut sounding: HashMap<(u8, u8), VecDeque<(f64, u8)>> = HashMap::new();
+        let mut notes = Vec::new();
+        for (timestamp, data) in events {
+            let key = (data[0] & 0x0F, data[1]);
+            if data[0] & 0xF0 == 0x90 && data[2] > 0 {
+                sounding.entry(key).or_default().push_back((timestamp, data[2]));
+            } else if let Some((start_time, velocity)) = sounding.get_mut(&key).and_then(VecDeque::pop_front) {
+                let end_time = timestamp.max(start_time);
+                if start_time <= end && end_time >= start {
+                    notes.push(CompletedNote { channel: key.0, note: key.1, velocity, start_time, end_time });
+                }
+            }
+        }
+        notes.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+        notes
+    }
+
+    /// Groups the stored note-ons into chords: onsets within `tolerance`
+    /// seconds of the first onset of a group are taken as struck together.
+    /// Returns each group's notes, ascending and without duplicates, in time
+    /// order; empty if `tolerance` is negative or NaN.
+    pub fn chord_onsets(&self, tolerance: f64) -> Vec<Vec<u8>> {
+        if tolerance.is_nan() || tolerance < 0.0 {
+            return Vec::new();
+        }
+        let mut onsets: Vec<(f64, u8)> = self.state().messages.iter()
+            .filter(|event| event.data.len() >= 3 && event.data[0] & 0xF0 == 0x90 && event.data[2] > 0)
+            .map(|event| (event.timestamp, event.data[1]))
+            .collect();
+        onsets.sort_by(|a, b| a.0.total_cmp(&b.0));
+        
+        let mut chords: Vec<Vec<u8>> = Vec::new();
+        let mut chord_start = f64::NEG_INFINITY;
+        for (timestamp, note) in onsets {
+            match chords.last_mut() {
+                Some(chord) if timestamp - chord_start <= tolerance => chord.push(note),
+                _ => {
+                    chords.push(vec![note]);
+                    chord_start = timestamp;
+                },
+            }
+        }
+        for chord in &mut chords {
+            chord.sort_unstable();
+            chord.dedup();
+        }
+        chords
+    }
+
+    /// Polyphony over time among the stored messages: for each bucket of
+    /// `bucket_seconds`, starting at the first note event, the bucket's start
+    /// time and the most notes sounding at once within it. Empty if there are
+    /// no note events, the bucket size is not positive, or it is so small the
+    /// timeline would need more than `MAX_TIMELINE_BUCKETS` buckets.
+    pub fn polyphony_timeline(&self, bucket_seconds: f64) -> Vec<(f64, usize)> {
+        if !bucket_seconds.is_finite() || bucket_seconds <= 0.0 {
+            return Vec::new();
+        }
+        let changes = self.note_changes();
+        let (Some(&(start, _)), Some(&(end, _))) = (changes.first(), changes.last()) else {
+            return Vec::new();
+        };
+        
+        let buckets = ((end - start) / bucket_seconds).floor();
+        if buckets >= MAX_TIMELINE_BUCKETS as f64 {
+            return Vec::new();
+        }
+        let bucket_count = buckets as usize + 1;
+        let mut changes = changes.into_iter().peekable();
+        let mut active = 0i32;
+        let mut timeline = Vec::with_capacity(bucket_count);
+        for index in 0..bucket_count {
+            let bucket_start = start + index as f64 * bucket_seconds;
+            let bucket_end = bucket_start + bucket_seconds;
+            
+            // Only count levels held for some time, so a note ending exactly at
+            // the bucket start doesn't count in this bucket
+            let mut peak = 0;
+            let mut position = bucket_start;
+            while let Some((time, delta)) = changes.next_if(|&(time, _)| time < bucket_end || index + 1 == bucket_count) {
+                if time > position {
+                    peak = peak.max(active);
+                }
+                active += delta;
+                position = position.max(time);
+            }
+            if bucket_end > position {
+                peak = peak.max(active);
+            }
+            timeline.push((bucket_start, peak as usize));
+        }
+        timeline
+    }
+
+    /// Measures how much aftertouch the stored notes received.
+    pub fn aftertouch_stats(&self) -> AftertouchStats {
+        let mut events: Vec<(f64, MessageBytes)> = self.state().messages.iter()
+            .map(|event| (event.timestamp, event.data.clone()))
+            .collect();
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        
+        // Per held (channel, note): indices into `pressed` of its sounding instances
+        let mut held: HashMap<(u8, u8), Vec<usize>> = HashMap::new();
+        let mut pressed: Vec<bool> = Vec::new();
+        let mut pressures: Vec<u8> = Vec::new();
+        for (_, data) in events {
+            let Ok(parsed) = parse_midi_message(&data) else {
+                continue;
+            };
+            match parsed {
+                ParsedMessage::NoteOn { channel, note, .. } => {
+                    held.entry((channel, note)).or_default().push(pressed.len());
+                    pressed.push(false);
+                },
+                ParsedMessage::NoteOff { channel, note, .. } => {
+                    if let Some(instances) = held.get_mut(&(channel, note)) {
+                        if !instances.is_empty() {
+                            instances.remove(0);
+                        }
+                    }
+                },
+                ParsedMessage::PolyAftertouch { channel, note, pressure } if pressure > 0 => {
+                    pressures.push(pressure);
+                    for &index in held.get(&(channel, note)).into_iter().flatten() {
+                        pressed[index] = true;
+                    }
+                },
+                ParsedMessage::ChannelAftertouch { channel, pressure } if pressure > 0 => {
+                    pressures.push(pressure);
+                    for (_, instances) in held.iter().filter(|((held_channel, _), _)| *held_channel == channel) {
+                        for &index in instances {
+                            pressed[index] = true;
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+        
+        AftertouchStats {
+            average_pressure: if pressures.is_empty() {
+                0.0
+            } else {
+                pressures.iter().map(|&p| p as f64).sum::<f64>() / pressures.len() as f64
+            },
+            peak_pressure: pressures.iter().copied().max().unwrap_or(0),
+            usage: if pressed.is_empty() {
+                0.0
+            } else {
+                pressed.iter().filter(|&&p| p).count() as f64 / pressed.len() as f64
+            },
+        }
+    }
+
+    /// Nudges each stored note-on toward the nearest grid line, where the grid
+    /// is `grid_division` notes per whole note (16 = sixteenths) at `bpm`,
+    /// anchored at timestamp 0. `strength` is the fraction of the distance moved
+    /// (0.0 none, 1.0 fully on the grid). Each note's note-off moves by the same
+    /// amount, so durations are kept. Returns false for invalid arguments.
+    pub fn quantize(&self, grid_division: u32, bpm: f64, strength: f64) -> bool {
+        if grid_division == 0 || !bpm.is_finite() || bpm <= 0.0 || !(0.0..=1.0).contains(&strength) {
+            return false;
+        }
+        
+        let grid = 60.0 / bpm * 4.0 / grid_division as f64;
+        let mut state = self.state();
+        // Shifts applied to the held notes, keyed by (status channel, note),
+        // oldest first, so a retriggered note's note-offs match in order
+        let mut held: HashMap<(u8, u8), VecDeque<f64>> = HashMap::new();
+        
+        for event in state.messages.iter_mut() {
+            if event.data.len() < 3 {
+                continue;
+            }
+            let key = (event.data[0] & 0x0F, event.data[1]);
+            match event.data[0] & 0xF0 {
+                0x90 if event.data[2] > 0 => {
+                    let target = (event.timestamp / grid).round() * grid;
+                    let shift = (target - event.timestamp) * strength;
+                    event.timestamp += shift;
+                    held.entry(key).or_default().push_back(shift);
+                },
+                0x80 | 0x90 => {
+                    if let Some(shift) = held.get_mut(&key).and_then(VecDeque::pop_front) {
+                        event.timestamp += shift;
+                    }
+                },
+                _ => {},
+            }
+        }
+        // Shifted notes may have passed their neighbours; keep the store in time
+        // order (stable, so ties keep their arrival order) for retention
+        state.messages.make_contiguous().sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+        
+        true
+    }
+
+    /// Returns a copy of all stored messages, oldest first.
+    pub fn snapshot(&self) -> Vec<MidiEvent> {
+        self.state().messages.iter().cloned().collect()
+    }
+
+    /// Removes and returns up to `max` of the oldest stored messages.
+    pub fn drain(&self, max: usize) -> Vec<MidiEvent> {
+        let mut state = self.state();
+        let count = max.min(state.messages.len());
+        state.messages.drain(..count).collect()
+    }
+
+    /// Sets how long stored messages are kept, evicting any the new policy
+    /// no longer keeps. The running stats are unaffected by eviction.
+    pub fn set_retention_policy(&self, policy: RetentionPolicy) {
+        let mut state = self.state();
+        state.retention = policy;
+        policy.apply(&mut state.messages);
+    }
+
+    /// Clear all stored messages (if you want a "reset" feature).
+    pub fn clear(&self) {
+        self.state().messages.clear();
+    }
+
+    /// Starts a fresh measurement: clears the stored messages, statistics and
+    /// held notes, but keeps the configuration (velocity curve, transform,
+    /// note filter, stuck-note timeout, input offset and SysEx size limit).
+    pub fn reset(&self) {
+        let mut state = self.state();
+        state.messages.clear();
+        state.stats = MidiStats::default();
+        state.held_notes.clear();
+        state.channel_expression = Default::default();
+        state.clock_intervals.clear();
+        state.last_clock_interval = None;
+        state.measured_intervals = 0;
+        state.clock_resync = false;
+        state.recent_onsets.clear();
+        state.sysex_start = None;
+        state.sysex_buffer.clear();
+        state.last_sysex = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    
+    #[test]
+    fn test_retriggered_note_released_in_order() {
+        let engine = MidiEngine::new();
+        
+        // The same input note, retriggered under a different transpose before
+        // its note-off
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.update_transform(|transform| transform.transpose_semitones = 2);
+        engine.process_message(&[0x90, 60, 100], 0.5);
+        assert_eq!(engine.active_note_count(), 2);
+        
+        engine.process_message(&[0x80, 60, 0], 1.0);
+        assert_eq!(engine.active_note_count(), 1);
+        engine.process_message(&[0x80, 60, 0], 1.5);
+        assert_eq!(engine.active_note_count(), 0);
+        
+        let notes: Vec<_> = engine.snapshot().iter().map(|event| (event.data[0], event.data[1])).collect();
+        assert_eq!(notes, vec![(0x90, 60), (0x90, 62), (0x80, 60), (0x80, 62)]);
+    }
+    
+    #[test]
+    fn test_release_velocity() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.process_message(&[0x90, 64, 100], 0.0);
+        engine.process_message(&[0x80, 60, 90], 0.5);
+        let stats = engine.stats();
+        assert_eq!(stats.release_count, 1);
+        assert_eq!(stats.average_release_velocity, 90.0);
+        assert_eq!(stats.peak_release_velocity, 90);
+        
+        // Note-on with velocity 0 has no release velocity
+        engine.process_message(&[0x90, 64, 0], 0.6);
+        engine.process_message(&[0x90, 67, 100], 0.7);
+        engine.process_message(&[0x80, 67, 30], 0.8);
+        let stats = engine.stats();
+        assert_eq!(stats.release_count, 2);
+        assert_eq!(stats.average_release_velocity, 60.0);
+        assert_eq!(stats.peak_release_velocity, 90);
+    }
+    
+    #[test]
+    fn test_notes_in_range() {
+        let engine = MidiEngine::new();
+        for (note, start, end) in [(60u8, 0.0, 1.0), (62, 1.5, 2.0), (64, 2.5, 4.0), (65, 5.0, 6.0)] {
+            engine.process_message(&[0x90, note, 100], start);
+            engine.process_message(&[0x80, note, 0], end);
+        }
+        engine.process_message(&[0x90, 67, 100], 2.2);
+        
+        let notes: Vec<u8> = engine.notes_in_range(1.8, 3.0).iter().map(|note| note.note).collect();
+        assert_eq!(notes, vec![62, 64]);
+        assert_eq!(engine.notes_in_range(0.0, 0.5), vec![CompletedNote {
+            channel: 0,
+            note: 60,
+            velocity: 100,
+            start_time: 0.0,
+            end_time: 1.0,
+        }]);
+        assert!(engine.notes_in_range(4.2, 4.8).is_empty());
+    }
+    
+    #[test]
+    fn test_retrigger_mode() {
+        let engine = MidiEngine::with_config(EngineConfig::default().retrigger_mode(true));
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.process_message(&[0x90, 60, 90], 0.5);
+        
+        let completed = engine.notes_in_range(0.0, 10.0);
+        assert_eq!(completed.len(), 1);
+        assert_eq!((completed[0].velocity, completed[0].start_time, completed[0].end_time), (100, 0.0, 0.5));
+        let active = engine.active_notes();
+        assert_eq!(active.len(), 1);
+        assert_eq!((active[0].velocity, active[0].start_time), (90, 0.5));
+        
+        // The single note-off now ends it
+        engine.process_message(&[0x80, 60, 0], 1.0);
+        assert_eq!(engine.active_note_count(), 0);
+        assert_eq!(engine.notes_in_range(0.0, 10.0).len(), 2);
+        
+        // Off, the note is held once per note-on
+        engine.set_retrigger_mode(false);
+        engine.process_message(&[0x90, 62, 100], 2.0);
+        engine.process_message(&[0x90, 62, 90], 2.5);
+        assert_eq!(engine.active_note_count(), 2);
+        assert_eq!(engine.notes_in_range(2.0, 10.0).len(), 0);
+    }
+    
+    #[test]
+    fn test_retrigger_note_off_forwarded() {
+        let engine = MidiEngine::with_config(
+            EngineConfig::default().retrigger_mode(true).retention(RetentionPolicy::Count(2)),
+        );
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&forwarded);
+        engine.set_forward_callback(Some(Arc::new(move |data: &[u8], timestamp| {
+            sink.lock().unwrap().push((data.to_vec(), timestamp));
+        })));
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.process_message(&[0x90, 60, 90], 0.5);
+        
+        // The note-off is forwarded, counted and stored like a received one,
+        // ahead of the note-on that caused it
+        assert_eq!(*forwarded.lock().unwrap(), vec![
+            (vec![0x90, 60, 100], 0.0),
+            (vec![0x80, 60, 0], 0.5),
+            (vec![0x90, 60, 90], 0.5),
+        ]);
+        let counts = engine.stats().message_counts;
+        assert_eq!((counts.note_ons, counts.note_offs), (2, 1));
+        let stored: Vec<_> = engine.snapshot().iter().map(|e| e.data.to_vec()).collect();
+        assert_eq!(stored, vec![vec![0x80, 60, 0], vec![0x90, 60, 90]]);
+        
+        // A note-on the transform drops still ends the note it retriggers
+        engine.update_transform(|transform| transform.transpose_semitones = 100);
+        engine.process_message(&[0x90, 60, 80], 1.0);
+        assert_eq!(forwarded.lock().unwrap().last(), Some(&(vec![0x80, 60, 0], 1.0)));
+        assert_eq!(engine.active_note_count(), 0);
+    }
+    
+    #[test]
+    fn test_chord_onsets() {
+        let strike = |spread: f64| {
+            let engine = MidiEngine::new();
+            for (i, note) in [67u8, 60, 64].into_iter().enumerate() {
+                engine.process_message(&[0x90, note, 100], 1.0 + i as f64 * spread / 2.0);
+            }
+            engine
+        };
+        
+        assert_eq!(strike(0.02).chord_onsets(0.03), vec![vec![60, 64, 67]]);
+        assert_eq!(strike(0.2).chord_onsets(0.03), vec![vec![67], vec![60], vec![64]]);
+        
+        // Groups are anchored at their first onset, so a slow roll doesn't
+        // chain into one chord
+        let engine = MidiEngine::new();
+        for i in 0..4 {
+            engine.process_message(&[0x90, 60 + i * 4, 100], i as f64 * 0.02);
+        }
+        assert_eq!(engine.chord_onsets(0.03), vec![vec![60, 64], vec![68, 72]]);
+        assert!(engine.chord_onsets(f64::NAN).is_empty());
+        assert!(MidiEngine::new().chord_onsets(0.03).is_empty());
+    }
+    
+    #[test]
+    fn test_retention_policy() {
+        let engine = MidiEngine::new();
+        // An old phrase, a long pause, then a burst
+        for (i, note) in [60u8, 62, 64].into_iter().enumerate() {
+            engine.process_message(&[0x90, note, 100], i as f64 * 0.5);
+            engine.process_message(&[0x80, note, 0], i as f64 * 0.5 + 0.25);
+        }
+        for i in 0..20 {
+            engine.process_message(&[0x90, 72, 100], 60.0 + i as f64 * 0.05);
+            engine.process_message(&[0x80, 72, 0], 60.02 + i as f64 * 0.05);
+        }
+        
+        engine.set_retention_policy(RetentionPolicy::Duration(10.0));
+        let notes = engine.notes_in_range(0.0, 100.0);
+        assert_eq!(notes.len(), 20);
+        assert!(notes.iter().all(|note| note.note == 72));
+        assert_eq!(engine.stats().message_counts.note_ons, 23);
+        
+        engine.set_retention_policy(RetentionPolicy::Count(6));
+        assert_eq!(engine.snapshot().len(), 6);
+        engine.process_message(&[0x90, 74, 100], 70.0);
+        let stored = engine.snapshot();
+        assert_eq!(stored.len(), 6);
+        assert_eq!(stored.last().unwrap().data, vec![0x90, 74, 100]);
+        
+        // A new message far in the future leaves only itself in the window
+        engine.set_retention_policy(RetentionPolicy::Duration(5.0));
+        engine.process_message(&[0x90, 76, 100], 200.0);
+        assert_eq!(engine.snapshot().len(), 1);
+    }
+    
+    #[test]
+    fn test_fixed_capacity() {
+        let stored_notes = |engine: &MidiEngine| -> Vec<u8> {
+            engine.snapshot().iter().map(|event| event.data[1]).collect()
+        };
+        
+        let engine = MidiEngine::with_config(EngineConfig::default().fixed_capacity(4, OverflowPolicy::DropOldest));
+        let capacity = engine.state().messages.capacity();
+        assert!(capacity >= 4);
+        for note in 60..70 {
+            engine.process_message(&[0x90, note, 100], note as f64 * 0.1);
+        }
+        assert_eq!(engine.state().messages.capacity(), capacity);
+        assert_eq!(stored_notes(&engine), vec![66, 67, 68, 69]);
+        // Overflow only affects storage; every message is still analyzed
+        let stats = engine.stats();
+        assert_eq!(stats.storage_overflows, 6);
+        assert_eq!(stats.message_counts.note_ons, 10);
+        
+        let engine = MidiEngine::with_config(EngineConfig::default().fixed_capacity(4, OverflowPolicy::Refuse));
+        let capacity = engine.state().messages.capacity();
+        for note in 60..70 {
+            engine.process_message(&[0x90, note, 100], note as f64 * 0.1);
+        }
+        assert_eq!(engine.state().messages.capacity(), capacity);
+        assert_eq!(stored_notes(&engine), vec![60, 61, 62, 63]);
+        assert_eq!(engine.stats().storage_overflows, 6);
+        
+        // Draining makes room again
+        engine.drain(2);
+        engine.process_message(&[0x90, 70, 100], 7.0);
+        assert_eq!(stored_notes(&engine), vec![62, 63, 70]);
+    }
+    
+    /// Counts the allocations made by each thread, so a test can check that
+    /// code it runs doesn't allocate.
+    struct CountingAllocator;
+    
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+    
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+        
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+        
+        unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+    }
+    
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+    
+    #[test]
+    fn test_fixed_capacity_steady_state_does_not_allocate() {
+        let engine = MidiEngine::with_config(EngineConfig::default().fixed_capacity(64, OverflowPolicy::DropOldest));
+        let play = |start: f64| {
+            for i in 0..128 {
+                let time = start + i as f64 * 0.02;
+                engine.process_message(&[0xF8], time);
+                engine.process_message(&[0x90, 60 + i % 8, 100], time);
+                engine.process_message(&[0xB0, 74, i], time);
+                engine.process_message(&[0xE0, 0, 64 + i % 8], time);
+                engine.process_message(&[0xD0, i], time);
+                engine.process_message(&[0x80, 60 + i % 8, 0], time + 0.01);
+            }
+        };
+        
+        // Warm up: fill the store and tempo windows, and hold every note once
+        play(0.0);
+        
+        let before = ALLOCATIONS.with(|count| count.get());
+        play(3.0);
+        let allocations = ALLOCATIONS.with(|count| count.get()) - before;
+        assert_eq!(allocations, 0);
+        assert_eq!(engine.stats().message_counts.note_ons, 256);
+        assert_eq!(engine.snapshot().len(), 64);
+    }
+    
+    #[test]
+    fn test_cc_mapping() {
+        let engine = MidiEngine::with_config(EngineConfig::default().map_cc(1, ExpressionTarget::PitchBend));
+        engine.map_cc(16, Some(ExpressionTarget::Timbre));
+        engine.map_cc(0, Some(ExpressionTarget::Pressure));
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        
+        engine.process_message(&[0xB0, 16, 100], 0.1);
+        engine.process_message(&[0xB0, 0, 55], 0.2);
+        engine.process_message(&[0xB0, 1, 127], 0.3);
+        let note = engine.active_notes()[0];
+        assert_eq!((note.timbre, note.pressure, note.pitch_bend), (100, 55, 16383));
+        // Mapped away from bank select
+        assert_eq!(engine.stats().current_bank[0], 0);
+        
+        engine.process_message(&[0xB0, 1, 64], 0.4);
+        assert_eq!(engine.active_notes()[0].pitch_bend, 8192);
+        engine.process_message(&[0xB0, 1, 0], 0.5);
+        assert_eq!(engine.active_notes()[0].pitch_bend, 0);
+        
+        engine.map_cc(16, None);
+        engine.process_message(&[0xB0, 16, 20], 0.6);
+        assert_eq!(engine.active_notes()[0].timbre, 100);
+    }
+    
+    #[test]
+    fn test_finalize() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.process_message(&[0x90, 64, 90], 0.5);
+        engine.process_message(&[0x80, 60, 0], 1.0);
+        engine.process_message(&[0x91, 67, 80], 1.5);
+        
+        assert_eq!(engine.finalize(3.0), 2);
+        assert_eq!(engine.active_note_count(), 0);
+        let notes: Vec<(u8, f64)> = engine.notes_in_range(0.0, 3.0).iter().map(|note| (note.note, note.duration())).collect();
+        assert_eq!(notes, vec![(60, 1.0), (64, 2.5), (67, 1.5)]);
+        
+        assert_eq!(engine.finalize(4.0), 0);
+    }
+    
+    #[test]
+    fn test_out_of_order_note_off() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 1.0);
+        engine.process_message(&[0x80, 60, 0], 0.99);
+        engine.process_message(&[0x90, 62, 100], 2.0);
+        engine.process_message(&[0x80, 62, 0], 2.5);
+        
+        assert_eq!(engine.stats().out_of_order_notes, 1);
+        let durations: Vec<f64> = engine.notes_in_range(0.0, 10.0).iter().map(CompletedNote::duration).collect();
+        assert_eq!(durations, vec![0.0, 0.5]);
+    }
+    
+    #[test]
+    fn test_active_notes() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.process_message(&[0x91, 64, 90], 0.1);
+        engine.process_message(&[0x92, 67, 80], 0.2);
+        engine.process_message(&[0x80, 60, 0], 0.3);
+        engine.process_message(&[0xE1, 0x00, 0x50], 0.4);
+        engine.process_message(&[0xD1, 70], 0.5);
+        engine.process_message(&[0xB2, TIMBRE_CONTROLLER, 20], 0.6);
+        
+        let notes = engine.active_notes();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0], ActiveNote {
+            channel: 1,
+            note: 64,
+            velocity: 90,
+            pitch_bend: 0x2800,
+            pressure: 70,
+            timbre: 64,
+            start_time: 0.1,
+        });
+        assert_eq!((notes[1].channel, notes[1].note, notes[1].pitch_bend, notes[1].timbre), (2, 67, 8192, 20));
+        assert_eq!(notes[1].normalized_pitch_bend(), 0.0);
+        
+        engine.process_message(&[0xE1, 0x7F, 0x7F], 0.7);
+        assert!((engine.active_notes()[0].normalized_pitch_bend() - 1.0).abs() < 1e-9);
+    }
+    
+    #[test]
+    fn test_peak_polyphony() {
+        let engine = MidiEngine::with_config(EngineConfig::default().transpose(24));
+        for (i, note) in [60u8, 64, 67].into_iter().enumerate() {
+            engine.process_message(&[0x90, note, 100], i as f64 * 0.1);
+        }
+        engine.process_message(&[0x80, 64, 0], 0.3);
+        engine.process_message(&[0x90, 72, 100], 0.4);
+        // Transposed out of range, so never held
+        engine.process_message(&[0x90, 120, 100], 0.5);
+        
+        assert_eq!(engine.active_note_count(), 3);
+        assert_eq!(engine.stats().peak_polyphony, 3);
+        
+        engine.reset();
+        assert_eq!(engine.stats().peak_polyphony, 0);
+    }
+    
+    #[test]
+    fn test_polyphony_timeline() {
+        let engine = MidiEngine::new();
+        let notes = [(60, 0.0, 1.0), (64, 0.5, 1.5), (67, 0.6, 0.8), (72, 2.0, 2.5)];
+        for (note, on, off) in notes {
+            engine.process_message(&[0x90, note, 100], on);
+            engine.process_message(&[0x80, note, 0], off);
+        }
+        // A stray note-off doesn't drive the count negative
+        engine.process_message(&[0x80, 50, 0], 1.7);
+        
+        let timeline = engine.polyphony_timeline(0.5);
+        let expected = [(0.0, 1), (0.5, 3), (1.0, 1), (1.5, 0), (2.0, 1), (2.5, 0)];
+        assert_eq!(timeline.len(), expected.len());
+        for ((time, count), (expected_time, expected_count)) in timeline.iter().zip(expected) {
+            assert!((time - expected_time).abs() < 1e-9);
+            assert_eq!(*count, expected_count, "bucket at {}", time);
+        }
+        
+        assert!(engine.polyphony_timeline(0.0).is_empty());
+        assert!(engine.polyphony_timeline(1e-12).is_empty());
+        assert!(MidiEngine::new().polyphony_timeline(0.5).is_empty());
+        
+        // A note-off that arrives before its note-on in time still ends the note
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 1.0);
+        engine.process_message(&[0x80, 60, 0], 0.9);
+        engine.process_message(&[0x90, 64, 100], 2.0);
+        engine.process_message(&[0x80, 64, 0], 2.5);
+        let counts: Vec<usize> = engine.polyphony_timeline(0.5).iter().map(|&(_, count)| count).collect();
+        assert_eq!(counts, vec![0, 0, 1, 0]);
+    }
+    
+    #[test]
+    fn test_aftertouch_stats() {
+        let engine = MidiEngine::new();
+        assert_eq!(engine.aftertouch_stats(), AftertouchStats::default());
+        
+        // Four notes: one pressed with poly aftertouch, two on channel 1 with
+        // channel pressure, one untouched
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.process_message(&[0xA0, 60, 40], 0.1);
+        engine.process_message(&[0x80, 60, 0], 0.2);
+        engine.process_message(&[0x91, 64, 100], 0.3);
+        engine.process_message(&[0x91, 67, 100], 0.3);
+        engine.process_message(&[0xD1, 80], 0.4);
+        engine.process_message(&[0x81, 64, 0], 0.5);
+        engine.process_message(&[0x81, 67, 0], 0.5);
+        engine.process_message(&[0x90, 72, 100], 0.6);
+        // Pressure after the note is released doesn't count for it
+        engine.process_message(&[0xA0, 60, 120], 0.7);
+        engine.process_message(&[0x80, 72, 0], 0.8);
+        
+        let stats = engine.aftertouch_stats();
+        assert!((stats.usage - 0.75).abs() < 1e-9);
+        assert_eq!(stats.peak_pressure, 120);
+        assert!((stats.average_pressure - 80.0).abs() < 1e-9);
+    }
+    
+    #[test]
+    fn test_reset_keeps_configuration() {
+        let engine = MidiEngine::new();
+        let transform = TransformConfig { transpose_semitones: 12, ..TransformConfig::default() };
+        engine.update_transform(|current| *current = transform.clone());
+        engine.set_stuck_note_timeout(Some(10.0));
+        
+        let interval = 60.0 / (120.0 * 24.0);
+        for i in 0..25 {
+            engine.process_message(&[0xF8], i as f64 * interval);
+        }
+        engine.process_message(&[0xC0, 5], 0.5);
+        engine.process_message(&[0x90, 60, 100], 0.5);
+        assert_eq!(engine.active_note_count(), 1);
+        
+        engine.reset();
+        let stats = engine.stats();
+        assert_eq!(stats.clock_count, 0);
+        assert_eq!(stats.current_bpm, 0.0);
+        assert_eq!(stats.current_program, [0; 16]);
+        assert_eq!(engine.active_note_count(), 0);
+        assert!(engine.snapshot().is_empty());
+        
+        // Configuration survives
+        assert_eq!(engine.state().transform, transform);
+        engine.process_message(&[0x90, 60, 100], 1.0);
+        assert_eq!(engine.snapshot()[0].data, vec![0x90, 72, 100]);
+    }
+    
+    #[test]
+    fn test_input_offset() {
+        let direct = MidiEngine::new();
+        let delayed = MidiEngine::new();
+        delayed.set_input_offset(0.1);
+        
+        let interval = 60.0 / (120.0 * 24.0);
+        for engine in [&direct, &delayed] {
+            for i in 0..25 {
+                engine.process_message(&[0xF8], 1.0 + i as f64 * interval);
+            }
+            engine.process_message(&[0x90, 60, 100], 2.0);
+            engine.process_message(&[0x80, 60, 0], 2.25);
+        }
+        
+        // Absolute times shift, relative timing is unchanged
+        let (direct_messages, delayed_messages) = (direct.snapshot(), delayed.snapshot());
+        for (a, b) in direct_messages.iter().zip(&delayed_messages) {
+            assert!((a.timestamp - b.timestamp - 0.1).abs() < 1e-9);
+        }
+        let duration = |messages: &[MidiEvent]| messages[26].timestamp - messages[25].timestamp;
+        assert!((duration(&direct_messages) - duration(&delayed_messages)).abs() < 1e-9);
+        assert!((delayed.stats().current_bpm - direct.stats().current_bpm).abs() < 1e-6);
+        assert!((delayed.stats().first_clock_time - 0.9).abs() < 1e-9);
+        
+        // Timestamps never go negative
+        delayed.set_input_offset(5.0);
+        delayed.process_message(&[0x90, 62, 100], 1.0);
+        assert_eq!(delayed.snapshot().last().unwrap().timestamp, 0.0);
+    }
+    
+    #[test]
+    fn test_onset_tempo_without_clock() {
+        let engine = MidiEngine::new();
+        for beat in 0..8 {
+            let time = beat as f64 * 0.5 + if beat % 2 == 0 { 0.0 } else { 0.01 };
+            engine.process_message(&[0x90, 60, 100], time);
+            engine.process_message(&[0x80, 60, 0], time + 0.25);
+        }
+        let stats = engine.stats();
+        assert!((stats.current_bpm - 120.0).abs() <= 3.0, "{}", stats.current_bpm);
+        assert_eq!(stats.onset_bpm, Some(stats.current_bpm));
+        
+        // A clock takes precedence
+        let engine = MidiEngine::new();
+        let interval = 60.0 / (100.0 * 24.0);
+        for i in 0..48 {
+            engine.process_message(&[0xF8], i as f64 * interval);
+        }
+        for beat in 0..8 {
+            engine.process_message(&[0x90, 60, 100], 47.0 * interval + beat as f64 * 0.05);
+        }
+        assert!((engine.stats().current_bpm - 100.0).abs() < 0.01);
+    }
+    
+    #[test]
+    fn test_duplicate_clock_ignored() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0xF8], 0.0);
+        engine.process_message(&[0xF8], 0.020_833);
+        engine.process_message(&[0xF8], 0.020_834);
+        
+        let stats = engine.stats();
+        assert!(stats.current_bpm.is_finite());
+        assert!((stats.current_bpm - 120.0).abs() < 0.1, "{}", stats.current_bpm);
+        assert!((stats.average_bpm - 120.0).abs() < 0.1, "{}", stats.average_bpm);
+        assert_eq!(stats.clock_count, 2);
+        
+        // The next clock is measured from the last real one
+        engine.process_message(&[0xF8], 0.041_667);
+        assert!((engine.stats().current_bpm - 120.0).abs() < 0.1);
+    }
+    
+    #[test]
+    fn test_continue_resumes_clock() {
+        let engine = MidiEngine::new();
+        let interval = 60.0 / (120.0 * 24.0);
+        
+        engine.process_message(&[0xF2, 0x10, 0x00], 0.0);
+        let stats = engine.process_message(&[0xFA], 0.0);
+        assert!(stats.transport_playing);
+        assert_eq!(stats.current_beat, 0);
+        let mut time = 0.01;
+        for _ in 0..24 {
+            engine.process_message(&[0xF8], time);
+            time += interval;
+        }
+        engine.process_message(&[0xF2, 0x08, 0x00], time);
+        assert!(!engine.process_message(&[0xFC], time).transport_playing);
+        
+        // Paused for a second: short enough to be measured as one huge interval
+        time += 1.0;
+        let stats = engine.process_message(&[0xFB], time);
+        assert!(stats.transport_playing);
+        assert_eq!(stats.current_beat, 8);
+        time += 0.005;
+        for _ in 0..4 {
+            engine.process_message(&[0xF8], time);
+            time += interval;
+        }
+        
+        let stats = engine.stats();
+        assert!((stats.current_bpm - 120.0).abs() < 0.1, "{}", stats.current_bpm);
+        assert!((stats.average_bpm - 120.0).abs() < 0.1, "{}", stats.average_bpm);
+        assert_eq!(stats.dropped_clocks, 0);
+        assert_eq!(stats.clock_count, 28);
+    }
+    
+    #[test]
+    fn test_tempo_us_per_quarter() {
+        let stats = |current_bpm| MidiStats { current_bpm, ..MidiStats::default() };
+        assert_eq!(stats(120.0).tempo_us_per_quarter(), 500_000);
+        assert_eq!(stats(90.0).tempo_us_per_quarter(), 666_667);
+        assert_eq!(stats(0.0).tempo_us_per_quarter(), 0);
+        assert_eq!(stats(f64::NAN).tempo_us_per_quarter(), 0);
+    }
+    
+    #[test]
+    fn test_average_bpm_skips_unmeasured_clocks() {
+        let engine = MidiEngine::new();
+        let interval = 60.0 / (120.0 * 24.0);
+        // A lone clock, then a gap too long to measure, then steady clocks
+        engine.process_message(&[0xF8], 0.0);
+        for i in 0..10 {
+            engine.process_message(&[0xF8], 3.0 + i as f64 * interval);
+        }
+        
+        let stats = engine.stats();
+        assert_eq!(stats.clock_count, 11);
+        assert!((stats.average_bpm - 120.0).abs() < 1e-6, "{}", stats.average_bpm);
+    }
+    
+    #[test]
+    fn test_dropped_clocks() {
+        let engine = MidiEngine::new();
+        let interval = 60.0 / (120.0 * 24.0);
+        let mut time = 0.0;
+        for _ in 0..10 {
+            engine.process_message(&[0xF8], time);
+            time += interval;
+        }
+        // One clock lost
+        time += interval;
+        engine.process_message(&[0xF8], time);
+        
+        let stats = engine.stats();
+        assert_eq!(stats.dropped_clocks, 1);
+        assert!((stats.current_bpm - 120.0).abs() < 0.1, "{}", stats.current_bpm);
+        
+        // A gradual slow-down is a tempo change, not a dropout
+        for _ in 0..10 {
+            time += interval * 1.05;
+            engine.process_message(&[0xF8], time);
+        }
+        assert_eq!(engine.stats().dropped_clocks, 1);
+        
+        engine.reset();
+        assert_eq!(engine.stats().dropped_clocks, 0);
+    }
+    
+    #[test]
+    fn test_with_config() {
+        let engine = MidiEngine::with_config(EngineConfig::default().ppqn(96).transpose(12));
+        
+        let interval = 60.0 / (120.0 * 96.0);
+        for i in 0..8 {
+            engine.process_message(&[0xF8], i as f64 * interval);
+        }
+        assert!((engine.stats().current_bpm - 120.0).abs() < 0.1, "{}", engine.stats().current_bpm);
+        
+        engine.process_message(&[0x90, 60, 100], 1.0);
+        assert_eq!(engine.snapshot().last().unwrap().data, vec![0x90, 72, 100]);
+        
+        assert_eq!(EngineConfig::default().transpose(12).transform, engine.state().transform);
+    }
+    
+    #[test]
+    fn test_tempo_stability() {
+        let interval = 60.0 / (120.0 * 24.0);
+        
+        let steady = MidiEngine::new();
+        let mut time = 0.0;
+        for i in 0..=TEMPO_STABILITY_WINDOW {
+            // Not enough intervals yet before the last clock
+            assert_eq!(steady.stats().tempo_stability, None, "clock {}", i);
+            steady.process_message(&[0xF8], time);
+            time += interval;
+        }
+        assert!(steady.stats().tempo_stability.unwrap() > 0.999);
+        
+        // Intervals alternating 20% short and long
+        let wobbly = MidiEngine::new();
+        let mut time = 0.0;
+        for i in 0..=TEMPO_STABILITY_WINDOW {
+            wobbly.process_message(&[0xF8], time);
+            time += interval * if i % 2 == 0 { 0.8 } else { 1.2 };
+        }
+        assert!((wobbly.stats().tempo_stability.unwrap() - 0.8).abs() < 1e-9);
+    }
+    
+    #[test]
+    fn test_message_size_limits() {
+        let engine = MidiEngine::new();
+        
+        // A 2 KB SysEx dump is accepted
+        let mut dump = vec![0xF0, 0x43];
+        dump.resize(2047, 0x11);
+        dump.push(0xF7);
+        assert!(engine.try_process_message(&dump, 0.0).is_ok());
+        
+        // Until the limit is lowered
+        engine.set_max_sysex_size(1024);
+        match engine.try_process_message(&dump, 0.1) {
+            Err(MidiError::InvalidData(message)) => assert_eq!(message, "message of 2048 bytes exceeds the 1024-byte limit"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        
+        // Channel messages have a fixed limit
+        assert!(engine.try_process_message(&[0x90, 60, 100, 0], 0.2).is_err());
+        assert!(engine.try_process_message(&[0x90, 60, 100], 0.2).is_ok());
+        assert_eq!(engine.snapshot().len(), 2);
+    }
+    
+    #[test]
+    fn test_sysex_dispatch() {
+        let engine = MidiEngine::new();
+        
+        // A complete message (universal identity request) does not leave a SysEx open
+        assert!(!engine.process_message(&[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7], 0.0).sysex_in_progress);
+        
+        // A message split across packets stays open until the end byte
+        assert!(engine.process_message(&[0xF0, 0x43, 0x10], 0.1).sysex_in_progress);
+        assert!(!engine.process_message(&[0xF7], 0.2).sysex_in_progress);
+        assert_eq!(engine.snapshot().len(), 3);
+    }
+    
+    #[test]
+    fn test_message_counts() {
+        let engine = MidiEngine::new();
+        let messages: [&[u8]; 14] = [
+            &[0xFA],
+            &[0xF8], &[0xF8],
+            &[0x90, 60, 100], &[0x90, 60, 0], &[0x80, 62, 0],
+            &[0xB0, 7, 100],
+            &[0xE0, 0, 64],
+            &[0xC0, 5],
+            &[0xD0, 30],
+            &[0xA0, 60, 30],
+            &[0xF0, 0x7E, 0x01], &[0x02, 0xF7],
+            &[0xFE],
+        ];
+        for (i, data) in messages.iter().enumerate() {
+            engine.process_message(data, i as f64 * 0.01);
+        }
+        engine.process_message(&[0xFC], 0.2);
+        engine.process_message(&[0xFB], 0.3);
+        // Rejected messages are not counted
+        engine.process_message(&[0x90, 60], 0.4);
+        
+        assert_eq!(engine.stats().message_counts, MessageCounts {
+            note_ons: 1,
+            note_offs: 2,
+            control_changes: 1,
+            pitch_bends: 1,
+            program_changes: 1,
+            aftertouch: 1,
+            poly_aftertouch: 1,
+            clocks: 2,
+            starts: 1,
+            stops: 1,
+            continues: 1,
+            sysex: 1,
+            other: 1,
+        });
+        
+        engine.reset();
+        assert_eq!(engine.stats().message_counts, MessageCounts::default());
+    }
+    
+    #[test]
+    fn test_recovers_from_poisoned_lock() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _state = engine.state();
+            panic!("injected panic while holding the state lock");
+        }));
+        assert!(result.is_err());
+        assert!(engine.state.is_poisoned());
+        
+        assert!(engine.try_process_message(&[0x80, 60, 0], 0.5).is_ok());
+        assert!(!engine.state.is_poisoned());
+        assert_eq!(engine.active_note_count(), 0);
+        assert_eq!(engine.snapshot().len(), 2);
+        
+        // A panicking forward callback runs without the callback lock
+        engine.set_forward_callback(Some(Arc::new(|_, _| panic!("injected panic in forward callback"))));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            engine.process_message(&[0x90, 62, 100], 1.0);
+        }));
+        assert!(result.is_err());
+        assert!(!engine.forward.is_poisoned());
+        engine.set_forward_callback(None);
+        assert!(engine.try_process_message(&[0x80, 62, 0], 1.5).is_ok());
+        assert_eq!(engine.snapshot().len(), 4);
+    }
+    
+    #[test]
+    fn test_mtc_full_frame() {
+        let engine = MidiEngine::new();
+        // 01:02:03:04 at 25 fps, device ID "all call"
+        let stats = engine.process_message(&[0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 0x02, 0x03, 0x04, 0xF7], 0.0);
+        assert_eq!((stats.mtc_hours, stats.mtc_minutes, stats.mtc_seconds, stats.mtc_frames), (1, 2, 3, 4));
+        assert_eq!(stats.mtc_frame_rate, 25.0);
+        
+        // Split across packets, at 30 fps
+        engine.process_message(&[0xF0, 0x7F, 0x00, 0x01, 0x01], 0.1);
+        let stats = engine.process_message(&[0x77, 0x3B, 0x3B, 0x1D, 0xF7], 0.1);
+        assert_eq!((stats.mtc_hours, stats.mtc_minutes, stats.mtc_seconds, stats.mtc_frames), (23, 59, 59, 29));
+        assert_eq!(stats.mtc_frame_rate, 30.0);
+        
+        // Other universal real-time messages leave the timecode alone
+        let stats = engine.process_message(&[0xF0, 0x7F, 0x7F, 0x06, 0x01, 0x21, 0x02, 0x03, 0x04, 0xF7], 0.2);
+        assert_eq!(stats.mtc_hours, 23);
+    }
+    
+    #[test]
+    fn test_sysex_reassembly() {
+        let engine = MidiEngine::new();
+        
+        // A clock interleaved in each fragment of a two-packet SysEx
+        engine.process_message(&[0xF0, 0x43, 0xF8, 0x10], 0.0);
+        assert!(engine.stats().sysex_in_progress);
+        assert_eq!(engine.last_sysex(), None);
+        let stats = engine.process_message(&[0x4C, 0xF8, 0x00, 0xF7], 0.02);
+        assert!(!stats.sysex_in_progress);
+        assert_eq!(stats.clock_count, 2);
+        assert_eq!(engine.last_sysex(), Some(vec![0xF0, 0x43, 0x10, 0x4C, 0x00, 0xF7]));
+        
+        // A lone 0xF7 terminates a SysEx too
+        engine.process_message(&[0xF0, 0x7E], 0.1);
+        engine.process_message(&[0x01, 0x02], 0.1);
+        assert!(!engine.process_message(&[0xF7], 0.2).sysex_in_progress);
+        assert_eq!(engine.last_sysex(), Some(vec![0xF0, 0x7E, 0x01, 0x02, 0xF7]));
+        
+        // Data bytes with no SysEx in progress are still rejected
+        assert!(matches!(engine.try_process_message(&[0x01, 0x02], 0.3), Err(MidiError::InvalidData(_))));
+        
+        // As is a status byte inside a SysEx, which abandons it
+        engine.process_message(&[0xF0, 0x7E], 0.4);
+        assert!(engine.try_process_message(&[0x01, 0x90, 0x02], 0.4).is_err());
+        assert!(!engine.stats().sysex_in_progress);
+    }
+    
+    #[test]
+    fn test_sysex_timeout() {
+        let engine = MidiEngine::new();
+        assert!(engine.process_message(&[0xF0, 0x43, 0x10], 1.0).sysex_in_progress);
+        
+        // Still within the timeout
+        assert!(engine.process_message(&[0xF8], 1.4).sysex_in_progress);
+        
+        // The terminator never came
+        let stats = engine.process_message(&[0x90, 60, 100], 1.6);
+        assert!(!stats.sysex_in_progress);
+        assert_eq!(engine.active_note_count(), 1);
+        
+        // A new SysEx starts its own timeout
+        engine.set_sysex_timeout(2.0);
+        assert!(engine.process_message(&[0xF0, 0x43, 0x10], 2.0).sysex_in_progress);
+        assert!(engine.process_message(&[0xF8], 3.5).sysex_in_progress);
+        assert!(!engine.process_message(&[0xF7], 3.6).sysex_in_progress);
+        assert!(!engine.process_message(&[0xF8], 10.0).sysex_in_progress);
+    }
+    
+    #[test]
+    fn test_process_message_stores_and_updates_stats() {
+        let engine = MidiEngine::new();
+        let interval = 60.0 / (120.0 * 24.0);
+        
+        // Clock, MTC quarter frames, SPP and SysEx all land in both places
+        for i in 0..3 {
+            engine.process_message(&[0xF8], i as f64 * interval);
+        }
+        engine.process_message(&[0xF1, 0x05], 0.1);
+        engine.process_message(&[0xF1, 0x12], 0.1);
+        engine.process_message(&[0xF1, 0x76], 0.1);
+        engine.process_message(&[0xF2, 0x10, 0x01], 0.2);
+        let returned = engine.process_message(&[0xF0, 0x7E, 0x01], 0.3);
+        
+        let stats = engine.stats();
+        assert_eq!(stats.clock_count, 3);
+        assert!((stats.current_bpm - 120.0).abs() < 1e-6);
+        assert_eq!(stats.mtc_frames, 0x25);
+        assert_eq!(stats.mtc_frame_rate, 30.0);
+        assert_eq!(stats.current_beat, (1 << 7) | 0x10);
+        assert!(stats.sysex_in_progress);
+        
+        // The returned snapshot matches the engine's stats
+        assert_eq!(returned.clock_count, stats.clock_count);
+        assert!(returned.sysex_in_progress);
+        
+        let messages = engine.snapshot();
+        assert_eq!(messages.len(), 8);
+        assert_eq!(messages[3].data, vec![0xF1, 0x05]);
+        assert_eq!(messages[7].timestamp, 0.3);
+    }
+    
+    #[test]
+    fn test_concurrent_process_and_clear() {
+        let engine = Arc::new(MidiEngine::new());
+        let interval = 60.0 / (120.0 * 24.0);
+        
+        let writer = {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for i in 0..10_000 {
+                    engine.process_message(&[0xF8], i as f64 * interval);
+                }
+            })
+        };
+        let clearer = {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for _ in 0..10_000 {
+                    engine.clear();
+                    let _ = engine.stats();
+                }
+            })
+        };
+        
+        writer.join().unwrap();
+        clearer.join().unwrap();
+        
+        let stats = engine.stats();
+        assert_eq!(stats.clock_count, 10_000);
+        assert!((stats.current_bpm - 120.0).abs() < 1e-6);
+    }
+    
+    #[test]
+    fn test_rhythmic_regularity() {
+        let even = MidiEngine::new();
+        for i in 0..8 {
+            even.process_message(&[0x90, 60, 100], i as f64 * 0.25);
+            even.process_message(&[0x80, 60, 0], i as f64 * 0.25 + 0.1);
+        }
+        assert_eq!(even.inter_onset_intervals().len(), 7);
+        assert!(even.rhythmic_regularity() < 1e-9);
+        
+        let uneven = MidiEngine::new();
+        for &onset in &[0.0, 0.1, 0.45, 0.5, 1.2, 1.3, 2.0] {
+            uneven.process_message(&[0x90, 60, 100], onset);
+        }
+        // Note-on with velocity 0 is not an onset
+        uneven.process_message(&[0x90, 60, 0], 2.05);
+        assert_eq!(uneven.inter_onset_intervals().len(), 6);
+        assert!(uneven.rhythmic_regularity() > 0.5);
+    }
+    
+    #[test]
+    fn test_swing_ratio() {
+        let engine = MidiEngine::new();
+        let beat = 0.5;
+        
+        // No tempo yet
+        assert_eq!(engine.swing_ratio(), -1.0);
+        
+        // 120 BPM clock
+        for i in 0..25 {
+            engine.process_message(&[0xF8], i as f64 * beat / 24.0);
+        }
+        assert_eq!(engine.swing_ratio(), -1.0);
+        
+        // Swung eighths: the off-beat lands two thirds of the way through each beat
+        for i in 0..8 {
+            let downbeat = 1.0 + i as f64 * beat;
+            engine.process_message(&[0x90, 60, 100], downbeat);
+            engine.process_message(&[0x90, 62, 90], downbeat + beat * 2.0 / 3.0);
+        }
+        let ratio = engine.swing_ratio();
+        assert!(ratio > 55.0, "ratio = {}", ratio);
+        assert!((ratio - 66.7).abs() < 0.5);
+        
+        // Straight eighths
+        let straight = MidiEngine::new();
+        for i in 0..25 {
+            straight.process_message(&[0xF8], i as f64 * beat / 24.0);
+        }
+        for i in 0..16 {
+            straight.process_message(&[0x90, 60, 100], 1.0 + i as f64 * beat / 2.0);
+        }
+        assert!((straight.swing_ratio() - 50.0).abs() < 0.5);
+    }
+    
+    #[test]
+    fn test_velocity_curve() {
+        for velocity in 0..=127 {
+            assert_eq!(VelocityCurve::Linear.apply(velocity), velocity);
+        }
+        
+        let exponential = VelocityCurve::Exponential(2.0);
+        assert!(exponential.apply(64) < 64);
+        assert_eq!(exponential.apply(127), 127);
+        assert_eq!(exponential.apply(1), 1);
+        
+        assert!(VelocityCurve::Logarithmic.apply(32) > 32);
+        
+        let mut table = [0u8; 128];
+        table[100] = 42;
+        assert_eq!(VelocityCurve::Custom(table).apply(100), 42);
+        
+        let engine = MidiEngine::new();
+        engine.set_velocity_curve(VelocityCurve::Exponential(2.0));
+        engine.process_message(&[0x90, 60, 64], 0.0);
+        engine.process_message(&[0x80, 60, 64], 0.1);
+        let messages = engine.snapshot();
+        assert!(messages[0].data[2] < 64);
+        // Only note-on velocities are remapped
+        assert_eq!(messages[1].data[2], 64);
+    }
+    
+    #[test]
+    fn test_transform() {
+        let engine = MidiEngine::new();
+        let mut transform = TransformConfig { transpose_semitones: 12, ..Default::default() };
+        transform.channel_map[2] = 5;
+        engine.update_transform(|current| *current = transform);
+        
+        engine.process_message(&[0x92, 60, 100], 0.0);
+        engine.process_message(&[0x82, 60, 0], 0.1);
+        // Pushed out of range: dropped
+        engine.process_message(&[0x90, 120, 100], 0.2);
+        // Controllers are remapped but not transposed
+        engine.process_message(&[0xB2, 7, 100], 0.3);
+        
+        let messages: Vec<Vec<u8>> = engine.snapshot().into_iter().map(|e| e.data.to_vec()).collect();
+        assert_eq!(messages, vec![
+            vec![0x95, 72, 100],
+            vec![0x85, 72, 0],
+            vec![0xB5, 7, 100],
+        ]);
+    }
+    
+    #[test]
+    fn test_transform_change_while_held() {
+        let engine = MidiEngine::new();
+        engine.update_transform(|transform| transform.transpose_semitones = 5);
+        engine.process_message(&[0x90, 60, 100], 0.0);
+        engine.process_message(&[0x90, 125, 100], 0.1);
+        
+        engine.update_transform(|transform| transform.transpose_semitones = -12);
+        engine.process_message(&[0x80, 60, 0], 0.2);
+        // The note-on was dropped, so its note-off is dropped too
+        engine.process_message(&[0x90, 125, 0], 0.3);
+        
+        let messages: Vec<Vec<u8>> = engine.snapshot().into_iter().map(|e| e.data.to_vec()).collect();
+        assert_eq!(messages, vec![vec![0x90, 65, 100], vec![0x80, 65, 0]]);
+    }
+    
+    #[test]
+    fn test_note_filter() {
+        let engine = MidiEngine::new();
+        // Notes 48-72 on channels 0, 4 and 8
+        engine.set_note_filter(NoteFilter {
+            min_note: 48,
+            max_note: 72,
+            allowed_channels: (1 << 0) | (1 << 4) | (1 << 8),
+        });
+        
+        engine.process_message(&[0x90, 40, 100], 0.0);
+        engine.process_message(&[0x91, 60, 100], 0.1);
+        engine.process_message(&[0x94, 60, 100], 0.2);
+        engine.process_message(&[0x84, 60, 0], 0.3);
+        // Non-note messages are not filtered
+        engine.process_message(&[0xB1, 7, 100], 0.4);
+        
+        let messages: Vec<Vec<u8>> = engine.snapshot().into_iter().map(|e| e.data.to_vec()).collect();
+        assert_eq!(messages, vec![
+            vec![0x94, 60, 100],
+            vec![0x84, 60, 0],
+            vec![0xB1, 7, 100],
+        ]);
+    }
+    
+    #[test]
+    fn test_quantize() {
+        let engine = MidiEngine::new();
+        // Sixteenths at 120 BPM are 0.125s apart
+        engine.process_message(&[0x90, 60, 100], 0.131);
+        engine.process_message(&[0x80, 60, 0], 0.231);
+        engine.process_message(&[0x90, 62, 100], 0.49);
+        engine.process_message(&[0x90, 62, 0], 0.60);
+        
+        assert!(!engine.quantize(0, 120.0, 1.0));
+        assert!(!engine.quantize(16, 120.0, 1.5));
+        
+        assert!(engine.quantize(16, 120.0, 1.0));
+        let timestamps: Vec<f64> = engine.snapshot().iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps[0], 0.125);
+        assert_eq!(timestamps[2], 0.5);
+        // Durations are unchanged
+        assert!((timestamps[1] - timestamps[0] - 0.1).abs() < 1e-9);
+        assert!((timestamps[3] - timestamps[2] - 0.11).abs() < 1e-9);
+        
+        // Half strength moves halfway
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 0.135);
+        assert!(engine.quantize(16, 120.0, 0.5));
+        assert!((engine.snapshot()[0].timestamp - 0.13).abs() < 1e-9);
+        
+        // A retriggered note's note-offs each keep their own note-on's shift,
+        // and a note moved past another message is re-sorted
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 0.131);
+        engine.process_message(&[0x90, 60, 100], 0.26);
+        engine.process_message(&[0xB0, 1, 64], 0.265);
+        engine.process_message(&[0x80, 60, 0], 0.331);
+        engine.process_message(&[0x80, 60, 0], 0.4);
+        assert!(engine.quantize(16, 120.0, 1.0));
+        let stored = engine.snapshot();
+        let timestamps: Vec<f64> = stored.iter().map(|e| e.timestamp).collect();
+        let expected = [0.125, 0.25, 0.265, 0.325, 0.39];
+        for (timestamp, expected) in timestamps.iter().zip(expected) {
+            assert!((timestamp - expected).abs() < 1e-9, "{:?}", timestamps);
+        }
+        assert_eq!(stored[2].data, vec![0xB0, 1, 64]);
+        
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x90, 60, 100], 0.12);
+        engine.process_message(&[0xB0, 1, 64], 0.122);
+        assert!(engine.quantize(16, 120.0, 1.0));
+        let stored = engine.snapshot();
+        assert_eq!((stored[0].data[0], stored[0].timestamp), (0xB0, 0.122));
+        assert_eq!((stored[1].data[0], stored[1].timestamp), (0x90, 0.125));
+    }
+    
+    #[test]
+    fn test_active_sensing_watchdog() {
+        let engine = MidiEngine::new();
+        // Not armed until Active Sensing is seen
+        assert!(engine.is_link_alive(100.0));
+        
+        engine.process_message(&[0xFE], 1.0);
+        assert!(engine.is_link_alive(1.2));
+        engine.process_message(&[0xFE], 1.25);
+        assert!(engine.is_link_alive(1.5));
+        assert!(!engine.is_link_alive(1.6));
+        
+        // Sensing resumes
+        engine.process_message(&[0xFE], 2.0);
+        assert!(engine.is_link_alive(2.1));
+    }
+    
+    #[test]
+    fn test_program_and_bank() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0xB3, 0, 2], 0.0);
+        engine.process_message(&[0xB3, 32, 5], 0.0);
+        engine.process_message(&[0xC3, 42], 0.0);
+        
+        let stats = engine.stats();
+        assert_eq!(stats.current_bank[3], (2 << 7) | 5);
+        assert_eq!(stats.current_program[3], 42);
+        assert_eq!(stats.current_program[0], 0);
+        
+        // LSB alone keeps the MSB
+        engine.process_message(&[0xB3, 32, 1], 0.0);
+        assert_eq!(engine.stats().current_bank[3], (2 << 7) | 1);
+    }
+    
+    #[test]
+    fn test_reap_stuck_notes() {
+        let engine = MidiEngine::new();
+        engine.set_stuck_note_timeout(Some(5.0));
+        
+        engine.process_message(&[0x91, 60, 100], 0.0);
+        engine.process_message(&[0x91, 64, 100], 4.0);
+        assert_eq!(engine.active_note_count(), 2);
+        
+        // Unrelated traffic past the timeout releases the first note only
+        engine.process_message(&[0xB0, 1, 10], 6.0);
+        assert_eq!(engine.active_note_count(), 1);
+        let messages = engine.snapshot();
+        assert_eq!(messages[2].data, vec![0x81, 60, 0]);
+        assert_eq!(messages[2].timestamp, 6.0);
+        
+        assert_eq!(engine.reap_stuck_notes(6.0, 1.0), 1);
+        assert_eq!(engine.active_note_count(), 0);
+        
+        // A late note-off for a reaped note is passed through unchanged
+        engine.process_message(&[0x81, 60, 0], 7.0);
+        assert_eq!(engine.snapshot().last().unwrap().data, vec![0x81, 60, 0]);
+    }
+    
+    #[test]
+    fn test_truncated_messages() {
+        let engine = MidiEngine::new();
+        for data in [&[0x90][..], &[0x90, 60], &[0xB0], &[0xB0, 1]] {
+            match engine.try_process_message(data, 0.0) {
+                Err(MidiError::InvalidData(message)) => assert!(message.contains("truncated"), "{}", message),
+                other => panic!("expected InvalidData for {:?}, got {:?}", data, other),
+            }
+        }
+        assert!(matches!(engine.try_process_message(&[], 0.0), Err(MidiError::InvalidData(_))));
+        assert!(engine.snapshot().is_empty());
+        
+        // Ignored by the infallible entry point
+        engine.process_message(&[0x90, 60], 0.0);
+        assert!(engine.snapshot().is_empty());
+        
+        assert!(engine.try_process_message(&[0x90, 60, 100], 0.0).is_ok());
+        assert_eq!(engine.snapshot().len(), 1);
+    }    
+    #[test]
+    fn test_two_byte_channel_messages() {
+        let engine = MidiEngine::new();
+        engine.process_message(&[0x92, 60, 100], 0.0);
+        
+        assert!(engine.try_process_message(&[0xC2, 17], 0.1).is_ok());
+        assert!(engine.try_process_message(&[0xD2, 90], 0.2).is_ok());
+        assert_eq!(engine.stats().current_program[2], 17);
+        assert_eq!(engine.active_notes()[0].pressure, 90);
+        
+        for data in [&[0xC2][..], &[0xD2]] {
+            match engine.try_process_message(data, 0.3) {
+                Err(MidiError::InvalidData(message)) => assert!(message.contains("needs 2 bytes"), "{}", message),
+                other => panic!("expected InvalidData for {:?}, got {:?}", data, other),
+            }
+        }
+        assert_eq!(engine.stats().current_program[2], 17);
+        assert_eq!(engine.active_notes()[0].pressure, 90);
+        assert_eq!(engine.snapshot().len(), 3);
+    }
+}
@@ -0,0 +1,287 @@
+// midi_file.rs
+//! Standard MIDI File (SMF) import and export of observed events.
+
+use crate::error::MidiError;
+use crate::shared_buffer::MidiEvent;
+use midly::live::LiveEvent;
+use midly::num::{u15, u24, u28};
+use midly::{Arena, Format, Header, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+/// Tempo assumed until the first tempo meta-event (120 BPM).
+const DEFAULT_US_PER_QUARTER: u32 = 500_000;
+
+/// Converts a tempo in BPM to microseconds per quarter note.
+fn us_per_quarter(tempo_bpm: f64) -> u32 {
+    (60_000_000.0 / tempo_bpm).round() as u32
+}
+
+/// Writes `events` to a Format-0 Standard MIDI File at `path`.
+///
+/// The single track starts with a tempo meta-event for `tempo_bpm`, followed by
+/// the events with delta-times derived from their (microsecond) timestamps,
+/// relative to the first event, at `ppqn` ticks per quarter note. System
+/// real-time messages (clock, start/stop, active sensing, ...) have no meaning
+/// in a file and are skipped, as are messages that cannot be decoded.
+pub fn write_smf(events: &[MidiEvent], ppqn: u16, tempo_bpm: f64, path: &str) -> Result<(), MidiError> {
+    if ppqn == 0 || ppqn > 0x7FFF {
+        return Err(MidiError::InvalidData(format!("PPQN {} out of range", ppqn)));
+    }
+    if !tempo_bpm.is_finite() || tempo_bpm <= 0.0 {
+        return Err(MidiError::InvalidData(format!("Tempo {} BPM is not positive", tempo_bpm)));
+    }
+    
+    let tempo = us_per_quarter(tempo_bpm);
+    if tempo > 0xFF_FFFF {
+        return Err(MidiError::InvalidData(format!("Tempo {} BPM is too slow", tempo_bpm)));
+    }
+    let ticks_per_us = ppqn as f64 / tempo as f64;
+    
+    let arena = Arena::new();
+    let mut track = vec![TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo))),
+    }];
+    
+    let start = events.first().map_or(0, |event| event.timestamp);
+    let mut last_tick = 0u64;
+    for event in events {
+        if !matches!(event.data.first(), Some(&status) if status < 0xF8) {
+            continue;
+        }
+        let live = match LiveEvent::parse(&event.data) {
+            Ok(live) => live,
+            Err(e) => {
+                log::debug!("Skipping undecodable event {:02X?}: {}", event.data, e);
+                continue;
+            }
+        };
+        
+        let tick = (event.timestamp.saturating_sub(start) as f64 * ticks_per_us).round() as u64;
+        // Out-of-order timestamps are written with a zero delta
+        let delta = tick.saturating_sub(last_tick).min(u28::max_value().as_int() as u64);
+        last_tick = last_tick.max(tick);
+        
+        track.push(TrackEvent {
+            delta: u28::new(delta as u32),
+            kind: live.as_track_event(&arena),
+        });
+    }
+    
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    
+    let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(ppqn))));
+    smf.tracks.push(track);
+    smf.save(path)?;
+    Ok(())
+}
+
+/// Converts an absolute tick position to microseconds using a tempo map of
+/// `(tick, microseconds per quarter)` changes sorted by tick.
+fn ticks_to_us(tick: u64, ppqn: u16, tempo_map: &[(u64, u32)]) -> u64 {
+    let mut us = 0.0;
+    let mut last_tick = 0u64;
+    let mut tempo = DEFAULT_US_PER_QUARTER;
+    
+    for &(change_tick, change_tempo) in tempo_map {
+        if change_tick >= tick {
+            break;
+        }
+        us += (change_tick - last_tick) as f64 * tempo as f64 / ppqn as f64;
+        last_tick = change_tick;
+        tempo = change_tempo;
+    }
+    
+    us += (tick - last_tick) as f64 * tempo as f64 / ppqn as f64;
+    us.round() as u64
+}
+
+/// Reads a Format-0 or Format-1 Standard MIDI File into `MidiEvent`s.
+///
+/// Delta-times are accumulated per track and converted to absolute microsecond
+/// timestamps using the file's tempo meta-events (from any track), and the
+/// tracks are merged in time order. Meta-events are consumed, not returned.
+/// Running status and variable-length quantities are handled by the parser.
+pub fn read_smf(path: &str) -> Result<Vec<MidiEvent>, MidiError> {
+    let bytes = std::fs::read(path)?;
+    let smf = Smf::parse(&bytes).map_err(|e| MidiError::InvalidData(format!("{}: {}", path, e)))?;
+    
+    if smf.header.format == Format::Sequential {
+        return Err(MidiError::InvalidData(format!("{}: Format-2 files are not supported", path)));
+    }
+    
+    // Absolute-tick events of every track, plus the shared tempo map
+    let mut timed: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut tempo_map: Vec<(u64, u32)> = Vec::new();
+    
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Midi { channel, message } => {
+                    let mut data = Vec::with_capacity(3);
+                    LiveEvent::Midi { channel, message }.write_std(&mut data)?;
+                    timed.push((tick, data));
+                },
+                TrackEventKind::SysEx(data) => {
+                    let mut sysex = Vec::with_capacity(data.len() + 1);
+                    sysex.push(0xF0);
+                    sysex.extend_from_slice(data);
+                    timed.push((tick, sysex));
+                },
+                TrackEventKind::Escape(data) => timed.push((tick, data.to_vec())),
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => tempo_map.push((tick, tempo.as_int())),
+                TrackEventKind::Meta(_) => {},
+            }
+        }
+    }
+    
+    // Stable sorts keep same-tick events in track order
+    tempo_map.sort_by_key(|&(tick, _)| tick);
+    timed.sort_by_key(|&(tick, _)| tick);
+    
+    let to_us = |tick: u64| -> u64 {
+        match smf.header.timing {
+            Timing::Metrical(ppqn) => ticks_to_us(tick, ppqn.as_int().max(1), &tempo_map),
+            Timing::Timecode(fps, subframes) => {
+                let ticks_per_second = fps.as_f32() as f64 * subframes as f64;
+                (tick as f64 * 1_000_000.0 / ticks_per_second.max(1.0)).round() as u64
+            },
+        }
+    };
+    
+    Ok(timed.into_iter()
+        .map(|(tick, data)| MidiEvent {
+            data,
+            timestamp: to_us(tick),
+            device_name: String::new(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn note(data: [u8; 3], timestamp: u64) -> MidiEvent {
+        MidiEvent {
+            data: data.to_vec(),
+            timestamp,
+            device_name: "Test".to_string(),
+        }
+    }
+    
+    #[test]
+    fn test_write_smf() {
+        let path = std::env::temp_dir().join(format!("midi_file_write_{}.mid", std::process::id()));
+        let path = path.to_str().unwrap();
+        
+        // At 120 BPM and 480 PPQN, half a second is one quarter note = 480 ticks
+        let events = vec![
+            note([0x90, 60, 100], 1_000_000),
+            note([0x80, 60, 0], 1_500_000),
+            note([0x90, 64, 100], 1_500_000),
+            note([0x80, 64, 0], 2_000_000),
+        ];
+        write_smf(&events, 480, 120.0, path).unwrap();
+        
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        
+        // Header chunk: format 0, one track, 480 ticks per quarter
+        assert_eq!(&bytes[0..4], b"MThd");
+        assert_eq!(&bytes[4..8], &6u32.to_be_bytes());
+        assert_eq!(&bytes[8..10], &0u16.to_be_bytes());
+        assert_eq!(&bytes[10..12], &1u16.to_be_bytes());
+        assert_eq!(&bytes[12..14], &480u16.to_be_bytes());
+        
+        // The track chunk spans the rest of the file
+        assert_eq!(&bytes[14..18], b"MTrk");
+        let track_len = u32::from_be_bytes([bytes[18], bytes[19], bytes[20], bytes[21]]) as usize;
+        assert_eq!(track_len, bytes.len() - 22);
+        
+        // Tempo meta-event for 500000 us per quarter, and end of track
+        assert_eq!(&bytes[22..29], &[0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]);
+        assert_eq!(&bytes[bytes.len() - 3..], &[0xFF, 0x2F, 0x00]);
+        
+        // Delta-times follow the timestamps
+        let smf = Smf::parse(&bytes).unwrap();
+        let deltas: Vec<u32> = smf.tracks[0].iter().map(|event| event.delta.as_int()).collect();
+        assert_eq!(deltas, vec![0, 0, 480, 0, 480, 0]);
+    }
+    
+    #[test]
+    fn test_write_smf_rejects_bad_timing() {
+        assert!(matches!(write_smf(&[], 0, 120.0, "unused.mid"), Err(MidiError::InvalidData(_))));
+        assert!(matches!(write_smf(&[], 480, 0.0, "unused.mid"), Err(MidiError::InvalidData(_))));
+    }
+    
+    #[test]
+    fn test_read_smf() {
+        let path = std::env::temp_dir().join(format!("midi_file_read_{}.mid", std::process::id()));
+        let path = path.to_str().unwrap();
+        
+        let mut bytes = Vec::new();
+        // Header: format 1, two tracks, 96 ticks per quarter
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&[0, 0, 0, 6, 0, 1, 0, 2, 0, 96]);
+        
+        // Conductor track: 120 BPM, then 60 BPM after one quarter
+        let conductor = [
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x60, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(conductor.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&conductor);
+        
+        // Note track using running status and a two-byte delta (0x81 0x00 = 128)
+        let notes = [
+            0x00, 0x90, 0x3C, 0x64,
+            0x60, 0x3C, 0x00,
+            0x60, 0x3E, 0x64,
+            0x81, 0x00, 0x80, 0x3E, 0x40,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(notes.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&notes);
+        
+        std::fs::write(path, &bytes).unwrap();
+        let events = read_smf(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        
+        let decoded: Vec<(Vec<u8>, u64)> = events.into_iter().map(|e| (e.data, e.timestamp)).collect();
+        assert_eq!(decoded, vec![
+            (vec![0x90, 0x3C, 0x64], 0),
+            (vec![0x90, 0x3C, 0x00], 500_000),
+            (vec![0x90, 0x3E, 0x64], 1_500_000),
+            (vec![0x80, 0x3E, 0x40], 2_833_333),
+        ]);
+    }
+    
+    #[test]
+    fn test_smf_round_trip() {
+        let path = std::env::temp_dir().join(format!("midi_file_round_trip_{}.mid", std::process::id()));
+        let path = path.to_str().unwrap();
+        
+        let events = vec![
+            note([0x90, 60, 100], 0),
+            note([0x80, 60, 0], 250_000),
+            note([0xB0, 1, 64], 750_000),
+        ];
+        write_smf(&events, 960, 120.0, path).unwrap();
+        let read = read_smf(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        
+        assert_eq!(read.len(), events.len());
+        for (original, read) in events.iter().zip(&read) {
+            assert_eq!(original.data, read.data);
+            assert_eq!(original.timestamp, read.timestamp);
+        }
+    }
+}
@@ -6,11 +6,13 @@
  * MIDI message types and the musical context struct.
  */
 
-use std::collections::{VecDeque, HashMap};
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
 use crate::shared_buffer::MidiEvent;
+use crate::parser::{parse_midi_message, ParsedMessage};
 
 /// MIDI message types
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MidiMessage {
     /// Note on message
     NoteOn {
@@ -91,6 +93,256 @@ pub enum MidiMessageType {
     Other,
 }
 
+impl From<ParsedMessage> for MidiMessage {
+    fn from(message: ParsedMessage) -> Self {
+        match message {
+            ParsedMessage::NoteOn { channel, note, velocity } => MidiMessage::NoteOn { channel, note, velocity },
+            ParsedMessage::NoteOff { channel, note, velocity } => MidiMessage::NoteOff { channel, note, velocity },
+            ParsedMessage::PolyAftertouch { channel, note, pressure } => MidiMessage::PolyphonicAftertouch { channel, note, pressure },
+            ParsedMessage::ControlChange { channel, controller, value } => MidiMessage::ControlChange { channel, controller, value },
+            ParsedMessage::ProgramChange { channel, program } => MidiMessage::ProgramChange { channel, program },
+            ParsedMessage::ChannelAftertouch { channel, pressure } => MidiMessage::ChannelAftertouch { channel, pressure },
+            ParsedMessage::PitchBend { channel, value } => MidiMessage::PitchBend { channel, value },
+            _ => MidiMessage::Other,
+        }
+    }
+}
+
+impl MidiMessage {
+    /// Encodes the message as raw MIDI 1.0 bytes. Channels and data values are
+    /// masked to their valid ranges; `Other` encodes to no bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let status = |kind: u8, channel: u8| kind | (channel & 0x0F);
+        match *self {
+            MidiMessage::NoteOn { channel, note, velocity } => vec![status(0x90, channel), note & 0x7F, velocity & 0x7F],
+            MidiMessage::NoteOff { channel, note, velocity } => vec![status(0x80, channel), note & 0x7F, velocity & 0x7F],
+            MidiMessage::PolyphonicAftertouch { channel, note, pressure } => vec![status(0xA0, channel), note & 0x7F, pressure & 0x7F],
+            MidiMessage::ControlChange { channel, controller, value } => vec![status(0xB0, channel), controller & 0x7F, value & 0x7F],
+            MidiMessage::ProgramChange { channel, program } => vec![status(0xC0, channel), program & 0x7F],
+            MidiMessage::ChannelAftertouch { channel, pressure } => vec![status(0xD0, channel), pressure & 0x7F],
+            MidiMessage::PitchBend { channel, value } => {
+                vec![status(0xE0, channel), (value & 0x7F) as u8, ((value >> 7) & 0x7F) as u8]
+            },
+            MidiMessage::Other => Vec::new(),
+        }
+    }
+}
+
+impl From<&ParsedMessage> for MidiMessageType {
+    fn from(message: &ParsedMessage) -> Self {
+        match message {
+            ParsedMessage::NoteOn { .. } => MidiMessageType::NoteOn,
+            ParsedMessage::NoteOff { .. } => MidiMessageType::NoteOff,
+            ParsedMessage::PolyAftertouch { .. } => MidiMessageType::PolyAftertouch,
+            ParsedMessage::ControlChange { .. } => MidiMessageType::ControlChange,
+            ParsedMessage::ProgramChange { .. } => MidiMessageType::ProgramChange,
+            ParsedMessage::ChannelAftertouch { .. } => MidiMessageType::Aftertouch,
+            ParsedMessage::PitchBend { .. } => MidiMessageType::PitchBend,
+            ParsedMessage::SysEx(_) => MidiMessageType::SysEx,
+            ParsedMessage::Clock => MidiMessageType::Clock,
+            ParsedMessage::Start => MidiMessageType::Start,
+            ParsedMessage::Continue => MidiMessageType::Continue,
+            ParsedMessage::Stop => MidiMessageType::Stop,
+            _ => MidiMessageType::Other,
+        }
+    }
+}
+
+/// Pitch-class names, indexed by MIDI note number modulo 12
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Chord qualities recognized by `MusicalContext::detect_chord`, as
+/// (suffix, pitch-class intervals above the root)
+const CHORD_TYPES: [(&str, &[u8]); 7] = [
+    ("maj", &[0, 4, 7]),
+    ("min", &[0, 3, 7]),
+    ("dim", &[0, 3, 6]),
+    ("aug", &[0, 4, 8]),
+    ("7", &[0, 4, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("min7", &[0, 3, 7, 10]),
+];
+
+/// Krumhansl-Kessler major key profile, starting at the tonic
+const MAJOR_KEY_PROFILE: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+
+/// Krumhansl-Kessler minor key profile, starting at the tonic
+const MINOR_KEY_PROFILE: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// Number of note-ons needed before `MusicalContext::detected_key` reports a key
+pub const MIN_KEY_NOTES: u32 = 8;
+
+/// Mode of a detected key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    /// Major (Ionian) scale
+    Major,
+    /// Natural minor (Aeolian) scale
+    Minor,
+}
+
+impl Scale {
+    /// Gets the scale's name, "Major" or "Minor"
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scale::Major => "Major",
+            Scale::Minor => "Minor",
+        }
+    }
+}
+
+/// Key profile weights for scales without a measured profile, averaged from
+/// the Krumhansl-Kessler profiles
+const TONIC_WEIGHT: f64 = 6.34;
+const FIFTH_WEIGHT: f64 = 4.97;
+const THIRD_WEIGHT: f64 = 4.88;
+const SCALE_TONE_WEIGHT: f64 = 3.62;
+const OUT_OF_SCALE_WEIGHT: f64 = 2.52;
+
+/// A named scale the key detector can match, as semitones above the tonic
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaleTemplate {
+    name: String,
+    intervals: Vec<u8>,
+    /// Expected weight of each pitch class, starting at the tonic
+    profile: [f64; 12],
+}
+
+impl ScaleTemplate {
+    /// Creates a template from its intervals, e.g. `[0, 2, 3, 5, 7, 9, 10]`
+    /// for Dorian. The intervals must include the tonic (0) and lie in 0-11.
+    pub fn new(name: &str, intervals: &[u8]) -> Result<Self, ModelError> {
+        if name.is_empty() {
+            return Err(ModelError::InvalidInput("scale name is empty".to_string()));
+        }
+        if !intervals.contains(&0) || intervals.iter().any(|&interval| interval > 11) {
+            return Err(ModelError::InvalidInput(format!("invalid intervals {:?} for scale {}", intervals, name)));
+        }
+        
+        let mut intervals = intervals.to_vec();
+        intervals.sort_unstable();
+        intervals.dedup();
+        
+        let mut profile = [OUT_OF_SCALE_WEIGHT; 12];
+        for &interval in &intervals {
+            profile[interval as usize] = match interval {
+                0 => TONIC_WEIGHT,
+                7 => FIFTH_WEIGHT,
+                3 | 4 => THIRD_WEIGHT,
+                _ => SCALE_TONE_WEIGHT,
+            };
+        }
+        Ok(Self { name: name.to_string(), intervals, profile })
+    }
+    
+    /// Creates a template with a measured key profile
+    fn with_profile(name: &str, intervals: &[u8], profile: [f64; 12]) -> Self {
+        Self { name: name.to_string(), intervals: intervals.to_vec(), profile }
+    }
+    
+    /// Gets the scale's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    /// Gets the scale's intervals above the tonic, in ascending order
+    pub fn intervals(&self) -> &[u8] {
+        &self.intervals
+    }
+    
+    /// Major if the scale has a major third, minor otherwise
+    pub fn quality(&self) -> Scale {
+        if self.intervals.contains(&4) { Scale::Major } else { Scale::Minor }
+    }
+}
+
+/// The scales the key detector chooses between
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaleTemplates {
+    templates: Vec<ScaleTemplate>,
+}
+
+impl ScaleTemplates {
+    /// Adds a template, replacing any with the same name
+    pub fn add(&mut self, template: ScaleTemplate) {
+        match self.templates.iter_mut().find(|existing| existing.name == template.name) {
+            Some(existing) => *existing = template,
+            None => self.templates.push(template),
+        }
+    }
+    
+    /// Gets the template at `index`, in the order they were added
+    pub fn get(&self, index: usize) -> Option<&ScaleTemplate> {
+        self.templates.get(index)
+    }
+    
+    /// Iterates over the templates in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &ScaleTemplate> {
+        self.templates.iter()
+    }
+    
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+}
+
+impl Default for ScaleTemplates {
+    /// Major and natural minor, with the Krumhansl-Kessler profiles
+    fn default() -> Self {
+        Self {
+            templates: vec![
+                ScaleTemplate::with_profile("Major", &[0, 2, 4, 5, 7, 9, 11], MAJOR_KEY_PROFILE),
+                ScaleTemplate::with_profile("Minor", &[0, 2, 3, 5, 7, 8, 10], MINOR_KEY_PROFILE),
+            ],
+        }
+    }
+}
+
+/// Gets the name of a pitch class (0 = C, 1 = C#, etc.), without an octave
+pub fn pitch_class_name(pitch_class: u8) -> &'static str {
+    NOTE_NAMES[pitch_class as usize % 12]
+}
+
+/// Number of recent clock intervals averaged for the tempo estimate (one beat)
+const TEMPO_WINDOW: usize = 24;
+
+/// Octave of middle C (note 60) in the default naming convention ("C4")
+pub const DEFAULT_MIDDLE_C_OCTAVE: i32 = 4;
+
+/// Gets the name of a MIDI note number, e.g. "C4" or "F#3", with middle C in octave 4
+pub fn note_name(note: u8) -> String {
+    note_name_with_octave(note, DEFAULT_MIDDLE_C_OCTAVE)
+}
+
+/// Gets the name of a MIDI note number, numbering octaves so that middle C
+/// (note 60) falls in `middle_c_octave` (4 for "C4", 3 for the "C3" convention)
+pub fn note_name_with_octave(note: u8, middle_c_octave: i32) -> String {
+    let octave = note as i32 / 12 - 5 + middle_c_octave;
+    format!("{}{}", NOTE_NAMES[note as usize % 12], octave)
+}
+
+/// Pearson correlation of two equally long series (0.0 if either is constant)
+fn correlation(a: &[f64; 12], b: &[f64; 12]) -> f64 {
+    let mean_a = a.iter().sum::<f64>() / 12.0;
+    let mean_b = b.iter().sum::<f64>() / 12.0;
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    if variance_a == 0.0 || variance_b == 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_a * variance_b).sqrt()
+}
+
 /// Represents a musical note with timing information
 #[derive(Debug, Clone)]
 pub struct Note {
@@ -108,6 +360,13 @@ pub struct Note {
     pub duration: Option<u64>,
 }
 
+impl Note {
+    /// Gets the note's name, e.g. "C4" (see `note_name`)
+    pub fn name(&self) -> String {
+        note_name(self.note)
+    }
+}
+
 /// Musical context for ML models
 pub struct MusicalContext {
     /// Recent MIDI messages
@@ -116,24 +375,66 @@ pub struct MusicalContext {
     max_messages: usize,
     /// Active notes (note number -> velocity)
     active_notes: [Option<u8>; 128],
+    /// Aftertouch pressure of each active note (note number -> pressure),
+    /// from poly aftertouch or the channel aftertouch of any channel
+    active_pressure: [u8; 128],
+    /// Pitch bend per channel (0-16383, 8192 = center)
+    channel_bend: [u16; 16],
     /// Current tempo (beats per minute)
     tempo: f32,
     /// Current time signature (numerator, denominator)
     time_signature: (u8, u8),
     /// Current key signature (0 = C, 1 = C#, etc.)
     key_signature: u8,
+    /// Mode of the current key
+    scale: Scale,
+    /// Scales the key detector chooses between
+    scale_templates: ScaleTemplates,
+    /// Index in `scale_templates` of the detected scale
+    scale_template: usize,
+    /// Sustain pedal (CC#64) state per channel
+    sustain: [bool; 16],
+    /// Released notes held by the sustain pedal, as (channel, note)
+    sustained_notes: Vec<(u8, u8)>,
+    /// Note-on count per pitch class, used to infer the key
+    pitch_class_counts: [u32; 12],
+    /// Timestamp (microseconds) of the last MIDI clock
+    last_clock_time: Option<u64>,
+    /// Recent clock intervals (microseconds), used to infer the tempo
+    clock_intervals: VecDeque<u64>,
+    /// Recent notes with their timing, oldest first, at most `max_messages`
+    notes: VecDeque<Note>,
 }
 
+/// Default number of recent messages kept by `MusicalContext` and `ModelContext`
+pub const DEFAULT_WINDOW_SIZE: usize = 1000;
+
 impl MusicalContext {
     /// Creates a new musical context
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_WINDOW_SIZE)
+    }
+    
+    /// Creates a new musical context that keeps at most `max_messages` recent messages
+    pub fn with_capacity(max_messages: usize) -> Self {
         Self {
-            messages: VecDeque::new(),
-            max_messages: 1000,
+            messages: VecDeque::with_capacity(max_messages),
+            max_messages,
             active_notes: [None; 128],
+            active_pressure: [0; 128],
+            channel_bend: [8192; 16],
             tempo: 120.0,
             time_signature: (4, 4),
             key_signature: 0,
+            scale: Scale::Major,
+            scale_templates: ScaleTemplates::default(),
+            scale_template: 0,
+            sustain: [false; 16],
+            sustained_notes: Vec::new(),
+            pitch_class_counts: [0; 12],
+            last_clock_time: None,
+            clock_intervals: VecDeque::with_capacity(TEMPO_WINDOW),
+            notes: VecDeque::new(),
         }
     }
     
@@ -149,25 +450,214 @@ impl MusicalContext {
         
         // Update the active notes
         match message {
-            MidiMessage::NoteOn { note, velocity, .. } => {
-                if velocity > 0 {
-                    self.active_notes[note as usize] = Some(velocity);
-                } else {
-                    self.active_notes[note as usize] = None;
+            MidiMessage::NoteOn { channel, note, velocity } if velocity > 0 => {
+                // A retriggered note is held by the key again, not the pedal
+                self.sustained_notes.retain(|&held| held != (channel, note));
+                self.active_notes[note as usize] = Some(velocity);
+                self.active_pressure[note as usize] = 0;
+                self.pitch_class_counts[note as usize % 12] += 1;
+                self.update_key();
+            }
+            MidiMessage::NoteOn { channel, note, .. } | MidiMessage::NoteOff { channel, note, .. } => {
+                self.release_note(channel, note);
+            }
+            MidiMessage::ControlChange { channel, controller: 64, value } => {
+                let channel = channel & 0x0F;
+                self.sustain[channel as usize] = value >= 64;
+                if value < 64 {
+                    // Pedal up: complete the notes it was holding
+                    let (released, held): (Vec<_>, Vec<_>) = self.sustained_notes
+                        .iter()
+                        .partition(|&&(held_channel, _)| held_channel == channel);
+                    self.sustained_notes = held;
+                    for (_, note) in released {
+                        self.active_notes[note as usize] = None;
+                        self.active_pressure[note as usize] = 0;
+                    }
+                }
+            }
+            MidiMessage::PolyphonicAftertouch { note, pressure, .. } if self.active_notes[note as usize & 0x7F].is_some() => {
+                self.active_pressure[note as usize & 0x7F] = pressure;
+            }
+            MidiMessage::ChannelAftertouch { pressure, .. } => {
+                // Notes aren't tracked per channel, so channel pressure applies to every held note
+                for (velocity, note_pressure) in self.active_notes.iter().zip(self.active_pressure.iter_mut()) {
+                    if velocity.is_some() {
+                        *note_pressure = pressure;
+                    }
                 }
             }
-            MidiMessage::NoteOff { note, .. } => {
-                self.active_notes[note as usize] = None;
+            MidiMessage::PitchBend { channel, value } => {
+                self.channel_bend[(channel & 0x0F) as usize] = value.min(16383);
             }
             _ => {}
         }
     }
     
+    /// Handles a note-off: the note stays active while the channel's sustain pedal is down
+    fn release_note(&mut self, channel: u8, note: u8) {
+        if self.sustain[(channel & 0x0F) as usize] {
+            if self.active_notes[note as usize].is_some() && !self.sustained_notes.contains(&(channel, note)) {
+                self.sustained_notes.push((channel, note));
+            }
+        } else {
+            self.active_notes[note as usize] = None;
+            self.active_pressure[note as usize] = 0;
+        }
+    }
+    
+    /// Re-estimates the key from the note histogram
+    fn update_key(&mut self) {
+        let (key, template) = self.estimate_key();
+        self.key_signature = key;
+        self.scale_template = template;
+        self.scale = self.scale_templates.templates[template].quality();
+    }
+    
+    /// Estimates the key (tonic 0 = C, 1 = C#, etc. and index of the scale
+    /// template) that best fits the note histogram, by correlation with each
+    /// template's key profile
+    fn estimate_key(&self) -> (u8, usize) {
+        let counts = self.pitch_class_counts.map(|count| count as f64);
+        let mut best = (0, 0);
+        let mut best_score = f64::MIN;
+        for (index, template) in self.scale_templates.iter().enumerate() {
+            for key in 0..12 {
+                let rotated: [f64; 12] = std::array::from_fn(|pitch_class| template.profile[(pitch_class + 12 - key) % 12]);
+                let score = correlation(&rotated, &counts);
+                if score > best_score {
+                    best = (key as u8, index);
+                    best_score = score;
+                }
+            }
+        }
+        best
+    }
+    
+    /// Gets the scales the key detector chooses between
+    pub fn scale_templates(&self) -> &ScaleTemplates {
+        &self.scale_templates
+    }
+    
+    /// Adds a scale for the key detector to consider, replacing any with the
+    /// same name, and re-estimates the key
+    pub fn add_scale_template(&mut self, template: ScaleTemplate) {
+        self.scale_templates.add(template);
+        if self.pitch_class_counts.iter().any(|&count| count > 0) {
+            self.update_key();
+        }
+    }
+    
+    /// Updates the tempo from a MIDI clock (24 per quarter note) received at
+    /// `timestamp` microseconds, averaging over the last beat of clocks.
+    /// Gaps of two seconds or more restart the estimate.
+    pub fn observe_clock(&mut self, timestamp: u64) {
+        if let Some(last) = self.last_clock_time {
+            let interval = timestamp.saturating_sub(last);
+            if interval > 0 && interval < 2_000_000 {
+                self.clock_intervals.push_back(interval);
+                while self.clock_intervals.len() > TEMPO_WINDOW {
+                    self.clock_intervals.pop_front();
+                }
+                let average = self.clock_intervals.iter().sum::<u64>() as f64 / self.clock_intervals.len() as f64;
+                self.tempo = (60_000_000.0 / (average * 24.0)) as f32;
+            } else {
+                self.clock_intervals.clear();
+            }
+        }
+        self.last_clock_time = Some(timestamp);
+    }
+    
+    /// Checks whether the sustain pedal is down on a channel
+    pub fn is_sustained(&self, channel: u8) -> bool {
+        self.sustain[(channel & 0x0F) as usize]
+    }
+    
     /// Gets the active notes
     pub fn active_notes(&self) -> &[Option<u8>; 128] {
         &self.active_notes
     }
     
+    /// Gets the aftertouch pressure of each active note (0 if none or not active)
+    pub fn active_pressure(&self) -> &[u8; 128] {
+        &self.active_pressure
+    }
+    
+    /// Gets the pitch bend of each channel (0-16383, 8192 = center)
+    pub fn channel_bend(&self) -> &[u16; 16] {
+        &self.channel_bend
+    }
+    
+    /// Gets the pitch classes of the active notes as a 12-bit mask (bit 0 = C,
+    /// bit 1 = C#, etc.)
+    pub fn active_pitch_class_set(&self) -> u16 {
+        self.active_notes.iter()
+            .enumerate()
+            .filter(|(_, velocity)| velocity.is_some())
+            .fold(0, |mask, (note, _)| mask | 1 << (note % 12))
+    }
+    
+    /// Names the chord formed by the active notes, e.g. "Cmaj" or "Cmin7"
+    ///
+    /// The lowest active note is tried as the root first, then the other pitch
+    /// classes, so inversions are named after their root. Returns `None` for
+    /// fewer than three distinct pitch classes or an unrecognized chord.
+    pub fn detect_chord(&self) -> Option<String> {
+        self.detect_chord_root()
+            .map(|(root, suffix)| format!("{}{}", NOTE_NAMES[root as usize], suffix))
+    }
+    
+    /// Detects the chord formed by the active notes, as its root pitch class
+    /// (0 = C, 1 = C#, etc.) and quality suffix (e.g. "min"); see `detect_chord`
+    pub fn detect_chord_root(&self) -> Option<(u8, &'static str)> {
+        // Distinct pitch classes, lowest note first
+        let mut pitch_classes: Vec<u8> = Vec::new();
+        for (note, velocity) in self.active_notes.iter().enumerate() {
+            let pitch_class = (note % 12) as u8;
+            if velocity.is_some() && !pitch_classes.contains(&pitch_class) {
+                pitch_classes.push(pitch_class);
+            }
+        }
+        
+        if pitch_classes.len() < 3 {
+            return None;
+        }
+        
+        for &root in &pitch_classes {
+            let mut intervals: Vec<u8> = pitch_classes.iter().map(|&pc| (pc + 12 - root) % 12).collect();
+            intervals.sort_unstable();
+            
+            if let Some((suffix, _)) = CHORD_TYPES.iter().find(|(_, chord)| *chord == intervals.as_slice()) {
+                return Some((root, suffix));
+            }
+        }
+        
+        None
+    }
+    
+    /// Gets the maximum number of recent messages kept
+    pub fn max_messages(&self) -> usize {
+        self.max_messages
+    }
+    
+    /// Sets the maximum number of recent messages kept, dropping the oldest
+    /// messages immediately if the window shrinks
+    pub fn set_max_messages(&mut self, max_messages: usize) {
+        self.max_messages = max_messages;
+        while self.messages.len() > self.max_messages {
+            self.messages.pop_front();
+        }
+        while self.notes.len() > self.max_messages {
+            self.notes.pop_front();
+        }
+    }
+    
+    /// Gets the recent notes, oldest first. A note ends when its key is
+    /// released, even if the sustain pedal keeps it sounding.
+    pub fn recent_notes(&self) -> &VecDeque<Note> {
+        &self.notes
+    }
+    
     /// Gets the recent messages
     pub fn messages(&self) -> &VecDeque<MidiMessage> {
         &self.messages
@@ -203,49 +693,76 @@ impl MusicalContext {
         self.key_signature = key;
     }
     
+    /// Gets the mode of the current key
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+    
+    /// Gets the detected key and its mode, or None until `MIN_KEY_NOTES`
+    /// note-ons have been seen
+    pub fn detected_key(&self) -> Option<(u8, Scale)> {
+        let notes: u32 = self.pitch_class_counts.iter().sum();
+        (notes >= MIN_KEY_NOTES).then_some((self.key_signature, self.scale))
+    }
+    
+    /// Gets the detected key and the scale template it matched, or None until
+    /// `MIN_KEY_NOTES` note-ons have been seen
+    pub fn detected_scale(&self) -> Option<(u8, &ScaleTemplate)> {
+        let (key, _) = self.detected_key()?;
+        Some((key, &self.scale_templates.templates[self.scale_template]))
+    }
+    
     /// Determines the type of a MIDI event
     pub fn get_message_type(event: &MidiEvent) -> MidiMessageType {
-        // Check the status byte (first byte of MIDI message)
-        if event.data.is_empty() {
-            return MidiMessageType::Other;
-        }
-        
-        let status = event.data[0];
-        
-        // Extract the high nibble (message type)
-        match status & 0xF0 {
-            0x80 => MidiMessageType::NoteOff,
-            0x90 => {
-                // Note On with velocity 0 is treated as Note Off
-                if event.data.len() > 2 && event.data[2] == 0 {
-                    MidiMessageType::NoteOff
-                } else {
-                    MidiMessageType::NoteOn
+        match parse_midi_message(&event.data) {
+            Ok(message) => MidiMessageType::from(&message),
+            Err(_) => MidiMessageType::Other,
+        }
+    }
+    
+    /// Updates the context with a raw MIDI event: clocks update the tempo,
+    /// channel messages are recorded, and anything else is ignored
+    pub fn observe_event(&mut self, event: &MidiEvent) {
+        match parse_midi_message(&event.data) {
+            Ok(ParsedMessage::Clock) => self.observe_clock(event.timestamp),
+            Ok(message) => match MidiMessage::from(message) {
+                MidiMessage::Other => {},
+                message => {
+                    self.track_note(&message, event.timestamp);
+                    self.update(message);
                 }
             },
-            0xA0 => MidiMessageType::PolyAftertouch,
-            0xB0 => MidiMessageType::ControlChange,
-            0xC0 => MidiMessageType::ProgramChange,
-            0xD0 => MidiMessageType::Aftertouch,
-            0xE0 => MidiMessageType::PitchBend,
-            0xF0 => {
-                // System messages
-                match status {
-                    0xF0 => MidiMessageType::SysEx,
-                    0xF8 => MidiMessageType::Clock,
-                    0xFA => MidiMessageType::Start,
-                    0xFB => MidiMessageType::Continue,
-                    0xFC => MidiMessageType::Stop,
-                    _ => MidiMessageType::Other,
+            Err(_) => {},
+        }
+    }
+    
+    /// Records note timing: a note-on starts a note, and a note-off ends the
+    /// latest note of the same channel and number that hasn't ended
+    fn track_note(&mut self, message: &MidiMessage, timestamp: u64) {
+        match *message {
+            MidiMessage::NoteOn { channel, note, velocity } if velocity > 0 => {
+                self.notes.push_back(Note { note, velocity, channel, start_time: timestamp, end_time: None, duration: None });
+                while self.notes.len() > self.max_messages {
+                    self.notes.pop_front();
                 }
-            },
-            _ => MidiMessageType::Other,
+            }
+            MidiMessage::NoteOn { channel, note, .. } | MidiMessage::NoteOff { channel, note, .. } => {
+                let open = self.notes
+                    .iter_mut()
+                    .rev()
+                    .find(|open| open.channel == channel && open.note == note && open.end_time.is_none());
+                if let Some(open) = open {
+                    open.end_time = Some(timestamp);
+                    open.duration = Some(timestamp.saturating_sub(open.start_time));
+                }
+            }
+            _ => {}
         }
     }
 }
 
 /// Represents a detected pattern in MIDI data
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Pattern {
     /// Unique identifier for the pattern
     pub id: u64,
@@ -259,6 +776,12 @@ pub struct Pattern {
     pub pattern_type: String,
 }
 
+/// Insights scoring at or below this are not reported, unless configured otherwise
+pub const DEFAULT_SIGNIFICANCE_THRESHOLD: f64 = 0.5;
+
+/// Resolution (microseconds) at which event timing is compared when matching patterns
+pub const RHYTHM_QUANTUM_US: u64 = 10_000;
+
 impl Pattern {
     /// Creates a new pattern from a sequence of MIDI events
     pub fn new(events: Vec<MidiEvent>) -> Self {
@@ -272,16 +795,57 @@ impl Pattern {
         }
     }
 
-    /// Calculates a hash for the pattern based on its events
+    /// Gaps between consecutive events, quantized to `RHYTHM_QUANTUM_US`
+    /// (0 for the first event), so small timing jitter doesn't split a pattern
+    pub fn rhythm(events: &[MidiEvent]) -> Vec<u64> {
+        let mut previous = events.first();
+        events.iter()
+            .map(|event| {
+                let gap = previous.map_or(0, |previous| Self::quantized_gap(previous, event));
+                previous = Some(event);
+                gap
+            })
+            .collect()
+    }
+    
+    /// Gap between two events, quantized to `RHYTHM_QUANTUM_US`
+    pub fn quantized_gap(previous: &MidiEvent, event: &MidiEvent) -> u64 {
+        let gap = event.timestamp.saturating_sub(previous.timestamp);
+        (gap + RHYTHM_QUANTUM_US / 2) / RHYTHM_QUANTUM_US
+    }
+
+    /// Calculates a hash for the pattern based on its events and their rhythm
+    ///
+    /// This is 64-bit FNV-1a over, for each event, its message length (u32,
+    /// little-endian), its message bytes and its quantized gap (u64,
+    /// little-endian), so the same phrase gets the same ID across runs and
+    /// Rust versions.
     fn calculate_hash(events: &[MidiEvent]) -> u64 {
-        use std::hash::{Hash, Hasher};
-        use std::collections::hash_map::DefaultHasher;
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut feed = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
         
-        let mut hasher = DefaultHasher::new();
-        for event in events {
-            event.data.hash(&mut hasher);
+        for (event, gap) in events.iter().zip(Self::rhythm(events)) {
+            feed(&(event.data.len() as u32).to_le_bytes());
+            feed(&event.data);
+            feed(&gap.to_le_bytes());
         }
-        hasher.finish()
+        hash
+    }
+
+    /// Checks whether a sequence of events is this pattern: the same messages
+    /// with the same quantized rhythm. Used to confirm a hash match.
+    pub fn matches(&self, events: &[MidiEvent]) -> bool {
+        self.events.len() == events.len()
+            && self.events.iter().zip(events).all(|(a, b)| a.data == b.data)
+            && Self::rhythm(&self.events) == Self::rhythm(events)
     }
 
     /// Gets the hash of this pattern
@@ -291,7 +855,7 @@ impl Pattern {
 }
 
 /// Represents an insight generated from MIDI analysis
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Insight {
     /// A detected pattern
     Pattern(Pattern),
@@ -313,6 +877,55 @@ pub enum Insight {
     },
 }
 
+/// What makes two insights the same, for deduplication
+#[derive(PartialEq, Eq, Hash)]
+enum InsightKey {
+    Pattern(u64),
+    Performance(String),
+    Style(String),
+}
+
+impl Insight {
+    /// The insight's score: significance, score or confidence (0.0 - 1.0)
+    pub fn score(&self) -> f64 {
+        match self {
+            Insight::Pattern(pattern) => pattern.significance_score,
+            Insight::Performance { score, .. } => *score,
+            Insight::Style { confidence, .. } => *confidence,
+        }
+    }
+    
+    fn key(&self) -> InsightKey {
+        match self {
+            Insight::Pattern(pattern) => InsightKey::Pattern(pattern.id),
+            Insight::Performance { description, .. } => InsightKey::Performance(description.clone()),
+            Insight::Style { style, .. } => InsightKey::Style(style.clone()),
+        }
+    }
+}
+
+/// Removes repeated insights: patterns with the same ID, and performance or
+/// style insights with the same description. The highest-scoring instance is
+/// kept, in the position where the insight first appeared.
+pub fn dedup_insights(insights: Vec<Insight>) -> Vec<Insight> {
+    let mut positions: HashMap<InsightKey, usize> = HashMap::new();
+    let mut unique: Vec<Insight> = Vec::with_capacity(insights.len());
+    for insight in insights {
+        match positions.get(&insight.key()) {
+            Some(&index) => {
+                if insight.score() > unique[index].score() {
+                    unique[index] = insight;
+                }
+            },
+            None => {
+                positions.insert(insight.key(), unique.len());
+                unique.push(insight);
+            },
+        }
+    }
+    unique
+}
+
 /// Error type for model operations
 #[derive(Debug, thiserror::Error)]
 pub enum ModelError {
@@ -320,10 +933,27 @@ pub enum ModelError {
     ModelNotFound,
     #[error("Failed to load model: {0}")]
     LoadFailed(String),
+    #[error("Failed to save model: {0}")]
+    SaveFailed(String),
     #[error("Invalid input: {0}")]
     InvalidInput(String),
 }
 
+/// Descriptive metadata reported by a model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelMetadata {
+    /// Human-readable model name
+    pub name: String,
+    /// Short description of what the model does
+    pub description: String,
+    /// Model version
+    pub version: String,
+    /// Model author
+    pub author: String,
+    /// License the model is distributed under
+    pub license: String,
+}
+
 /// Trait for MIDI models
 pub trait MidiModel {
     /// Processes a MIDI event
@@ -331,6 +961,26 @@ pub trait MidiModel {
     
     /// Generates insights based on the current context
     fn generate_insights(&self, context: &MusicalContext) -> Vec<Insight>;
+    
+    /// Gets the model's metadata
+    fn metadata(&self) -> ModelMetadata;
+    
+    /// Sets the score an insight must exceed to be reported (0.0 - 1.0)
+    fn set_significance_threshold(&mut self, _threshold: f64) {}
+    
+    /// Enables or disables fuzzy matching of learned patterns, allowing up
+    /// to `threshold` interval edits
+    fn set_fuzzy_matching(&mut self, _enabled: bool, _threshold: usize) {}
+    
+    /// Saves the patterns the model has learned to a file
+    fn save_patterns(&self, _path: &str) -> Result<(), ModelError> {
+        Err(ModelError::InvalidInput(format!("{} does not learn patterns", self.metadata().name)))
+    }
+    
+    /// Replaces the patterns the model has learned with those saved in a file
+    fn load_patterns(&mut self, _path: &str) -> Result<(), ModelError> {
+        Err(ModelError::InvalidInput(format!("{} does not learn patterns", self.metadata().name)))
+    }
 }
 
 /// The main model context that manages MIDI data and models
@@ -341,18 +991,54 @@ pub struct ModelContext {
     pub patterns: Vec<Pattern>,
     /// Current musical context
     pub musical_context: MusicalContext,
+    /// Musical context of each device, keyed by device name
+    device_contexts: HashMap<String, MusicalContext>,
     /// Active model
     pub model: Option<Box<dyn MidiModel>>,
+    /// Maximum number of recent events kept
+    max_events: usize,
+    /// Score a pattern must exceed to be reported as an insight
+    significance_threshold: f64,
 }
 
 impl ModelContext {
     /// Creates a new model context
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_WINDOW_SIZE)
+    }
+    
+    /// Creates a new model context that keeps at most `window_size` recent
+    /// events (and musical context messages)
+    pub fn with_capacity(window_size: usize) -> Self {
         Self {
-            recent_events: VecDeque::with_capacity(1000),
+            recent_events: VecDeque::with_capacity(window_size),
             patterns: Vec::new(),
-            musical_context: MusicalContext::new(),
+            musical_context: MusicalContext::with_capacity(window_size),
+            device_contexts: HashMap::new(),
             model: None,
+            max_events: window_size,
+            significance_threshold: DEFAULT_SIGNIFICANCE_THRESHOLD,
+        }
+    }
+    
+    /// Sets the number of recent events (and musical context messages) kept,
+    /// dropping the oldest immediately if the window shrinks
+    pub fn set_window_size(&mut self, window_size: usize) {
+        self.max_events = window_size;
+        while self.recent_events.len() > self.max_events {
+            self.recent_events.pop_front();
+        }
+        self.musical_context.set_max_messages(window_size);
+        for context in self.device_contexts.values_mut() {
+            context.set_max_messages(window_size);
+        }
+    }
+    
+    /// Sets the score an insight must exceed to be reported, clamped to 0.0 - 1.0
+    pub fn set_significance_threshold(&mut self, threshold: f64) {
+        self.significance_threshold = threshold.clamp(0.0, 1.0);
+        if let Some(model) = self.model.as_mut() {
+            model.set_significance_threshold(self.significance_threshold);
         }
     }
     
@@ -360,16 +1046,17 @@ impl ModelContext {
     pub fn add_event(&mut self, event: MidiEvent) {
         // Add to recent events
         self.recent_events.push_back(event.clone());
-        if self.recent_events.len() > 1000 {
+        while self.recent_events.len() > self.max_events {
             self.recent_events.pop_front();
         }
         
         // Update musical context
-        self.musical_context.update(MidiMessage::NoteOn {
-            channel: event.data[0] & 0x0F,
-            note: event.data[1],
-            velocity: event.data[2],
-        });
+        self.musical_context.observe_event(&event);
+        let max_events = self.max_events;
+        self.device_contexts
+            .entry(event.device_name.clone())
+            .or_insert_with(|| MusicalContext::with_capacity(max_events))
+            .observe_event(&event);
         
         // Process with model if available
         if let Some(model) = &mut self.model {
@@ -383,7 +1070,7 @@ impl ModelContext {
         
         // Add pattern-based insights
         for pattern in &self.patterns {
-            if pattern.significance_score > 0.5 {
+            if pattern.significance_score > self.significance_threshold {
                 insights.push(Insight::Pattern(pattern.clone()));
             }
         }
@@ -393,6 +1080,318 @@ impl ModelContext {
             insights.extend(model.generate_insights(&self.musical_context));
         }
         
-        insights
+        dedup_insights(insights)
+    }
+    
+    /// Gets the musical context built from a single device's events
+    pub fn device_context(&self, device_name: &str) -> Option<&MusicalContext> {
+        self.device_contexts.get(device_name)
+    }
+    
+    /// Generates insights from a single device: patterns made only of its
+    /// events, and model insights over its own musical context
+    pub fn generate_insights_for_device(&self, device_name: &str) -> Vec<Insight> {
+        let Some(context) = self.device_context(device_name) else {
+            return Vec::new();
+        };
+        
+        let mut insights: Vec<Insight> = self.patterns.iter()
+            .filter(|pattern| pattern.significance_score > self.significance_threshold)
+            .filter(|pattern| pattern.events.iter().all(|event| event.device_name == device_name))
+            .cloned()
+            .map(Insight::Pattern)
+            .collect();
+        
+        if let Some(model) = &self.model {
+            insights.extend(model.generate_insights(context));
+        }
+        
+        dedup_insights(insights)
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_message_round_trip() {
+        let messages: [&[u8]; 8] = [
+            &[0x93, 60, 100],
+            &[0x85, 61, 64],
+            &[0xA1, 62, 20],
+            &[0xB2, 64, 127],
+            &[0xC4, 5],
+            &[0xDF, 90],
+            &[0xE6, 0x00, 0x40],
+            &[0xE0, 0x7F, 0x7F],
+        ];
+        for bytes in messages {
+            let message = MidiMessage::from(parse_midi_message(bytes).unwrap());
+            assert_ne!(message, MidiMessage::Other);
+            assert_eq!(message.to_bytes(), bytes, "{:?}", message);
+        }
+        
+        // 14-bit pitch bend splits into LSB then MSB
+        assert_eq!(MidiMessage::PitchBend { channel: 0, value: 8192 }.to_bytes(), vec![0xE0, 0x00, 0x40]);
+        assert_eq!(MidiMessage::PitchBend { channel: 0, value: 0x1ABC }.to_bytes(), vec![0xE0, 0x3C, 0x35]);
+        assert!(MidiMessage::Other.to_bytes().is_empty());
+    }
+    
+    #[test]
+    fn test_note_name() {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(61), "C#4");
+        assert_eq!(note_name(54), "F#3");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(note_name(127), "G9");
+        
+        assert_eq!(note_name_with_octave(60, 3), "C3");
+        assert_eq!(note_name_with_octave(61, 3), "C#3");
+        
+        let note = Note { note: 69, velocity: 100, channel: 0, start_time: 0, end_time: None, duration: None };
+        assert_eq!(note.name(), "A4");
+    }
+    
+    #[test]
+    fn test_recent_notes() {
+        let event = |data: Vec<u8>, timestamp: u64| MidiEvent { data, timestamp, device_name: String::new() };
+        let mut context = MusicalContext::with_capacity(2);
+        context.observe_event(&event(vec![0x90, 60, 100], 1_000));
+        context.observe_event(&event(vec![0x91, 60, 90], 1_500));
+        context.observe_event(&event(vec![0x80, 60, 0], 4_000));
+        
+        let notes: Vec<_> = context.recent_notes().iter().map(|n| (n.name(), n.channel, n.end_time, n.duration)).collect();
+        assert_eq!(notes, vec![
+            ("C4".to_string(), 0, Some(4_000), Some(3_000)),
+            ("C4".to_string(), 1, None, None),
+        ]);
+        
+        // A note-on with velocity 0 ends the note, and the oldest notes drop out
+        context.observe_event(&event(vec![0x91, 60, 0], 5_000));
+        context.observe_event(&event(vec![0x90, 64, 80], 6_000));
+        let notes: Vec<_> = context.recent_notes().iter().map(|n| (n.note, n.velocity, n.start_time, n.duration)).collect();
+        assert_eq!(notes, vec![(60, 90, 1_500, Some(3_500)), (64, 80, 6_000, None)]);
+    }
+    
+    fn context_with_notes(notes: &[u8]) -> MusicalContext {
+        let mut context = MusicalContext::new();
+        for &note in notes {
+            context.update(MidiMessage::NoteOn { channel: 0, note, velocity: 100 });
+        }
+        context
+    }
+    
+    #[test]
+    fn test_detect_chord() {
+        assert_eq!(context_with_notes(&[60, 64, 67]).detect_chord().as_deref(), Some("Cmaj"));
+        assert_eq!(context_with_notes(&[60, 63, 67, 70]).detect_chord().as_deref(), Some("Cmin7"));
+        assert_eq!(context_with_notes(&[67, 71, 74, 77]).detect_chord().as_deref(), Some("G7"));
+        
+        // First inversion is named after its root
+        assert_eq!(context_with_notes(&[64, 67, 72]).detect_chord().as_deref(), Some("Cmaj"));
+        // Doubled notes don't matter
+        assert_eq!(context_with_notes(&[57, 60, 64, 69]).detect_chord().as_deref(), Some("Amin"));
+        
+        assert_eq!(context_with_notes(&[60, 64]).detect_chord(), None);
+        assert_eq!(context_with_notes(&[60, 61, 62]).detect_chord(), None);
+        
+        let mut context = context_with_notes(&[60, 64, 67]);
+        context.update(MidiMessage::NoteOff { channel: 0, note: 67, velocity: 0 });
+        assert_eq!(context.detect_chord(), None);
+    }
+    
+    #[test]
+    fn test_active_pitch_class_set() {
+        assert_eq!(MusicalContext::new().active_pitch_class_set(), 0);
+        assert_eq!(context_with_notes(&[60, 64, 67]).active_pitch_class_set(), 0b0000_1001_0001);
+        // Octave doublings share a bit
+        assert_eq!(context_with_notes(&[47, 59, 71, 62]).active_pitch_class_set(), 0b1000_0000_0100);
+    }
+    
+    #[test]
+    fn test_sustain_pedal() {
+        let mut context = MusicalContext::new();
+        context.update(MidiMessage::ControlChange { channel: 0, controller: 64, value: 127 });
+        assert!(context.is_sustained(0));
+        
+        context.update(MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 });
+        context.update(MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 });
+        assert_eq!(context.active_notes()[60], Some(100));
+        
+        // Retriggered while sustained, then released again
+        context.update(MidiMessage::NoteOn { channel: 0, note: 62, velocity: 80 });
+        context.update(MidiMessage::NoteOff { channel: 0, note: 62, velocity: 0 });
+        context.update(MidiMessage::NoteOn { channel: 0, note: 62, velocity: 90 });
+        assert_eq!(context.active_notes()[62], Some(90));
+        
+        // Another channel's pedal doesn't hold notes
+        context.update(MidiMessage::NoteOn { channel: 1, note: 64, velocity: 100 });
+        context.update(MidiMessage::NoteOff { channel: 1, note: 64, velocity: 0 });
+        assert_eq!(context.active_notes()[64], None);
+        
+        context.update(MidiMessage::ControlChange { channel: 0, controller: 64, value: 0 });
+        assert!(!context.is_sustained(0));
+        assert_eq!(context.active_notes()[60], None);
+        // Still held by the key
+        assert_eq!(context.active_notes()[62], Some(90));
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_window_size() {
+        let mut context = MusicalContext::with_capacity(3);
+        for note in 60..65 {
+            context.update(MidiMessage::NoteOn { channel: 0, note, velocity: 100 });
+        }
+        assert_eq!(context.messages().len(), 3);
+        assert!(matches!(context.messages()[0], MidiMessage::NoteOn { note: 62, .. }));
+        
+        // Shrinking evicts immediately
+        context.set_max_messages(1);
+        assert_eq!(context.messages().len(), 1);
+        assert!(matches!(context.messages()[0], MidiMessage::NoteOn { note: 64, .. }));
+        
+        let mut model_context = ModelContext::with_capacity(4);
+        for note in 60..70 {
+            model_context.add_event(MidiEvent { data: vec![0x90, note, 100], timestamp: 0, device_name: String::new() });
+        }
+        assert_eq!(model_context.recent_events.len(), 4);
+        assert_eq!(model_context.recent_events[0].data[1], 66);
+        model_context.set_window_size(2);
+        assert_eq!(model_context.recent_events.len(), 2);
+        assert_eq!(model_context.musical_context.messages().len(), 2);
+    }
+    
+    #[test]
+    fn test_tempo_and_key_inference() {
+        let mut context = ModelContext::new();
+        let event = |data: Vec<u8>, timestamp: u64| MidiEvent { data, timestamp, device_name: String::new() };
+        
+        // 100 BPM clock: 25ms per clock
+        for i in 0..48 {
+            context.add_event(event(vec![0xF8], i * 25_000));
+        }
+        assert!((context.musical_context.tempo() - 100.0).abs() < 0.01);
+        
+        // G major scale and triad
+        for &note in &[67u8, 69, 71, 72, 74, 76, 78, 79, 67, 71, 74, 67, 71, 74] {
+            context.add_event(event(vec![0x90, note, 100], 2_000_000));
+            context.add_event(event(vec![0x80, note, 0], 2_100_000));
+        }
+        assert_eq!(context.musical_context.key_signature(), 7);
+        assert_eq!(context.musical_context.detected_key(), Some((7, Scale::Major)));
+        assert_eq!(context.musical_context.active_notes().iter().flatten().count(), 0);
+    }
+    
+    #[test]
+    fn test_device_contexts() {
+        let mut context = ModelContext::new();
+        let event = |data: Vec<u8>, device: &str| MidiEvent { data, timestamp: 0, device_name: device.to_string() };
+        
+        for &note in &[60u8, 64, 67] {
+            context.add_event(event(vec![0x90, note, 100], "Keyboard A"));
+        }
+        context.add_event(event(vec![0x99, 36, 110], "Pads B"));
+        
+        let held = |device: &str| context.device_context(device).unwrap().active_notes().iter().flatten().count();
+        assert_eq!(held("Keyboard A"), 3);
+        assert_eq!(held("Pads B"), 1);
+        assert_eq!(context.musical_context.active_notes().iter().flatten().count(), 4);
+        assert!(context.device_context("Unknown").is_none());
+        
+        // Patterns are only reported for the device that played them
+        let mut pattern = Pattern::new(context.recent_events.iter().take(3).cloned().collect());
+        pattern.significance_score = 0.9;
+        context.patterns.push(pattern);
+        assert_eq!(context.generate_insights_for_device("Keyboard A").len(), 1);
+        assert!(context.generate_insights_for_device("Pads B").is_empty());
+    }
+    
+    #[test]
+    fn test_note_on_velocity_zero_is_note_off() {
+        let mut context = ModelContext::new();
+        let event = |data: Vec<u8>| MidiEvent { data, timestamp: 0, device_name: "Keyboard".to_string() };
+        context.add_event(event(vec![0x90, 60, 100]));
+        context.add_event(event(vec![0x90, 60, 0]));
+        
+        let musical_context = &context.musical_context;
+        assert!(matches!(musical_context.messages().back(), Some(MidiMessage::NoteOff { note: 60, .. })));
+        assert_eq!(musical_context.active_notes().iter().flatten().count(), 0);
+        assert_eq!(musical_context.pitch_class_counts.iter().sum::<u32>(), 1);
+        assert_eq!(MusicalContext::get_message_type(&event(vec![0x90, 60, 0])), MidiMessageType::NoteOff);
+    }
+    
+    #[test]
+    fn test_minor_key_detection() {
+        let mut context = MusicalContext::new();
+        for &note in &[57u8, 59, 60, 62, 64, 65, 67, 69, 57, 60, 64, 57, 60, 64] {
+            context.update(MidiMessage::NoteOn { channel: 0, note, velocity: 100 });
+            context.update(MidiMessage::NoteOff { channel: 0, note, velocity: 0 });
+        }
+        assert_eq!(context.detected_key(), Some((9, Scale::Minor)));
+        assert_eq!(context.detected_scale().map(|(key, template)| (key, template.name())), Some((9, "Minor")));
+        assert_eq!(MusicalContext::new().detected_key(), None);
+    }
+    
+    #[test]
+    fn test_expression_state() {
+        let mut context = MusicalContext::new();
+        context.update(MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 });
+        context.update(MidiMessage::NoteOn { channel: 0, note: 64, velocity: 90 });
+        
+        context.update(MidiMessage::PolyphonicAftertouch { channel: 0, note: 60, pressure: 70 });
+        assert_eq!((context.active_pressure()[60], context.active_pressure()[64]), (70, 0));
+        // Not held, so not recorded
+        context.update(MidiMessage::PolyphonicAftertouch { channel: 0, note: 67, pressure: 50 });
+        assert_eq!(context.active_pressure()[67], 0);
+        
+        context.update(MidiMessage::ChannelAftertouch { channel: 0, pressure: 40 });
+        assert_eq!((context.active_pressure()[60], context.active_pressure()[64]), (40, 40));
+        
+        context.update(MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 });
+        assert_eq!(context.active_pressure()[60], 0);
+        
+        assert_eq!(context.channel_bend()[3], 8192);
+        context.update(MidiMessage::PitchBend { channel: 3, value: 12000 });
+        assert_eq!(context.channel_bend()[3], 12000);
+    }
+    
+    #[test]
+    fn test_custom_scale_template() {
+        let mut context = MusicalContext::new();
+        // D Dorian, centered on D
+        for &note in &[62u8, 64, 65, 67, 69, 71, 72, 74, 62, 65, 69, 62, 71, 62, 65, 69] {
+            context.update(MidiMessage::NoteOn { channel: 0, note, velocity: 100 });
+            context.update(MidiMessage::NoteOff { channel: 0, note, velocity: 0 });
+        }
+        assert_ne!(context.detected_scale().map(|(_, template)| template.name()), Some("Dorian"));
+        
+        context.add_scale_template(ScaleTemplate::new("Dorian", &[0, 2, 3, 5, 7, 9, 10]).unwrap());
+        assert_eq!(context.scale_templates().len(), 3);
+        let (key, template) = context.detected_scale().unwrap();
+        assert_eq!((key, template.name()), (2, "Dorian"));
+        assert_eq!(context.scale(), Scale::Minor);
+        
+        assert!(ScaleTemplate::new("Broken", &[2, 4, 7]).is_err());
+        assert!(ScaleTemplate::new("Broken", &[0, 12]).is_err());
+        assert!(ScaleTemplate::new("", &[0]).is_err());
+    }
+    
+    #[test]
+    fn test_pattern_hash_is_stable() {
+        let event = |data: Vec<u8>, timestamp: u64| MidiEvent { data, timestamp, device_name: String::new() };
+        let events = vec![
+            event(vec![0x90, 60, 100], 0),
+            event(vec![0x90, 64, 100], 250_000),
+            event(vec![0x90, 67, 100], 500_000),
+        ];
+        
+        assert_eq!(Pattern::new(Vec::new()).id, 0xcbf2_9ce4_8422_2325);
+        assert_eq!(Pattern::new(events.clone()).id, 0x49c9_91dd_0036_3f09);
+        
+        // Same phrase later in time and from another device
+        let shifted: Vec<MidiEvent> = events.iter()
+            .map(|e| MidiEvent { timestamp: e.timestamp + 5_000_000, device_name: "Other".to_string(), ..e.clone() })
+            .collect();
+        assert_eq!(Pattern::new(shifted).id, Pattern::new(events).id);
+    }
+}
@@ -7,11 +7,14 @@
 
 pub mod context;
 pub mod pattern;
+pub mod performance;
 
 use std::collections::HashMap;
-use crate::shared_buffer::MidiEvent;
-use self::context::{ModelContext, MidiModel, Insight, ModelError};
+use std::sync::{Arc, Mutex, PoisonError};
+use crate::shared_buffer::{dedup_consecutive, MidiEvent};
+use self::context::{dedup_insights, ModelContext, MidiModel, MusicalContext, Insight, ModelError, DEFAULT_SIGNIFICANCE_THRESHOLD};
 use self::pattern::PatternRecognitionModel;
+use self::performance::PerformanceAnalysisModel;
 
 /// Available model types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -24,6 +27,9 @@ pub enum ModelType {
     PerformanceAnalysis,
 }
 
+/// Callback invoked with the insights produced after processing an event
+pub type InsightCallback = Arc<dyn Fn(&[Insight]) + Send + Sync>;
+
 /// The main model context protocol that manages models and insights
 pub struct ModelContextProtocol {
     /// Current context
@@ -32,26 +38,98 @@ pub struct ModelContextProtocol {
     models: HashMap<String, Box<dyn MidiModel>>,
     /// Active model
     active_model: Option<String>,
+    /// Callback notified when processing an event yields insights. Locked
+    /// only to register or copy it, never while it runs.
+    insight_callback: Mutex<Option<InsightCallback>>,
+    /// Score an insight must exceed to be reported
+    significance_threshold: f64,
+    /// Edit threshold for fuzzy pattern matching, or `None` when disabled
+    fuzzy_threshold: Option<usize>,
+    /// Insights from the last `generate_insights` call, or `None` once an
+    /// event or configuration change has made them stale
+    insight_cache: Mutex<Option<Vec<Insight>>>,
+    /// Insights last pushed to the callback, so unchanged ones aren't sent again
+    notified_insights: Mutex<Option<Vec<Insight>>>,
 }
 
 impl ModelContextProtocol {
     /// Creates a new model context protocol
     pub fn new() -> Self {
+        Self::with_context(ModelContext::new())
+    }
+    
+    /// Creates a new model context protocol whose context keeps at most
+    /// `window_size` recent events
+    pub fn with_capacity(window_size: usize) -> Self {
+        Self::with_context(ModelContext::with_capacity(window_size))
+    }
+    
+    /// Creates a model context protocol around an existing context
+    fn with_context(context: ModelContext) -> Self {
         Self {
-            context: ModelContext::new(),
+            context,
             models: HashMap::new(),
             active_model: None,
+            insight_callback: Mutex::new(None),
+            significance_threshold: DEFAULT_SIGNIFICANCE_THRESHOLD,
+            fuzzy_threshold: None,
+            insight_cache: Mutex::new(None),
+            notified_insights: Mutex::new(None),
+        }
+    }
+    
+    /// Marks the cached insights stale, so the next `generate_insights` call
+    /// regenerates them
+    fn invalidate_insights(&mut self) {
+        *self.insight_cache.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+    
+    /// Sets the number of recent events kept by the context
+    pub fn set_window_size(&mut self, window_size: usize) {
+        self.invalidate_insights();
+        self.context.set_window_size(window_size);
+    }
+    
+    /// Sets the score an insight must exceed to be reported, for the context
+    /// and every model. Clamped to 0.0 - 1.0.
+    pub fn set_significance_threshold(&mut self, threshold: f64) {
+        self.invalidate_insights();
+        self.significance_threshold = threshold.clamp(0.0, 1.0);
+        self.context.set_significance_threshold(self.significance_threshold);
+        for model in self.models.values_mut() {
+            model.set_significance_threshold(self.significance_threshold);
+        }
+    }
+    
+    /// Gets the score an insight must exceed to be reported
+    pub fn significance_threshold(&self) -> f64 {
+        self.significance_threshold
+    }
+    
+    /// Enables or disables fuzzy pattern matching for every model, allowing
+    /// up to `threshold` interval edits
+    pub fn set_fuzzy_matching(&mut self, enabled: bool, threshold: usize) {
+        self.invalidate_insights();
+        self.fuzzy_threshold = enabled.then_some(threshold);
+        for model in self.models.values_mut() {
+            model.set_fuzzy_matching(enabled, threshold);
         }
     }
     
     /// Registers a model with the protocol
-    pub fn register_model(&mut self, name: &str, model: Box<dyn MidiModel>) {
+    pub fn register_model(&mut self, name: &str, mut model: Box<dyn MidiModel>) {
+        self.invalidate_insights();
+        model.set_significance_threshold(self.significance_threshold);
+        if let Some(threshold) = self.fuzzy_threshold {
+            model.set_fuzzy_matching(true, threshold);
+        }
         self.models.insert(name.to_string(), model);
     }
     
     /// Activates a model by name
     pub fn activate_model(&mut self, name: &str) -> Result<(), ModelError> {
         if self.models.contains_key(name) {
+            self.invalidate_insights();
             self.active_model = Some(name.to_string());
             Ok(())
         } else {
@@ -72,20 +150,207 @@ impl ModelContextProtocol {
                 Err(ModelError::LoadFailed("Style classification not implemented yet".to_string()))
             },
             ModelType::PerformanceAnalysis => {
-                // Not implemented yet
-                Err(ModelError::LoadFailed("Performance analysis not implemented yet".to_string()))
+                let model = PerformanceAnalysisModel::new();
+                self.register_model("performance_analysis", Box::new(model));
+                self.activate_model("performance_analysis")
             },
         }
     }
     
+    /// Saves the patterns learned by the active model to a file
+    pub fn save_patterns(&self, path: &str) -> Result<(), ModelError> {
+        self.active_model.as_ref()
+            .and_then(|name| self.models.get(name))
+            .ok_or(ModelError::ModelNotFound)?
+            .save_patterns(path)
+    }
+    
+    /// Loads previously saved patterns into the active model
+    pub fn load_patterns(&mut self, path: &str) -> Result<(), ModelError> {
+        self.invalidate_insights();
+        self.active_model.as_ref()
+            .and_then(|name| self.models.get_mut(name))
+            .ok_or(ModelError::ModelNotFound)?
+            .load_patterns(path)
+    }
+    
+    /// Sets the callback notified when processing an event yields insights.
+    /// A notification already under way still goes to the previous callback.
+    pub fn set_insight_callback(&self, callback: InsightCallback) {
+        *self.insight_callback.lock().unwrap_or_else(PoisonError::into_inner) = Some(callback);
+        // The new callback hasn't seen any insights yet
+        *self.notified_insights.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+    
+    /// Removes the insight callback
+    pub fn clear_insight_callback(&self) {
+        *self.insight_callback.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+    
     /// Processes a MIDI event
     pub fn process_event(&mut self, event: MidiEvent) {
+        self.observe_event(event);
+        self.notify_insight_callback();
+    }
+    
+    /// Processes a MIDI event without notifying the insight callback (see
+    /// `notify_insight_callback`)
+    pub fn observe_event(&mut self, event: MidiEvent) {
+        self.invalidate_insights();
+        
         // Update context with new event
-        self.context.add_event(event);
+        self.context.add_event(event.clone());
+        
+        // Let the active model observe the event
+        if let Some(model) = self.active_model.as_ref().and_then(|name| self.models.get_mut(name)) {
+            model.process_event(&event, &self.context.musical_context);
+        }
     }
     
-    /// Generates insights from the current context
+    /// Processes a batch of MIDI events in order without notifying the
+    /// insight callback, after collapsing runs of equal events (e.g. a
+    /// controller resending the same value). Returns the number processed.
+    pub fn observe_events(&mut self, mut events: Vec<MidiEvent>) -> usize {
+        dedup_consecutive(&mut events);
+        let count = events.len();
+        for event in events {
+            self.observe_event(event);
+        }
+        count
+    }
+    
+    /// Pushes the current insights, if any, to the registered callback when
+    /// they differ from those it was last sent. The callback is copied out
+    /// first and runs without the lock, so it may register or unregister
+    /// callbacks or read from the context itself.
+    pub fn notify_insight_callback(&self) {
+        let callback = self.insight_callback.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        let Some(callback) = callback else {
+            return;
+        };
+        
+        let insights = self.generate_insights();
+        {
+            let mut notified = self.notified_insights.lock().unwrap_or_else(PoisonError::into_inner);
+            if notified.as_ref() == Some(&insights) {
+                return;
+            }
+            *notified = Some(insights.clone());
+        }
+        if !insights.is_empty() {
+            callback(&insights);
+        }
+    }
+    
+    /// Generates insights from the current context and the active model.
+    /// They are cached until the next event or configuration change, so
+    /// polling without new events doesn't rerun the model.
     pub fn generate_insights(&self) -> Vec<Insight> {
-        self.context.generate_insights()
+        // Held while generating, so concurrent pollers run the model only once
+        let mut cache = self.insight_cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(insights) = cache.as_ref() {
+            return insights.clone();
+        }
+        
+        let mut insights = self.context.generate_insights();
+        
+        if let Some(model) = self.active_model.as_ref().and_then(|name| self.models.get(name)) {
+            insights.extend(model.generate_insights(&self.context.musical_context));
+        }
+        
+        let insights = dedup_insights(insights);
+        *cache = Some(insights.clone());
+        insights
+    }
+    
+    /// Generates insights from a single device's events, or none if the
+    /// device has not sent anything
+    pub fn generate_insights_for_device(&self, device_name: &str) -> Vec<Insight> {
+        let Some(device_context) = self.context.device_context(device_name) else {
+            return Vec::new();
+        };
+        let mut insights = self.context.generate_insights_for_device(device_name);
+        
+        if let Some(model) = self.active_model.as_ref().and_then(|name| self.models.get(name)) {
+            insights.extend(model.generate_insights(device_context));
+        }
+        
+        dedup_insights(insights)
+    }
+}
+
+/// State behind the `*_ml` FFI handle: a musical context plus the models
+/// loaded into it, keyed by the integer IDs handed out to C++
+pub struct MlContext {
+    /// Musical context fed by incoming MIDI
+    pub musical_context: MusicalContext,
+    /// Loaded models by ID
+    models: HashMap<i32, Box<dyn MidiModel>>,
+    /// Next model ID to hand out
+    next_model_id: i32,
+    /// Insights generated per model since the last event, so indexing into
+    /// them over FFI doesn't regenerate them on every call
+    insight_cache: Mutex<HashMap<i32, Vec<Insight>>>,
+}
+
+impl MlContext {
+    /// Creates an ML context with no models loaded
+    pub fn new() -> Self {
+        Self {
+            musical_context: MusicalContext::new(),
+            models: HashMap::new(),
+            next_model_id: 1,
+            insight_cache: Mutex::new(HashMap::new()),
+        }
     }
-} 
\ No newline at end of file
+    
+    /// Adds a model and returns its ID
+    pub fn add_model(&mut self, model: Box<dyn MidiModel>) -> i32 {
+        let id = self.next_model_id;
+        self.next_model_id += 1;
+        self.models.insert(id, model);
+        id
+    }
+    
+    /// Removes a model, returning whether it was loaded
+    pub fn remove_model(&mut self, id: i32) -> bool {
+        self.insight_cache.get_mut().unwrap_or_else(PoisonError::into_inner).remove(&id);
+        self.models.remove(&id).is_some()
+    }
+    
+    /// Gets a loaded model by ID
+    pub fn model(&self, id: i32) -> Option<&dyn MidiModel> {
+        self.models.get(&id).map(|model| model.as_ref())
+    }
+    
+    /// Updates the musical context and feeds the event to every loaded model
+    pub fn process_event(&mut self, event: &MidiEvent) {
+        self.insight_cache.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+        self.musical_context.observe_event(event);
+        for model in self.models.values_mut() {
+            model.process_event(event, &self.musical_context);
+        }
+    }
+    
+    /// Gets one of the insights of a loaded model, or `None` if there is no
+    /// such model or insight. Insights are generated once per event and cached
+    /// until the next one.
+    pub fn insight(&self, id: i32, index: usize) -> Option<Insight> {
+        self.with_insights(id, |insights| insights.get(index).cloned()).flatten()
+    }
+    
+    /// Gets the number of insights a loaded model reports, or `None` if no
+    /// model has the given ID
+    pub fn insight_count(&self, id: i32) -> Option<usize> {
+        self.with_insights(id, |insights| insights.len())
+    }
+    
+    /// Runs `f` on the cached insights of a model, generating them if needed
+    fn with_insights<R>(&self, id: i32, f: impl FnOnce(&[Insight]) -> R) -> Option<R> {
+        let model = self.model(id)?;
+        let mut cache = self.insight_cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let insights = cache.entry(id)
+            .or_insert_with(|| dedup_insights(model.generate_insights(&self.musical_context)));
+        Some(f(insights))
+    }
+}
@@ -7,13 +7,69 @@
  */
 
 use std::collections::{HashMap, VecDeque};
-use crate::ml::context::{MidiModel, MusicalContext, Insight, Pattern, MidiMessageType, MidiMessage};
+use serde::{Deserialize, Serialize};
+use crate::ml::context::{dedup_insights, DEFAULT_SIGNIFICANCE_THRESHOLD, MidiModel, ModelMetadata, ModelError, MusicalContext, Insight, Pattern, MidiMessageType};
 use crate::shared_buffer::MidiEvent;
 
+/// A named note sequence the model should recognize, as stored in a model file
+#[derive(Debug, Clone, Deserialize)]
+pub struct PatternTemplate {
+    /// Name of the pattern (e.g. "C major arpeggio")
+    pub name: String,
+    /// MIDI note numbers that make up the pattern, in order
+    pub notes: Vec<u8>,
+    /// Significance reported when the pattern is matched (0.0 - 1.0)
+    #[serde(default = "PatternTemplate::default_significance")]
+    pub significance: f64,
+}
+
+impl PatternTemplate {
+    fn default_significance() -> f64 {
+        1.0
+    }
+}
+
+/// On-disk model format: a JSON document listing pattern templates
+///
+/// ```json
+/// { "templates": [ { "name": "C major arpeggio", "notes": [60, 64, 67] } ] }
+/// ```
+///
+/// The pattern lengths the model learns and matches can also be set with
+/// `"min_pattern_length"` and `"max_pattern_length"`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PatternModelFile {
+    /// Templates to recognize
+    pub templates: Vec<PatternTemplate>,
+    /// Minimum pattern length, if not the default
+    #[serde(default)]
+    pub min_pattern_length: Option<usize>,
+    /// Maximum pattern length, if not the default
+    #[serde(default)]
+    pub max_pattern_length: Option<usize>,
+}
+
+/// Match state for a single template
+struct TemplateMatch {
+    /// The template being matched
+    template: PatternTemplate,
+    /// Number of times the template has been matched
+    count: u32,
+    /// The note-on events of the most recent match
+    events: Vec<MidiEvent>,
+}
+
+/// Trie edge key: the message bytes and the quantized gap since the previous event
+type TrieKey = (Vec<u8>, u64);
+
+/// Index of a node in `PatternTrie::nodes`
+pub type NodeId = usize;
+
 /// A trie node for pattern matching
+#[derive(Serialize, Deserialize)]
 struct TrieNode {
     /// Children nodes
-    children: HashMap<Vec<u8>, TrieNode>,
+    children: HashMap<TrieKey, NodeId>,
     /// Whether this node represents the end of a pattern
     is_pattern: bool,
     /// Pattern ID if this is the end of a pattern
@@ -34,19 +90,50 @@ impl TrieNode {
     }
 }
 
+/// Pitch-class intervals (0-11) between consecutive note events, ignoring
+/// velocity, channel and any non-note events
+fn pitch_class_intervals(events: &[MidiEvent]) -> Vec<u8> {
+    let notes: Vec<i16> = events.iter()
+        .filter(|event| matches!(event.data.first(), Some(status) if status & 0xE0 == 0x80))
+        .filter_map(|event| event.data.get(1).map(|&note| note as i16))
+        .collect();
+    notes.windows(2)
+        .map(|pair| (pair[1] - pair[0]).rem_euclid(12) as u8)
+        .collect()
+}
+
+/// Levenshtein distance between two sequences
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(x != y);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 /// A trie for efficient pattern matching
+#[derive(Serialize, Deserialize)]
 pub struct PatternTrie {
-    /// Root node
-    root: TrieNode,
-    /// Patterns by ID
-    patterns: HashMap<u64, Pattern>,
+    /// All nodes; the root is `PatternTrie::ROOT`. Nodes are never removed,
+    /// so a `NodeId` stays valid while the trie grows.
+    nodes: Vec<TrieNode>,
+    /// Patterns by ID; a bucket holds more than one pattern only on a hash collision
+    patterns: HashMap<u64, Vec<Pattern>>,
 }
 
 impl PatternTrie {
+    /// The root node, where every match starts
+    pub const ROOT: NodeId = 0;
+    
     /// Creates a new pattern trie
     pub fn new() -> Self {
         Self {
-            root: TrieNode::new(),
+            nodes: vec![TrieNode::new()],
             patterns: HashMap::new(),
         }
     }
@@ -61,9 +148,10 @@ impl PatternTrie {
         let pattern = Pattern::new(events.to_vec());
         let pattern_id = pattern.id;
         
-        // Add to patterns map
-        let existing = self.patterns.get_mut(&pattern_id);
-        if let Some(existing) = existing {
+        // Count another occurrence if the pattern is known; equal hashes alone
+        // are not trusted
+        let bucket = self.patterns.entry(pattern_id).or_default();
+        if let Some(existing) = bucket.iter_mut().find(|existing| existing.matches(events)) {
             existing.occurrence_count += 1;
             // Update significance score based on occurrence count
             existing.significance_score = (existing.occurrence_count as f64).min(10.0) / 10.0;
@@ -71,38 +159,110 @@ impl PatternTrie {
         }
         
         // Add new pattern
-        self.patterns.insert(pattern_id, pattern);
+        bucket.push(pattern);
         
         // Add to trie
-        let mut current = &mut self.root;
-        for event in events {
-            let key = event.data.clone();
-            current = current.children.entry(key).or_insert_with(TrieNode::new);
+        let mut current = Self::ROOT;
+        for key in Self::keys(events) {
+            current = match self.nodes[current].children.get(&key) {
+                Some(&next) => next,
+                None => {
+                    let next = self.nodes.len();
+                    self.nodes.push(TrieNode::new());
+                    self.nodes[current].children.insert(key, next);
+                    next
+                }
+            };
         }
         
+        let current = &mut self.nodes[current];
         current.is_pattern = true;
         current.pattern_id = Some(pattern_id);
         current.count += 1;
     }
     
+    /// Saves the trie and its patterns to a file (bincode)
+    pub fn save(&self, path: &str) -> Result<(), ModelError> {
+        let bytes = bincode::serialize(self)
+            .map_err(|e| ModelError::SaveFailed(format!("{}: {}", path, e)))?;
+        std::fs::write(path, bytes)
+            .map_err(|e| ModelError::SaveFailed(format!("{}: {}", path, e)))
+    }
+    
+    /// Loads a trie previously written by `save`
+    pub fn load(path: &str) -> Result<Self, ModelError> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| ModelError::LoadFailed(format!("{}: {}", path, e)))?;
+        let trie: Self = bincode::deserialize(&bytes)
+            .map_err(|e| ModelError::LoadFailed(format!("{}: {}", path, e)))?;
+        trie.validate()
+            .map_err(|e| ModelError::LoadFailed(format!("{}: {}", path, e)))?;
+        Ok(trie)
+    }
+    
+    /// Checks that a deserialized trie is consistent: every edge leads to an
+    /// existing node, and every stored pattern is found by matching its own
+    /// events, so a damaged file can't make later lookups panic or miss
+    fn validate(&self) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return Err("the trie has no root node".to_string());
+        }
+        let node_count = self.nodes.len();
+        if self.nodes.iter().flat_map(|node| node.children.values()).any(|&child| child >= node_count) {
+            return Err("the trie links to a missing node".to_string());
+        }
+        for pattern in self.patterns.values().flatten() {
+            if !self.find_patterns(&pattern.events).iter().any(|found| found.id == pattern.id) {
+                return Err(format!("pattern {} is not reachable in the trie", pattern.id));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Trie keys for a sequence of events
+    fn keys(events: &[MidiEvent]) -> impl Iterator<Item = TrieKey> + '_ {
+        events.iter()
+            .zip(Pattern::rhythm(events))
+            .map(|(event, gap)| (event.data.clone(), gap))
+    }
+    
+    /// Follows the edge for `key` out of `node`, if there is one
+    pub fn step(&self, node: NodeId, key: &TrieKey) -> Option<NodeId> {
+        self.nodes[node].children.get(key).copied()
+    }
+    
+    /// Gets the pattern that ends at `node`, if `matched` (the events that led
+    /// there) really is that pattern
+    pub fn pattern_at(&self, node: NodeId, matched: &[MidiEvent]) -> Option<&Pattern> {
+        let node = &self.nodes[node];
+        if !node.is_pattern {
+            return None;
+        }
+        self.patterns.get(&node.pattern_id?)?
+            .iter()
+            .find(|pattern| pattern.matches(matched))
+    }
+    
     /// Finds patterns in a sequence of events
+    ///
+    /// This tries every start position, so it is O(n * L) for n events and
+    /// patterns of up to L events. It suits batches such as a recorded take;
+    /// `PatternRecognitionModel` matches live input incrementally instead.
     pub fn find_patterns(&self, events: &[MidiEvent]) -> Vec<Pattern> {
         let mut result = Vec::new();
         
         // Try all possible subsequences
         for start in 0..events.len() {
-            let mut current = &self.root;
-            for i in start..events.len() {
-                let key = &events[i].data;
-                if let Some(next) = current.children.get(key) {
-                    current = next;
-                    if current.is_pattern && current.pattern_id.is_some() {
-                        if let Some(pattern) = self.patterns.get(&current.pattern_id.unwrap()) {
-                            result.push(pattern.clone());
-                        }
-                    }
-                } else {
+            let candidate = &events[start..];
+            let mut current = Self::ROOT;
+            for (length, key) in Self::keys(candidate).enumerate() {
+                let Some(next) = self.step(current, &key) else {
                     break;
+                };
+                current = next;
+                
+                if let Some(pattern) = self.pattern_at(current, &candidate[..=length]) {
+                    result.push(pattern.clone());
                 }
             }
         }
@@ -111,20 +271,67 @@ impl PatternTrie {
     }
 }
 
+impl PatternTrie {
+    /// Finds patterns whose melodic shape appears in a sequence of events
+    ///
+    /// Each stored pattern is compared against every window of the same
+    /// length by the pitch-class intervals between its notes, so transposed
+    /// motifs match and velocity and channel are ignored. A window matches if
+    /// the interval sequences are within `max_distance` edits.
+    pub fn find_patterns_fuzzy(&self, events: &[MidiEvent], max_distance: usize) -> Vec<Pattern> {
+        let mut result = Vec::new();
+        
+        for pattern in self.patterns.values().flatten() {
+            let length = pattern.events.len();
+            if length < 2 || length > events.len() {
+                continue;
+            }
+            
+            let intervals = pitch_class_intervals(&pattern.events);
+            if events.windows(length)
+                .any(|window| edit_distance(&intervals, &pitch_class_intervals(window)) <= max_distance)
+            {
+                result.push(pattern.clone());
+            }
+        }
+        
+        result
+    }
+}
+
+/// A learned pattern found in the live input
+struct DetectedPattern {
+    /// Sequence number of the note-on the match starts at
+    start: u64,
+    /// The pattern as it was when matched
+    pattern: Pattern,
+}
+
 /// A pattern recognition model
 pub struct PatternRecognitionModel {
     /// The minimum length of a pattern
     min_pattern_length: usize,
     /// The maximum length of a pattern
     max_pattern_length: usize,
-    /// The detected patterns
-    patterns: Vec<Pattern>,
-    /// The recent notes
-    recent_notes: VecDeque<MidiMessage>,
+    /// Learned patterns matched within the recent note-ons
+    patterns: Vec<DetectedPattern>,
     /// Current sequence of events
     current_sequence: VecDeque<MidiEvent>,
     /// Pattern trie
     trie: PatternTrie,
+    /// Recent note-on events, used for template and pattern matching
+    recent_note_ons: VecDeque<MidiEvent>,
+    /// Number of note-ons seen, i.e. the sequence number of the newest one
+    note_on_count: u64,
+    /// Partial matches ending at the newest note-on: the trie node reached
+    /// and the number of note-ons matched
+    cursors: Vec<(NodeId, usize)>,
+    /// Templates loaded from a model file
+    templates: Vec<TemplateMatch>,
+    /// Maximum interval edit distance for fuzzy matching, or None for exact matching
+    fuzzy_threshold: Option<usize>,
+    /// Score a pattern must exceed to be reported as an insight
+    significance_threshold: f64,
 }
 
 impl PatternRecognitionModel {
@@ -134,46 +341,139 @@ impl PatternRecognitionModel {
             min_pattern_length: 3,
             max_pattern_length: 16,
             patterns: Vec::new(),
-            recent_notes: VecDeque::new(),
             current_sequence: VecDeque::new(),
             trie: PatternTrie::new(),
+            recent_note_ons: VecDeque::new(),
+            note_on_count: 0,
+            cursors: Vec::new(),
+            templates: Vec::new(),
+            fuzzy_threshold: None,
+            significance_threshold: DEFAULT_SIGNIFICANCE_THRESHOLD,
         }
     }
     
-    /// Updates the model with a new musical context
-    pub fn update(&mut self, context: &MusicalContext) {
-        // Get the recent messages
-        let messages = context.messages();
+    /// Creates a pattern recognition model from the templates in a model file
+    pub fn from_file(path: &str) -> Result<Self, ModelError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| ModelError::LoadFailed(format!("{}: {}", path, e)))?;
+        let file: PatternModelFile = serde_json::from_str(&contents)
+            .map_err(|e| ModelError::LoadFailed(format!("{}: {}", path, e)))?;
         
-        // Update the recent notes
-        self.recent_notes.clear();
-        for message in messages {
-            match message {
-                MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } => {
-                    self.recent_notes.push_back(message.clone());
-                }
-                _ => {}
+        let mut model = Self::new();
+        if let Some(length) = file.min_pattern_length {
+            model.set_min_pattern_length(length);
+        }
+        if let Some(length) = file.max_pattern_length {
+            model.set_max_pattern_length(length);
+        }
+        if model.min_pattern_length > model.max_pattern_length {
+            return Err(ModelError::LoadFailed(format!(
+                "{}: min_pattern_length {} exceeds max_pattern_length {}",
+                path, model.min_pattern_length, model.max_pattern_length,
+            )));
+        }
+        for template in file.templates {
+            model.add_template(template);
+        }
+        Ok(model)
+    }
+    
+    /// Adds a template to recognize
+    pub fn add_template(&mut self, template: PatternTemplate) {
+        self.templates.push(TemplateMatch {
+            template,
+            count: 0,
+            events: Vec::new(),
+        });
+    }
+    
+    /// Checks whether the most recent note-ons complete any template
+    fn match_templates(&mut self) {
+        for entry in &mut self.templates {
+            let notes = &entry.template.notes;
+            if notes.is_empty() || notes.len() > self.recent_note_ons.len() {
+                continue;
+            }
+            
+            let start = self.recent_note_ons.len() - notes.len();
+            let matched = self.recent_note_ons.iter()
+                .skip(start)
+                .zip(notes)
+                .all(|(event, &note)| event.data.get(1) == Some(&note));
+            
+            if matched {
+                entry.count += 1;
+                entry.events = self.recent_note_ons.iter().skip(start).cloned().collect();
             }
         }
+    }
+    
+    /// Records the last `min_pattern_length` note-ons as an occurrence of a pattern
+    fn learn_recent_note_ons(&mut self) {
+        if self.min_pattern_length == 0 || self.recent_note_ons.len() < self.min_pattern_length {
+            return;
+        }
         
-        // Detect patterns
-        self.detect_patterns();
+        let start = self.recent_note_ons.len() - self.min_pattern_length;
+        let events: Vec<_> = self.recent_note_ons.iter().skip(start).cloned().collect();
+        self.trie.add_sequence(&events);
+    }
+    
+    /// Adds a note-on to the window of recent note-ons
+    fn push_note_on(&mut self, event: MidiEvent) {
+        self.recent_note_ons.push_back(event);
+        while self.recent_note_ons.len() > self.max_pattern_length.max(1) {
+            self.recent_note_ons.pop_front();
+        }
+        self.note_on_count += 1;
     }
     
-    /// Detects patterns in the recent notes
+    /// Detects the learned patterns completed by the newest note-on
+    ///
+    /// Rather than re-matching the whole window, this keeps the trie nodes
+    /// reached by every partial match and extends each of them by the new
+    /// note-on. A partial match is a suffix of the last `max_pattern_length`
+    /// note-ons, so there are at most that many and the work per event is
+    /// O(max_pattern_length), plus confirming any completed matches.
     fn detect_patterns(&mut self) {
-        // Clear the patterns
-        self.patterns.clear();
+        let window: &[MidiEvent] = self.recent_note_ons.make_contiguous();
+        let Some(event) = window.last() else {
+            return;
+        };
+        let gap = match window {
+            [.., previous, _] => Pattern::quantized_gap(previous, event),
+            _ => 0,
+        };
         
-        // For now, just add a dummy pattern
-        let dummy_events = Vec::new();
-        let dummy_pattern = Pattern::new(dummy_events);
-        self.patterns.push(dummy_pattern);
+        // Extend the partial matches, and start a new one at this note-on
+        let trie = &self.trie;
+        let extended_key = (event.data.clone(), gap);
+        let mut cursors: Vec<_> = self.cursors.iter()
+            .filter(|&&(_, length)| length < window.len())
+            .filter_map(|&(node, length)| trie.step(node, &extended_key).map(|next| (next, length + 1)))
+            .collect();
+        if let Some(next) = trie.step(PatternTrie::ROOT, &(event.data.clone(), 0)) {
+            cursors.push((next, 1));
+        }
+        
+        for &(node, length) in &cursors {
+            if let Some(pattern) = trie.pattern_at(node, &window[window.len() - length..]) {
+                self.patterns.push(DetectedPattern {
+                    start: self.note_on_count + 1 - length as u64,
+                    pattern: pattern.clone(),
+                });
+            }
+        }
+        self.cursors = cursors;
+        
+        // Forget matches that have left the window
+        let window_start = self.note_on_count + 1 - window.len() as u64;
+        self.patterns.retain(|detected| detected.start >= window_start);
     }
     
-    /// Gets the detected patterns
-    pub fn patterns(&self) -> &[Pattern] {
-        &self.patterns
+    /// Gets the learned patterns matched within the recent note-ons
+    pub fn patterns(&self) -> impl Iterator<Item = &Pattern> {
+        self.patterns.iter().map(|detected| &detected.pattern)
     }
     
     /// Sets the minimum pattern length
@@ -201,8 +501,13 @@ impl MidiModel for PatternRecognitionModel {
                     self.current_sequence.pop_front();
                 }
                 
-                // Detect patterns
-                self.detect_patterns();
+                // Track note-ons for template matching
+                if message_type == MidiMessageType::NoteOn {
+                    self.push_note_on(event.clone());
+                    self.match_templates();
+                    self.learn_recent_note_ons();
+                    self.detect_patterns();
+                }
             },
             _ => {
                 // Ignore other message types for pattern recognition
@@ -213,17 +518,262 @@ impl MidiModel for PatternRecognitionModel {
     fn generate_insights(&self, _context: &MusicalContext) -> Vec<Insight> {
         let mut insights = Vec::new();
         
-        // Find patterns in current sequence
-        let events: Vec<_> = self.current_sequence.iter().cloned().collect();
-        let patterns = self.trie.find_patterns(&events);
+        // Learned patterns in the recent note-ons
+        let patterns = match self.fuzzy_threshold {
+            Some(threshold) => {
+                let events: Vec<_> = self.recent_note_ons.iter().cloned().collect();
+                self.trie.find_patterns_fuzzy(&events, threshold)
+            },
+            None => self.patterns().cloned().collect(),
+        };
         
         // Add significant patterns as insights
         for pattern in patterns {
-            if pattern.significance_score > 0.5 {
+            if pattern.significance_score > self.significance_threshold {
+                insights.push(Insight::Pattern(pattern));
+            }
+        }
+        
+        // Add matched templates as insights
+        for entry in &self.templates {
+            if entry.count > 0 && entry.template.significance > self.significance_threshold {
+                let mut pattern = Pattern::new(entry.events.clone());
+                pattern.occurrence_count = entry.count;
+                pattern.significance_score = entry.template.significance;
+                pattern.pattern_type = entry.template.name.clone();
                 insights.push(Insight::Pattern(pattern));
             }
         }
         
-        insights
+        dedup_insights(insights)
+    }
+    
+    fn metadata(&self) -> ModelMetadata {
+        ModelMetadata {
+            name: "Pattern Recognition".to_string(),
+            description: "Detects recurring note sequences in incoming MIDI".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            author: "MidiPortal".to_string(),
+            license: "MIT".to_string(),
+        }
+    }
+    
+    fn set_significance_threshold(&mut self, threshold: f64) {
+        self.significance_threshold = threshold.clamp(0.0, 1.0);
+    }
+    
+    /// With fuzzy matching, a pattern matches if the pitch-class intervals of
+    /// the played notes are within `threshold` edits of the pattern's, so
+    /// transpositions and small variations are recognized.
+    fn set_fuzzy_matching(&mut self, enabled: bool, threshold: usize) {
+        self.fuzzy_threshold = enabled.then_some(threshold);
+    }
+    
+    fn save_patterns(&self, path: &str) -> Result<(), ModelError> {
+        self.trie.save(path)
+    }
+    
+    fn load_patterns(&mut self, path: &str) -> Result<(), ModelError> {
+        self.trie = PatternTrie::load(path)?;
+        // Partial matches point into the old trie
+        self.cursors.clear();
+        self.patterns.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn note_on(note: u8, timestamp: u64) -> MidiEvent {
+        MidiEvent {
+            data: vec![0x90, note, 100],
+            timestamp,
+            device_name: String::new(),
+        }
+    }
+    
+    #[test]
+    fn test_same_notes_different_rhythm() {
+        let mut trie = PatternTrie::new();
+        let straight = [note_on(60, 0), note_on(64, 250_000), note_on(67, 500_000)];
+        let dotted = [note_on(60, 0), note_on(64, 375_000), note_on(67, 500_000)];
+        // Same rhythm, later in time and with a little jitter
+        let straight_again = [note_on(60, 1_000_000), note_on(64, 1_252_000), note_on(67, 1_499_000)];
+        
+        trie.add_sequence(&straight);
+        trie.add_sequence(&dotted);
+        trie.add_sequence(&straight_again);
+        assert_eq!(trie.patterns.values().map(Vec::len).sum::<usize>(), 2);
+        
+        let found = trie.find_patterns(&straight_again);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].occurrence_count, 2);
+        
+        let found = trie.find_patterns(&dotted);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].occurrence_count, 1);
+    }
+    
+    #[test]
+    fn test_save_and_load_trie() {
+        let mut trie = PatternTrie::new();
+        let motif = [note_on(60, 0), note_on(64, 250_000), note_on(67, 500_000)];
+        trie.add_sequence(&motif);
+        trie.add_sequence(&motif);
+        
+        let path = std::env::temp_dir().join(format!("midi_engine_trie_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        trie.save(path).unwrap();
+        let loaded = PatternTrie::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        
+        let found = loaded.find_patterns(&motif);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, Pattern::new(motif.to_vec()).id);
+        assert_eq!(found[0].occurrence_count, 2);
+        
+        assert!(matches!(PatternTrie::load("/nonexistent/patterns.bin"), Err(ModelError::LoadFailed(_))));
+        
+        // A pattern the trie can't reach is rejected rather than loaded
+        let mut damaged = PatternTrie::new();
+        let pattern = Pattern::new(motif.to_vec());
+        damaged.patterns.insert(pattern.id, vec![pattern]);
+        damaged.save(path).unwrap();
+        let loaded = PatternTrie::load(path);
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(loaded, Err(ModelError::LoadFailed(_))));
+    }
+    
+    #[test]
+    fn test_pattern_lengths_from_file() {
+        let path = std::env::temp_dir().join(format!("midi_engine_lengths_{}.model", std::process::id()));
+        let path = path.to_str().unwrap();
+        
+        std::fs::write(path, r#"{ "templates": [], "min_pattern_length": 2, "max_pattern_length": 4 }"#).unwrap();
+        let model = PatternRecognitionModel::from_file(path).unwrap();
+        assert_eq!((model.min_pattern_length, model.max_pattern_length), (2, 4));
+        
+        std::fs::write(path, r#"{ "templates": [] }"#).unwrap();
+        let model = PatternRecognitionModel::from_file(path).unwrap();
+        assert_eq!((model.min_pattern_length, model.max_pattern_length), (3, 16));
+        
+        std::fs::write(path, r#"{ "templates": [], "min_pattern_length": 5, "max_pattern_length": 4 }"#).unwrap();
+        let model = PatternRecognitionModel::from_file(path);
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(model, Err(ModelError::LoadFailed(_))));
+    }
+
+    #[test]
+    fn test_fuzzy_matching_transposed_motif() {
+        let c_major = [note_on(60, 0), note_on(64, 250_000), note_on(67, 500_000)];
+        let d_major = [
+            MidiEvent { data: vec![0x91, 62, 40], timestamp: 2_000_000, device_name: String::new() },
+            note_on(66, 2_250_000),
+            note_on(69, 2_500_000),
+        ];
+        
+        let mut trie = PatternTrie::new();
+        trie.add_sequence(&c_major);
+        assert!(trie.find_patterns(&d_major).is_empty());
+        assert_eq!(trie.find_patterns_fuzzy(&d_major, 0).len(), 1);
+        
+        // An augmented triad differs by one interval
+        let d_augmented = [note_on(62, 0), note_on(66, 250_000), note_on(70, 500_000)];
+        assert!(trie.find_patterns_fuzzy(&d_augmented, 0).is_empty());
+        assert_eq!(trie.find_patterns_fuzzy(&d_augmented, 1).len(), 1);
+        
+        // Through the model
+        let mut model = PatternRecognitionModel::new();
+        for _ in 0..6 {
+            model.trie.add_sequence(&c_major);
+        }
+        let context = MusicalContext::new();
+        for event in &d_major {
+            model.process_event(event, &context);
+        }
+        let pattern_count = |model: &PatternRecognitionModel| {
+            model.generate_insights(&context).iter().filter(|insight| matches!(insight, Insight::Pattern(_))).count()
+        };
+        assert_eq!(pattern_count(&model), 0);
+        model.set_fuzzy_matching(true, 0);
+        assert_eq!(pattern_count(&model), 1);
+        model.set_fuzzy_matching(false, 0);
+        assert_eq!(pattern_count(&model), 0);
+    }
+    
+    #[test]
+    fn test_incremental_detection_matches_batch() {
+        let mut model = PatternRecognitionModel::new();
+        model.trie.add_sequence(&[note_on(60, 0), note_on(62, 100_000), note_on(64, 200_000)]);
+        model.trie.add_sequence(&[note_on(62, 0), note_on(64, 100_000)]);
+        model.trie.add_sequence(&[note_on(64, 0)]);
+        model.trie.add_sequence(&[note_on(64, 0), note_on(60, 300_000)]);
+        
+        let notes = [60, 62, 64, 60, 62, 64, 64, 60, 67, 60, 62, 64];
+        let gaps = [0, 100_000, 100_000, 300_000, 100_000, 100_000, 200_000, 300_000, 100_000, 100_000, 100_000, 100_000];
+        let mut timestamp = 0;
+        let events: Vec<_> = notes.iter().zip(gaps).map(|(&note, gap)| {
+            timestamp += gap;
+            note_on(note, timestamp)
+        }).collect();
+        
+        let mut incremental = Vec::new();
+        for event in &events {
+            model.push_note_on(event.clone());
+            model.detect_patterns();
+            incremental.extend(model.patterns.iter()
+                .filter(|detected| detected.start + detected.pattern.events.len() as u64 == model.note_on_count + 1)
+                .map(|detected| detected.pattern.id));
+        }
+        let mut batch: Vec<_> = model.trie.find_patterns(&events).iter().map(|pattern| pattern.id).collect();
+        
+        incremental.sort_unstable();
+        batch.sort_unstable();
+        assert_eq!(batch.len(), 12);
+        assert_eq!(incremental, batch);
+    }
+    
+    #[test]
+    fn test_streaming_detection_bounded_work() {
+        let mut model = PatternRecognitionModel::new();
+        let context = MusicalContext::new();
+        let riff = [60, 63, 65, 67, 70, 67, 65, 63];
+        
+        // The work per event is bounded by the window, however long the stream
+        for i in 0..10_000u64 {
+            model.process_event(&note_on(riff[i as usize % riff.len()], i * 125_000), &context);
+            assert!(model.cursors.len() <= model.max_pattern_length);
+            assert!(model.patterns.len() <= model.max_pattern_length * model.max_pattern_length);
+        }
+        
+        assert!(model.patterns().any(|pattern| pattern.occurrence_count > 1000));
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_pattern_matched_twice_reported_once() {
+        let mut model = PatternRecognitionModel::new();
+        for _ in 0..6 {
+            model.trie.add_sequence(&[note_on(60, 0), note_on(64, 250_000)]);
+        }
+        
+        let id = Pattern::new(vec![note_on(60, 0), note_on(64, 250_000)]).id;
+        
+        let context = MusicalContext::new();
+        for (i, note) in [60, 64, 60, 64].into_iter().enumerate() {
+            model.process_event(&note_on(note, i as u64 * 250_000), &context);
+        }
+        assert_eq!(model.patterns().filter(|pattern| pattern.id == id).count(), 2);
+        
+        let insights = model.generate_insights(&context);
+        let patterns: Vec<_> = insights.iter()
+            .filter_map(|insight| match insight {
+                Insight::Pattern(pattern) => Some(pattern),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(patterns.len(), 1);
+        assert_eq!(patterns[0].id, id);
+    }
+}
@@ -0,0 +1,171 @@
+//! Defines the performance analysis model.
+//!
+//! This file defines the performance analysis model, which follows the
+//! chord progression being played and rates how functional it is.
+
+use std::collections::{HashSet, VecDeque};
+use crate::ml::context::{pitch_class_name, MidiModel, ModelMetadata, MusicalContext, Insight, Scale, DEFAULT_SIGNIFICANCE_THRESHOLD};
+use crate::shared_buffer::MidiEvent;
+
+/// Number of most recent chord changes kept
+pub const MAX_PROGRESSION_LENGTH: usize = 16;
+
+/// Chord moves common in functional harmony, as the roots' semitones above the
+/// tonic of a major key (0 = I, 2 = ii, 4 = iii, 5 = IV, 7 = V, 9 = vi, 11 = vii)
+const FUNCTIONAL_MOVES: [(u8, u8); 21] = [
+    (0, 2), (0, 4), (0, 5), (0, 7), (0, 9),
+    (2, 7), (2, 11),
+    (4, 5), (4, 9),
+    (5, 0), (5, 2), (5, 7), (5, 11),
+    (7, 0), (7, 9),
+    (9, 2), (9, 5), (9, 7),
+    (11, 0), (11, 4), (11, 9),
+];
+
+/// A chord in the observed progression
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Chord {
+    /// Root pitch class (0 = C, 1 = C#, etc.)
+    root: u8,
+    /// Quality suffix, e.g. "maj" or "min7"
+    quality: &'static str,
+}
+
+impl Chord {
+    fn name(&self) -> String {
+        format!("{}{}", pitch_class_name(self.root), self.quality)
+    }
+}
+
+/// Performance analysis model
+pub struct PerformanceAnalysisModel {
+    /// Chords played, oldest first, recorded each time the chord changes
+    progression: VecDeque<Chord>,
+    /// Score an insight must exceed to be reported
+    significance_threshold: f64,
+}
+
+impl PerformanceAnalysisModel {
+    /// Creates a new performance analysis model
+    pub fn new() -> Self {
+        Self {
+            progression: VecDeque::with_capacity(MAX_PROGRESSION_LENGTH),
+            significance_threshold: DEFAULT_SIGNIFICANCE_THRESHOLD,
+        }
+    }
+    
+    /// Fraction (0.0 - 1.0) of chord changes that are common functional moves
+    /// in the given key
+    fn functional_score(&self, key: u8, scale: Scale) -> f64 {
+        // Minor keys are rated against their relative major
+        let tonic = match scale {
+            Scale::Major => key,
+            Scale::Minor => (key + 3) % 12,
+        };
+        let degree = |chord: &Chord| (chord.root + 12 - tonic) % 12;
+        
+        let moves = self.progression.len() - 1;
+        let functional = self.progression.iter()
+            .zip(self.progression.iter().skip(1))
+            .filter(|(from, to)| FUNCTIONAL_MOVES.contains(&(degree(from), degree(to))))
+            .count();
+        functional as f64 / moves as f64
+    }
+}
+
+impl MidiModel for PerformanceAnalysisModel {
+    fn process_event(&mut self, _event: &MidiEvent, context: &MusicalContext) {
+        let Some((root, quality)) = context.detect_chord_root() else {
+            return;
+        };
+        
+        let chord = Chord { root, quality };
+        if self.progression.back() != Some(&chord) {
+            self.progression.push_back(chord);
+            if self.progression.len() > MAX_PROGRESSION_LENGTH {
+                self.progression.pop_front();
+            }
+        }
+    }
+    
+    fn generate_insights(&self, context: &MusicalContext) -> Vec<Insight> {
+        let distinct: HashSet<&Chord> = self.progression.iter().collect();
+        if distinct.len() < 2 {
+            return Vec::new();
+        }
+        
+        let score = self.functional_score(context.key_signature(), context.scale());
+        if score <= self.significance_threshold {
+            return Vec::new();
+        }
+        
+        let chords: Vec<String> = self.progression.iter().map(Chord::name).collect();
+        vec![Insight::Performance {
+            description: format!("Chord progression: {}", chords.join(" \u{2192} ")),
+            score,
+            suggestions: Vec::new(),
+        }]
+    }
+    
+    fn metadata(&self) -> ModelMetadata {
+        ModelMetadata {
+            name: "Performance Analysis".to_string(),
+            description: "Follows the chord progression and rates how functional it is".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            author: "MidiPortal".to_string(),
+            license: "MIT".to_string(),
+        }
+    }
+    
+    fn set_significance_threshold(&mut self, threshold: f64) {
+        self.significance_threshold = threshold.clamp(0.0, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn play_chords(model: &mut PerformanceAnalysisModel, context: &mut MusicalContext, chords: &[[u8; 3]]) {
+        for (i, chord) in chords.iter().enumerate() {
+            let timestamp = i as u64 * 500_000;
+            for &note in chord {
+                let event = MidiEvent { data: vec![0x90, note, 100], timestamp, device_name: String::new() };
+                context.observe_event(&event);
+                model.process_event(&event, context);
+            }
+            for &note in chord {
+                let event = MidiEvent { data: vec![0x80, note, 0], timestamp: timestamp + 400_000, device_name: String::new() };
+                context.observe_event(&event);
+                model.process_event(&event, context);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_chord_progression() {
+        let mut model = PerformanceAnalysisModel::new();
+        let mut context = MusicalContext::new();
+        
+        // I - vi - IV - V in C major
+        play_chords(&mut model, &mut context, &[[60, 64, 67], [57, 60, 64], [53, 57, 60], [55, 59, 62]]);
+        
+        let insights = model.generate_insights(&context);
+        assert_eq!(insights.len(), 1);
+        match &insights[0] {
+            Insight::Performance { description, score, .. } => {
+                assert_eq!(description, "Chord progression: Cmaj \u{2192} Amin \u{2192} Fmaj \u{2192} Gmaj");
+                assert_eq!(*score, 1.0);
+            },
+            other => panic!("unexpected insight {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_single_chord_has_no_progression() {
+        let mut model = PerformanceAnalysisModel::new();
+        let mut context = MusicalContext::new();
+        play_chords(&mut model, &mut context, &[[60, 64, 67], [64, 67, 72]]);
+        assert!(model.generate_insights(&context).is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+// parser.rs
+//! Decoding of raw MIDI 1.0 messages into a typed form, shared by the engine
+//! and the ML code so every path treats edge cases (e.g. note-on with
+//! velocity 0) the same way.
+
+use crate::error::MidiError;
+
+/// A decoded MIDI 1.0 message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedMessage {
+    /// Note-on with a non-zero velocity.
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    /// Note-off, including note-on with velocity 0.
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    PolyAftertouch { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelAftertouch { channel: u8, pressure: u8 },
+    /// Pitch bend, 0-16383 with 8192 as center.
+    PitchBend { channel: u8, value: u16 },
+    /// System exclusive message, including the 0xF0 and any trailing 0xF7.
+    SysEx(Vec<u8>),
+    /// MTC quarter frame data byte.
+    MtcQuarterFrame(u8),
+    /// Song position in MIDI beats (sixteenth notes).
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    /// End of a SysEx message sent on its own.
+    EndOfExclusive,
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+/// Length in bytes of a message with the given status byte, or None for
+/// variable-length (SysEx) and undefined statuses.
+pub fn message_len(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => Some(3),
+        0xC0..=0xDF | 0xF1 | 0xF3 => Some(2),
+        0xF6..=0xF8 | 0xFA..=0xFC | 0xFE | 0xFF => Some(1),
+        _ => None,
+    }
+}
+
+/// Maps a 14-bit pitch bend value to -1.0 (0) through 0.0 (8192) to 1.0
+/// (16383). The up side has one step fewer than the down side, so each side
+/// is scaled separately to make full bends symmetric.
+pub fn normalize_pitch_bend(value: u16) -> f64 {
+    let offset = value.min(16383) as f64 - 8192.0;
+    if offset >= 0.0 {
+        offset / 8191.0
+    } else {
+        offset / 8192.0
+    }
+}
+
+/// Decodes a single MIDI 1.0 message.
+///
+/// Fails on an empty buffer, a buffer that starts with a data byte (running
+/// status is not tracked here), an undefined status byte, or a message that is
+/// shorter than its status byte requires. Extra trailing bytes are ignored.
+pub fn parse_midi_message(data: &[u8]) -> Result<ParsedMessage, MidiError> {
+    let status = *data.first().ok_or_else(|| MidiError::InvalidData("empty message".to_string()))?;
+    if status < 0x80 {
+        return Err(MidiError::InvalidData(format!("expected a status byte, found {:#04x}", status)));
+    }
+
+    let data_byte = |index: usize| -> Result<u8, MidiError> {
+        data.get(index)
+            .map(|byte| byte & 0x7F)
+            .ok_or_else(|| MidiError::InvalidData(format!(
+                "truncated message: status {:#04x} needs {} bytes, got {}",
+                status,
+                message_len(status).unwrap_or(index + 1),
+                data.len()
+            )))
+    };
+    let channel = status & 0x0F;
+
+    let message = match status & 0xF0 {
+        0x80 => ParsedMessage::NoteOff { channel, note: data_byte(1)?, velocity: data_byte(2)? },
+        0x90 => {
+            let (note, velocity) = (data_byte(1)?, data_byte(2)?);
+            if velocity == 0 {
+                ParsedMessage::NoteOff { channel, note, velocity }
+            } else {
+                ParsedMessage::NoteOn { channel, note, velocity }
+            }
+        },
+        0xA0 => ParsedMessage::PolyAftertouch { channel, note: data_byte(1)?, pressure: data_byte(2)? },
+        0xB0 => ParsedMessage::ControlChange { channel, controller: data_byte(1)?, value: data_byte(2)? },
+        0xC0 => ParsedMessage::ProgramChange { channel, program: data_byte(1)? },
+        0xD0 => ParsedMessage::ChannelAftertouch { channel, pressure: data_byte(1)? },
+        0xE0 => ParsedMessage::PitchBend {
+            channel,
+            value: ((data_byte(2)? as u16) << 7) | data_byte(1)? as u16,
+        },
+        _ => match status {
+            0xF0 => ParsedMessage::SysEx(data.to_vec()),
+            0xF1 => ParsedMessage::MtcQuarterFrame(data_byte(1)?),
+            0xF2 => ParsedMessage::SongPosition(((data_byte(2)? as u16) << 7) | data_byte(1)? as u16),
+            0xF3 => ParsedMessage::SongSelect(data_byte(1)?),
+            0xF6 => ParsedMessage::TuneRequest,
+            0xF7 => ParsedMessage::EndOfExclusive,
+            0xF8 => ParsedMessage::Clock,
+            0xFA => ParsedMessage::Start,
+            0xFB => ParsedMessage::Continue,
+            0xFC => ParsedMessage::Stop,
+            0xFE => ParsedMessage::ActiveSensing,
+            0xFF => ParsedMessage::Reset,
+            _ => return Err(MidiError::InvalidData(format!("undefined status byte {:#04x}", status))),
+        },
+    };
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_messages() {
+        assert_eq!(parse_midi_message(&[0x93, 60, 100]).unwrap(), ParsedMessage::NoteOn { channel: 3, note: 60, velocity: 100 });
+        assert_eq!(parse_midi_message(&[0x93, 60, 0]).unwrap(), ParsedMessage::NoteOff { channel: 3, note: 60, velocity: 0 });
+        assert_eq!(parse_midi_message(&[0x80, 60, 64]).unwrap(), ParsedMessage::NoteOff { channel: 0, note: 60, velocity: 64 });
+        assert_eq!(parse_midi_message(&[0xA1, 60, 20]).unwrap(), ParsedMessage::PolyAftertouch { channel: 1, note: 60, pressure: 20 });
+        assert_eq!(parse_midi_message(&[0xB2, 64, 127]).unwrap(), ParsedMessage::ControlChange { channel: 2, controller: 64, value: 127 });
+        assert_eq!(parse_midi_message(&[0xC4, 5]).unwrap(), ParsedMessage::ProgramChange { channel: 4, program: 5 });
+        assert_eq!(parse_midi_message(&[0xD5, 90]).unwrap(), ParsedMessage::ChannelAftertouch { channel: 5, pressure: 90 });
+        assert_eq!(parse_midi_message(&[0xE6, 0x00, 0x40]).unwrap(), ParsedMessage::PitchBend { channel: 6, value: 8192 });
+    }
+
+    #[test]
+    fn test_system_messages() {
+        assert_eq!(parse_midi_message(&[0xF0, 0x7E, 0x01, 0xF7]).unwrap(), ParsedMessage::SysEx(vec![0xF0, 0x7E, 0x01, 0xF7]));
+        assert_eq!(parse_midi_message(&[0xF1, 0x23]).unwrap(), ParsedMessage::MtcQuarterFrame(0x23));
+        assert_eq!(parse_midi_message(&[0xF2, 0x01, 0x02]).unwrap(), ParsedMessage::SongPosition(0x101));
+        assert_eq!(parse_midi_message(&[0xF3, 7]).unwrap(), ParsedMessage::SongSelect(7));
+        assert_eq!(parse_midi_message(&[0xF6]).unwrap(), ParsedMessage::TuneRequest);
+        assert_eq!(parse_midi_message(&[0xF7]).unwrap(), ParsedMessage::EndOfExclusive);
+        assert_eq!(parse_midi_message(&[0xF8]).unwrap(), ParsedMessage::Clock);
+        assert_eq!(parse_midi_message(&[0xFA]).unwrap(), ParsedMessage::Start);
+        assert_eq!(parse_midi_message(&[0xFB]).unwrap(), ParsedMessage::Continue);
+        assert_eq!(parse_midi_message(&[0xFC]).unwrap(), ParsedMessage::Stop);
+        assert_eq!(parse_midi_message(&[0xFE]).unwrap(), ParsedMessage::ActiveSensing);
+        assert_eq!(parse_midi_message(&[0xFF]).unwrap(), ParsedMessage::Reset);
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        assert!(parse_midi_message(&[]).is_err());
+        assert!(parse_midi_message(&[0x3C, 0x40]).is_err());
+        assert!(parse_midi_message(&[0xF4]).is_err());
+
+        // Truncated
+        assert!(parse_midi_message(&[0x90]).is_err());
+        assert!(parse_midi_message(&[0x90, 60]).is_err());
+        assert!(parse_midi_message(&[0xB0, 7]).is_err());
+        assert!(parse_midi_message(&[0xC0]).is_err());
+        assert!(parse_midi_message(&[0xE0, 0]).is_err());
+        assert!(parse_midi_message(&[0xF2, 0]).is_err());
+
+        match parse_midi_message(&[0xB0, 7]) {
+            Err(MidiError::InvalidData(message)) => assert_eq!(message, "truncated message: status 0xb0 needs 3 bytes, got 2"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_normalize_pitch_bend() {
+        assert!((normalize_pitch_bend(16383) - 1.0).abs() < 1e-9);
+        assert!((normalize_pitch_bend(0) + 1.0).abs() < 1e-9);
+        assert_eq!(normalize_pitch_bend(8192), 0.0);
+        assert!(normalize_pitch_bend(8193) > 0.0 && normalize_pitch_bend(8191) < 0.0);
+    }
+
+    #[test]
+    fn test_message_len() {
+        assert_eq!(message_len(0x90), Some(3));
+        assert_eq!(message_len(0xC5), Some(2));
+        assert_eq!(message_len(0xF8), Some(1));
+        assert_eq!(message_len(0xF0), None);
+        assert_eq!(message_len(0xF5), None);
+    }
+}
@@ -1,8 +1,11 @@
+use std::borrow::Cow;
+use std::hash::{Hash, Hasher};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
 
 /// Represents a MIDI event with timestamp and device information
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MidiEvent {
     /// Raw MIDI data bytes
     pub data: Vec<u8>,
@@ -12,13 +15,72 @@ pub struct MidiEvent {
     pub device_name: String,
 }
 
-/// A lock-free ring buffer for sharing MIDI data between C++ and Rust
+/// Resolution (microseconds) at which event timestamps are compared for equality
+pub const EVENT_TIME_QUANTUM_US: u64 = 1_000;
+
+/// Longest device name (in bytes) stored with an event; longer names are truncated
+pub const MAX_DEVICE_NAME_LEN: usize = 256;
+
+/// Cuts a device name to at most `MAX_DEVICE_NAME_LEN` bytes, on a character boundary
+fn truncate_device_name(name: &str) -> &str {
+    if name.len() <= MAX_DEVICE_NAME_LEN {
+        return name;
+    }
+    let mut end = MAX_DEVICE_NAME_LEN;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    log::warn!("Truncating {}-byte device name to {} bytes", name.len(), end);
+    &name[..end]
+}
+
+impl MidiEvent {
+    /// Timestamp rounded down to `EVENT_TIME_QUANTUM_US`
+    fn quantized_timestamp(&self) -> u64 {
+        self.timestamp / EVENT_TIME_QUANTUM_US
+    }
+}
+
+/// Events are equal if they carry the same message from the same device
+/// within the same `EVENT_TIME_QUANTUM_US` slot.
+impl PartialEq for MidiEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+            && self.quantized_timestamp() == other.quantized_timestamp()
+            && self.device_name == other.device_name
+    }
+}
+
+impl Eq for MidiEvent {}
+
+impl Hash for MidiEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.data.hash(state);
+        self.quantized_timestamp().hash(state);
+        self.device_name.hash(state);
+    }
+}
+
+/// Collapses runs of equal consecutive events (e.g. a controller spamming the
+/// same value) into their first event.
+pub fn dedup_consecutive(events: &mut Vec<MidiEvent>) {
+    events.dedup();
+}
+
+/// A ring buffer for sharing MIDI data between C++ and Rust
+///
+/// Any number of threads may write concurrently; each write reserves its
+/// region with a compare-and-swap, and writes are published in reservation
+/// order, so a writer briefly waits for earlier writers still copying. Reads
+/// must come from a single consumer.
 pub struct SharedMidiBuffer {
     /// Pointer to the shared memory region
     buffer: *mut u8,
     /// Total capacity of the buffer in bytes
     capacity: usize,
-    /// Current write position (atomic for thread safety)
+    /// End of the space reserved by writers, possibly not yet written
+    reserve_pos: AtomicUsize,
+    /// End of the written events visible to the reader
     write_pos: AtomicUsize,
     /// Current read position (atomic for thread safety)
     read_pos: AtomicUsize,
@@ -42,6 +104,7 @@ impl SharedMidiBuffer {
         Self {
             buffer,
             capacity,
+            reserve_pos: AtomicUsize::new(0),
             write_pos: AtomicUsize::new(0),
             read_pos: AtomicUsize::new(0),
             owns_buffer: true,
@@ -60,6 +123,7 @@ impl SharedMidiBuffer {
         Self {
             buffer,
             capacity,
+            reserve_pos: AtomicUsize::new(0),
             write_pos: AtomicUsize::new(0),
             read_pos: AtomicUsize::new(0),
             owns_buffer: false,
@@ -76,65 +140,151 @@ impl SharedMidiBuffer {
         self.buffer
     }
     
+    /// Copies `bytes` into the ring starting at `pos`, wrapping at the end.
+    /// Returns the position just past the copied bytes.
+    ///
+    /// # Safety
+    ///
+    /// The caller must have reserved `bytes.len()` free bytes starting at `pos`.
+    unsafe fn copy_in(&self, pos: usize, bytes: &[u8]) -> usize {
+        let first = bytes.len().min(self.capacity - pos);
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.buffer.add(pos), first);
+        std::ptr::copy_nonoverlapping(bytes.as_ptr().add(first), self.buffer, bytes.len() - first);
+        (pos + bytes.len()) % self.capacity
+    }
+    
+    /// Copies `out.len()` bytes out of the ring starting at `pos`, wrapping at the end.
+    /// Returns the position just past the copied bytes.
+    ///
+    /// # Safety
+    ///
+    /// `out.len()` bytes starting at `pos` must belong to a written event.
+    unsafe fn copy_out(&self, pos: usize, out: &mut [u8]) -> usize {
+        let first = out.len().min(self.capacity - pos);
+        std::ptr::copy_nonoverlapping(self.buffer.add(pos), out.as_mut_ptr(), first);
+        std::ptr::copy_nonoverlapping(self.buffer, out.as_mut_ptr().add(first), out.len() - first);
+        (pos + out.len()) % self.capacity
+    }
+    
+    /// Borrows `len` bytes of the ring starting at `pos`, or copies them if
+    /// they wrap around the end. Returns the bytes and the position after them.
+    ///
+    /// # Safety
+    ///
+    /// Same as `copy_out`, and the bytes must not be overwritten while borrowed.
+    unsafe fn view(&self, pos: usize, len: usize) -> (Cow<'_, [u8]>, usize) {
+        if pos + len <= self.capacity {
+            let bytes = std::slice::from_raw_parts(self.buffer.add(pos), len);
+            (Cow::Borrowed(bytes), (pos + len) % self.capacity)
+        } else {
+            let mut bytes = vec![0u8; len];
+            let pos = self.copy_out(pos, &mut bytes);
+            (Cow::Owned(bytes), pos)
+        }
+    }
+    
+    /// Reads a u32 field at `pos`. Returns the value and the position after it.
+    ///
+    /// # Safety
+    ///
+    /// Same as `copy_out`.
+    unsafe fn read_u32(&self, pos: usize) -> (u32, usize) {
+        let mut bytes = [0u8; 4];
+        let pos = self.copy_out(pos, &mut bytes);
+        (u32::from_ne_bytes(bytes), pos)
+    }
+    
+    /// Number of bytes currently occupied by unread events
+    fn used_bytes(&self, read_pos: usize, write_pos: usize) -> usize {
+        if write_pos >= read_pos {
+            write_pos - read_pos
+        } else {
+            self.capacity - (read_pos - write_pos)
+        }
+    }
+    
+    /// Gets the number of bytes that can still be written.
+    ///
+    /// One byte of capacity is always kept free so a full buffer can be told
+    /// apart from an empty one. Each event takes 20 bytes of header plus its
+    /// data and device name.
+    pub fn available_bytes(&self) -> usize {
+        let reserve_pos = self.reserve_pos.load(Ordering::Acquire);
+        let read_pos = self.read_pos.load(Ordering::Acquire);
+        (self.capacity - self.used_bytes(read_pos, reserve_pos)).saturating_sub(1)
+    }
+    
+    /// Gets the number of unread events by walking the length headers
+    /// between the read and write positions.
+    pub fn event_count(&self) -> usize {
+        let mut pos = self.read_pos.load(Ordering::Acquire);
+        let write_pos = self.write_pos.load(Ordering::Acquire);
+        
+        let mut count = 0;
+        while pos != write_pos {
+            unsafe {
+                let (total_size, after) = self.read_u32(pos);
+                pos = (after + total_size as usize) % self.capacity;
+            }
+            count += 1;
+        }
+        count
+    }
+    
     /// Writes a MIDI event to the buffer
     /// 
+    /// Device names longer than `MAX_DEVICE_NAME_LEN` bytes are truncated.
     /// Returns true if the write was successful, false if the buffer is full
     pub fn write(&self, event: &MidiEvent) -> bool {
+        let device_name = truncate_device_name(&event.device_name);
+        
         // Calculate the total size needed for this event
         let data_len = event.data.len();
-        let device_name_len = event.device_name.len();
+        let device_name_len = device_name.len();
         let total_size = 8 + 4 + data_len + 4 + device_name_len;
         
-        // Check if there's enough space in the buffer
-        let write_pos = self.write_pos.load(Ordering::Relaxed);
-        let read_pos = self.read_pos.load(Ordering::Relaxed);
-        
-        let available_space = if write_pos >= read_pos {
-            self.capacity - (write_pos - read_pos)
-        } else {
-            read_pos - write_pos
+        // Reserve space for the event, retrying if another writer got there first
+        let reserved = total_size + 4;
+        let mut start = self.reserve_pos.load(Ordering::Acquire);
+        let end = loop {
+            let read_pos = self.read_pos.load(Ordering::Acquire);
+            let available = (self.capacity - self.used_bytes(read_pos, start)).saturating_sub(1);
+            if reserved > available {
+                return false; // Not enough space
+            }
+            
+            let end = (start + reserved) % self.capacity;
+            match self.reserve_pos.compare_exchange_weak(start, end, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break end,
+                Err(current) => start = current,
+            }
         };
         
-        if total_size + 4 > available_space {
-            return false; // Not enough space
-        }
-        
-        // Write the event to the buffer
+        // Write the event to the reserved region, wrapping around the end as needed
         unsafe {
-            let mut pos = write_pos;
+            let mut pos = start;
             
             // Write total size (for easy skipping when reading)
-            *(self.buffer.add(pos) as *mut u32) = total_size as u32;
-            pos += 4;
+            pos = self.copy_in(pos, &(total_size as u32).to_ne_bytes());
             
             // Write timestamp
-            *(self.buffer.add(pos) as *mut u64) = event.timestamp;
-            pos += 8;
+            pos = self.copy_in(pos, &event.timestamp.to_ne_bytes());
             
             // Write data length and data
-            *(self.buffer.add(pos) as *mut u32) = data_len as u32;
-            pos += 4;
-            std::ptr::copy_nonoverlapping(
-                event.data.as_ptr(),
-                self.buffer.add(pos),
-                data_len
-            );
-            pos += data_len;
+            pos = self.copy_in(pos, &(data_len as u32).to_ne_bytes());
+            pos = self.copy_in(pos, &event.data);
             
             // Write device name length and device name
-            *(self.buffer.add(pos) as *mut u32) = device_name_len as u32;
-            pos += 4;
-            std::ptr::copy_nonoverlapping(
-                event.device_name.as_ptr(),
-                self.buffer.add(pos),
-                device_name_len
-            );
-            pos += device_name_len;
-            
-            // Update write position atomically
-            self.write_pos.store(pos % self.capacity, Ordering::Release);
+            pos = self.copy_in(pos, &(device_name_len as u32).to_ne_bytes());
+            self.copy_in(pos, device_name.as_bytes());
         }
         
+        // Publish in reservation order: wait for writers that reserved earlier
+        while self.write_pos.load(Ordering::Acquire) != start {
+            std::hint::spin_loop();
+        }
+        self.write_pos.store(end, Ordering::Release);
+        
         true
     }
     
@@ -150,40 +300,26 @@ impl SharedMidiBuffer {
         }
         
         unsafe {
-            let mut pos = read_pos;
-            
-            // Read total size
-            let total_size = *(self.buffer.add(pos) as *const u32) as usize;
-            pos += 4;
+            // Skip total size; the fields carry their own lengths
+            let (_, mut pos) = self.read_u32(read_pos);
             
             // Read timestamp
-            let timestamp = *(self.buffer.add(pos) as *const u64);
-            pos += 8;
+            let mut timestamp_bytes = [0u8; 8];
+            pos = self.copy_out(pos, &mut timestamp_bytes);
+            let timestamp = u64::from_ne_bytes(timestamp_bytes);
             
             // Read data
-            let data_len = *(self.buffer.add(pos) as *const u32) as usize;
-            pos += 4;
-            let mut data = vec![0u8; data_len];
-            std::ptr::copy_nonoverlapping(
-                self.buffer.add(pos),
-                data.as_mut_ptr(),
-                data_len
-            );
-            pos += data_len;
+            let (data_len, after) = self.read_u32(pos);
+            let mut data = vec![0u8; data_len as usize];
+            pos = self.copy_out(after, &mut data);
             
             // Read device name
-            let device_name_len = *(self.buffer.add(pos) as *const u32) as usize;
-            pos += 4;
-            let mut device_name_bytes = vec![0u8; device_name_len];
-            std::ptr::copy_nonoverlapping(
-                self.buffer.add(pos),
-                device_name_bytes.as_mut_ptr(),
-                device_name_len
-            );
-            pos += device_name_len;
+            let (device_name_len, after) = self.read_u32(pos);
+            let mut device_name_bytes = vec![0u8; device_name_len as usize];
+            pos = self.copy_out(after, &mut device_name_bytes);
             
             // Update read position atomically
-            self.read_pos.store(pos % self.capacity, Ordering::Release);
+            self.read_pos.store(pos, Ordering::Release);
             
             // Convert device name bytes to string
             let device_name = String::from_utf8_lossy(&device_name_bytes).to_string();
@@ -196,6 +332,68 @@ impl SharedMidiBuffer {
         }
     }
     
+    /// Reads a MIDI event in place, passing its data, timestamp and device name
+    /// to `f` without allocating (unless the event wraps around the end of the
+    /// ring, in which case it is copied). The event is only consumed once `f`
+    /// returns. Invalid UTF-8 in the device name is replaced like `read` does.
+    /// 
+    /// Returns `f`'s result, or None if the buffer is empty
+    pub fn read_with<R>(&self, f: impl FnOnce(&[u8], u64, &str) -> R) -> Option<R> {
+        let read_pos = self.read_pos.load(Ordering::Relaxed);
+        let write_pos = self.write_pos.load(Ordering::Acquire);
+        
+        if read_pos == write_pos {
+            return None; // Buffer is empty
+        }
+        
+        unsafe {
+            // The writer never touches unread bytes, so the views stay valid
+            // until read_pos moves past them
+            let (_, mut pos) = self.read_u32(read_pos);
+            
+            let mut timestamp_bytes = [0u8; 8];
+            pos = self.copy_out(pos, &mut timestamp_bytes);
+            let timestamp = u64::from_ne_bytes(timestamp_bytes);
+            
+            let (data_len, after) = self.read_u32(pos);
+            let (data, after) = self.view(after, data_len as usize);
+            
+            let (device_name_len, after) = self.read_u32(after);
+            let (device_name_bytes, after) = self.view(after, device_name_len as usize);
+            let device_name = String::from_utf8_lossy(&device_name_bytes);
+            
+            let result = f(&data, timestamp, &device_name);
+            
+            self.read_pos.store(after, Ordering::Release);
+            Some(result)
+        }
+    }
+    
+    /// Discards every unread event by moving the read position up to the
+    /// write position, keeping the memory mapping
+    /// 
+    /// Like reads, this must come from the single consumer, but writers need
+    /// not be stopped: events still being written when it runs are kept.
+    pub fn clear(&self) {
+        let write_pos = self.write_pos.load(Ordering::Acquire);
+        self.read_pos.store(write_pos, Ordering::Release);
+    }
+    
+    /// Reads up to `max` events, appending them to `buf`
+    /// 
+    /// Returns the number of events read
+    pub fn read_into(&self, buf: &mut Vec<MidiEvent>, max: usize) -> usize {
+        let mut count = 0;
+        while count < max {
+            match self.read() {
+                Some(event) => buf.push(event),
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+    
     /// Gets the current timestamp in microseconds
     pub fn current_timestamp() -> u64 {
         SystemTime::now()
@@ -218,6 +416,96 @@ impl Drop for SharedMidiBuffer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+    
+    #[test]
+    fn test_concurrent_writers() {
+        use std::sync::Arc;
+        use std::thread;
+        
+        const EVENTS_PER_WRITER: u32 = 5_000;
+        let buffer = Arc::new(SharedMidiBuffer::new(1024));
+        
+        let writers: Vec<_> = ["Writer A", "Writer B"].into_iter().map(|device_name| {
+            let buffer = Arc::clone(&buffer);
+            thread::spawn(move || {
+                for i in 0..EVENTS_PER_WRITER {
+                    let bytes = i.to_le_bytes();
+                    let event = MidiEvent {
+                        data: vec![0x90, bytes[0] & 0x7F, bytes[1] & 0x7F, bytes[0], bytes[1]],
+                        timestamp: i as u64,
+                        device_name: device_name.to_string(),
+                    };
+                    while !buffer.write(&event) {
+                        thread::yield_now();
+                    }
+                }
+            })
+        }).collect();
+        
+        // Each writer's events arrive intact and in order
+        let mut next = HashMap::new();
+        let mut received = 0;
+        while received < 2 * EVENTS_PER_WRITER {
+            let Some(event) = buffer.read() else {
+                thread::yield_now();
+                continue;
+            };
+            let expected = next.entry(event.device_name.clone()).or_insert(0u32);
+            let bytes = expected.to_le_bytes();
+            assert_eq!(event.timestamp, *expected as u64);
+            assert_eq!(event.data, vec![0x90, bytes[0] & 0x7F, bytes[1] & 0x7F, bytes[0], bytes[1]]);
+            *expected += 1;
+            received += 1;
+        }
+        
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(next.len(), 2);
+        assert!(buffer.read().is_none());
+    }
+    
+    #[test]
+    fn test_read_with() {
+        let buffer = SharedMidiBuffer::new(256);
+        for note in [60u8, 64, 67] {
+            let event = MidiEvent { data: vec![0x90, note, 100], timestamp: note as u64, device_name: "Keys".to_string() };
+            assert!(buffer.write(&event));
+        }
+        
+        let start = buffer.buffer as usize;
+        let mut sum = 0u32;
+        while let Some(borrowed) = buffer.read_with(|data, timestamp, device_name| {
+            assert_eq!(device_name, "Keys");
+            assert_eq!(timestamp, data[1] as u64);
+            sum += data[1] as u32;
+            (start..start + buffer.capacity).contains(&(data.as_ptr() as usize))
+        }) {
+            // Handed a view into the ring, not a copy
+            assert!(borrowed);
+        }
+        assert_eq!(sum, 60 + 64 + 67);
+        assert_eq!(buffer.event_count(), 0);
+        
+        // An event wrapping around the end is copied but reads the same
+        let buffer = SharedMidiBuffer::new(64);
+        let event = MidiEvent { data: vec![0xF0; 20], timestamp: 1, device_name: "Wrap".to_string() };
+        assert!(buffer.write(&event));
+        assert!(buffer.read().is_some());
+        assert!(buffer.write(&event));
+        let read = buffer.read_with(|data, timestamp, device_name| (data.to_vec(), timestamp, device_name.to_string()));
+        assert_eq!(read, Some((event.data.clone(), 1, "Wrap".to_string())));
+    }
+    
+    #[test]
+    fn test_truncate_device_name() {
+        assert_eq!(truncate_device_name("Keys"), "Keys");
+        
+        // Cut on a character boundary
+        let name = format!("{}\u{e9}", "a".repeat(MAX_DEVICE_NAME_LEN - 1));
+        assert_eq!(truncate_device_name(&name), "a".repeat(MAX_DEVICE_NAME_LEN - 1));
+    }
     
     #[test]
     fn test_write_read() {
@@ -264,4 +552,149 @@ mod tests {
         // Buffer should be empty now
         assert!(buffer.read().is_none());
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_event_count() {
+        let buffer = SharedMidiBuffer::new(1024);
+        assert_eq!(buffer.event_count(), 0);
+        assert_eq!(buffer.available_bytes(), 1023);
+        
+        for i in 0..5 {
+            let event = MidiEvent {
+                data: vec![0x90, i, 0x7F],
+                timestamp: i as u64,
+                device_name: "Dev".to_string(),
+            };
+            assert!(buffer.write(&event));
+        }
+        assert_eq!(buffer.event_count(), 5);
+        // 4 length + 8 timestamp + 4 + 3 data + 4 + 3 name = 26 bytes per event
+        assert_eq!(buffer.available_bytes(), 1023 - 5 * 26);
+        
+        buffer.read().unwrap();
+        buffer.read().unwrap();
+        assert_eq!(buffer.event_count(), 3);
+        assert_eq!(buffer.available_bytes(), 1023 - 3 * 26);
+    }
+    
+    #[test]
+    fn test_clear() {
+        let buffer = SharedMidiBuffer::new(256);
+        let event = |i: u8| MidiEvent {
+            data: vec![0x90, i, 0x7F],
+            timestamp: i as u64,
+            device_name: "Dev".to_string(),
+        };
+        // Wrap around first, so clearing doesn't just happen to land on 0
+        for i in 0..15 {
+            assert!(buffer.write(&event(i)));
+            assert_eq!(buffer.read().unwrap(), event(i));
+        }
+        for i in 0..5 {
+            assert!(buffer.write(&event(i)));
+        }
+        
+        buffer.clear();
+        assert!(buffer.read().is_none());
+        assert_eq!(buffer.event_count(), 0);
+        assert_eq!(buffer.available_bytes(), 255);
+        
+        assert!(buffer.write(&event(42)));
+        assert_eq!(buffer.read().unwrap(), event(42));
+    }
+    
+    #[test]
+    fn test_wraparound() {
+        // Small buffer so events straddle the end of the ring
+        let buffer = SharedMidiBuffer::new(100);
+        
+        for i in 0..50u8 {
+            let first = MidiEvent {
+                data: vec![0x90, i, 0x7F],
+                timestamp: i as u64,
+                device_name: "Wrap".to_string(),
+            };
+            let second = MidiEvent {
+                data: vec![0x80, i, 0x00],
+                timestamp: i as u64 + 1,
+                device_name: "Wrap".to_string(),
+            };
+            assert!(buffer.write(&first));
+            assert!(buffer.write(&second));
+            assert_eq!(buffer.event_count(), 2);
+            
+            assert_eq!(buffer.read().unwrap().data, first.data);
+            assert_eq!(buffer.event_count(), 1);
+            let read = buffer.read().unwrap();
+            assert_eq!(read.data, second.data);
+            assert_eq!(read.timestamp, second.timestamp);
+            assert_eq!(read.device_name, second.device_name);
+            assert_eq!(buffer.event_count(), 0);
+        }
+    }
+    
+    #[test]
+    fn test_full_buffer() {
+        let buffer = SharedMidiBuffer::new(64);
+        let event = MidiEvent {
+            data: vec![0x90, 0x40, 0x7F],
+            timestamp: 0,
+            device_name: "Full".to_string(),
+        };
+        
+        // 27-byte records: two fit, a third does not
+        assert!(buffer.write(&event));
+        assert!(buffer.write(&event));
+        assert!(!buffer.write(&event));
+        assert_eq!(buffer.event_count(), 2);
+    }
+    
+    #[test]
+    fn test_read_into() {
+        let buffer = SharedMidiBuffer::new(64 * 1024);
+        
+        for i in 0..1000u32 {
+            let event = MidiEvent {
+                data: vec![0x90, (i % 128) as u8, 0x7F],
+                timestamp: i as u64,
+                device_name: "Batch".to_string(),
+            };
+            assert!(buffer.write(&event));
+        }
+        
+        let mut events = Vec::new();
+        assert_eq!(buffer.read_into(&mut events, 600), 600);
+        assert_eq!(buffer.read_into(&mut events, 600), 400);
+        assert_eq!(buffer.read_into(&mut events, 600), 0);
+        
+        assert_eq!(events.len(), 1000);
+        for (i, event) in events.iter().enumerate() {
+            assert_eq!(event.timestamp, i as u64);
+            assert_eq!(event.data, vec![0x90, (i % 128) as u8, 0x7F]);
+        }
+    }
+    
+    #[test]
+    fn test_event_equality_and_dedup() {
+        let cc = |timestamp: u64| MidiEvent {
+            data: vec![0xB0, 7, 100],
+            timestamp,
+            device_name: "Fader".to_string(),
+        };
+        
+        assert_eq!(cc(12_000), cc(12_999));
+        assert_ne!(cc(12_000), cc(13_000));
+        let hash = |event: &MidiEvent| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            event.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&cc(12_000)), hash(&cc(12_500)));
+        
+        let mut changed = cc(12_000);
+        changed.data[2] = 101;
+        let mut events = vec![cc(12_000), cc(12_100), cc(12_200), cc(12_300), changed.clone(), cc(12_400)];
+        dedup_consecutive(&mut events);
+        assert_eq!(events, vec![cc(12_000), changed, cc(12_400)]);
+    }
+}
@@ -0,0 +1,35 @@
+// timestamp.rs
+//! Conversions between the two timestamp units used in the crate: the engine
+//! (`MidiEngine`, clicks, the `*_midi_message` FFI) works in `f64` seconds,
+//! while the shared buffer, MIDI files and the ML contexts use `u64`
+//! microseconds. Every crossing between the two should go through these.
+
+/// Microseconds per second
+pub const MICROS_PER_SEC: f64 = 1_000_000.0;
+
+/// Converts seconds to whole microseconds, rounding to the nearest one.
+/// Negative and NaN inputs become 0.
+pub fn secs_to_micros(secs: f64) -> u64 {
+    (secs * MICROS_PER_SEC).round() as u64
+}
+
+/// Converts microseconds to seconds.
+pub fn micros_to_secs(micros: u64) -> f64 {
+    micros as f64 / MICROS_PER_SEC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(secs_to_micros(1.5), 1_500_000);
+        assert_eq!(micros_to_secs(250_000), 0.25);
+        assert_eq!(secs_to_micros(micros_to_secs(123_456_789)), 123_456_789);
+        // 0.3 is not exact in binary; rounding keeps it from becoming 299_999
+        assert_eq!(secs_to_micros(0.3), 300_000);
+        assert_eq!(secs_to_micros(-1.0), 0);
+        assert_eq!(secs_to_micros(f64::NAN), 0);
+    }
+}
@@ -0,0 +1,113 @@
+// ump.rs
+//! Decoding of MIDI 2.0 Universal MIDI Packets (UMP) into MIDI 1.0 byte messages,
+//! so MIDI 2.0 input can be fed through the existing engine.
+
+/// Number of 32-bit words in a packet, from the message type nibble of its first word.
+pub fn packet_len(first_word: u32) -> usize {
+    match first_word >> 28 {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+/// Downscales a 16-bit MIDI 2.0 velocity to 7 bits.
+fn velocity_to_7bit(velocity: u16) -> u8 {
+    (velocity >> 9) as u8
+}
+
+/// Downscales a 32-bit MIDI 2.0 controller/pressure value to 7 bits.
+fn value_to_7bit(value: u32) -> u8 {
+    (value >> 25) as u8
+}
+
+/// Converts one packet to its MIDI 1.0 byte form.
+///
+/// Supports system real-time/common (type 0x1), MIDI 1.0 channel-voice (type 0x2)
+/// and MIDI 2.0 channel-voice (type 0x4) messages; MIDI 2.0 values are downscaled
+/// to MIDI 1.0 resolution. Returns `None` for other message types, for MIDI 2.0
+/// messages with no MIDI 1.0 equivalent, or if `packet` is too short.
+pub fn to_midi1(packet: &[u32]) -> Option<Vec<u8>> {
+    let word = *packet.first()?;
+    if packet.len() < packet_len(word) {
+        return None;
+    }
+
+    let status = (word >> 16) as u8;
+    let byte1 = (word >> 8) as u8 & 0x7F;
+    let byte2 = word as u8 & 0x7F;
+
+    match word >> 28 {
+        0x1 => match status {
+            0xF1 | 0xF3 => Some(vec![status, byte1]),
+            0xF2 => Some(vec![status, byte1, byte2]),
+            0xF6 | 0xF8..=0xFF => Some(vec![status]),
+            _ => None,
+        },
+        0x2 => match status & 0xF0 {
+            0x80 | 0x90 | 0xA0 | 0xB0 | 0xE0 => Some(vec![status, byte1, byte2]),
+            0xC0 | 0xD0 => Some(vec![status, byte1]),
+            _ => None,
+        },
+        0x4 => {
+            let data = packet[1];
+            match status & 0xF0 {
+                0x80 => Some(vec![status, byte1, velocity_to_7bit((data >> 16) as u16)]),
+                // Every MIDI 2.0 note-on is a note-on, even at velocity 0, but a
+                // MIDI 1.0 note-on with velocity 0 means note-off
+                0x90 => Some(vec![status, byte1, velocity_to_7bit((data >> 16) as u16).max(1)]),
+                0xA0 | 0xB0 => Some(vec![status, byte1, value_to_7bit(data)]),
+                0xC0 => Some(vec![status, (data >> 24) as u8 & 0x7F]),
+                0xD0 => Some(vec![status, value_to_7bit(data)]),
+                0xE0 => {
+                    let bend = data >> 18;
+                    Some(vec![status, bend as u8 & 0x7F, (bend >> 7) as u8 & 0x7F])
+                },
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_midi2_note_on() {
+        // Type 0x4, group 0, note-on channel 3, note 60, 16-bit velocity 0xFFFF
+        let packet = [0x4093_3C00, 0xFFFF_0000];
+        assert_eq!(packet_len(packet[0]), 2);
+        assert_eq!(to_midi1(&packet), Some(vec![0x93, 60, 127]));
+
+        // A quiet note-on must not turn into a note-off
+        assert_eq!(to_midi1(&[0x4090_3C00, 0x0100_0000]), Some(vec![0x90, 60, 1]));
+        assert_eq!(to_midi1(&[0x4090_3C00, 0x0000_0000]), Some(vec![0x90, 60, 1]));
+        // Note-off velocities pass through
+        assert_eq!(to_midi1(&[0x4080_3C00, 0x0000_0000]), Some(vec![0x80, 60, 0]));
+
+        // Truncated packet
+        assert_eq!(to_midi1(&packet[..1]), None);
+    }
+
+    #[test]
+    fn test_midi2_controllers() {
+        // CC 7 at full scale
+        assert_eq!(to_midi1(&[0x40B0_0700, 0xFFFF_FFFF]), Some(vec![0xB0, 7, 127]));
+        // Pitch bend center
+        assert_eq!(to_midi1(&[0x40E0_0000, 0x8000_0000]), Some(vec![0xE0, 0x00, 0x40]));
+        // Program change
+        assert_eq!(to_midi1(&[0x40C1_0000, 0x0500_0000]), Some(vec![0xC1, 5]));
+    }
+
+    #[test]
+    fn test_midi1_packets() {
+        assert_eq!(to_midi1(&[0x2090_3C64]), Some(vec![0x90, 60, 100]));
+        assert_eq!(to_midi1(&[0x20C0_0500]), Some(vec![0xC0, 5]));
+        assert_eq!(to_midi1(&[0x10F8_0000]), Some(vec![0xF8]));
+        assert_eq!(packet_len(0x3000_0000), 2);
+        assert_eq!(to_midi1(&[0x3000_0000, 0]), None);
+    }
+}
@@ -98,13 +98,14 @@ extern "C" {
     bool unload_model(void* context, int model_id);
     void process_midi_message_ml(void* context, const unsigned char* data, int size, const char* device_name);
     int get_num_insights(void* context, int model_id);
-    const char* get_insight_description(void* context, int model_id, int insight_index);
+    char* get_insight_description(void* context, int model_id, int insight_index);
     float get_insight_score(void* context, int model_id, int insight_index);
     int get_insight_type(void* context, int model_id, int insight_index);
-    const char* get_model_description(void* context, int model_id);
-    const char* get_model_version(void* context, int model_id);
-    const char* get_model_author(void* context, int model_id);
-    const char* get_model_license(void* context, int model_id);
+    char* get_model_description(void* context, int model_id);
+    char* get_model_version(void* context, int model_id);
+    char* get_model_author(void* context, int model_id);
+    char* get_model_license(void* context, int model_id);
+    void free_model_string(char* s);
 }
 
 #ifdef __cplusplus
@@ -264,11 +264,13 @@ juce::String MidiAIManager::getModelDescription(int modelId) const
     // Get the description of a model
     if (mlContext != nullptr)
     {
-        const char* description = get_model_description(mlContext, modelId);
+        char* description = get_model_description(mlContext, modelId);
         
         if (description != nullptr)
         {
-            return juce::String(description);
+            juce::String result(description);
+            free_model_string(description);
+            return result;
         }
     }
     
@@ -287,11 +289,13 @@ juce::String MidiAIManager::getModelVersion(int modelId) const
     // Get the version of a model
     if (mlContext != nullptr)
     {
-        const char* version = get_model_version(mlContext, modelId);
+        char* version = get_model_version(mlContext, modelId);
         
         if (version != nullptr)
         {
-            return juce::String(version);
+            juce::String result(version);
+            free_model_string(version);
+            return result;
         }
     }
     
@@ -310,11 +314,13 @@ juce::String MidiAIManager::getModelAuthor(int modelId) const
     // Get the author of a model
     if (mlContext != nullptr)
     {
-        const char* author = get_model_author(mlContext, modelId);
+        char* author = get_model_author(mlContext, modelId);
         
         if (author != nullptr)
         {
-            return juce::String(author);
+            juce::String result(author);
+            free_model_string(author);
+            return result;
         }
     }
     
@@ -333,11 +339,13 @@ juce::String MidiAIManager::getModelLicense(int modelId) const
     // Get the license of a model
     if (mlContext != nullptr)
     {
-        const char* license = get_model_license(mlContext, modelId);
+        char* license = get_model_license(mlContext, modelId);
         
         if (license != nullptr)
         {
-            return juce::String(license);
+            juce::String result(license);
+            free_model_string(license);
+            return result;
         }
     }
     
@@ -378,13 +386,14 @@ void MidiAIManager::timerCallback()
                 for (int i = 0; i < numInsights; ++i)
                 {
                     // Get the insight
-                    const char* description = get_insight_description(mlContext, modelId, i);
+                    char* description = get_insight_description(mlContext, modelId, i);
                     float score = get_insight_score(mlContext, modelId, i);
                     int type = get_insight_type(mlContext, modelId, i);
                     
                     // Create an insight
                     AIInsight insight;
                     insight.description = juce::String(description);
+                    free_model_string(description);
                     insight.score = score;
                     
                     // Convert the type